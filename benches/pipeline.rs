@@ -0,0 +1,110 @@
+//! Benchmarks for each stage of the pipeline on generated programs.
+//!
+//! Run with `cargo bench --profile perf` (see Cargo.toml for why not the
+//! default profile). The workload size defaults to 100k instructions; set
+//! `NASEMBLER_BENCH_INSTRUCTIONS` to measure bigger programs.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use nasembler::elf::ElfGenerator;
+use nasembler::encoder::MachineCodeEncoder;
+use nasembler::parser::Parser;
+use nasembler::prelude::Program;
+use nasembler::tokenizer::Tokenizer;
+
+const DEFAULT_INSTRUCTIONS: usize = 100_000;
+
+/// Instructions in the code workload, from `NASEMBLER_BENCH_INSTRUCTIONS`
+fn instruction_count() -> usize {
+    std::env::var("NASEMBLER_BENCH_INSTRUCTIONS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(DEFAULT_INSTRUCTIONS)
+}
+
+/// A program of `count` instructions, with a label every 16 and label
+/// references mixed in so relocations are exercised too
+fn code_workload(count: usize) -> String {
+    let mut source = String::with_capacity(count * 20);
+    source.push_str("section .data\nmsg db \"Hello, World!\", 10\n\nsection .text\nglobal _start\n_start:\n");
+    for i in 0..count {
+        if i % 16 == 0 {
+            source.push_str(&format!("block_{}:\n", i / 16));
+        }
+        match i % 4 {
+            0 => source.push_str("    mov rax, 1\n"),
+            1 => source.push_str("    lea rsi, [msg]\n"),
+            2 => source.push_str("    mov rdx, 0x1f ; length\n"),
+            _ => source.push_str("    xor rdi, rdi\n"),
+        }
+    }
+    source.push_str("    mov rax, 60\n    xor rdi, rdi\n    syscall\n");
+    source
+}
+
+/// A data section of `count / 4` lines of byte, word and string definitions
+fn data_workload(count: usize) -> String {
+    let mut source = String::with_capacity(count * 10);
+    source.push_str("section .data\n");
+    for i in 0..count / 4 {
+        match i % 3 {
+            0 => source.push_str(&format!("bytes_{} db 1, 2, 3, 4, 5, 6, 7, 8\n", i)),
+            1 => source.push_str(&format!("words_{} dq 0x{:x}, {}\n", i, i, i * 3)),
+            _ => source.push_str(&format!("text_{} db \"entry {}\", 0\n", i, i)),
+        }
+    }
+    source.push_str("section .text\nglobal _start\n_start:\n    mov rax, 60\n    xor rdi, rdi\n    syscall\n");
+    source
+}
+
+fn parse(source: &str) -> Program {
+    let mut tokenizer = Tokenizer::new(source);
+    tokenizer.tokenize();
+    Parser::new(tokenizer.into_tokens()).parse().expect("workload parses")
+}
+
+fn bench_workload(c: &mut Criterion, name: &str, source: &str) {
+    let program = parse(source);
+    let mut group = c.benchmark_group(name);
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(source.len() as u64));
+
+    group.bench_function("tokenize", |b| b.iter(|| {
+        let mut tokenizer = Tokenizer::new(black_box(source));
+        tokenizer.tokenize();
+        tokenizer.into_tokens()
+    }));
+
+    group.bench_function("parse", |b| b.iter_batched(
+        || {
+            let mut tokenizer = Tokenizer::new(source);
+            tokenizer.tokenize();
+            tokenizer.into_tokens()
+        },
+        |tokens| Parser::new(tokens).parse().expect("workload parses"),
+        BatchSize::LargeInput,
+    ));
+
+    group.bench_function("encode", |b| {
+        let encoder = MachineCodeEncoder::new();
+        b.iter(|| encoder.encode_statements(black_box(&program.statements)))
+    });
+
+    group.bench_function("write", |b| b.iter_batched(
+        || program.clone(),
+        |program| ElfGenerator::new(program).with_verbose(false).generate_bytes().expect("workload assembles"),
+        BatchSize::LargeInput,
+    ));
+
+    group.finish();
+}
+
+fn benchmarks(c: &mut Criterion) {
+    let count = instruction_count();
+    bench_workload(c, "code", &code_workload(count));
+    bench_workload(c, "data", &data_workload(count));
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+//! Arbitrary source assembled into an ELF executable
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = nasembler::fuzz_assemble(data);
+});
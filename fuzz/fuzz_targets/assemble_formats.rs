@@ -0,0 +1,8 @@
+//! Arbitrary source assembled into each output format; the first byte picks the format
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = nasembler::fuzz_assemble_any(data);
+});
@@ -0,0 +1,2 @@
+section .bss
+    resb 18446744073709551615
@@ -0,0 +1,2 @@
+section .bss
+    resq 0xffffffffffffffff
@@ -0,0 +1,72 @@
+/*
+ * nasembler - C interface
+ *
+ * Build the shared library with:
+ *     cargo build --profile bindings --features ffi
+ * and link against target/bindings/libnasembler.so (or .dylib / .dll). The
+ * bindings profile is the release profile with unwinding panics, which
+ * NASEMBLER_STATUS_PANIC relies on; a build with panic = "abort", such as
+ * --release, takes the host process down instead.
+ */
+#ifndef NASEMBLER_H
+#define NASEMBLER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Output formats */
+#define NASEMBLER_FORMAT_ELF 0
+#define NASEMBLER_FORMAT_BIN 1
+#define NASEMBLER_FORMAT_HEX 2
+#define NASEMBLER_FORMAT_ELF32 3
+#define NASEMBLER_FORMAT_OBJ 4
+
+/* Result status codes */
+#define NASEMBLER_STATUS_OK               0
+#define NASEMBLER_STATUS_ERROR            1
+#define NASEMBLER_STATUS_INVALID_ARGUMENT 2
+#define NASEMBLER_STATUS_PANIC            3  /* only with unwinding panics, see above */
+
+/* Diagnostic severities */
+#define NASEMBLER_SEVERITY_FATAL   0
+#define NASEMBLER_SEVERITY_ERROR   1
+#define NASEMBLER_SEVERITY_WARNING 2
+#define NASEMBLER_SEVERITY_INFO    3
+
+typedef struct nasembler_diagnostic {
+    int severity;
+    char *message;
+    char *file;      /* NULL when the diagnostic has no source location */
+    size_t line;
+    size_t column;
+} nasembler_diagnostic;
+
+typedef struct nasembler_result {
+    int status;
+    uint8_t *output;             /* NULL when nothing was produced */
+    size_t output_len;
+    nasembler_diagnostic *diagnostics;
+    size_t diagnostic_count;
+} nasembler_result;
+
+/*
+ * Assemble a NUL-terminated source string. file_name may be NULL and is only
+ * used in diagnostics. Never returns NULL; release with nasembler_result_free.
+ */
+nasembler_result *nasembler_assemble(const char *source, const char *file_name, int format);
+
+/* Release a result returned by nasembler_assemble. Accepts NULL. */
+void nasembler_result_free(nasembler_result *result);
+
+/* Library version as a static string */
+const char *nasembler_version(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* NASEMBLER_H */
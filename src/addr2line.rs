@@ -0,0 +1,221 @@
+//! Mapping addresses in a binary back to labels and source lines.
+//!
+//! Reads the `.symtab` and `.debug_line` sections of an ELF32 or ELF64 file:
+//! executables assembled with `-g`, relocatable objects (which always have a
+//! symbol table), and programs linked from them. The address is looked up as
+//! the program sees it; in an object, where every section starts at 0, it is
+//! an offset into the code.
+//!
+//! ```no_run
+//! use nasembler::addr2line::lookup;
+//!
+//! let binary = std::fs::read("program").unwrap();
+//! let location = lookup(&binary, 0x401005).unwrap();
+//! println!("{}", location);
+//! ```
+
+use std::fmt;
+
+use crate::dwarf;
+use crate::elf::{read_u16, read_u32, read_u64};
+use crate::relocatable::{ET_REL, SHF_EXECINSTR, SHN_UNDEF, SHT_SYMTAB, STT_SECTION};
+
+const STT_FILE: u8 = 4;
+const SHN_LORESERVE: u16 = 0xFF00;
+
+/// Why a binary couldn't be searched
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LookupError {
+    /// The file isn't a little-endian ELF file, or its headers are cut short
+    InvalidBinary(String),
+    /// The file has neither a symbol table nor a line table
+    NoDebugInfo,
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LookupError::InvalidBinary(reason) => write!(f, "Can't read binary: {}", reason),
+            LookupError::NoDebugInfo => write!(f, "The binary has no symbols or line numbers (assemble it with -g)"),
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+/// What is known about an address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    /// The label at or before the address, and the distance from it
+    pub symbol: Option<(String, u64)>,
+    /// Source file and line of the instruction at the address
+    pub line: Option<(String, u64)>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.symbol {
+            Some((name, 0)) => write!(f, "{}", name)?,
+            Some((name, offset)) => write!(f, "{}+{:#x}", name, offset)?,
+            None => write!(f, "??")?,
+        }
+        match &self.line {
+            Some((file, line)) => write!(f, " at {}:{}", file, line),
+            None => write!(f, " at ??:0"),
+        }
+    }
+}
+
+/// The fields of a section header the lookup uses
+struct SectionHeader {
+    name: u32,
+    sh_type: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+}
+
+/// An ELF file's sections
+struct Binary<'a> {
+    bytes: &'a [u8],
+    elf64: bool,
+    relocatable: bool,
+    sections: Vec<SectionHeader>,
+    names_index: usize,
+}
+
+impl<'a> Binary<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Self, LookupError> {
+        let invalid = |reason: &str| LookupError::InvalidBinary(reason.to_string());
+        if bytes.get(..4) != Some(b"\x7FELF".as_slice()) {
+            return Err(invalid("not an ELF file"));
+        }
+        if bytes.get(5) != Some(&1) {
+            return Err(invalid("not a little-endian ELF file"));
+        }
+        let elf64 = match bytes.get(4) {
+            Some(1) => false,
+            Some(2) => true,
+            _ => return Err(invalid("unknown ELF class")),
+        };
+
+        let truncated = || invalid("truncated or malformed headers");
+        let (shoff, shentsize, shnum, shstrndx) = if elf64 {
+            (read_u64(bytes, 40), read_u16(bytes, 58), read_u16(bytes, 60), read_u16(bytes, 62))
+        } else {
+            (read_u32(bytes, 32).map(u64::from), read_u16(bytes, 46), read_u16(bytes, 48), read_u16(bytes, 50))
+        };
+        let (shoff, shentsize) = (shoff.ok_or_else(truncated)? as usize, shentsize.ok_or_else(truncated)? as usize);
+        let sections = (0..shnum.ok_or_else(truncated)? as usize)
+            .map(|index| {
+                let header = shoff.checked_add(index.checked_mul(shentsize)?)?;
+                Some(if elf64 {
+                    SectionHeader {
+                        name: read_u32(bytes, header)?,
+                        sh_type: read_u32(bytes, header + 4)?,
+                        flags: read_u64(bytes, header + 8)?,
+                        offset: read_u64(bytes, header + 24)?,
+                        size: read_u64(bytes, header + 32)?,
+                        link: read_u32(bytes, header + 40)?,
+                    }
+                } else {
+                    SectionHeader {
+                        name: read_u32(bytes, header)?,
+                        sh_type: read_u32(bytes, header + 4)?,
+                        flags: u64::from(read_u32(bytes, header + 8)?),
+                        offset: u64::from(read_u32(bytes, header + 16)?),
+                        size: u64::from(read_u32(bytes, header + 20)?),
+                        link: read_u32(bytes, header + 24)?,
+                    }
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(truncated)?;
+
+        let relocatable = read_u16(bytes, 16) == Some(ET_REL);
+        Ok(Self { bytes, elf64, relocatable, sections, names_index: shstrndx.ok_or_else(truncated)? as usize })
+    }
+
+    fn contents(&self, section: &SectionHeader) -> Option<&'a [u8]> {
+        let start = usize::try_from(section.offset).ok()?;
+        self.bytes.get(start..start.checked_add(usize::try_from(section.size).ok()?)?)
+    }
+
+    fn string(&self, table: usize, offset: u32) -> Option<String> {
+        let strings = self.contents(self.sections.get(table)?)?;
+        let tail = strings.get(offset as usize..)?;
+        let end = tail.iter().position(|&byte| byte == 0)?;
+        Some(String::from_utf8_lossy(&tail[..end]).into_owned())
+    }
+
+    fn section_named(&self, name: &str) -> Option<&SectionHeader> {
+        self.sections.iter().find(|section| self.string(self.names_index, section.name).as_deref() == Some(name))
+    }
+
+    /// Named symbols defined in a section, as name offset, value and size.
+    /// In objects only symbols in code count, since data sections overlap it.
+    fn defined_symbols(&self, symtab: &SectionHeader) -> Option<Vec<(u32, u64, u64)>> {
+        let table = self.contents(symtab)?;
+        let entry_size = if self.elf64 { 24 } else { 16 };
+        let mut symbols = Vec::new();
+        for entry in table.chunks_exact(entry_size).skip(1) {
+            let (name, info, shndx, value, size) = if self.elf64 {
+                (read_u32(entry, 0)?, entry[4], read_u16(entry, 6)?, read_u64(entry, 8)?, read_u64(entry, 16)?)
+            } else {
+                (read_u32(entry, 0)?, entry[12], read_u16(entry, 14)?, u64::from(read_u32(entry, 4)?), u64::from(read_u32(entry, 8)?))
+            };
+            let kind = info & 0xF;
+            if name == 0 || kind == STT_SECTION || kind == STT_FILE || shndx == SHN_UNDEF || shndx >= SHN_LORESERVE {
+                continue;
+            }
+            if self.relocatable && self.sections.get(usize::from(shndx)).is_none_or(|section| section.flags & SHF_EXECINSTR == 0) {
+                continue;
+            }
+            symbols.push((name, value, size));
+        }
+        Some(symbols)
+    }
+
+    /// The closest symbol at or below `address`, skipping sections and files.
+    /// A symbol with a size only covers addresses inside it.
+    fn symbol_at(&self, symtab: &SectionHeader, address: u64) -> Option<(String, u64)> {
+        let mut best: Option<(u32, u64)> = None;
+        for (name, value, size) in self.defined_symbols(symtab)? {
+            if value > address || (size > 0 && address - value >= size) {
+                continue;
+            }
+            if best.is_none_or(|(_, best_value)| value > best_value) {
+                best = Some((name, value));
+            }
+        }
+        let (name, value) = best?;
+        Some((self.string(symtab.link as usize, name)?, address - value))
+    }
+}
+
+/// Find the label and source line of `address` in the ELF file `binary`
+pub fn lookup(binary: &[u8], address: u64) -> Result<Location, LookupError> {
+    let binary = Binary::parse(binary)?;
+    let symtab = binary.sections.iter().find(|section| section.sh_type == SHT_SYMTAB);
+    let debug_line = binary.section_named(".debug_line").and_then(|section| binary.contents(section));
+    if symtab.is_none() && debug_line.is_none() {
+        return Err(LookupError::NoDebugInfo);
+    }
+
+    Ok(Location {
+        symbol: symtab.and_then(|symtab| binary.symbol_at(symtab, address)),
+        line: debug_line.and_then(|section| dwarf::find_line(section, address)),
+    })
+}
+
+/// The labels in the symbol table of the ELF file `binary`, with their addresses
+pub fn symbols(binary: &[u8]) -> Result<Vec<(String, u64)>, LookupError> {
+    let binary = Binary::parse(binary)?;
+    let symtab = binary.sections.iter().find(|section| section.sh_type == SHT_SYMTAB).ok_or(LookupError::NoDebugInfo)?;
+    let symbols = binary.defined_symbols(symtab).unwrap_or_default();
+    Ok(symbols.into_iter()
+        .filter_map(|(name, value, _)| Some((binary.string(symtab.link as usize, name)?, value)))
+        .collect())
+}
@@ -0,0 +1,697 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use clap::ValueEnum;
+
+use crate::tokenizer::{Token, TokenType, Tokenizer};
+use crate::parser::Parser;
+use crate::parser::opcodes::OpcodeTable;
+use crate::parser::ast::Program;
+use crate::elf::{ElfGenerator, Fill, HeaderOptions, DEFAULT_MAX_SECTION_SIZE};
+use crate::binary::BinaryWriter;
+use crate::relocatable::RelocatableWriter;
+use crate::object::ObjectWriter;
+use crate::target::Target;
+use crate::render::MessageFormat;
+use crate::shellcode;
+use crate::size::SizeReport;
+use crate::symbol::Symbol;
+use crate::source_map::SourceMap;
+use crate::error::{self, AssemblerError, ElfError, ErrorCollector, Error, ErrorType, ErrorDetail, ErrorSeverity, SourceLocation};
+
+/// Output format for the assembled code
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum Format {
+    /// Raw binary output
+    Bin,
+    /// Intel hex format
+    Hex,
+    /// ELF executable (default)
+    Elf,
+    /// 32-bit i386 ELF executable; code starts in `bits 32`
+    Elf32,
+    /// x86-64 relocatable ELF object (`.o`), for linking with ld or cc
+    Obj,
+}
+
+impl Format {
+    /// File extension used when no output path is given (empty for ELF executables)
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Bin => "bin",
+            Format::Hex => "hex",
+            Format::Elf | Format::Elf32 => "",
+            Format::Obj => "o",
+        }
+    }
+
+    /// Code mode (16, 32 or 64) a program starts in before any `bits` directive
+    pub fn default_bits(&self) -> u16 {
+        match self {
+            Format::Elf32 => 32,
+            _ => 64,
+        }
+    }
+}
+
+/// Builder-style front end driving the whole assembly pipeline
+///
+/// ```no_run
+/// use nasembler::{Assembler, Format};
+///
+/// let mut assembler = Assembler::new()
+///     .format(Format::Elf)
+///     .entry("_start")
+///     .define("DEBUG", 1);
+/// assembler.assemble_str("section .text\n_start:\n    syscall\n", "out").unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Assembler {
+    format: Format,
+    target: Option<Target>,
+    entry: String,
+    defines: HashMap<String, i64>,
+    include_paths: Vec<PathBuf>,
+    file_name: String,
+    continue_on_errors: bool,
+    verbose: bool,
+    strict_sections: bool,
+    strict_case: bool,
+    max_section_size: u64,
+    debug_info: bool,
+    comment: bool,
+    /// Byte values no instruction may encode to
+    forbidden_bytes: Vec<u8>,
+    report_sizes: bool,
+    /// Sizes of the last output, when `report_sizes` is set
+    size_report: Option<SizeReport>,
+    /// OS/ABI, ABI version and flags of ELF output
+    elf_header: HeaderOptions,
+    /// What `align` pads code and data with when it names no fill
+    code_fill: Fill,
+    data_fill: Fill,
+    map_source: bool,
+    /// Source map of the last output, when `map_source` is set
+    source_map: Option<SourceMap>,
+    /// Forms operands are checked against, the built-in table unless set
+    opcode_table: Option<Arc<OpcodeTable>>,
+    error_collector: ErrorCollector,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Assembler {
+    /// Create an assembler with the default settings (ELF output, `_start` entry point)
+    pub fn new() -> Self {
+        Self {
+            format: Format::Elf,
+            target: None,
+            entry: "_start".to_string(),
+            defines: HashMap::new(),
+            include_paths: Vec::new(),
+            file_name: "unknown".to_string(),
+            continue_on_errors: true,
+            verbose: false,
+            strict_sections: false,
+            strict_case: false,
+            max_section_size: DEFAULT_MAX_SECTION_SIZE,
+            debug_info: false,
+            comment: true,
+            forbidden_bytes: Vec::new(),
+            report_sizes: false,
+            size_report: None,
+            elf_header: HeaderOptions::default(),
+            code_fill: Fill::Nop,
+            data_fill: Fill::Byte(0),
+            map_source: false,
+            source_map: None,
+            opcode_table: None,
+            error_collector: ErrorCollector::new(),
+        }
+    }
+
+    /// Set the output format
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set the target, which selects the code mode, load addresses and
+    /// output format together. Call [`format`](Self::format) afterwards to
+    /// pick a different format for the same target.
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = Some(target);
+        self.format = target.format();
+        self
+    }
+
+    /// Set the symbol used as the program entry point
+    pub fn entry(mut self, symbol: &str) -> Self {
+        self.entry = symbol.to_string();
+        self
+    }
+
+    /// Predefine an absolute symbol, as if `name equ value` appeared in the source
+    pub fn define(mut self, name: &str, value: i64) -> Self {
+        self.defines.insert(name.to_string(), value);
+        self
+    }
+
+    /// Add a directory to search for `%include` files that aren't next to
+    /// the file including them; directories are searched in the order added
+    pub fn include_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    /// Set the file name used in diagnostics for `assemble_str`
+    pub fn file_name(mut self, file_name: &str) -> Self {
+        self.file_name = file_name.to_string();
+        self
+    }
+
+    /// Set whether to keep collecting errors after the first one
+    pub fn continue_on_errors(mut self, continue_on_errors: bool) -> Self {
+        self.continue_on_errors = continue_on_errors;
+        self
+    }
+
+    /// Set whether the output generators print progress messages
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Set whether data in .bss and instructions outside .text are errors
+    /// instead of warnings
+    pub fn strict_sections(mut self, strict: bool) -> Self {
+        self.strict_sections = strict;
+        self
+    }
+
+    /// Set whether mnemonics, registers and directives that aren't lowercase
+    /// are errors
+    pub fn strict_case(mut self, strict: bool) -> Self {
+        self.strict_case = strict;
+        self
+    }
+
+    /// Set the largest size in bytes a section may grow to before assembly fails
+    pub fn max_section_size(mut self, bytes: u64) -> Self {
+        self.max_section_size = bytes;
+        self
+    }
+
+    /// Set what `align` pads .text with when it names no fill: NOPs by
+    /// default, or e.g. `Fill::Byte(INT3)` so stray jumps into padding trap
+    pub fn code_fill(mut self, fill: Fill) -> Self {
+        self.code_fill = fill;
+        self
+    }
+
+    /// Set what `align` pads sections other than .text with when it names no
+    /// fill; zero bytes by default
+    pub fn data_fill(mut self, fill: Fill) -> Self {
+        self.data_fill = fill;
+        self
+    }
+
+    /// Set whether ELF executables carry a symbol table and source line
+    /// numbers, for debuggers and `nasembler addr2line`
+    pub fn debug_info(mut self, debug_info: bool) -> Self {
+        self.debug_info = debug_info;
+        self
+    }
+
+    /// Make it an error for any instruction in .text to contain one of
+    /// `bytes`, as shellcode that is copied like a string has to avoid 0x00
+    pub fn forbid_bytes(mut self, bytes: &[u8]) -> Self {
+        self.forbidden_bytes = bytes.to_vec();
+        self
+    }
+
+    /// Set whether generating output records how many bytes each section and
+    /// label accounts for, available afterwards from [`size_report`](Self::size_report)
+    pub fn report_sizes(mut self, report_sizes: bool) -> Self {
+        self.report_sizes = report_sizes;
+        self
+    }
+
+    /// Check instruction operands against `table` instead of the built-in
+    /// opcode table, e.g. one from [`OpcodeTable::layered`]
+    pub fn opcode_table(mut self, table: OpcodeTable) -> Self {
+        self.opcode_table = Some(Arc::new(table));
+        self
+    }
+
+    /// Set whether ELF executables and objects get a .comment section naming
+    /// nasembler and its version, as compilers add; on by default
+    pub fn comment(mut self, comment: bool) -> Self {
+        self.comment = comment;
+        self
+    }
+
+    /// Set whether generating output records which source line each byte
+    /// came from, available afterwards from [`source_map`](Self::source_map)
+    pub fn map_source(mut self, map_source: bool) -> Self {
+        self.map_source = map_source;
+        self
+    }
+
+    /// Set the `EI_OSABI` byte of ELF output, e.g.
+    /// [`ELFOSABI_FREEBSD`](crate::elf::ELFOSABI_FREEBSD); the default is System V
+    pub fn os_abi(mut self, os_abi: u8) -> Self {
+        self.elf_header.os_abi = os_abi;
+        self
+    }
+
+    /// Set the `EI_ABIVERSION` byte of ELF output
+    pub fn abi_version(mut self, abi_version: u8) -> Self {
+        self.elf_header.abi_version = abi_version;
+        self
+    }
+
+    /// Set the `e_flags` field of ELF output
+    pub fn elf_flags(mut self, flags: u32) -> Self {
+        self.elf_header.flags = flags;
+        self
+    }
+
+    /// Set how many source lines diagnostics show around the offending line
+    pub fn context_lines(mut self, lines: usize) -> Self {
+        self.error_collector.set_context_lines(lines);
+        self
+    }
+
+    /// Set how diagnostics are formatted by `errors().display_errors()`
+    pub fn message_format(mut self, message_format: MessageFormat) -> Self {
+        self.error_collector.set_message_format(message_format);
+        self
+    }
+
+    /// Draw diagnostics with plain ASCII instead of box-drawing characters and emoji
+    pub fn ascii_diagnostics(mut self, ascii: bool) -> Self {
+        self.error_collector.set_ascii(ascii);
+        self
+    }
+
+    /// Set whether warnings fail the assembly like errors do
+    pub fn warnings_as_errors(mut self, warnings_as_errors: bool) -> Self {
+        self.error_collector.set_warnings_as_errors(warnings_as_errors);
+        self
+    }
+
+    /// Get the configured output format
+    pub fn get_format(&self) -> Format {
+        self.format
+    }
+
+    /// Get the configured target, if one was set
+    pub fn get_target(&self) -> Option<Target> {
+        self.target
+    }
+
+    /// Code mode programs start in: the target's, or else the format's
+    fn bits(&self) -> u16 {
+        self.target.map_or(self.format.default_bits(), |target| target.bits())
+    }
+
+    /// Load addresses of .text and the data segment, if they differ from the
+    /// ELF generator's defaults. Flat binaries start at 0 unless the target
+    /// says otherwise, and place their data after the code.
+    fn addresses(&self) -> Option<(u64, Option<u64>)> {
+        match self.target {
+            Some(target) => Some(target.addresses()),
+            None if self.format == Format::Bin => Some((0, None)),
+            None => None,
+        }
+    }
+
+    /// Sizes of the sections and labels in the last output, if
+    /// [`report_sizes`](Self::report_sizes) is set
+    pub fn size_report(&self) -> Option<&SizeReport> {
+        self.size_report.as_ref()
+    }
+
+    /// Byte ranges of the last output and the statements they came from, if
+    /// [`map_source`](Self::map_source) is set
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+
+    /// Get the configured include directories
+    pub fn include_paths(&self) -> &[PathBuf] {
+        &self.include_paths
+    }
+
+    /// Get the errors and warnings collected so far
+    pub fn errors(&self) -> &ErrorCollector {
+        &self.error_collector
+    }
+
+    /// Derive the default output path for an input file from its stem and the output format
+    pub fn default_output_path(&self, input: &str) -> String {
+        let path = PathBuf::from(input);
+        let stem = path.file_stem().unwrap_or_default();
+        let extension = self.target.and_then(|target| target.extension()).unwrap_or(self.format.extension());
+        if extension.is_empty() {
+            format!("{}", stem.to_string_lossy())
+        } else {
+            format!("{}.{}", stem.to_string_lossy(), extension)
+        }
+    }
+
+    /// Read, assemble and write `path` to `output_path`
+    pub fn assemble_file<P: AsRef<Path>>(&mut self, path: P, output_path: &str) -> Result<(), AssemblerError> {
+        let source = self.read_source(path)?;
+        self.assemble_str(&source, output_path)
+    }
+
+    /// Assemble `source` and write the result to `output_path`
+    pub fn assemble_str(&mut self, source: &str, output_path: &str) -> Result<(), AssemblerError> {
+        let program = self.assemble_program(source)?;
+        self.generate(program, output_path)
+    }
+
+    /// Assemble `source` entirely in memory, returning the output bytes
+    pub fn assemble(&mut self, source: &str) -> Result<Vec<u8>, AssemblerError> {
+        let program = self.assemble_program(source)?;
+        self.generate_bytes(program)
+    }
+
+    /// Tokenize and parse `source`, failing if any errors were collected
+    pub fn assemble_program(&mut self, source: &str) -> Result<Program, AssemblerError> {
+        let tokens = self.tokenize(source);
+        let program = self.parse(tokens)?;
+
+        if self.error_collector.has_errors() {
+            return Err(AssemblerError::Diagnostics { errors: self.error_collector.error_count() });
+        }
+
+        Ok(program)
+    }
+
+    /// Load a source file, recording a file error on failure
+    pub fn read_source<P: AsRef<Path>>(&mut self, path: P) -> Result<String, AssemblerError> {
+        let path = path.as_ref();
+        self.file_name = path.to_string_lossy().to_string();
+
+        fs::read_to_string(path).map_err(|source| {
+            let message = format!("Failed to read input file: {}", source);
+            self.error_collector.add_error(error::file_error(message, &self.file_name));
+            AssemblerError::Io { path: self.file_name.clone(), source }
+        })
+    }
+
+    /// Tokenize source text, recording tokenizer diagnostics in the collector.
+    /// `%include "file"` lines are replaced by the tokens of that file.
+    pub fn tokenize<'src>(&mut self, source: &'src str) -> Vec<Token<'src>> {
+        let file_name = self.file_name.clone();
+        let mut including = Path::new(&file_name).canonicalize().into_iter().collect();
+        self.tokenize_file(&file_name, source, &mut including)
+    }
+
+    /// Tokenize `source`, read from `file_name`, inside the includes of `including`
+    fn tokenize_file<'src>(&mut self, file_name: &str, source: &'src str, including: &mut Vec<PathBuf>) -> Vec<Token<'src>> {
+        self.error_collector.add_source(file_name, source);
+        let mut tokenizer = Tokenizer::new(source).with_strict_case(self.strict_case);
+        tokenizer.tokenize();
+
+        for diagnostic in tokenizer.diagnostics() {
+            let location = self.error_collector
+                .source_location(file_name, diagnostic.line, diagnostic.column)
+                .with_span(diagnostic.span);
+            let mut detail = ErrorDetail::new(diagnostic.message.clone());
+            detail.help = diagnostic.help.clone();
+            self.error_collector.add_error(Error::new(diagnostic.error_type.clone(), detail).with_location(location));
+        }
+        self.error_collector.add_suppressions(file_name, tokenizer.suppressions());
+
+        let tokens = tokenizer.into_tokens();
+        if !tokens.iter().any(is_include) {
+            return tokens;
+        }
+        let mut expanded = Vec::with_capacity(tokens.len());
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(token) = tokens.next() {
+            if !is_include(&token) {
+                expanded.push(token);
+                continue;
+            }
+            let Some(name) = tokens.next_if(|next| next.token_type == TokenType::StringLiteral) else {
+                self.include_error(file_name, &token, "Expected a file name in quotes after %include, as in %include \"defs.inc\"".to_string());
+                continue;
+            };
+            // Statements from the file are reported at the line including it
+            for included in self.tokenize_include(file_name, &name.value, &token, including) {
+                expanded.push(Token { line: token.line, column: token.column, span: token.span, ..included });
+            }
+        }
+        expanded
+    }
+
+    /// The tokens of the file `%include "name"` refers to, with no end of file
+    fn tokenize_include(&mut self, file_name: &str, name: &str, token: &Token<'_>, including: &mut Vec<PathBuf>) -> Vec<Token<'static>> {
+        let directory = Path::new(file_name).parent().unwrap_or(Path::new(""));
+        let Some(path) = std::iter::once(directory)
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+            .map(|directory| directory.join(name))
+            .find(|path| path.is_file())
+        else {
+            let searched = if self.include_paths.is_empty() { "" } else { " or in the include paths" };
+            self.include_error(file_name, token, format!(
+                "Can't find '{}' to include: it isn't next to {}{}. Add its directory with -I", name, file_name, searched));
+            return Vec::new();
+        };
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if including.contains(&canonical) {
+            self.include_error(file_name, token, format!("'{}' includes itself, through %include \"{}\"", path.display(), name));
+            return Vec::new();
+        }
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(err) => {
+                self.include_error(file_name, token, format!("Failed to read include file '{}': {}", path.display(), err));
+                return Vec::new();
+            },
+        };
+
+        including.push(canonical);
+        let tokens = self.tokenize_file(&path.to_string_lossy(), &source, including)
+            .into_iter()
+            .filter(|token| token.token_type != TokenType::EOF)
+            .map(|token| Token { value: Cow::Owned(token.value.into_owned()), ..token })
+            .collect();
+        including.pop();
+        tokens
+    }
+
+    /// Report a problem with the `%include` at `token`
+    fn include_error(&mut self, file_name: &str, token: &Token<'_>, message: String) {
+        let location = self.error_collector.source_location(file_name, token.line, token.column).with_span(token.span);
+        self.error_collector.add_error(Error::new(ErrorType::FileError, ErrorDetail::new(message)).with_location(location));
+    }
+
+    /// Parse tokens into a program, merging parser diagnostics into the collector
+    pub fn parse(&mut self, tokens: Vec<Token<'_>>) -> Result<Program, AssemblerError> {
+        let mut parser = Parser::new(tokens)
+            .with_error_collector(std::mem::take(&mut self.error_collector))
+            .with_file_name(self.file_name.clone())
+            .with_continue_on_errors(self.continue_on_errors)
+            .with_bits(self.bits())
+            .with_relocatable(self.format == Format::Obj)
+            .with_max_section_size(self.max_section_size)
+            .with_defines(&self.defines);
+        if let Some(table) = &self.opcode_table {
+            parser = parser.with_opcode_table(Arc::clone(table));
+        }
+
+        let result = parser.parse();
+
+        if let Some(collector) = parser.take_error_collector() {
+            self.error_collector = collector;
+        }
+
+        match result {
+            Ok(program) => Ok(program),
+            // If we're continuing on errors, use an empty program so the errors can be reported
+            Err(_) if self.continue_on_errors => Ok(Program::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Generate output in the configured format and write it to `output_path`
+    pub fn generate(&mut self, program: Program, output_path: &str) -> Result<(), AssemblerError> {
+        let bytes = self.generate_bytes(program)?;
+
+        fs::write(output_path, bytes).map_err(|source| {
+            self.record_error(ErrorType::FileError, AssemblerError::Io { path: output_path.to_string(), source })
+        })?;
+
+        // ELF output is an executable, so mark it as one
+        #[cfg(unix)]
+        if matches!(self.format, Format::Elf | Format::Elf32) {
+            use std::os::unix::fs::PermissionsExt;
+            let result = fs::metadata(output_path).and_then(|metadata| {
+                let mut perms = metadata.permissions();
+                perms.set_mode(perms.mode() | 0o755);
+                fs::set_permissions(output_path, perms)
+            });
+            result.map_err(|source| {
+                self.record_error(ErrorType::FileError, AssemblerError::Io { path: output_path.to_string(), source })
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Generate output in the configured format into an in-memory buffer
+    pub fn generate_bytes(&mut self, program: Program) -> Result<Vec<u8>, AssemblerError> {
+        let mut buffer = Vec::new();
+        self.generate_to(program, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Generate output in the configured format into any writer
+    pub fn generate_to<W: Write>(&mut self, program: Program, writer: &mut W) -> Result<(), AssemblerError> {
+        let constants = shellcode::constants(&program);
+        let mut object_writer = self.object_writer(program)
+            .map_err(|err| self.record_error(ErrorType::Other, err))?;
+
+        let bytes = self.write_object(object_writer.as_mut(), &constants)
+            .map_err(|err| self.record_error(ErrorType::ElfWriteError, err))?;
+
+        writer.write_all(&bytes)
+            .map_err(|source| self.record_error(ErrorType::ElfWriteError, ElfError::Io(source).into()))
+    }
+
+    /// Pick the object writer backend for the configured output format
+    pub fn object_writer(&self, program: Program) -> Result<Box<dyn ObjectWriter>, AssemblerError> {
+        let mut generator = ElfGenerator::new(program)
+            .with_bits(self.bits())
+            .with_elf32(self.format == Format::Elf32)
+            .with_entry(&self.entry)
+            .with_verbose(self.verbose)
+            .with_ascii_decorations(self.error_collector.ascii())
+            .with_strict_sections(self.strict_sections)
+            .with_max_section_size(self.max_section_size)
+            .with_header(self.elf_header)
+            .with_fill(self.code_fill, self.data_fill)
+            .with_comment(self.comment);
+        if let Some((text_address, data_address)) = self.addresses() {
+            generator = generator.with_addresses(text_address, data_address.unwrap_or(text_address));
+        }
+        if self.debug_info {
+            generator = generator.with_debug_info(&self.file_name);
+        }
+
+        match self.format {
+            Format::Elf | Format::Elf32 => Ok(Box::new(generator)),
+            Format::Bin => Ok(Box::new(BinaryWriter::new(generator))),
+            Format::Obj => Ok(Box::new(RelocatableWriter::new(generator))),
+            // Placeholder for the hex output format
+            Format::Hex => Err(AssemblerError::UnsupportedFormat(self.format)),
+        }
+    }
+
+    /// Drive a backend through all steps, making predefined symbols visible to
+    /// relocations. `constants` are the program's equ names.
+    fn write_object(&mut self, object_writer: &mut dyn ObjectWriter, constants: &[Symbol]) -> Result<Vec<u8>, AssemblerError> {
+        object_writer.layout_sections()?;
+
+        for warning in object_writer.warnings() {
+            let warning = Error::new(ErrorType::SectionError, ErrorDetail::new(warning))
+                .with_severity(ErrorSeverity::Warning);
+            self.error_collector.add_error(warning);
+        }
+        if self.error_collector.has_errors() {
+            return Err(AssemblerError::Diagnostics { errors: self.error_collector.error_count() });
+        }
+
+        for (name, value) in &self.defines {
+            if object_writer.symbol(name).is_none() {
+                object_writer.define_symbol(name, *value as u64);
+            }
+        }
+
+        object_writer.apply_relocations()?;
+        if !self.forbidden_bytes.is_empty() {
+            self.check_forbidden_bytes(object_writer, constants)?;
+        }
+        let bytes = object_writer.finish()?;
+        self.size_report = if self.report_sizes { object_writer.size_report(bytes.len() as u64) } else { None };
+        self.source_map = if self.map_source { object_writer.source_map(&self.file_name) } else { None };
+        Ok(bytes)
+    }
+
+    /// Report each instruction whose final bytes include a forbidden value
+    fn check_forbidden_bytes(&mut self, object_writer: &dyn ObjectWriter, constants: &[Symbol]) -> Result<(), AssemblerError> {
+        // Predefined symbols are constants too
+        let constants: HashMap<Symbol, i64> = constants.iter().copied()
+            .chain(self.defines.keys().map(|name| Symbol::intern(name)))
+            .filter_map(|name| Some((name, object_writer.symbol(name.as_str())? as i64)))
+            .collect();
+        let findings = shellcode::check(&object_writer.code(), &self.forbidden_bytes, self.bits(), &constants);
+        if findings.is_empty() {
+            return Ok(());
+        }
+        for finding in findings {
+            let location = self.statement_location(finding.line);
+            let mut detail = ErrorDetail::new(finding.message);
+            detail.help = finding.help;
+            self.error_collector.add_error(Error::new(ErrorType::EncodingError, detail).with_location(location));
+        }
+        Err(AssemblerError::Diagnostics { errors: self.error_collector.error_count() })
+    }
+
+    /// Location of the statement on `line`, pointing at its first token
+    /// rather than its indentation
+    fn statement_location(&mut self, line: usize) -> SourceLocation {
+        let mut location = self.error_collector.source_location(&self.file_name, line, 1);
+        if let Some(content) = &location.line_content {
+            location.column = content.len() - content.trim_start().len() + 1;
+        }
+        location
+    }
+
+    /// Record a generation failure in the collector and hand the error back
+    fn record_error(&mut self, error_type: ErrorType, err: AssemblerError) -> AssemblerError {
+        // Those errors are in the collector already
+        if matches!(err, AssemblerError::Diagnostics { .. }) {
+            return err;
+        }
+        // Errors about one statement point at it, like parse errors do
+        if let AssemblerError::Elf(elf) = &err {
+            if let Some(line) = elf.line() {
+                let error_type = match elf {
+                    ElfError::WrongSection { .. } | ElfError::SectionTooLarge { .. } => ErrorType::SectionError,
+                    ElfError::Unencodable { .. } => ErrorType::EncodingError,
+                    _ => ErrorType::SemanticError,
+                };
+                let location = self.statement_location(line);
+                self.error_collector.add_error(Error::new(error_type, ErrorDetail::new(elf.located_message())).with_location(location));
+                return err;
+            }
+        }
+        let error = Error::new(
+            error_type,
+            ErrorDetail::new(error::error_chain(&err))
+        ).with_severity(ErrorSeverity::Error);
+
+        self.error_collector.add_error(error);
+        err
+    }
+}
+
+/// Whether `token` starts an `%include` line
+fn is_include(token: &Token<'_>) -> bool {
+    token.token_type == TokenType::Preprocessor && token.value.eq_ignore_ascii_case("%include")
+}
@@ -0,0 +1,89 @@
+//! Flat binary output.
+//!
+//! A flat binary is just the code followed by the data, with no headers, as
+//! used for boot sectors, DOS `.com` programs and firmware images. Layout and
+//! relocations are done by the ELF generator; this writer only places the data
+//! segment right after the code and concatenates the two. The initial contents
+//! of thread-local variables (.tdata), if any, follow the data.
+
+use crate::elf::{round_up, ElfGenerator};
+use crate::error::AssemblerError;
+use crate::object::ObjectWriter;
+use crate::parser::ast::Instruction;
+use crate::size::SizeReport;
+use crate::source_map::SourceMap;
+
+/// Alignment of the data segment after the code, as NASM's bin format uses
+const DATA_ALIGN: u64 = 4;
+
+/// [`ObjectWriter`] producing a flat binary loaded at the generator's .text address
+pub struct BinaryWriter {
+    generator: ElfGenerator,
+}
+
+impl BinaryWriter {
+    /// Wrap a configured generator; its .text address is the origin
+    pub fn new(generator: ElfGenerator) -> Self {
+        Self { generator }
+    }
+
+    /// Offset of the data segment from the start of the file
+    fn data_offset(&self) -> usize {
+        let code_size = self.generator.text_section().len() as u64;
+        code_size.next_multiple_of(DATA_ALIGN) as usize
+    }
+}
+
+impl ObjectWriter for BinaryWriter {
+    fn layout_sections(&mut self) -> Result<(), AssemblerError> {
+        // Sizes don't depend on addresses, so a first layout gives the size of
+        // the code; the second places the data right after it
+        self.generator.layout()?;
+        let data_address = self.generator.text_address() + self.data_offset() as u64;
+        self.generator.set_data_address(data_address);
+        self.generator.layout_sections()
+    }
+
+    fn define_symbol(&mut self, name: &str, address: u64) {
+        self.generator.define_symbol(name, address);
+    }
+
+    fn symbol(&self, name: &str) -> Option<u64> {
+        self.generator.symbol(name)
+    }
+
+    fn apply_relocations(&mut self) -> Result<(), AssemblerError> {
+        self.generator.apply_relocations()
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, AssemblerError> {
+        let mut bytes = self.generator.text_section().to_vec();
+        let data = self.generator.data_section();
+        if !data.is_empty() {
+            bytes.resize(self.data_offset(), 0);
+            bytes.extend_from_slice(data);
+        }
+        if let Some(tls) = self.generator.thread_local_block().filter(|tls| !tls.image.is_empty()) {
+            let data_end = self.data_offset() as u64 + data.len() as u64;
+            bytes.resize(round_up(data_end, tls.align) as usize, 0);
+            bytes.extend_from_slice(tls.image);
+        }
+        Ok(bytes)
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        self.generator.warnings()
+    }
+
+    fn code(&self) -> Vec<(&Instruction, &[u8])> {
+        self.generator.code()
+    }
+
+    fn size_report(&self, file_size: u64) -> Option<SizeReport> {
+        Some(self.generator.layout_sizes(file_size))
+    }
+
+    fn source_map(&self, file: &str) -> Option<SourceMap> {
+        Some(self.generator.layout_source_map(file))
+    }
+}
@@ -0,0 +1,198 @@
+//! Assembling from Cargo build scripts.
+//!
+//! [`Build`] assembles `.asm` files into relocatable objects, bundles them into
+//! a static library in `OUT_DIR`, and prints the `cargo:` directives that link
+//! the library into the crate and rebuild it when a source changes. Add
+//! nasembler under `[build-dependencies]` and call it from `build.rs`:
+//!
+//! ```no_run
+//! // In build.rs's main
+//! nasembler::build::Build::new()
+//!     .file("src/checksum.asm")
+//!     .file("src/memcpy.asm")
+//!     .compile("asm");
+//! ```
+//!
+//! Globals in the sources can then be declared in an `extern "C"` block.
+//! Objects are x86-64 ELF, so the crate must be built for an x86-64 target
+//! that uses ELF, such as Linux or FreeBSD.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::assembler::{Assembler, Format};
+use crate::link::{Archive, LinkError};
+use crate::render::MessageFormat;
+
+/// Target operating systems whose object files aren't ELF
+const NON_ELF_OS: [&str; 5] = ["windows", "macos", "ios", "tvos", "uefi"];
+
+/// Failure while building a library
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// `OUT_DIR` isn't set and no output directory was given
+    MissingOutDir,
+    /// The crate is built for a target nasembler can't produce objects for
+    UnsupportedTarget(String),
+    /// A source failed to assemble; `diagnostics` holds one line per problem
+    Assembly { file: PathBuf, diagnostics: String },
+    /// An assembled object couldn't be added to the library
+    Archive(LinkError),
+    /// Writing the library failed
+    Io { path: PathBuf, source: io::Error },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingOutDir => write!(f, "OUT_DIR isn't set; run from a build script or call out_dir"),
+            BuildError::UnsupportedTarget(target) => {
+                write!(f, "nasembler objects are x86-64 ELF and can't be linked for the target {}", target)
+            },
+            BuildError::Assembly { file, diagnostics } => {
+                write!(f, "Failed to assemble {}", file.display())?;
+                if !diagnostics.trim().is_empty() { write!(f, ":\n{}", diagnostics.trim_end())?; }
+                Ok(())
+            },
+            BuildError::Archive(_) => write!(f, "Failed to archive the assembled objects"),
+            BuildError::Io { path, .. } => write!(f, "Failed to write {}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Archive(source) => Some(source),
+            BuildError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<LinkError> for BuildError {
+    fn from(error: LinkError) -> Self {
+        BuildError::Archive(error)
+    }
+}
+
+/// Assembles sources into a static library for a Cargo build script
+#[derive(Debug, Clone, Default)]
+pub struct Build {
+    files: Vec<PathBuf>,
+    include_paths: Vec<PathBuf>,
+    defines: Vec<(String, i64)>,
+    out_dir: Option<PathBuf>,
+    cargo_metadata: bool,
+}
+
+impl Build {
+    /// A build with no sources that prints `cargo:` directives
+    pub fn new() -> Self {
+        Self { cargo_metadata: true, ..Self::default() }
+    }
+
+    /// Add a source file
+    pub fn file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.files.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Add several source files
+    pub fn files<P: AsRef<Path>>(mut self, paths: impl IntoIterator<Item = P>) -> Self {
+        self.files.extend(paths.into_iter().map(|path| path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Add a directory to search for included files
+    pub fn include<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.include_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Predefine an absolute symbol in every source, as if `name equ value` appeared in it
+    pub fn define(mut self, name: &str, value: i64) -> Self {
+        self.defines.push((name.to_string(), value));
+        self
+    }
+
+    /// Write the library to `dir` instead of `OUT_DIR`
+    pub fn out_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.out_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set whether `cargo:` directives are printed, which only make sense in a build script
+    pub fn cargo_metadata(mut self, cargo_metadata: bool) -> Self {
+        self.cargo_metadata = cargo_metadata;
+        self
+    }
+
+    /// Build `lib<name>.a` and link it in, panicking with the diagnostics if anything fails
+    pub fn compile(&self, name: &str) {
+        if let Err(err) = self.try_compile(name) {
+            panic!("{}", crate::error::error_chain(&err));
+        }
+    }
+
+    /// Build `lib<name>.a`, returning its path
+    pub fn try_compile(&self, name: &str) -> Result<PathBuf, BuildError> {
+        if let Ok(arch) = env::var("CARGO_CFG_TARGET_ARCH") {
+            let os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+            if arch != "x86_64" || NON_ELF_OS.contains(&os.as_str()) {
+                return Err(BuildError::UnsupportedTarget(env::var("TARGET").unwrap_or(format!("{}-{}", arch, os))));
+            }
+        }
+        let out_dir = match &self.out_dir {
+            Some(dir) => dir.clone(),
+            None => env::var_os("OUT_DIR").map(PathBuf::from).ok_or(BuildError::MissingOutDir)?,
+        };
+
+        let mut archive = Archive::new();
+        for file in &self.files {
+            self.print(format_args!("cargo:rerun-if-changed={}", file.display()));
+            let object = self.assemble(file)?;
+            let stem = file.file_stem().unwrap_or(file.as_os_str()).to_string_lossy();
+            archive.add(&format!("{}.o", stem), object)?;
+        }
+        for path in &self.include_paths {
+            self.print(format_args!("cargo:rerun-if-changed={}", path.display()));
+        }
+
+        let library = out_dir.join(format!("lib{}.a", name));
+        fs::write(&library, archive.finish()).map_err(|source| BuildError::Io { path: library.clone(), source })?;
+        self.print(format_args!("cargo:rustc-link-search=native={}", out_dir.display()));
+        self.print(format_args!("cargo:rustc-link-lib=static={}", name));
+        Ok(library)
+    }
+
+    /// Assemble one source into a relocatable object
+    fn assemble(&self, file: &Path) -> Result<Vec<u8>, BuildError> {
+        let mut assembler = self.include_paths.iter()
+            .fold(Assembler::new().format(Format::Obj), |assembler, path| assembler.include_path(path));
+        for (name, value) in &self.defines {
+            assembler = assembler.define(name, *value);
+        }
+        let mut assembler = assembler.message_format(MessageFormat::Compact).ascii_diagnostics(true);
+
+        let result = assembler.read_source(file).and_then(|source| assembler.assemble(&source));
+        result.map_err(|err| {
+            let diagnostics = if assembler.errors().has_errors() {
+                assembler.errors().display_errors()
+            } else {
+                crate::error::error_chain(&err)
+            };
+            BuildError::Assembly { file: file.to_path_buf(), diagnostics }
+        })
+    }
+
+    fn print(&self, directive: fmt::Arguments) {
+        if self.cargo_metadata {
+            println!("{}", directive);
+        }
+    }
+}
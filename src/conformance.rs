@@ -0,0 +1,170 @@
+//! NASM conformance runs.
+//!
+//! A conformance run assembles every `.asm` file in a directory as a flat
+//! binary, once with nasembler and once with a reference assembler (`nasm -f
+//! bin`), and compares the two outputs byte for byte. Flat binaries have no
+//! headers, so any difference is a difference in encoding or layout. Test
+//! cases should start with a `bits` directive, since NASM's bin format
+//! otherwise assembles 16-bit code.
+//!
+//! When no reference assembler is installed, each case only checks that
+//! nasembler accepts the file.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::assembler::{Assembler, Format};
+use crate::error::ErrorSeverity;
+
+/// Reference assembler looked for on `PATH` when none is given
+pub const DEFAULT_REFERENCE: &str = "nasm";
+
+/// Numbers the reference assembler's temporary output files
+static OUTPUT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Result of one test case
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Outcome {
+    /// Both assemblers produced the same bytes
+    Match,
+    /// The outputs differ first at `offset`; a side is `None` when its output
+    /// ended before that byte
+    Diverged { offset: usize, ours: Option<u8>, reference: Option<u8> },
+    /// nasembler assembled the file, and there was no reference to compare with
+    Assembled,
+    /// nasembler rejected the file
+    Failed(String),
+    /// The reference assembler rejected the file or couldn't be run
+    ReferenceFailed(String),
+}
+
+impl Outcome {
+    /// Whether the case counts as a pass
+    pub fn passed(&self) -> bool {
+        matches!(self, Outcome::Match | Outcome::Assembled)
+    }
+}
+
+/// A test case and how it went
+#[derive(Debug, Clone)]
+pub struct CaseResult {
+    pub path: PathBuf,
+    pub outcome: Outcome,
+}
+
+/// Runs test cases against an optional reference assembler
+#[derive(Debug, Clone, Default)]
+pub struct Conformance {
+    reference: Option<PathBuf>,
+}
+
+impl Conformance {
+    /// Compare against `reference`, or only check that files assemble when it is `None`
+    pub fn new(reference: Option<PathBuf>) -> Self {
+        Self { reference }
+    }
+
+    /// The reference assembler in use, if any
+    pub fn reference(&self) -> Option<&Path> {
+        self.reference.as_deref()
+    }
+
+    /// Run every `.asm` file directly inside `dir`, in file name order
+    pub fn run_dir<P: AsRef<Path>>(&self, dir: P) -> io::Result<Vec<CaseResult>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|extension| extension == "asm") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        Ok(paths.into_iter().map(|path| self.run_case(path)).collect())
+    }
+
+    /// Assemble one file with both assemblers and compare the results
+    pub fn run_case(&self, path: PathBuf) -> CaseResult {
+        let outcome = match assemble(&path) {
+            Err(message) => Outcome::Failed(message),
+            Ok(ours) => match &self.reference {
+                None => Outcome::Assembled,
+                Some(reference) => match assemble_reference(reference, &path) {
+                    Err(message) => Outcome::ReferenceFailed(message),
+                    Ok(theirs) => compare(&ours, &theirs),
+                },
+            },
+        };
+
+        CaseResult { path, outcome }
+    }
+}
+
+/// `program` if it runs, for use as a reference assembler
+pub fn find_reference(program: &str) -> Option<PathBuf> {
+    let found = Command::new(program)
+        .arg("-v")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    found.then(|| PathBuf::from(program))
+}
+
+/// First difference between two outputs
+fn compare(ours: &[u8], reference: &[u8]) -> Outcome {
+    let offset = ours.iter().zip(reference).position(|(a, b)| a != b)
+        .unwrap_or(ours.len().min(reference.len()));
+    if offset == ours.len() && offset == reference.len() {
+        Outcome::Match
+    } else {
+        Outcome::Diverged { offset, ours: ours.get(offset).copied(), reference: reference.get(offset).copied() }
+    }
+}
+
+/// Assemble `path` as a flat binary with nasembler, describing the first error on failure
+fn assemble(path: &Path) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler::new().format(Format::Bin);
+    let result = assembler.read_source(path).and_then(|source| assembler.assemble(&source));
+
+    result.map_err(|err| {
+        let first = assembler.errors().errors().iter()
+            .find(|error| matches!(error.severity, ErrorSeverity::Error | ErrorSeverity::Fatal));
+        match first {
+            Some(error) => match &error.location {
+                Some(location) => format!("line {}: {}", location.line, error.detail.message),
+                None => error.detail.message.clone(),
+            },
+            None => crate::error::error_chain(&err),
+        }
+    })
+}
+
+/// Assemble `path` with `reference -f bin`, describing the failure from its stderr
+fn assemble_reference(reference: &Path, path: &Path) -> Result<Vec<u8>, String> {
+    let output_path = std::env::temp_dir().join(format!(
+        "nasembler-conformance-{}-{}.bin",
+        process::id(),
+        OUTPUT_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let output = Command::new(reference)
+        .args(["-f", "bin", "-o"])
+        .arg(&output_path)
+        .arg(path)
+        .output()
+        .map_err(|err| format!("failed to run {}: {}", reference.display(), err))?;
+
+    let result = if output.status.success() {
+        fs::read(&output_path).map_err(|err| format!("failed to read {}: {}", output_path.display(), err))
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(stderr.lines().next().unwrap_or("assembly failed").to_string())
+    };
+    let _ = fs::remove_file(&output_path);
+    result
+}
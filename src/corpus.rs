@@ -0,0 +1,315 @@
+//! Test vectors for the encoder.
+//!
+//! [`generate`] lists an assembly line and the bytes nasembler encodes it to
+//! for every instruction form the encoder supports, in each code mode it is
+//! supported in: every register of every width, a spread of immediates, and
+//! each label operand form. The corpus is meant for seeding fuzzers and as a
+//! golden file: [`check`] reassembles the vectors of a saved corpus and
+//! reports each one whose bytes changed.
+//!
+//! Each vector is assembled on its own as a flat binary, after a `bits`
+//! directive for its mode and a `target` label at address 0, which label
+//! operands refer to. That makes the lines reproducible with `nasm -f bin`
+//! too. In text form a vector is one line of the mode, the assembly and the
+//! bytes, separated by tabs:
+//!
+//! ```text
+//! 64    mov rax, 0x1    48 c7 c0 01 00 00 00
+//! 16    jne target      75 fe
+//! ```
+//!
+//! ```no_run
+//! use nasembler::corpus;
+//!
+//! let golden = std::fs::read_to_string("encoder.tsv").unwrap();
+//! for mismatch in corpus::check(&corpus::read(&golden).unwrap()) {
+//!     eprintln!("{}", mismatch);
+//! }
+//! ```
+
+use std::fmt;
+
+use crate::assembler::{Assembler, Format};
+
+/// Label that label operands in test vectors refer to, placed at address 0
+pub const TARGET: &str = "target";
+
+/// First line of [`to_text`] output, describing the columns
+pub const HEADER: &str = "# bits\tassembly\tbytes (label operands refer to `target:` at address 0)";
+
+/// Code modes vectors are generated for
+pub const MODES: [u16; 3] = [16, 32, 64];
+
+const REGISTERS_8: [&str; 20] = [
+    "al", "cl", "dl", "bl", "ah", "ch", "dh", "bh", "spl", "bpl", "sil", "dil",
+    "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b",
+];
+const REGISTERS_16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w",
+];
+const REGISTERS_32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d",
+];
+const REGISTERS_64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+/// Addresses of every form: each base and index, each displacement size,
+/// and the bases that need a displacement or SIB byte
+const ADDRESSES: [&str; 16] = [
+    "[rax]", "[rsp]", "[rbp]", "[r12]", "[r13]", "[rbx+0x8]", "[r12+0x80]", "[rbx+rcx*4]",
+    "[r8+r9*8+0x10]", "[rbp+r12*2]", "[r13+r14]", "[rax+rsp]", "[eax]", "[esp+0x8]", "[bx+si]", "[bp+di+0x10]",
+];
+const CONDITIONAL_JUMPS: [&str; 30] = [
+    "jo", "jno", "jb", "jc", "jnae", "jae", "jnb", "jnc", "je", "jz", "jne", "jnz", "jbe", "jna", "ja",
+    "jnbe", "js", "jns", "jp", "jpe", "jnp", "jpo", "jl", "jnge", "jge", "jnl", "jle", "jng", "jg", "jnle",
+];
+const ARITHMETIC: [&str; 4] = ["add", "sub", "xor", "cmp"];
+const UNARY: [&str; 8] = ["inc", "dec", "not", "neg", "mul", "imul", "div", "idiv"];
+const SHIFTS: [&str; 6] = ["shl", "shr", "sal", "sar", "rol", "ror"];
+const INTERRUPT_VECTORS: [&str; 4] = ["0x0", "0x3", "0x80", "0xff"];
+
+/// A corpus line that can't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CorpusError {
+    /// The line doesn't have the bits, assembly and bytes columns
+    MissingColumn { line: usize },
+    /// The mode isn't 16, 32 or 64
+    InvalidMode { line: usize, mode: String },
+    /// A byte isn't two hexadecimal digits
+    InvalidByte { line: usize, byte: String },
+}
+
+impl fmt::Display for CorpusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorpusError::MissingColumn { line } => write!(f, "Line {} doesn't have bits, assembly and bytes columns separated by tabs", line),
+            CorpusError::InvalidMode { line, mode } => write!(f, "Line {}: '{}' isn't a code mode (16, 32 or 64)", line, mode),
+            CorpusError::InvalidByte { line, byte } => write!(f, "Line {}: '{}' isn't a hexadecimal byte", line, byte),
+        }
+    }
+}
+
+impl std::error::Error for CorpusError {}
+
+/// An assembly line and the bytes it encodes to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVector {
+    /// Code mode the line is assembled in
+    pub bits: u16,
+    pub source: String,
+    pub bytes: Vec<u8>,
+}
+
+impl TestVector {
+    /// The line and what nasembler encodes it to now, or `None` if it isn't
+    /// supported in `bits`-bit mode
+    pub fn encode(bits: u16, source: &str) -> Option<Self> {
+        let bytes = assemble(bits, source).ok()?;
+        Some(Self { bits, source: source.to_string(), bytes })
+    }
+}
+
+impl fmt::Display for TestVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\t{}\t{}", self.bits, self.source, hex(&self.bytes))
+    }
+}
+
+/// A vector that no longer assembles to its recorded bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub vector: TestVector,
+    /// What the line assembles to now, or why it no longer assembles
+    pub actual: Result<Vec<u8>, String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bits {}: '{}': expected {}, ", self.vector.bits, self.vector.source, hex(&self.vector.bytes))?;
+        match &self.actual {
+            Ok(bytes) => write!(f, "got {}", hex(bytes)),
+            Err(message) => write!(f, "failed: {}", message),
+        }
+    }
+}
+
+/// Vectors for every supported instruction form, optionally only those of one mode
+pub fn generate(bits: Option<u16>) -> Vec<TestVector> {
+    MODES.iter()
+        .filter(|&&mode| bits.is_none_or(|bits| bits == mode))
+        .flat_map(|&mode| candidates().into_iter().filter_map(move |source| TestVector::encode(mode, &source)))
+        .collect()
+}
+
+/// Lines for every instruction form, in every mode; those a mode doesn't
+/// support fail to assemble and are left out by [`generate`]
+fn candidates() -> Vec<String> {
+    let mut lines = Vec::new();
+    let widths: [(&[&str], &str); 4] = [
+        (&REGISTERS_8, "0xff"),
+        (&REGISTERS_16, "0xffff"),
+        (&REGISTERS_32, "0xffffffff"),
+        (&REGISTERS_64, "0xffffffffffffffff"),
+    ];
+    for (registers, maximum) in widths {
+        for register in registers {
+            for value in ["0x0", "0x1", "0x7f", maximum] {
+                lines.push(format!("mov {}, {}", register, value));
+            }
+            lines.push(format!("mov {}, {}", register, TARGET));
+            lines.push(format!("lea {}, [{}]", register, TARGET));
+            for operation in ["movzx", "movsx"] {
+                for source in REGISTERS_8.iter().chain(&REGISTERS_16) {
+                    lines.push(format!("{} {}, {}", operation, register, source));
+                }
+                lines.push(format!("{} {}, byte [{}]", operation, register, TARGET));
+                lines.push(format!("{} {}, word [{}]", operation, register, TARGET));
+            }
+            lines.extend(REGISTERS_32.iter().map(|source| format!("movsxd {}, {}", register, source)));
+            lines.push(format!("movsxd {}, [{}]", register, TARGET));
+            lines.push(format!("mov {}, [{}]", register, TARGET));
+            lines.push(format!("mov [{}], {}", TARGET, register));
+            for operation in ARITHMETIC {
+                for value in ["0x1", "0x7f", "0x80", maximum] {
+                    lines.push(format!("{} {}, {}", operation, register, value));
+                }
+                lines.push(format!("{} {}, [{}]", operation, register, TARGET));
+                lines.push(format!("{} [{}], {}", operation, TARGET, register));
+            }
+            for value in ["0x1", "0x80", maximum] {
+                lines.push(format!("imul {}, {}", register, value));
+                lines.push(format!("imul {}, [{}], {}", register, TARGET, value));
+            }
+            lines.push(format!("imul {}, [{}]", register, TARGET));
+            for operation in UNARY {
+                lines.push(format!("{} {}", operation, register));
+            }
+            for operation in SHIFTS {
+                for count in ["1", "0x7", "cl"] {
+                    lines.push(format!("{} {}, {}", operation, register, count));
+                }
+            }
+            lines.extend(CONDITIONAL_JUMPS.iter().map(|jump| format!("cmov{} {}, [{}]", &jump[1..], register, TARGET)));
+            lines.push(format!("push {}", register));
+            lines.push(format!("pop {}", register));
+        }
+        for destination in registers {
+            for source in registers {
+                lines.push(format!("mov {}, {}", destination, source));
+                for operation in ARITHMETIC {
+                    lines.push(format!("{} {}, {}", operation, destination, source));
+                }
+                lines.push(format!("imul {}, {}", destination, source));
+                lines.push(format!("imul {}, {}, 0x7f", destination, source));
+                lines.push(format!("cmove {}, {}", destination, source));
+            }
+        }
+    }
+    for address in ADDRESSES {
+        lines.push(format!("mov eax, {}", address));
+        lines.push(format!("mov {}, ecx", address));
+        lines.push(format!("mov r10, {}", address));
+        lines.push(format!("lea r15, {}", address));
+        lines.push(format!("nop dword {}", address));
+    }
+    // Memory operands with a size, up to the largest imm32 a qword sign-extends
+    for (size, maximum) in [("byte", "0xff"), ("word", "0xffff"), ("dword", "0xffffffff"), ("qword", "0x7fffffff")] {
+        for value in ["0x1", maximum] {
+            lines.push(format!("mov {} [{}], {}", size, TARGET, value));
+        }
+        lines.push(format!("mov {} [{}], {}", size, TARGET, TARGET));
+        for operation in ARITHMETIC {
+            for value in ["0x1", "0x80"] {
+                lines.push(format!("{} {} [{}], {}", operation, size, TARGET, value));
+            }
+        }
+        for operation in UNARY {
+            lines.push(format!("{} {} [{}]", operation, size, TARGET));
+        }
+        for operation in SHIFTS {
+            lines.push(format!("{} {} [{}], 1", operation, size, TARGET));
+            lines.push(format!("{} {} [{}], cl", operation, size, TARGET));
+        }
+    }
+    for register in REGISTERS_8 {
+        // The conditions are the jumps' mnemonics after the `j`
+        lines.extend(CONDITIONAL_JUMPS.iter().map(|jump| format!("set{} {}", &jump[1..], register)));
+    }
+    for value in ["0x1", "0x7f", "0x80", "0xffff"] {
+        lines.push(format!("push {}", value));
+    }
+    lines.push(format!("push {}", TARGET));
+    lines.push(format!("push [{}]", TARGET));
+    lines.push(format!("pop [{}]", TARGET));
+    lines.push("syscall".to_string());
+    lines.push("ret".to_string());
+    lines.push("nop".to_string());
+    for register in ["ax", "r9d", "r9"] {
+        lines.push(format!("nop {}", register));
+    }
+    lines.push(format!("nop word [{}]", TARGET));
+    lines.extend(INTERRUPT_VECTORS.iter().map(|vector| format!("int {}", vector)));
+    lines.push(format!("jmp {}", TARGET));
+    lines.push(format!("call {}", TARGET));
+    lines.extend(CONDITIONAL_JUMPS.iter().map(|jump| format!("{} {}", jump, TARGET)));
+    lines
+}
+
+/// Assemble `source` on its own for `bits`-bit mode, after the `target` label
+pub fn assemble(bits: u16, source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler::new().format(Format::Bin).verbose(false);
+    let program = format!("bits {}\n{}:\n    {}\n", bits, TARGET, source);
+    assembler.assemble(&program).map_err(|err| err.to_string())
+}
+
+/// The vectors as text, one per line after [`HEADER`]
+pub fn to_text(vectors: &[TestVector]) -> String {
+    let mut text = format!("{}\n", HEADER);
+    for vector in vectors {
+        text.push_str(&format!("{}\n", vector));
+    }
+    text
+}
+
+/// Read vectors written by [`to_text`], skipping blank lines and `#` comments
+pub fn read(text: &str) -> Result<Vec<TestVector>, CorpusError> {
+    let mut vectors = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut columns = line.split('\t');
+        let (Some(mode), Some(source), Some(bytes)) = (columns.next(), columns.next(), columns.next()) else {
+            return Err(CorpusError::MissingColumn { line: line_number });
+        };
+        let bits = mode.trim().parse().ok().filter(|bits| MODES.contains(bits))
+            .ok_or_else(|| CorpusError::InvalidMode { line: line_number, mode: mode.to_string() })?;
+        let bytes = bytes.split_whitespace()
+            .map(|byte| match byte.len() {
+                2 => u8::from_str_radix(byte, 16).ok(),
+                _ => None,
+            }.ok_or_else(|| CorpusError::InvalidByte { line: line_number, byte: byte.to_string() }))
+            .collect::<Result<_, _>>()?;
+        vectors.push(TestVector { bits, source: source.trim().to_string(), bytes });
+    }
+    Ok(vectors)
+}
+
+/// Reassemble each vector, returning those whose bytes differ from the recorded ones
+pub fn check(vectors: &[TestVector]) -> Vec<Mismatch> {
+    vectors.iter()
+        .filter_map(|vector| {
+            let actual = assemble(vector.bits, &vector.source);
+            match &actual {
+                Ok(bytes) if *bytes == vector.bytes => None,
+                _ => Some(Mismatch { vector: vector.clone(), actual }),
+            }
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,339 @@
+//! Stepping through a program in the emulator.
+//!
+//! The [`Debugger`] runs an executable in the [`Emulator`] one instruction
+//! at a time, or until it reaches a breakpoint on a label, and shows the
+//! registers, flags and memory between steps. Executables assembled with
+//! `-g` have a symbol table and line numbers, so each stop shows the source
+//! line being executed and breakpoints can name labels; without them the
+//! debugger works with addresses only.
+//!
+//! [`Debugger::run`] reads gdb-style commands, one per line:
+//!
+//! | Command | Does |
+//! |---------|------|
+//! | `step [N]`, `s` | Execute one instruction, or `N` |
+//! | `continue`, `c` | Run until a breakpoint or the program exits |
+//! | `break [LOCATION]`, `b` | Stop at a label or address, or list the breakpoints |
+//! | `delete LOCATION`, `d` | Remove a breakpoint |
+//! | `registers`, `regs` | Show the registers and flags |
+//! | `x LOCATION [BYTES]` | Show memory at a label, address or the address in a register (64 bytes by default) |
+//! | `list`, `l` | Show the source around the current line |
+//! | `help`, `h` | List the commands |
+//! | `quit`, `q` | Stop debugging |
+//!
+//! An empty line repeats the last command.
+//!
+//! ```no_run
+//! use nasembler::debugger::Debugger;
+//!
+//! let binary = std::fs::read("program").unwrap();
+//! let mut debugger = Debugger::new(&binary).unwrap();
+//! let stdin = std::io::stdin();
+//! debugger.run(&mut stdin.lock(), &mut std::io::stdout(), &mut std::io::stderr()).unwrap();
+//! ```
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::addr2line::{self, Location};
+use crate::emulator::{Emulator, EmulatorError, CF, OF, PF, REGISTER_NAMES, SF, ZF};
+
+const PROMPT: &str = "(nasembler) ";
+/// Bytes `x` shows when no count is given
+const DEFAULT_DUMP_BYTES: u64 = 64;
+/// Source lines `list` shows on each side of the current one
+const LIST_CONTEXT: u64 = 5;
+/// The interrupt flag, always set in user programs
+const IF: u64 = 1 << 9;
+
+const HELP: &str = "\
+step [N], s      execute one instruction, or N
+continue, c      run until a breakpoint or the program exits
+break [LOC], b   stop at a label or address, or list the breakpoints
+delete LOC, d    remove a breakpoint
+registers, regs  show the registers and flags
+x LOC [BYTES]    show memory at a label, address or register's address
+list, l          show the source around the current line
+help, h          show this list
+quit, q          stop debugging
+An empty line repeats the last command.";
+
+/// An emulated program under the debugger's control
+#[derive(Debug)]
+pub struct Debugger {
+    emulator: Emulator,
+    binary: Vec<u8>,
+    /// Labels from the symbol table, with their addresses
+    symbols: Vec<(String, u64)>,
+    breakpoints: BTreeSet<u64>,
+    /// Directories besides the current one to look for source files in
+    source_dirs: Vec<PathBuf>,
+    /// Lines of each source file read so far; `None` if it couldn't be read
+    sources: HashMap<String, Option<Vec<String>>>,
+    /// How the program ended: its exit code, or why emulation stopped
+    finished: Option<Result<i32, String>>,
+}
+
+impl Debugger {
+    /// Load `binary` into the emulator, stopped at its entry point
+    pub fn new(binary: &[u8]) -> Result<Self, EmulatorError> {
+        Ok(Self {
+            emulator: Emulator::load(binary)?,
+            binary: binary.to_vec(),
+            symbols: addr2line::symbols(binary).unwrap_or_default(),
+            breakpoints: BTreeSet::new(),
+            source_dirs: Vec::new(),
+            sources: HashMap::new(),
+            finished: None,
+        })
+    }
+
+    /// Also look for source files in `dir`, such as the directory the binary is in
+    pub fn with_source_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.source_dirs.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// The emulated program
+    pub fn emulator(&self) -> &Emulator {
+        &self.emulator
+    }
+
+    /// Address of a label, or of a hexadecimal address with or without `0x`
+    pub fn resolve(&self, location: &str) -> Option<u64> {
+        if let Some(&(_, address)) = self.symbols.iter().find(|(name, _)| name == location) {
+            return Some(address);
+        }
+        let digits = location.strip_prefix("0x").or_else(|| location.strip_prefix("0X")).unwrap_or(location);
+        u64::from_str_radix(digits, 16).ok()
+    }
+
+    /// Stop whenever the program is about to execute the instruction at `address`
+    pub fn add_breakpoint(&mut self, address: u64) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Read and carry out commands until `quit` or the end of `input`. The
+    /// program's own output goes to `output`, like the debugger's, and to
+    /// `program_stderr`.
+    pub fn run(&mut self, input: &mut dyn BufRead, output: &mut dyn Write, program_stderr: &mut dyn Write) -> io::Result<()> {
+        if self.symbols.is_empty() {
+            writeln!(output, "No symbols; assemble with -g to see labels and source lines")?;
+        }
+        self.show_stop(output)?;
+
+        let mut last = String::new();
+        loop {
+            write!(output, "{}", PROMPT)?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+            let line = match line.trim() {
+                "" => last.clone(),
+                command => command.to_string(),
+            };
+            if !self.command(&line, output, program_stderr)? {
+                return Ok(());
+            }
+            last = line;
+        }
+    }
+
+    /// Carry out one command, returning false for `quit`
+    fn command(&mut self, line: &str, output: &mut dyn Write, program_stderr: &mut dyn Write) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else { return Ok(true) };
+        let argument = words.next();
+        match command {
+            "step" | "s" => match argument.map(str::parse::<u64>) {
+                Some(Err(_)) => writeln!(output, "'{}' isn't a number of instructions", argument.unwrap_or_default())?,
+                count => {
+                    let count = count.and_then(Result::ok).unwrap_or(1);
+                    for _ in 0..count {
+                        if !self.step(output, program_stderr)? {
+                            break;
+                        }
+                    }
+                    self.show_stop(output)?;
+                },
+            },
+            "continue" | "c" => {
+                while self.step(output, program_stderr)? {
+                    if self.breakpoints.contains(&self.emulator.rip()) {
+                        writeln!(output, "Stopped at a breakpoint")?;
+                        break;
+                    }
+                }
+                self.show_stop(output)?;
+            },
+            "break" | "b" => match argument {
+                None if self.breakpoints.is_empty() => writeln!(output, "No breakpoints")?,
+                None => {
+                    for &address in &self.breakpoints {
+                        writeln!(output, "  {}", self.describe(address))?;
+                    }
+                },
+                Some(location) => match self.resolve(location) {
+                    Some(address) => {
+                        self.add_breakpoint(address);
+                        writeln!(output, "Breakpoint at {}", self.describe(address))?;
+                    },
+                    None => writeln!(output, "No label '{}'", location)?,
+                },
+            },
+            "delete" | "d" => match argument.and_then(|location| self.resolve(location)) {
+                Some(address) if self.breakpoints.remove(&address) => writeln!(output, "Deleted breakpoint at {:#x}", address)?,
+                Some(address) => writeln!(output, "No breakpoint at {:#x}", address)?,
+                None => writeln!(output, "Usage: delete LABEL or delete ADDRESS")?,
+            },
+            "registers" | "regs" => self.show_registers(output)?,
+            "x" => {
+                let length = words.next().map_or(Ok(DEFAULT_DUMP_BYTES), str::parse::<u64>);
+                // A register name stands for the address it holds
+                let address = argument.and_then(|location| match REGISTER_NAMES.iter().position(|&name| name == location) {
+                    Some(index) => Some(self.emulator.registers()[index]),
+                    None => self.resolve(location),
+                });
+                match (address, length) {
+                    (Some(address), Ok(length)) => self.show_memory(address, length, output)?,
+                    _ => writeln!(output, "Usage: x LABEL|ADDRESS|REGISTER [BYTES]")?,
+                }
+            },
+            "list" | "l" => self.show_source(output)?,
+            "help" | "h" => writeln!(output, "{}", HELP)?,
+            "quit" | "q" => return Ok(false),
+            _ => writeln!(output, "Unknown command '{}'; type 'help' for a list", command)?,
+        }
+        Ok(true)
+    }
+
+    /// Execute one instruction, returning false once the program has finished
+    fn step(&mut self, output: &mut dyn Write, program_stderr: &mut dyn Write) -> io::Result<bool> {
+        if let Some(finished) = &self.finished {
+            match finished {
+                Ok(code) => writeln!(output, "The program has exited with code {}", code)?,
+                Err(reason) => writeln!(output, "The program can't continue: {}", reason)?,
+            }
+            return Ok(false);
+        }
+        let result = self.emulator.step(output, program_stderr);
+        match result {
+            Ok(None) => return Ok(true),
+            Ok(Some(code)) => {
+                writeln!(output, "The program exited with code {}", code)?;
+                self.finished = Some(Ok(code));
+            },
+            Err(err) => {
+                let reason = crate::error::error_chain(&err);
+                writeln!(output, "{}", reason)?;
+                self.finished = Some(Err(reason));
+            },
+        }
+        Ok(false)
+    }
+
+    /// Where the program is stopped, and the source line there
+    fn show_stop(&mut self, output: &mut dyn Write) -> io::Result<()> {
+        if self.finished.is_some() {
+            return Ok(());
+        }
+        let rip = self.emulator.rip();
+        let location = self.location(rip);
+        writeln!(output, "{}", self.describe(rip))?;
+        if let Some((file, line)) = &location.line {
+            if let Some(text) = self.source_line(file, *line) {
+                writeln!(output, "{:>5} | {}", line, text)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn show_registers(&self, output: &mut dyn Write) -> io::Result<()> {
+        let registers = self.emulator.registers();
+        if self.emulator.bits() == 64 {
+            for row in REGISTER_NAMES.iter().zip(registers).collect::<Vec<_>>().chunks(4) {
+                let cells: Vec<String> = row.iter().map(|(name, value)| format!("{:<3} {:#018x}", name, value)).collect();
+                writeln!(output, "{}", cells.join("  "))?;
+            }
+        } else {
+            // 32-bit code only has the first eight, as eax to edi
+            for row in REGISTER_NAMES[..8].iter().zip(registers).collect::<Vec<_>>().chunks(4) {
+                let cells: Vec<String> = row.iter().map(|(name, value)| format!("e{} {:#010x}", &name[1..], value)).collect();
+                writeln!(output, "{}", cells.join("  "))?;
+            }
+        }
+        let flags = self.emulator.flags();
+        let set: Vec<&str> = [(CF, "CF"), (PF, "PF"), (ZF, "ZF"), (SF, "SF"), (IF, "IF"), (OF, "OF")].iter()
+            .filter(|&&(bit, _)| flags & bit != 0)
+            .map(|&(_, name)| name)
+            .collect();
+        let (rip_name, flags_name) = if self.emulator.bits() == 64 { ("rip", "rflags") } else { ("eip", "eflags") };
+        writeln!(output, "{} {:#x}  {} {:#x} [ {} ]", rip_name, self.emulator.rip(), flags_name, flags, set.join(" "))
+    }
+
+    /// A hex and ASCII dump of `length` bytes, 16 to a row
+    fn show_memory(&self, address: u64, length: u64, output: &mut dyn Write) -> io::Result<()> {
+        let Some(bytes) = self.emulator.read_memory(address, length) else {
+            return writeln!(output, "Memory at {:#x} isn't mapped for {} bytes", address, length);
+        };
+        for (row, chunk) in bytes.chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = chunk.iter().map(|&byte| if (0x20..0x7F).contains(&byte) { byte as char } else { '.' }).collect();
+            writeln!(output, "{:#010x}  {:<47}  {}", address + row as u64 * 16, hex.join(" "), text)?;
+        }
+        Ok(())
+    }
+
+    /// The source lines around the current one
+    fn show_source(&mut self, output: &mut dyn Write) -> io::Result<()> {
+        let Some((file, current)) = self.location(self.emulator.rip()).line else {
+            return writeln!(output, "No source line here; assemble with -g to record them");
+        };
+        let lines = self.source(&file).map(<[String]>::len).unwrap_or(0) as u64;
+        if lines == 0 {
+            return writeln!(output, "Can't read {}", file);
+        }
+        for line in current.saturating_sub(LIST_CONTEXT).max(1)..=(current + LIST_CONTEXT).min(lines) {
+            let marker = if line == current { "=>" } else { "  " };
+            let text = self.source_line(&file, line).unwrap_or_default();
+            writeln!(output, "{} {:>4} | {}", marker, line, text)?;
+        }
+        Ok(())
+    }
+
+    fn location(&self, address: u64) -> Location {
+        addr2line::lookup(&self.binary, address).unwrap_or_default()
+    }
+
+    /// An address with the label and source line it belongs to, when known
+    fn describe(&self, address: u64) -> String {
+        let location = self.location(address);
+        let mut text = format!("{:#x}", address);
+        if location.symbol.is_some() || location.line.is_some() {
+            text.push_str(&format!(" in {}", location));
+        }
+        text
+    }
+
+    fn source_line(&mut self, file: &str, line: u64) -> Option<String> {
+        let index = usize::try_from(line).ok()?.checked_sub(1)?;
+        self.source(file)?.get(index).cloned()
+    }
+
+    /// The lines of `file`, read from the current directory or a source directory
+    fn source(&mut self, file: &str) -> Option<&[String]> {
+        if !self.sources.contains_key(file) {
+            let text = std::iter::once(PathBuf::from(file))
+                .chain(self.source_dirs.iter().map(|dir| dir.join(file)))
+                .find_map(|path| fs::read_to_string(path).ok());
+            let lines = text.map(|text| text.lines().map(str::to_string).collect());
+            self.sources.insert(file.to_string(), lines);
+        }
+        self.sources.get(file)?.as_deref()
+    }
+}
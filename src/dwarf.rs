@@ -0,0 +1,341 @@
+//! DWARF line tables.
+//!
+//! `-g` executables carry a `.debug_line` section mapping each instruction's
+//! address to its source line, in the DWARF 4 format gdb, lldb and binutils'
+//! `addr2line` read. Those tools only look at line tables that a compilation
+//! unit in `.debug_info` points to, so one unit covering all of .text is
+//! written too. The reader here handles the line programs of DWARF versions 2
+//! to 4, so it also works on files from other assemblers and linkers.
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_LANGUAGE: u8 = 0x13;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA2: u8 = 0x05;
+const DW_FORM_DATA4: u8 = 0x06;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_SEC_OFFSET: u8 = 0x17;
+const DW_LANG_MIPS_ASSEMBLER: u16 = 0x8001;
+
+const LINE_BASE: i8 = -5;
+const LINE_RANGE: u8 = 14;
+const OPCODE_BASE: u8 = 13;
+/// Operand counts of the standard opcodes 1 to 12
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+fn write_uleb(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn write_sleb(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// `.debug_abbrev` and `.debug_info` sections with one compilation unit for
+/// `file`, covering `code` and using the line table at the start of `.debug_line`
+pub(crate) fn compile_unit(file: &str, code: std::ops::Range<u64>, address_size: u8) -> (Vec<u8>, Vec<u8>) {
+    // Abbreviation 1: a compilation unit without children
+    let mut abbrev = vec![1, DW_TAG_COMPILE_UNIT, 0];
+    for (attribute, form) in [
+        (DW_AT_PRODUCER, DW_FORM_STRING),
+        (DW_AT_LANGUAGE, DW_FORM_DATA2),
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        (DW_AT_LOW_PC, DW_FORM_ADDR),
+        (DW_AT_HIGH_PC, DW_FORM_DATA4),
+    ] {
+        abbrev.extend_from_slice(&[attribute, form]);
+    }
+    abbrev.extend_from_slice(&[0, 0, 0]);
+
+    let mut unit = vec![1];
+    unit.extend_from_slice(concat!("nasembler ", env!("CARGO_PKG_VERSION"), "\0").as_bytes());
+    unit.extend_from_slice(&DW_LANG_MIPS_ASSEMBLER.to_le_bytes());
+    unit.extend_from_slice(file.as_bytes());
+    unit.push(0);
+    unit.extend_from_slice(&0u32.to_le_bytes());
+    unit.extend_from_slice(&code.start.to_le_bytes()[..address_size as usize]);
+    // In DWARF 4 a constant high_pc is the size of the code
+    unit.extend_from_slice(&((code.end - code.start) as u32).to_le_bytes());
+
+    let mut info = Vec::with_capacity(11 + unit.len());
+    info.extend_from_slice(&((7 + unit.len()) as u32).to_le_bytes());
+    info.extend_from_slice(&4u16.to_le_bytes());
+    info.extend_from_slice(&0u32.to_le_bytes());
+    info.push(address_size);
+    info.extend_from_slice(&unit);
+    (abbrev, info)
+}
+
+/// A `.debug_line` section for one source file. `rows` are (address, line)
+/// pairs in address order, and `end` is the address just past the code.
+pub(crate) fn debug_line(file: &str, rows: &[(u64, usize)], end: u64, address_size: u8) -> Vec<u8> {
+    let mut header = vec![1, 1, 1, LINE_BASE as u8, LINE_RANGE, OPCODE_BASE];
+    header.extend_from_slice(&STANDARD_OPCODE_LENGTHS);
+    // No include directories, then the one file with no directory, time or size
+    header.push(0);
+    header.extend_from_slice(file.as_bytes());
+    header.extend_from_slice(&[0, 0, 0, 0, 0]);
+
+    let mut program = Vec::new();
+    if let Some(&(start, _)) = rows.first() {
+        program.extend_from_slice(&[0, 1 + address_size, DW_LNE_SET_ADDRESS]);
+        program.extend_from_slice(&start.to_le_bytes()[..address_size as usize]);
+        let (mut address, mut line) = (start, 1);
+        for &(row_address, row_line) in rows {
+            if row_line != line {
+                program.push(DW_LNS_ADVANCE_LINE);
+                write_sleb(&mut program, row_line as i64 - line as i64);
+                line = row_line;
+            }
+            if row_address != address {
+                program.push(DW_LNS_ADVANCE_PC);
+                write_uleb(&mut program, row_address - address);
+                address = row_address;
+            }
+            program.push(DW_LNS_COPY);
+        }
+        if end > address {
+            program.push(DW_LNS_ADVANCE_PC);
+            write_uleb(&mut program, end - address);
+        }
+        program.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+    }
+
+    let mut section = Vec::with_capacity(10 + header.len() + program.len());
+    section.extend_from_slice(&((6 + header.len() + program.len()) as u32).to_le_bytes());
+    section.extend_from_slice(&4u16.to_le_bytes());
+    section.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    section.extend_from_slice(&header);
+    section.extend_from_slice(&program);
+    section
+}
+
+/// Reads a section front to back
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.offset)?;
+        self.offset += 1;
+        Some(byte)
+    }
+
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.offset..self.offset.checked_add(count)?)?;
+        self.offset += count;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)?.try_into().ok().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)?.try_into().ok().map(u32::from_le_bytes)
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7F) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7F) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let tail = self.bytes.get(self.offset..)?;
+        let length = tail.iter().position(|&byte| byte == 0)?;
+        self.offset += length + 1;
+        Some(String::from_utf8_lossy(&tail[..length]).into_owned())
+    }
+}
+
+/// The source file and line of the instruction at `address`, if a line
+/// program in `section` covers it
+pub(crate) fn find_line(section: &[u8], address: u64) -> Option<(String, u64)> {
+    let mut reader = Reader { bytes: section, offset: 0 };
+    while reader.offset < section.len() {
+        let length = reader.u32()? as usize;
+        // 64-bit DWARF isn't produced for these files
+        if length >= 0xFFFF_FFF0 {
+            return None;
+        }
+        let unit_end = reader.offset.checked_add(length)?;
+        let unit = section.get(..unit_end)?;
+        let mut unit_reader = Reader { bytes: unit, offset: reader.offset };
+        if let Some(found) = find_line_in_unit(&mut unit_reader, address) {
+            return Some(found);
+        }
+        reader.offset = unit_end;
+    }
+    None
+}
+
+fn find_line_in_unit(reader: &mut Reader, address: u64) -> Option<(String, u64)> {
+    let version = reader.u16()?;
+    if !(2..=4).contains(&version) {
+        return None;
+    }
+    let header_length = reader.u32()? as usize;
+    let program_start = reader.offset.checked_add(header_length)?;
+    let minimum_instruction_length = u64::from(reader.u8()?);
+    if version >= 4 {
+        reader.u8()?;
+    }
+    reader.u8()?;
+    let line_base = reader.u8()? as i8;
+    let line_range = reader.u8()?;
+    let opcode_base = reader.u8()?;
+    if line_range == 0 || opcode_base == 0 {
+        return None;
+    }
+    let opcode_lengths = reader.take(usize::from(opcode_base - 1))?;
+
+    let mut directories = vec![String::new()];
+    loop {
+        let directory = reader.string()?;
+        if directory.is_empty() { break; }
+        directories.push(directory);
+    }
+    let mut files = Vec::new();
+    loop {
+        let name = reader.string()?;
+        if name.is_empty() { break; }
+        let directory = reader.uleb()?;
+        reader.uleb()?;
+        reader.uleb()?;
+        files.push((name, directory));
+    }
+    let file_name = |index: u64, files: &[(String, u64)]| {
+        let (name, directory) = files.get(usize::try_from(index).ok()?.checked_sub(1)?)?;
+        match directories.get(*directory as usize) {
+            Some(directory) if !directory.is_empty() && !name.starts_with('/') => Some(format!("{}/{}", directory, name)),
+            _ => Some(name.clone()),
+        }
+    };
+
+    reader.offset = program_start;
+    // The row before the current one, which covers addresses up to the current row
+    let mut previous: Option<(u64, u64, u64)> = None;
+    let (mut row_address, mut file, mut line) = (0u64, 1u64, 1u64);
+    while reader.offset < reader.bytes.len() {
+        let opcode = reader.u8()?;
+        let mut emit = false;
+        let mut end_sequence = false;
+        if opcode >= opcode_base {
+            let adjusted = opcode - opcode_base;
+            row_address = row_address.wrapping_add(u64::from(adjusted / line_range) * minimum_instruction_length);
+            line = line.wrapping_add_signed(i64::from(line_base) + i64::from(adjusted % line_range));
+            emit = true;
+        } else {
+            match opcode {
+                0 => {
+                    let length = reader.uleb()? as usize;
+                    let body = reader.take(length)?;
+                    match body.first() {
+                        Some(&DW_LNE_END_SEQUENCE) => { emit = true; end_sequence = true; },
+                        Some(&DW_LNE_SET_ADDRESS) => {
+                            let mut value = [0u8; 8];
+                            let operand = body.get(1..).filter(|operand| operand.len() <= 8)?;
+                            value[..operand.len()].copy_from_slice(operand);
+                            row_address = u64::from_le_bytes(value);
+                        },
+                        Some(&DW_LNE_DEFINE_FILE) => {
+                            let mut define = Reader { bytes: body, offset: 1 };
+                            files.push((define.string()?, define.uleb()?));
+                        },
+                        _ => {},
+                    }
+                },
+                DW_LNS_COPY => emit = true,
+                DW_LNS_ADVANCE_PC => {
+                    row_address = row_address.wrapping_add(reader.uleb()?.wrapping_mul(minimum_instruction_length));
+                },
+                DW_LNS_ADVANCE_LINE => line = line.wrapping_add_signed(reader.sleb()?),
+                DW_LNS_SET_FILE => file = reader.uleb()?,
+                DW_LNS_CONST_ADD_PC => {
+                    let adjusted = 255 - opcode_base;
+                    row_address = row_address.wrapping_add(u64::from(adjusted / line_range) * minimum_instruction_length);
+                },
+                DW_LNS_FIXED_ADVANCE_PC => row_address = row_address.wrapping_add(u64::from(reader.u16()?)),
+                _ => {
+                    for _ in 0..*opcode_lengths.get(usize::from(opcode - 1))? {
+                        reader.uleb()?;
+                    }
+                },
+            }
+        }
+
+        if emit {
+            if let Some((start, previous_file, previous_line)) = previous {
+                if (start..row_address).contains(&address) {
+                    return Some((file_name(previous_file, &files)?, previous_line));
+                }
+            }
+            previous = (!end_sequence).then_some((row_address, file, line));
+            if end_sequence {
+                (row_address, file, line) = (0, 1, 1);
+            }
+        }
+    }
+    None
+}
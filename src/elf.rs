@@ -1,31 +1,123 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
-use std::mem;
+use std::io::Write;
+use std::ops::Range;
 use colored::*;
 
-use crate::parser::ast::{Program, Statement, Instruction, Directive, Operand};
-use crate::encoder::MachineCodeEncoder;
+use crate::parser::ast::{ByteRange, Directive, Instruction, NodeSpan, Program, Statement, Operand, SpecialSymbol};
+use crate::encoder::{displacement_fits, fit_immediate, nop_padding, EncodedInstruction, Fixup, FixupKind, MachineCodeEncoder};
+use crate::error::{ascii_decorations, AssemblerError, ElfError};
+use crate::dwarf;
+use crate::object::ObjectWriter;
+use crate::relocatable::{
+    Elf64SectionHeader, Elf64Symbol, Section, StringTable, SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_PROGBITS, SHT_STRTAB,
+    SHT_SYMTAB, SHT_NOBITS, SHF_TLS, STB_GLOBAL, STT_FUNC, STT_OBJECT, STT_TLS, comment_section,
+};
+use crate::size::{self, SizeEntry, SizeReport};
+use crate::source_map::{Mapping, SourceMap};
+use crate::symbol::Symbol;
 
-const EI_NIDENT: usize = 16;
-const ET_EXEC: u16 = 2;
-const EM_X86_64: u16 = 62;
-const EV_CURRENT: u8 = 1;
+pub(crate) const EI_NIDENT: usize = 16;
+pub(crate) const ET_EXEC: u16 = 2;
+const EM_386: u16 = 3;
+pub(crate) const EM_X86_64: u16 = 62;
+const ELFCLASS32: u8 = 1;
+pub(crate) const ELFCLASS64: u8 = 2;
+pub(crate) const EV_CURRENT: u8 = 1;
 const PT_LOAD: u32 = 1;
+const PT_TLS: u32 = 7;
 const PF_R: u32 = 4;
 const PF_W: u32 = 2;
 const PF_X: u32 = 1;
-const PAGE_SIZE: u64 = 0x1000;
+pub(crate) const PAGE_SIZE: u64 = 0x1000;
 
-#[repr(C, packed)]
-struct Elf64Header {
+/// `EI_OSABI` value of plain System V files, which Linux and most loaders expect
+pub const ELFOSABI_SYSV: u8 = 0;
+pub const ELFOSABI_NETBSD: u8 = 2;
+/// GNU/Linux extensions, such as `STB_GNU_UNIQUE` symbols
+pub const ELFOSABI_GNU: u8 = 3;
+pub const ELFOSABI_SOLARIS: u8 = 6;
+pub const ELFOSABI_FREEBSD: u8 = 9;
+pub const ELFOSABI_OPENBSD: u8 = 12;
+/// Embedded code that runs without an operating system
+pub const ELFOSABI_STANDALONE: u8 = 255;
+
+/// Names accepted by [`os_abi`], with their `EI_OSABI` values
+pub const OS_ABI_NAMES: [(&str, u8); 8] = [
+    ("sysv", ELFOSABI_SYSV),
+    ("netbsd", ELFOSABI_NETBSD),
+    ("gnu", ELFOSABI_GNU),
+    ("linux", ELFOSABI_GNU),
+    ("solaris", ELFOSABI_SOLARIS),
+    ("freebsd", ELFOSABI_FREEBSD),
+    ("openbsd", ELFOSABI_OPENBSD),
+    ("standalone", ELFOSABI_STANDALONE),
+];
+
+/// The `EI_OSABI` value for an OS/ABI name such as `freebsd`
+pub fn os_abi(name: &str) -> Option<u8> {
+    OS_ABI_NAMES.iter().find(|(known, _)| known.eq_ignore_ascii_case(name)).map(|&(_, value)| value)
+}
+
+/// ELF header fields telling loaders which ABI a file follows. The defaults
+/// (all zero) describe a plain System V file; other loaders may check them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeaderOptions {
+    /// `EI_OSABI`, e.g. [`ELFOSABI_FREEBSD`]
+    pub os_abi: u8,
+    /// `EI_ABIVERSION`, whose meaning depends on the OS/ABI
+    pub abi_version: u8,
+    /// `e_flags`; x86 defines none, but some loaders use it for their own markers
+    pub flags: u32,
+}
+
+/// What `align` pads with when it has no fill operand of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fill {
+    /// As few NOP instructions as cover the gap (one-byte NOPs in 16-bit code)
+    Nop,
+    Byte(u8),
+}
+
+/// The `int3` breakpoint instruction, a fill that traps if execution runs into padding
+pub const INT3: u8 = 0xCC;
+
+pub(crate) struct Elf64Header {
+    pub(crate) e_ident: [u8; EI_NIDENT],
+    pub(crate) e_type: u16,
+    pub(crate) e_machine: u16,
+    pub(crate) e_version: u32,
+    pub(crate) e_entry: u64,
+    pub(crate) e_phoff: u64,
+    pub(crate) e_shoff: u64,
+    pub(crate) e_flags: u32,
+    pub(crate) e_ehsize: u16,
+    pub(crate) e_phentsize: u16,
+    pub(crate) e_phnum: u16,
+    pub(crate) e_shentsize: u16,
+    pub(crate) e_shnum: u16,
+    pub(crate) e_shstrndx: u16,
+}
+
+struct Elf64ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+struct Elf32Header {
     e_ident: [u8; EI_NIDENT],
     e_type: u16,
     e_machine: u16,
     e_version: u32,
-    e_entry: u64,
-    e_phoff: u64,
-    e_shoff: u64,
+    e_entry: u32,
+    e_phoff: u32,
+    e_shoff: u32,
     e_flags: u32,
     e_ehsize: u16,
     e_phentsize: u16,
@@ -35,268 +127,1721 @@ struct Elf64Header {
     e_shstrndx: u16,
 }
 
-#[repr(C, packed)]
-struct Elf64ProgramHeader {
+/// ELF32 program header; note `p_flags` comes after the sizes, unlike ELF64
+struct Elf32ProgramHeader {
     p_type: u32,
+    p_offset: u32,
+    p_vaddr: u32,
+    p_paddr: u32,
+    p_filesz: u32,
+    p_memsz: u32,
     p_flags: u32,
-    p_offset: u64,
-    p_vaddr: u64,
-    p_paddr: u64,
-    p_filesz: u64,
-    p_memsz: u64,
-    p_align: u64,
+    p_align: u32,
+}
+
+struct Elf32SectionHeader {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: u32,
+    sh_addr: u32,
+    sh_offset: u32,
+    sh_size: u32,
+    sh_link: u32,
+    sh_info: u32,
+    sh_addralign: u32,
+    sh_entsize: u32,
+}
+
+/// ELF32 symbol; the value and size come before the type, unlike ELF64
+struct Elf32Symbol {
+    st_name: u32,
+    st_value: u32,
+    st_size: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+}
+
+/// A PT_LOAD or PT_TLS segment, before it is written as an ELF32 or ELF64 program header
+struct Segment {
+    p_type: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+pub(crate) fn round_up(value: u64, align: u64) -> u64 {
+    if value.is_multiple_of(align) { value } else { value + align - (value % align) }
+}
+
+/// Little-endian integers read from a file, `None` past its end
+pub(crate) fn read<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    bytes.get(offset..offset.checked_add(N)?)?.try_into().ok()
+}
+
+pub(crate) fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+pub(crate) fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    read(bytes, offset).map(u64::from_le_bytes)
+}
+
+/// A header or table entry, written field by field as the ELF file layout
+/// orders it, in little-endian byte order
+pub(crate) trait Record {
+    /// Size of the record in the file
+    const SIZE: usize;
+
+    /// Append the record's bytes to `out`
+    fn write(&self, out: &mut Vec<u8>);
 }
 
-fn round_up(value: u64, align: u64) -> u64 {
-    if value % align == 0 { value } else { value + align - (value % align) }
+/// File bytes of a header or table entry
+pub(crate) fn record_bytes<T: Record>(record: &T) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(T::SIZE);
+    record.write(&mut bytes);
+    debug_assert_eq!(bytes.len(), T::SIZE);
+    bytes
+}
+
+/// Append each named integer field of `$record` to `$out` in little-endian order
+macro_rules! write_fields {
+    ($out:expr, $record:expr, $($field:ident),* $(,)?) => {
+        $($out.extend_from_slice(&$record.$field.to_le_bytes());)*
+    };
+}
+pub(crate) use write_fields;
+
+impl Record for Elf64Header {
+    const SIZE: usize = 64;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.e_ident);
+        write_fields!(out, self, e_type, e_machine, e_version, e_entry, e_phoff, e_shoff, e_flags,
+            e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx);
+    }
+}
+
+impl Record for Elf64ProgramHeader {
+    const SIZE: usize = 56;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        write_fields!(out, self, p_type, p_flags, p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_align);
+    }
+}
+
+impl Record for Elf32Header {
+    const SIZE: usize = 52;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.e_ident);
+        write_fields!(out, self, e_type, e_machine, e_version, e_entry, e_phoff, e_shoff, e_flags,
+            e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx);
+    }
+}
+
+impl Record for Elf32ProgramHeader {
+    const SIZE: usize = 32;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        write_fields!(out, self, p_type, p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_flags, p_align);
+    }
+}
+
+impl Record for Elf32SectionHeader {
+    const SIZE: usize = 40;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        write_fields!(out, self, sh_name, sh_type, sh_flags, sh_addr, sh_offset, sh_size, sh_link, sh_info,
+            sh_addralign, sh_entsize);
+    }
+}
+
+impl Record for Elf32Symbol {
+    const SIZE: usize = 16;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        write_fields!(out, self, st_name, st_value, st_size, st_info, st_other, st_shndx);
+    }
+}
+
+/// An `equ` definition, evaluated once every label has its address
+struct EquDefinition {
+    name: Symbol,
+    expression: String,
+    /// Value of `$` at the definition
+    here: u64,
+    line: usize,
+}
+
+/// Consecutive bytes of .text or the data segment that belong to one section
+struct SectionSpan {
+    name: String,
+    in_text: bool,
+    bytes: Range<usize>,
+}
+
+/// Bytes of .text or the data segment one statement emitted
+struct StatementSpan {
+    section: String,
+    in_text: bool,
+    bytes: Range<usize>,
+    statement: usize,
+}
+
+/// A label reference inside an encoded instruction
+pub(crate) struct Relocation {
+    /// Whether the instruction is in .text (otherwise the data segment)
+    pub(crate) in_text: bool,
+    /// Offset of the patched field within the section
+    pub(crate) offset: usize,
+    /// Offset of the end of the instruction, which relative fields count from
+    pub(crate) end: usize,
+    pub(crate) kind: FixupKind,
+    pub(crate) instruction: Symbol,
+    pub(crate) label: Symbol,
+    /// Number added to the label's address
+    pub(crate) addend: i64,
+    pub(crate) wrt: Option<SpecialSymbol>,
 }
 
 pub struct ElfGenerator {
     text_address: u64,
     data_address: u64,
     entry_point: u64,
+    entry_symbol: String,
     program: Program,
-    labels: HashMap<String, u64>,
+    labels: HashMap<Symbol, u64>,
+    /// Whether each label placed by the layout is in .text (otherwise the data segment)
+    label_sections: HashMap<Symbol, bool>,
     text_section: Vec<u8>,
     data_section: Vec<u8>,
+    /// Label references collected during layout, patched once all addresses are known
+    relocations: Vec<Relocation>,
+    equs: Vec<EquDefinition>,
     encoder: MachineCodeEncoder,
+    /// Write an i386 ELF32 file instead of an x86-64 ELF64 one
+    elf32: bool,
+    header: HeaderOptions,
+    verbose: bool,
+    /// Draw progress lines with plain ASCII
+    ascii: bool,
+    strict_sections: bool,
+    /// Whether to write a .comment section
+    comment: bool,
+    /// Largest size in bytes .text or the data segment may grow to
+    max_section_size: u64,
+    /// Padding `align` emits in .text and in the data segment
+    code_fill: Fill,
+    data_fill: Fill,
+    /// Largest `align` boundary in .text and in the data segment
+    text_alignment: u64,
+    data_alignment: u64,
+    /// Initialization image of the thread-local block
+    tdata_section: Vec<u8>,
+    /// Size of .tbss, the zeroed rest of the thread-local block
+    tbss_size: u64,
+    /// Largest `align` boundary in .tdata and .tbss
+    tls_alignment: u64,
+    /// Whether each thread-local label is in .tbss, and its offset in its section
+    thread_locals: HashMap<Symbol, (bool, u64)>,
+    /// Source file named in the debug information, when it is written
+    debug_file: Option<String>,
+    /// Address and source line of each instruction in .text, for the debug information
+    line_rows: Vec<(u64, usize)>,
+    /// Byte range and statement index of each instruction in .text
+    text_instructions: Vec<(Range<usize>, usize)>,
+    /// Runs of bytes each section contributes, in layout order
+    section_spans: Vec<SectionSpan>,
+    /// Bytes each statement emitted, in layout order
+    statement_spans: Vec<StatementSpan>,
+    /// Statements whose jump takes its near form, see [`ElfGenerator::lengthen_branches`]
+    near_branches: HashSet<usize>,
+    warnings: Vec<ElfError>,
 }
 
+/// Default for [`ElfGenerator::with_max_section_size`]: 256 MiB
+pub const DEFAULT_MAX_SECTION_SIZE: u64 = 256 * 1024 * 1024;
+
+/// What the .comment section of ELF output says
+pub const COMMENT: &str = concat!("nasembler ", env!("CARGO_PKG_VERSION"));
+
+/// Contents of the .comment section: [`COMMENT`] between NULs, as compilers write it
+const COMMENT_SECTION: &[u8] = concat!("\0nasembler ", env!("CARGO_PKG_VERSION"), "\0").as_bytes();
+
 impl ElfGenerator {
     pub fn new(program: Program) -> Self {
-        let gen = Self {
+        Self {
             text_address: 0x400000,
             data_address: 0x600000,
             entry_point: 0,
+            entry_symbol: "_start".to_string(),
             program,
             labels: HashMap::new(),
+            label_sections: HashMap::new(),
             text_section: Vec::new(),
             data_section: Vec::new(),
+            relocations: Vec::new(),
+            equs: Vec::new(),
             encoder: MachineCodeEncoder::new(),
-        };
-        println!("{}", "■ Initialized ELF generator".green());
-        gen
+            elf32: false,
+            header: HeaderOptions::default(),
+            verbose: true,
+            ascii: false,
+            strict_sections: false,
+            comment: true,
+            max_section_size: DEFAULT_MAX_SECTION_SIZE,
+            code_fill: Fill::Nop,
+            data_fill: Fill::Byte(0),
+            text_alignment: 1,
+            data_alignment: 1,
+            tdata_section: Vec::new(),
+            tbss_size: 0,
+            tls_alignment: 1,
+            thread_locals: HashMap::new(),
+            debug_file: None,
+            line_rows: Vec::new(),
+            text_instructions: Vec::new(),
+            section_spans: Vec::new(),
+            statement_spans: Vec::new(),
+            near_branches: HashSet::new(),
+            warnings: Vec::new(),
+        }
     }
 
-    pub fn generate(&mut self, output_path: &str) -> Result<(), String> {
-        println!("{}", "■ Processing AST...".green());
-        self.process_ast()?;
-        println!("{}", "■ AST processed".green());
-        let elf_header_size = mem::size_of::<Elf64Header>() as u64;
-        let ph_size = mem::size_of::<Elf64ProgramHeader>() as u64 * 2;
-        let headers_size = elf_header_size + ph_size;
-        let text_offset = (headers_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
-        let text_filesz = self.text_section.len() as u64;
-        let text_memsz = round_up(text_filesz, PAGE_SIZE);
-        let data_offset = text_offset + text_memsz;
-        let data_filesz = self.data_section.len() as u64;
-        let data_memsz = round_up(data_filesz, PAGE_SIZE);
-        println!("{}", format!("■ .text: offset=0x{:X} size={} bytes", text_offset, text_filesz).blue());
-        println!("{}", format!("■ .data: offset=0x{:X} size={} bytes", data_offset, data_filesz).blue());
-        let elf_header = self.create_elf_header();
-        let text_header = Elf64ProgramHeader {
-            p_type: PT_LOAD,
-            p_flags: PF_R | PF_X,
-            p_offset: text_offset,
-            p_vaddr: self.text_address,
-            p_paddr: self.text_address,
-            p_filesz: text_filesz,
-            p_memsz: text_memsz,
-            p_align: PAGE_SIZE,
-        };
-        let data_header = Elf64ProgramHeader {
-            p_type: PT_LOAD,
-            p_flags: PF_R | PF_W,
-            p_offset: data_offset,
-            p_vaddr: self.data_address,
-            p_paddr: self.data_address,
-            p_filesz: data_filesz,
-            p_memsz: data_memsz,
-            p_align: PAGE_SIZE,
-        };
-        let mut file = File::create(output_path)
-            .map_err(|e| format!("× Failed to create output file: {}", e))?;
-        println!("{}", "■ Writing ELF header...".green());
-        file.write_all(unsafe {
-            std::slice::from_raw_parts(&elf_header as *const Elf64Header as *const u8, mem::size_of::<Elf64Header>())
-        }).map_err(|e| format!("× Error writing ELF header: {}", e))?;
-        file.write_all(unsafe {
-            std::slice::from_raw_parts(&text_header as *const Elf64ProgramHeader as *const u8, mem::size_of::<Elf64ProgramHeader>())
-        }).map_err(|e| format!("× Error writing .text header: {}", e))?;
-        file.write_all(unsafe {
-            std::slice::from_raw_parts(&data_header as *const Elf64ProgramHeader as *const u8, mem::size_of::<Elf64ProgramHeader>())
-        }).map_err(|e| format!("× Error writing .data header: {}", e))?;
-        let current_pos = file.seek(SeekFrom::Current(0)).map_err(|e| e.to_string())?;
-        let pad_size = text_offset.checked_sub(current_pos).ok_or("× Negative padding for .text")?;
-        file.write_all(&vec![0u8; pad_size as usize]).map_err(|e| e.to_string())?;
-        file.write_all(&self.text_section).map_err(|e| e.to_string())?;
-        let text_pad = text_memsz.checked_sub(text_filesz).ok_or("× Negative .text padding")?;
-        if text_pad > 0 { file.write_all(&vec![0u8; text_pad as usize]).map_err(|e| e.to_string())?; }
-        let current_pos = file.seek(SeekFrom::Current(0)).map_err(|e| e.to_string())?;
-        let pad_size = data_offset.checked_sub(current_pos).ok_or("× Negative padding for .data")?;
-        file.write_all(&vec![0u8; pad_size as usize]).map_err(|e| e.to_string())?;
-        file.write_all(&self.data_section).map_err(|e| e.to_string())?;
-        let data_pad = data_memsz.checked_sub(data_filesz).ok_or("× Negative .data padding")?;
-        if data_pad > 0 { file.write_all(&vec![0u8; data_pad as usize]).map_err(|e| e.to_string())?; }
+    /// Set the symbol whose address becomes the ELF entry point
+    pub fn with_entry(mut self, symbol: &str) -> Self {
+        self.entry_symbol = symbol.to_string();
+        self
+    }
+
+    /// Set the virtual addresses .text and .data are laid out at
+    pub fn with_addresses(mut self, text_address: u64, data_address: u64) -> Self {
+        self.text_address = text_address;
+        self.data_address = data_address;
+        self
+    }
+
+    /// Set the code mode (16, 32 or 64) before any `bits` directive
+    pub fn with_bits(mut self, bits: u16) -> Self {
+        self.encoder = self.encoder.with_bits(bits);
+        self
+    }
+
+    /// Set whether to write an i386 ELF32 executable instead of an x86-64 ELF64 one
+    pub fn with_elf32(mut self, elf32: bool) -> Self {
+        self.elf32 = elf32;
+        self
+    }
+
+    /// Set the OS/ABI, ABI version and flags written into the ELF header
+    pub fn with_header(mut self, header: HeaderOptions) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// The OS/ABI, ABI version and flags written into the ELF header
+    pub fn header(&self) -> HeaderOptions {
+        self.header
+    }
+
+    /// Set whether progress messages are printed
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Set whether progress lines use plain ASCII instead of symbols such as `■`
+    pub fn with_ascii_decorations(mut self, ascii: bool) -> Self {
+        self.ascii = ascii;
+        self
+    }
+
+    /// Set whether data in .bss and instructions outside .text are errors.
+    ///
+    /// By default they are only warnings and get emitted into the section they
+    /// appear in. Data in .text is always allowed, for jump tables and shellcode.
+    pub fn with_strict_sections(mut self, strict: bool) -> Self {
+        self.strict_sections = strict;
+        self
+    }
+
+    /// Set the largest size in bytes .text or the data segment may grow to.
+    ///
+    /// Reservations are checked before anything is allocated, so a typo like
+    /// `resb 0x10000000000` fails with an error instead of exhausting memory.
+    pub fn with_max_section_size(mut self, bytes: u64) -> Self {
+        self.max_section_size = bytes;
+        self
+    }
+
+    /// Set whether the output has a .comment section naming the assembler,
+    /// [`COMMENT`]; on by default
+    pub fn with_comment(mut self, comment: bool) -> Self {
+        self.comment = comment;
+        self
+    }
+
+    /// Set what `align` pads .text and the data segment with when it doesn't
+    /// name a fill; NOPs and zero bytes by default
+    pub fn with_fill(mut self, code: Fill, data: Fill) -> Self {
+        self.code_fill = code;
+        self.data_fill = data;
+        self
+    }
+
+    /// Write a symbol table and a line table for `file` after the segments,
+    /// for debuggers and `nasembler addr2line`
+    pub fn with_debug_info(mut self, file: &str) -> Self {
+        self.debug_file = Some(file.to_string());
+        self
+    }
+
+    /// Encode the program and resolve relocations without producing an ELF image
+    pub fn build_sections(&mut self) -> Result<(), ElfError> {
+        self.process_ast()
+    }
+
+    /// Encoded .text contents (after `build_sections` or generation)
+    pub fn text_section(&self) -> &[u8] {
+        &self.text_section
+    }
+
+    /// Encoded .data contents (after `build_sections` or generation)
+    pub fn data_section(&self) -> &[u8] {
+        &self.data_section
+    }
+
+    /// Resolved address of a symbol
+    pub fn symbol_address(&self, name: &str) -> Option<u64> {
+        self.labels.get(&Symbol::lookup(name)?).copied()
+    }
+
+    /// All resolved symbol addresses
+    pub fn symbols(&self) -> &HashMap<Symbol, u64> {
+        &self.labels
+    }
+
+    /// Resolved address of the entry symbol (0 until it is placed)
+    pub fn entry_point(&self) -> u64 {
+        self.entry_point
+    }
+
+    /// Contents of the .comment section, if there is one
+    pub(crate) fn comment(&self) -> Option<&'static [u8]> {
+        self.comment.then_some(COMMENT_SECTION)
+    }
+
+    /// The program being generated
+    pub(crate) fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// The program, with machine code and the byte range of each statement
+    /// filled in by the last layout
+    pub fn into_program(self) -> Program {
+        self.program
+    }
+
+    fn log(&self, message: ColoredString) {
+        if self.verbose {
+            let message = message.to_string();
+            println!("{}", if self.ascii { ascii_decorations(&message) } else { message });
+        }
+    }
+
+    /// Generate the ELF executable and write it to `output_path`
+    pub fn generate(&mut self, output_path: &str) -> Result<(), ElfError> {
+        let mut file = File::create(output_path)?;
+        self.write_to(&mut file)?;
         #[cfg(unix)] {
             use std::os::unix::fs::PermissionsExt;
-            let metadata = std::fs::metadata(output_path).map_err(|e| e.to_string())?;
+            let metadata = std::fs::metadata(output_path)?;
             let mut perms = metadata.permissions();
             perms.set_mode(perms.mode() | 0o755);
-            std::fs::set_permissions(output_path, perms).map_err(|e| e.to_string())?;
+            std::fs::set_permissions(output_path, perms)?;
         }
-        println!("{}", format!("■ ELF file generated at '{}'", output_path).green());
+        self.log(format!("■ ELF file generated at '{}'", output_path).green());
         Ok(())
     }
 
-    fn process_ast(&mut self) -> Result<(), String> {
-        let statements = self.program.statements.clone();
+    /// Generate the ELF executable into an in-memory buffer
+    pub fn generate_bytes(&mut self) -> Result<Vec<u8>, ElfError> {
+        self.log("■ Initialized ELF generator".green());
+        self.log("■ Processing AST...".green());
+        self.process_ast()?;
+        self.log("■ AST processed".green());
+        self.check_entry()?;
+        Ok(self.image())
+    }
+
+    /// Generate the ELF executable into any writer
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> Result<(), ElfError> {
+        self.log("■ Initialized ELF generator".green());
+        self.log("■ Processing AST...".green());
+        self.process_ast()?;
+        self.log("■ AST processed".green());
+        self.serialize(writer)
+    }
+
+    /// Write the headers and the already laid out sections
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), ElfError> {
+        self.check_entry()?;
+        writer.write_all(&self.image())?;
+        Ok(())
+    }
+
+    /// Fail if the entry symbol is never defined, which would leave the
+    /// entry point at address 0
+    fn check_entry(&self) -> Result<(), ElfError> {
+        if self.symbol_address(&self.entry_symbol).is_none() {
+            return Err(ElfError::UndefinedEntry(self.entry_symbol.clone()));
+        }
+        Ok(())
+    }
+
+    /// The whole executable in one buffer
+    fn image(&self) -> Vec<u8> {
+        let executable = Executable {
+            elf32: self.elf32,
+            entry: self.entry_point,
+            text: &self.text_section,
+            text_address: self.text_address,
+            data: &self.data_section,
+            data_address: self.data_address,
+            header: self.header,
+            tls: self.thread_local_block(),
+            comment: self.comment(),
+            debug: self.debug_file.as_deref().map(|file| DebugInfo {
+                file,
+                labels: self.debug_labels(),
+                thread_locals: self.debug_thread_locals(),
+                lines: &self.line_rows,
+            }),
+        };
+        let (text_offset, data_offset) = executable.offsets();
+        self.log(format!("■ .text: offset=0x{:X} size={} bytes", text_offset, self.text_section.len()).blue());
+        self.log(format!("■ .data: offset=0x{:X} size={} bytes", data_offset, self.data_section.len()).blue());
+        self.log("■ Writing ELF header...".green());
+        executable.image()
+    }
+
+    /// Labels placed in .text or the data segment, for the symbol table
+    fn debug_labels(&self) -> Vec<SizedLabel> {
+        let placed = self.label_sections.keys()
+            .filter(|&&label| !self.is_equ(label))
+            .filter_map(|label| Some((*label, *self.labels.get(label)?)));
+        sized_labels(
+            placed,
+            self.text_address..self.text_address + self.text_section.len() as u64,
+            self.data_address..self.data_address + self.data_section.len() as u64,
+        )
+    }
+
+    /// Labels placed in .tdata or .tbss, for the symbol table
+    fn debug_thread_locals(&self) -> Vec<SizedLabel> {
+        let Some(block) = self.thread_local_block() else {
+            return Vec::new();
+        };
+        let placed = self.thread_locals.keys().filter_map(|label| Some((*label, *self.labels.get(label)?)));
+        sized_labels(placed, 0..block.image.len() as u64, self.tbss_offset()..block.size())
+    }
+
+    fn process_ast(&mut self) -> Result<(), ElfError> {
+        self.layout()?;
+        self.resolve_equs()?;
+        self.patch_relocations()
+    }
+
+    /// Virtual address .text is laid out at
+    pub fn text_address(&self) -> u64 {
+        self.text_address
+    }
+
+    /// Move the data segment, e.g. to just after .text once its size is known
+    pub(crate) fn set_data_address(&mut self, data_address: u64) {
+        self.data_address = data_address;
+    }
+
+    /// Encode every statement into its section and record label addresses.
+    /// Jumps start out short and the layout is repeated until every one
+    /// that can't reach its label has been lengthened.
+    pub(crate) fn layout(&mut self) -> Result<(), ElfError> {
+        self.near_branches.clear();
+        loop {
+            let result = self.layout_once();
+            if result.is_err() || !self.lengthen_branches() {
+                return result;
+            }
+        }
+    }
+
+    fn layout_once(&mut self) -> Result<(), ElfError> {
+        self.text_section.clear();
+        self.data_section.clear();
+        self.tdata_section.clear();
+        self.tbss_size = 0;
+        self.thread_locals.clear();
+        self.labels.clear();
+        self.label_sections.clear();
+        self.relocations.clear();
+        self.equs.clear();
+        self.line_rows.clear();
+        self.text_instructions.clear();
+        self.section_spans.clear();
+        self.statement_spans.clear();
+        self.warnings.clear();
+        self.entry_point = 0;
+        // Moved out rather than copied so machine code can be stored while encoding
+        let mut statements = std::mem::take(&mut self.program.statements);
+        let result = self.layout_statements(&mut statements);
+        self.program.statements = statements;
+        self.record_byte_ranges();
+        result
+    }
+
+    /// Store the bytes each statement emitted in the program's spans
+    fn record_byte_ranges(&mut self) {
+        let spans = &mut self.program.spans;
+        spans.resize(self.program.statements.len(), NodeSpan::default());
+        for span in spans.iter_mut() {
+            span.bytes = None;
+        }
+        for span in &self.statement_spans {
+            let base = if span.in_text { self.text_address } else { self.data_address };
+            spans[span.statement].bytes = Some(ByteRange {
+                section: span.section.clone(),
+                address: base + span.bytes.start as u64,
+                offset: span.bytes.start as u64,
+                size: span.bytes.len() as u64,
+            });
+        }
+    }
+
+    fn layout_statements(&mut self, statements: &mut [Statement]) -> Result<(), ElfError> {
         let mut current_section = ".text".to_string();
-        for (idx, stmt) in statements.iter().enumerate() {
+        // Code mode for NOP padding, tracked the way the encoder tracks it
+        let mut bits = self.encoder.bits();
+        // Encoding doesn't depend on addresses, so it is done up front and only placed here
+        let mut encoded = self.encoder.encode_statements(statements);
+        if !self.near_branches.is_empty() {
+            let near = self.encoder.with_near_branches(true).encode_statements(statements);
+            for (idx, code) in near.into_iter().enumerate() {
+                if self.near_branches.contains(&idx) && code.as_ref().is_some_and(Result::is_ok) {
+                    encoded[idx] = code;
+                }
+            }
+        }
+        for idx in 0..statements.len() {
+            let mut machine_code = None;
+            let stmt = &statements[idx];
+            // Anything outside .text is laid out in the data segment
+            let in_text = current_section == ".text";
+            let start = self.section_len(in_text);
+            if let Some(nobits) = thread_local_section(&current_section) {
+                let previous = idx.checked_sub(1).map(|prev| &statements[prev]);
+                if self.layout_thread_local(stmt, previous, &current_section, nobits, bits)? {
+                    continue;
+                }
+            }
             match stmt {
                 Statement::Section(sec) => {
                     current_section = sec.name.clone();
-                    println!("{}", format!("■ Switched to section '{}'", current_section).cyan());
+                    self.log(format!("■ Switched to section '{}'", current_section).cyan());
                 }
                 Statement::Label(label) => {
-                    if current_section == ".text" {
-                        let addr = self.text_address + self.text_section.len() as u64;
-                        self.labels.insert(label.clone(), addr);
-                        if label == "_start" { self.entry_point = addr; }
-                    } else if current_section == ".data" {
-                        let addr = self.data_address + self.data_section.len() as u64;
-                        self.labels.insert(label.clone(), addr);
-                    }
+                    self.place_label(*label, in_text);
+                    let addr = self.current_address(in_text);
+                    if in_text && *label == self.entry_symbol { self.entry_point = addr; }
                 }
                 Statement::Directive(dir) => {
+                    if let (true, Some(Operand::Immediate(mode))) = (dir.name == "bits", dir.operands.first()) {
+                        bits = mode.parse().unwrap_or(bits);
+                    }
                     if dir.name == "global" || dir.name == "extern" {
-                        if let Operand::Label(sym) = &dir.operands[0] {
-                            if dir.name == "global" { self.labels.insert(sym.clone(), 0); }
-                        } else { return Err("■ Directive operand must be a label".to_string()); }
+                        if let Some(Operand::Label(sym)) = dir.operands.first() {
+                            if dir.name == "global" { self.labels.insert(*sym, 0); }
+                        } else { return Err(ElfError::InvalidSymbolOperand(dir.name.clone())); }
                     } else if dir.name == "equ" {
-                        if let Operand::Immediate(val) = &dir.operands[0] {
-                            let value = parse_number(val)?;
-                            if idx > 0 {
-                                if let Statement::Label(prev) = &statements[idx - 1] {
-                                    self.labels.insert(prev.clone(), value);
-                                }
-                            }
+                        if let (Some(Operand::Immediate(expression)), Some(Statement::Label(name))) =
+                            (dir.operands.first(), idx.checked_sub(1).map(|prev| &statements[prev]))
+                        {
+                            self.equs.push(EquDefinition {
+                                name: *name,
+                                expression: expression.clone(),
+                                here: self.current_address(in_text),
+                                line: dir.line,
+                            });
                         }
                     } else if dir.name == "db" || dir.name == "dw" || dir.name == "dd" || dir.name == "dq" {
-                        if current_section != ".data" { return Err("■ Data directives must be in .data section".to_string()); }
+                        // Data may sit anywhere but .bss, e.g. jump tables in .text
+                        if current_section == ".bss" {
+                            self.misplaced(ElfError::WrongSection { statement: "Data directives", expected: ".data", found: current_section.clone(), line: dir.line })?;
+                        }
+                        if idx > 0 { if let Statement::Label(prev) = &statements[idx - 1] {
+                            self.place_label(*prev, in_text);
+                        } }
+                        let (bytes, fixups) = encode_data_directive(&dir.name, &dir.operands)?;
+                        self.check_section_size(&current_section, in_text, bytes.len() as u64, dir.line)?;
+                        let start = self.section_len(in_text);
+                        self.relocations.extend(fixups.into_iter().map(|fixup| Relocation {
+                            in_text,
+                            offset: start + fixup.offset,
+                            end: start + fixup.offset + fixup.kind.size(),
+                            kind: fixup.kind,
+                            instruction: Symbol::intern(&dir.name),
+                            label: fixup.label,
+                            addend: fixup.addend,
+                            wrt: fixup.wrt,
+                        }));
+                        self.section_mut(in_text).extend(bytes);
+                    } else if let Some(size) = reserve_element_size(&dir.name) {
+                        if current_section != ".data" && current_section != ".bss" {
+                            self.misplaced(ElfError::WrongSection { statement: "Reservations", expected: ".bss", found: current_section.clone(), line: dir.line })?;
+                        }
+                        if idx > 0 { if let Statement::Label(prev) = &statements[idx - 1] {
+                            self.place_label(*prev, in_text);
+                        } }
+                        let count = match dir.operands.first() {
+                            Some(Operand::Immediate(count)) => parse_number(count)?,
+                            Some(op) => return Err(ElfError::UnsupportedDataOperand(op.to_string())),
+                            None => 0,
+                        };
+                        let bytes = count.saturating_mul(size);
+                        self.check_section_size(&current_section, in_text, bytes, dir.line)?;
+                        // Checked against the limit above, which is far below usize::MAX
+                        let section = self.section_mut(in_text);
+                        section.resize(section.len() + bytes as usize, 0);
+                    } else if dir.name == "align" {
                         if idx > 0 { if let Statement::Label(prev) = &statements[idx - 1] {
-                            let addr = self.data_address + self.data_section.len() as u64;
-                            self.labels.insert(prev.clone(), addr);
+                            self.place_label(*prev, in_text);
                         } }
-                        self.process_data_directive(&dir.name, &dir.operands)?;
+                        let boundary = align_boundary(&dir.operands)?;
+                        let address = self.current_address(in_text);
+                        let padding = address.checked_next_multiple_of(boundary).unwrap_or(u64::MAX) - address;
+                        self.check_section_size(&current_section, in_text, padding, dir.line)?;
+                        let fill = self.align_fill(dir.operands.get(1), in_text)?;
+                        // Checked against the limit above, which is far below usize::MAX
+                        let padding = fill_bytes(fill, padding as usize, bits);
+                        self.section_mut(in_text).extend(padding);
+                        let alignment = if in_text { &mut self.text_alignment } else { &mut self.data_alignment };
+                        *alignment = (*alignment).max(boundary);
                     }
                 }
                 Statement::Instruction(instr) => {
-                    if current_section != ".text" { return Err("■ Instructions must be in .text section".to_string()); }
-                    let code = self.encoder.encode(instr);
-                    self.text_section.extend_from_slice(&code);
+                    if !in_text {
+                        self.misplaced(ElfError::WrongSection { statement: "Instructions", expected: ".text", found: current_section.clone(), line: instr.line })?;
+                    }
+                    let encoded = match encoded[idx].take() {
+                        Some(Ok(encoded)) => encoded,
+                        Some(Err(reason)) => return Err(ElfError::Unencodable { instruction: instr.to_string(), reason, line: instr.line }),
+                        None => EncodedInstruction::default(),
+                    };
+                    self.check_section_size(&current_section, in_text, encoded.bytes.len() as u64, instr.line)?;
+                    let start = self.section_mut(in_text).len();
+                    let end = start + encoded.bytes.len();
+                    if in_text {
+                        self.text_instructions.push((start..end, idx));
+                        if self.debug_file.is_some() {
+                            self.line_rows.push((self.text_address + start as u64, instr.line));
+                        }
+                    }
+                    self.relocations.extend(encoded.fixups.into_iter().map(|fixup| Relocation {
+                        in_text,
+                        offset: start + fixup.offset,
+                        end,
+                        kind: fixup.kind,
+                        instruction: instr.name,
+                        label: fixup.label,
+                        addend: fixup.addend,
+                        wrt: fixup.wrt,
+                    }));
+                    self.section_mut(in_text).extend_from_slice(&encoded.bytes);
+                    machine_code = Some(encoded.bytes);
                 }
                 Statement::Comment(_) | Statement::Empty => {}
             }
+            // Programs built directly (not parsed) arrive without machine code
+            if let (Some(code), Statement::Instruction(stored)) = (machine_code, &mut statements[idx]) {
+                stored.machine_code = code;
+            }
+            let end = self.section_len(in_text);
+            if end > start {
+                self.extend_span(&current_section, in_text, start..end);
+                self.statement_spans.push(StatementSpan { section: current_section.clone(), in_text, bytes: start..end, statement: idx });
+            }
+        }
+        // .tbss follows .tdata in the thread-local block, so its labels move once .tdata is complete
+        let tbss_offset = self.tbss_offset();
+        for (&label, &(nobits, offset)) in &self.thread_locals {
+            self.labels.insert(label, if nobits { tbss_offset + offset } else { offset });
         }
-        self.patch_relocations()?;
         Ok(())
     }
 
-    fn process_data_directive(&mut self, dir_name: &str, operands: &[Operand]) -> Result<(), String> {
-        for op in operands {
-            match op {
-                Operand::Immediate(val) => {
-                    let num = parse_number(val)?;
-                    let bytes = match dir_name {
-                        "db" => vec![num as u8],
-                        "dw" => (num as u16).to_le_bytes().to_vec(),
-                        "dd" => (num as u32).to_le_bytes().to_vec(),
-                        "dq" => num.to_le_bytes().to_vec(),
-                        _ => return Err(format!("■ Unknown data directive '{}'", dir_name)),
-                    };
-                    self.data_section.extend(bytes);
-                }
-                Operand::String(s) => {
-                    self.data_section.extend(s.as_bytes());
-                    self.data_section.push(0);
-                }
-                _ => return Err("■ Unsupported operand in data directive".to_string()),
+    /// Lay out a statement in .tdata or .tbss, whose labels are offsets in
+    /// the thread-local block rather than addresses. Returns whether the
+    /// statement was handled; directives such as `global` aren't.
+    fn layout_thread_local(&mut self, stmt: &Statement, previous: Option<&Statement>, section: &str, nobits: bool, bits: u16) -> Result<bool, ElfError> {
+        let Statement::Directive(dir) = stmt else {
+            if let Statement::Label(label) = stmt {
+                self.place_thread_local(*label, nobits);
+            }
+            return Ok(matches!(stmt, Statement::Label(_)));
+        };
+        let filled = if dir.name == "db" || dir.name == "dw" || dir.name == "dd" || dir.name == "dq" {
+            if nobits {
+                // As in NASM, the space is reserved and the values dropped
+                self.misplaced(ElfError::WrongSection { statement: "Data directives", expected: ".tdata", found: section.to_string(), line: dir.line })?;
             }
+            match encode_data_directive(&dir.name, &dir.operands)? {
+                (bytes, fixups) if fixups.is_empty() => bytes,
+                // Nothing relocates the thread-local image
+                (_, fixups) => return Err(ElfError::UnsupportedDataOperand(fixups[0].label.to_string())),
+            }
+        } else if let Some(size) = reserve_element_size(&dir.name) {
+            let count = match dir.operands.first() {
+                Some(Operand::Immediate(count)) => parse_number(count)?,
+                Some(op) => return Err(ElfError::UnsupportedDataOperand(op.to_string())),
+                None => 0,
+            };
+            let bytes = count.saturating_mul(size);
+            self.check_size(section, self.thread_local_len(nobits).saturating_add(bytes), dir.line)?;
+            vec![0; bytes as usize]
+        } else if dir.name == "align" {
+            let boundary = align_boundary(&dir.operands)?;
+            let offset = self.thread_local_len(nobits);
+            let padding = offset.checked_next_multiple_of(boundary).unwrap_or(u64::MAX) - offset;
+            self.check_size(section, offset.saturating_add(padding), dir.line)?;
+            self.tls_alignment = self.tls_alignment.max(boundary);
+            let fill = self.align_fill(dir.operands.get(1), false)?;
+            fill_bytes(fill, padding as usize, bits)
+        } else {
+            return Ok(false);
+        };
+        if let Some(Statement::Label(label)) = previous {
+            self.place_thread_local(*label, nobits);
+        }
+        self.check_size(section, self.thread_local_len(nobits).saturating_add(filled.len() as u64), dir.line)?;
+        if nobits {
+            self.tbss_size += filled.len() as u64;
+        } else {
+            self.tdata_section.extend(filled);
         }
+        Ok(true)
+    }
+
+    /// Give a label in .tdata or .tbss the offset the next byte there will have
+    fn place_thread_local(&mut self, label: Symbol, nobits: bool) {
+        let offset = self.thread_local_len(nobits);
+        self.thread_locals.insert(label, (nobits, offset));
+        self.labels.insert(label, offset);
+    }
+
+    /// Size of .tdata, or of .tbss
+    fn thread_local_len(&self, nobits: bool) -> u64 {
+        if nobits { self.tbss_size } else { self.tdata_section.len() as u64 }
+    }
+
+    /// Offset of .tbss in the thread-local block
+    fn tbss_offset(&self) -> u64 {
+        round_up(self.tdata_section.len() as u64, self.tls_alignment)
+    }
+
+    /// The thread-local block, if the program has .tdata or .tbss (after layout)
+    pub(crate) fn thread_local_block(&self) -> Option<ThreadLocalBlock<'_>> {
+        let empty = self.tdata_section.is_empty() && self.tbss_size == 0 && self.thread_locals.is_empty();
+        (!empty).then(|| ThreadLocalBlock { image: &self.tdata_section, bss_size: self.tbss_size, align: self.tls_alignment })
+    }
+
+    /// Whether `label` is a thread-local in .tbss (otherwise .tdata), and its
+    /// offset in that section, or `None` if it isn't thread-local
+    pub(crate) fn thread_local(&self, label: Symbol) -> Option<(bool, u64)> {
+        self.thread_locals.get(&label).copied()
+    }
+
+    /// What an `align` with `operand` as its fill pads with
+    fn align_fill(&self, operand: Option<&Operand>, in_text: bool) -> Result<Fill, ElfError> {
+        Ok(match operand {
+            Some(Operand::Immediate(byte)) => Fill::Byte(parse_number(byte)? as u8),
+            Some(Operand::Label(name)) if *name == "int3" => Fill::Byte(INT3),
+            Some(Operand::Label(name)) if *name == "nop" => Fill::Nop,
+            Some(op) => return Err(ElfError::UnsupportedDataOperand(op.to_string())),
+            None if in_text => self.code_fill,
+            None => self.data_fill,
+        })
+    }
+
+    /// Largest `align` boundary used in .text or the data segment (after layout)
+    pub(crate) fn alignment(&self, in_text: bool) -> u64 {
+        if in_text { self.text_alignment } else { self.data_alignment }
+    }
+
+    /// Record that `bytes` of .text or the data segment belong to `section`
+    fn extend_span(&mut self, section: &str, in_text: bool, bytes: Range<usize>) {
+        match self.section_spans.last_mut() {
+            Some(last) if last.name == section && last.in_text == in_text && last.bytes.end == bytes.start => {
+                last.bytes.end = bytes.end;
+            },
+            _ => self.section_spans.push(SectionSpan { name: section.to_string(), in_text, bytes }),
+        }
+    }
+
+    /// Bytes of a `file_size`-byte output attributed to sections and labels (after layout)
+    pub(crate) fn layout_sizes(&self, file_size: u64) -> SizeReport {
+        let mut labels: Vec<(Symbol, bool, usize)> = self.label_sections.iter()
+            .filter(|&(&label, _)| !self.is_equ(label))
+            .filter_map(|(&label, &in_text)| {
+                let base = if in_text { self.text_address } else { self.data_address };
+                let offset = self.labels.get(&label)?.checked_sub(base)?;
+                Some((label, in_text, usize::try_from(offset).ok()?))
+            })
+            .collect();
+        labels.sort_by_key(|&(label, in_text, offset)| (!in_text, offset, label));
+
+        let mut sections = Vec::new();
+        let mut symbols = Vec::new();
+        for span in &self.section_spans {
+            sections.push(SizeEntry { name: span.name.clone(), section: span.name.clone(), size: span.bytes.len() as u64 });
+            let inside: Vec<(Symbol, usize)> = labels.iter()
+                .filter(|&&(_, in_text, offset)| in_text == span.in_text && span.bytes.contains(&offset))
+                .map(|&(label, _, offset)| (label, offset))
+                .collect();
+            let first = inside.first().map_or(span.bytes.end, |&(_, offset)| offset);
+            symbols.push(SizeEntry { name: size::UNLABELED.to_string(), section: span.name.clone(), size: (first - span.bytes.start) as u64 });
+            for (index, &(label, offset)) in inside.iter().enumerate() {
+                let end = inside.get(index + 1).map_or(span.bytes.end, |&(_, next)| next);
+                symbols.push(SizeEntry { name: label.to_string(), section: span.name.clone(), size: (end - offset) as u64 });
+            }
+        }
+        SizeReport::new(file_size, sections, symbols)
+    }
+
+    /// Where the bytes of each statement were placed, with lines in `file` (after layout)
+    pub(crate) fn layout_source_map(&self, file: &str) -> SourceMap {
+        let mut spans: Vec<&StatementSpan> = self.statement_spans.iter().collect();
+        spans.sort_by_key(|span| (!span.in_text, span.bytes.start));
+        let mappings = spans.into_iter()
+            .filter_map(|span| {
+                let (line, statement) = match &self.program.statements[span.statement] {
+                    Statement::Instruction(instruction) => (instruction.line, instruction.to_string()),
+                    Statement::Directive(directive) => (directive.line, directive.to_string()),
+                    _ => return None,
+                };
+                let base = if span.in_text { self.text_address } else { self.data_address };
+                Some(Mapping {
+                    section: span.section.clone(),
+                    address: base + span.bytes.start as u64,
+                    offset: span.bytes.start as u64,
+                    size: span.bytes.len() as u64,
+                    line,
+                    statement,
+                })
+            })
+            .collect();
+        SourceMap::new(file, mappings)
+    }
+
+    /// Give `label` the address the next byte emitted into its section will have
+    fn place_label(&mut self, label: Symbol, in_text: bool) {
+        let addr = self.current_address(in_text);
+        self.labels.insert(label, addr);
+        self.label_sections.insert(label, in_text);
+    }
+
+    /// Whether the layout placed `label` in .text (`Some(false)` for the data segment)
+    pub(crate) fn label_in_text(&self, label: Symbol) -> Option<bool> {
+        self.label_sections.get(&label).copied()
+    }
+
+    /// Whether `name` is defined by an `equ`
+    pub(crate) fn is_equ(&self, name: Symbol) -> bool {
+        self.equs.iter().any(|equ| equ.name == name)
+    }
+
+    /// Label references not yet patched
+    pub(crate) fn relocations_mut(&mut self) -> &mut Vec<Relocation> {
+        &mut self.relocations
+    }
+
+    /// A statement outside the section it belongs in: an error in strict mode, otherwise a warning
+    fn misplaced(&mut self, error: ElfError) -> Result<(), ElfError> {
+        if self.strict_sections {
+            return Err(error);
+        }
+        self.log(format!("■ Warning: {}", error).yellow());
+        self.warnings.push(error);
         Ok(())
     }
 
-    fn patch_relocations(&mut self) -> Result<(), String> {
-        let mut lea_list = Vec::new();
-        for (idx, stmt) in self.program.statements.iter().enumerate() {
-            if let Statement::Instruction(instr) = stmt {
-                if instr.name.to_lowercase() == "lea" && instr.operands.len() == 2 {
-                    if let Operand::Label(label) = &instr.operands[1] {
-                        let offset = self.instruction_offset(idx);
-                        lea_list.push((offset, label.clone()));
-                    }
-                }
+    /// Fail if adding `additional` bytes would take a section past the size limit
+    fn check_section_size(&self, section: &str, in_text: bool, additional: u64, line: usize) -> Result<(), ElfError> {
+        self.check_size(section, (self.section_len(in_text) as u64).saturating_add(additional), line)
+    }
+
+    /// Fail if `size` is past the section size limit
+    fn check_size(&self, section: &str, size: u64, line: usize) -> Result<(), ElfError> {
+        if size > self.max_section_size {
+            return Err(ElfError::SectionTooLarge { section: section.to_string(), size, limit: self.max_section_size, line });
+        }
+        Ok(())
+    }
+
+    fn section_len(&self, in_text: bool) -> usize {
+        if in_text { self.text_section.len() } else { self.data_section.len() }
+    }
+
+    /// Bytes of .text or of the data segment
+    fn section_mut(&mut self, in_text: bool) -> &mut Vec<u8> {
+        if in_text { &mut self.text_section } else { &mut self.data_section }
+    }
+
+    /// Address the next byte emitted into .text or the data segment will have
+    fn current_address(&self, in_text: bool) -> u64 {
+        if in_text {
+            self.text_address + self.text_section.len() as u64
+        } else {
+            self.data_address + self.data_section.len() as u64
+        }
+    }
+
+    /// Evaluate every `equ` now that all labels have addresses
+    pub(crate) fn resolve_equs(&mut self) -> Result<(), ElfError> {
+        let definitions: HashMap<Symbol, &EquDefinition> = self.equs.iter().map(|equ| (equ.name, equ)).collect();
+        let mut values = HashMap::new();
+        for equ in &self.equs {
+            self.equ_value(equ, &definitions, &mut values, &mut Vec::new())?;
+        }
+        self.labels.extend(values);
+        Ok(())
+    }
+
+    /// Value of one `equ`, resolving the equs it refers to first. `pending`
+    /// holds the chain of definitions being evaluated, to detect cycles.
+    fn equ_value<'a>(
+        &self,
+        equ: &'a EquDefinition,
+        definitions: &HashMap<Symbol, &'a EquDefinition>,
+        values: &mut HashMap<Symbol, u64>,
+        pending: &mut Vec<Symbol>,
+    ) -> Result<u64, ElfError> {
+        if let Some(&value) = values.get(&equ.name) {
+            return Ok(value);
+        }
+        if let Some(start) = pending.iter().position(|name| *name == equ.name) {
+            let mut cycle: Vec<String> = pending[start..].iter().map(ToString::to_string).collect();
+            cycle.push(equ.name.to_string());
+            return Err(ElfError::EquCycle { cycle, line: equ.line });
+        }
+
+        pending.push(equ.name);
+        let mut value: i128 = 0;
+        for (negative, term) in expression_terms(&equ.expression) {
+            let term_value = if term == "$" {
+                equ.here
+            } else if term.starts_with(|c: char| c.is_ascii_digit()) {
+                parse_number(term)?
+            } else if let Some(definition) = Symbol::lookup(term).and_then(|symbol| definitions.get(&symbol)) {
+                self.equ_value(definition, definitions, values, pending)?
+            } else if let Some(address) = self.symbol_address(term) {
+                address
+            } else {
+                return Err(ElfError::UndefinedEquSymbol { name: equ.name.to_string(), symbol: term.to_string(), line: equ.line });
+            };
+            value = if negative { value - term_value as i128 } else { value + term_value as i128 };
+        }
+        pending.pop();
+
+        // Negative results wrap, as they would in a 64-bit register
+        let value = value as u64;
+        values.insert(equ.name, value);
+        Ok(value)
+    }
+
+    /// Mark the short jumps that don't reach their label to take their near
+    /// form, returning whether there were any. Labels outside .text, equs
+    /// and labels left to the linker may be anywhere, so jumps to them are
+    /// always near. Lengthening a jump only moves labels further apart, so
+    /// laying out again until there are none left converges.
+    fn lengthen_branches(&mut self) -> bool {
+        let mut lengthened = false;
+        for relocation in &self.relocations {
+            if relocation.kind != FixupKind::Relative8 || !relocation.in_text {
+                continue;
+            }
+            let reaches = self.label_sections.get(&relocation.label) == Some(&true)
+                && !self.is_equ(relocation.label)
+                && self.labels.get(&relocation.label).is_some_and(|&address| {
+                    let distance = address.wrapping_add_signed(relocation.addend) as i64 - (self.text_address + relocation.end as u64) as i64;
+                    displacement_fits(distance, 8)
+                });
+            if reaches {
+                continue;
+            }
+            let index = self.text_instructions.partition_point(|(bytes, _)| bytes.end <= relocation.offset);
+            if let Some(&(_, statement)) = self.text_instructions.get(index) {
+                lengthened |= self.near_branches.insert(statement);
             }
         }
-        for (offset, label) in lea_list {
-            if let Some(&target_addr) = self.labels.get(&label) {
-                let rip = self.text_address + offset as u64 + 7;
-                let disp = (target_addr as i64 - rip as i64) as i32;
-                let disp_bytes = disp.to_le_bytes();
-                if offset + 7 <= self.text_section.len() {
-                    self.text_section[offset + 3 .. offset + 7].copy_from_slice(&disp_bytes);
-                } else { return Err(format!("■ LEA patch offset out of bounds for label '{}'", label)); }
-            } else { return Err(format!("■ Label '{}' not found for LEA patching", label)); }
+        lengthened
+    }
+
+    pub(crate) fn patch_relocations(&mut self) -> Result<(), ElfError> {
+        for relocation in std::mem::take(&mut self.relocations) {
+            let Relocation { in_text, offset, end, kind, instruction, label, addend, wrt } = relocation;
+            // Calls through the PLT go straight to a function linked in
+            // statically, but nothing builds a global offset table here
+            if let Some(special @ (SpecialSymbol::Got | SpecialSymbol::GotPcRel)) = wrt {
+                return Err(ElfError::GlobalOffsetTable { instruction: instruction.to_string(), label: label.to_string(), special: special.to_string() });
+            }
+            let Some(&address) = self.labels.get(&label) else {
+                if self.is_extern(&label) {
+                    return Err(ElfError::UnresolvedExtern { instruction: instruction.to_string(), symbol: label.to_string() });
+                }
+                return Err(ElfError::UndefinedSymbol(label.to_string()));
+            };
+            let target = address.wrapping_add_signed(addend);
+
+            // Code reaches a thread-local through the thread pointer, which
+            // points just past the thread-local block
+            let thread_pointer_offset = self.thread_local_block()
+                .filter(|_| self.thread_locals.contains_key(&label))
+                .map(|block| block.thread_pointer_offset(target));
+            let bytes = match (kind, thread_pointer_offset) {
+                (FixupKind::Absolute64, Some(offset)) => offset.to_le_bytes().to_vec(),
+                (FixupKind::Absolute32, Some(offset)) if i32::try_from(offset).is_ok() => offset.to_le_bytes()[..4].to_vec(),
+                (_, Some(_)) => {
+                    return Err(ElfError::ThreadLocalReference { instruction: instruction.to_string(), label: label.to_string() });
+                },
+                (FixupKind::Absolute64, None) => target.to_le_bytes().to_vec(),
+                (FixupKind::Absolute32 | FixupKind::Absolute16, None) => {
+                    let bits = kind.size() as u32 * 8;
+                    if target >> bits != 0 {
+                        return Err(ElfError::AddressOutOfRange { instruction: instruction.to_string(), label: label.to_string(), address: target, bits });
+                    }
+                    target.to_le_bytes()[..kind.size()].to_vec()
+                },
+                (FixupKind::Relative32 | FixupKind::Relative16 | FixupKind::Relative8, None) => {
+                    // Relative to the address of the next instruction
+                    let base = if in_text { self.text_address } else { self.data_address };
+                    let distance = target as i64 - (base + end as u64) as i64;
+                    let bits = kind.size() as u32 * 8;
+                    if !displacement_fits(distance, bits) {
+                        return Err(ElfError::DisplacementOutOfRange { instruction: instruction.to_string(), label: label.to_string(), distance, bits });
+                    }
+                    distance.to_le_bytes()[..kind.size()].to_vec()
+                },
+            };
+
+            let section = self.section_mut(in_text);
+            if end <= section.len() {
+                section[offset .. offset + bytes.len()].copy_from_slice(&bytes);
+            } else { return Err(ElfError::RelocationOutOfBounds(label.to_string())); }
         }
         Ok(())
     }
 
-    fn instruction_offset(&self, idx: usize) -> usize {
-        let mut offset = 0;
-        for i in 0..idx {
-            if let Some(Statement::Instruction(instr)) = self.program.statements.get(i) {
-                offset += instr.machine_code.len();
+    /// Whether the program declares `name` with `extern`
+    fn is_extern(&self, name: &str) -> bool {
+        self.program.statements.iter().any(|statement| matches!(statement,
+            Statement::Directive(dir) if dir.name == "extern"
+                && matches!(dir.operands.first(), Some(Operand::Label(symbol)) if symbol == name)))
+    }
+}
+
+/// A label and how far it extends, which debuggers use to tell which
+/// function an address is in
+pub(crate) struct SizedLabel {
+    pub(crate) name: Symbol,
+    pub(crate) address: u64,
+    pub(crate) size: u64,
+    pub(crate) in_text: bool,
+}
+
+/// The labels inside `text` or `data`, in address order, each extending to
+/// the next label in its section or to the end of the section
+pub(crate) fn sized_labels(
+    labels: impl IntoIterator<Item = (Symbol, u64)>,
+    text: Range<u64>,
+    data: Range<u64>,
+) -> Vec<SizedLabel> {
+    let mut placed: Vec<(Symbol, u64, bool)> = labels.into_iter()
+        .filter_map(|(name, address)| {
+            let in_text = text.contains(&address);
+            (in_text || data.contains(&address)).then_some((name, address, in_text))
+        })
+        .collect();
+    placed.sort_by_key(|&(name, address, _)| (address, name));
+
+    placed.iter().enumerate()
+        .map(|(index, &(name, address, in_text))| {
+            let section_end = if in_text { text.end } else { data.end };
+            let end = placed[index + 1..].iter()
+                .find(|&&(_, next, next_in_text)| next_in_text == in_text && next > address)
+                .map_or(section_end, |&(_, next, _)| next);
+            SizedLabel { name, address, size: end - address, in_text }
+        })
+        .collect()
+}
+
+/// Symbols and line numbers of a `-g` executable
+pub(crate) struct DebugInfo<'a> {
+    pub(crate) file: &'a str,
+    pub(crate) labels: Vec<SizedLabel>,
+    /// Thread-local labels, by offset in the thread-local block; `in_text`
+    /// is set for those in .tdata rather than .tbss
+    pub(crate) thread_locals: Vec<SizedLabel>,
+    /// Address and source line of each instruction in .text, in address order
+    pub(crate) lines: &'a [(u64, usize)],
+}
+
+/// A section of a `-g` executable, written as an ELF32 or ELF64 section header
+struct SectionHeader {
+    name: u32,
+    sh_type: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+/// Where the section header table is, for the ELF header
+#[derive(Default)]
+struct SectionTable {
+    offset: u64,
+    count: u16,
+    names_index: u16,
+}
+
+/// The thread-local block every thread gets a copy of: .tdata's contents
+/// followed by .tbss, zeroed
+#[derive(Clone, Copy)]
+pub(crate) struct ThreadLocalBlock<'a> {
+    /// Initial contents of .tdata
+    pub(crate) image: &'a [u8],
+    pub(crate) bss_size: u64,
+    pub(crate) align: u64,
+}
+
+impl ThreadLocalBlock<'_> {
+    /// Size of the block in memory, .tbss included
+    pub(crate) fn size(&self) -> u64 {
+        round_up(self.image.len() as u64, self.align) + self.bss_size
+    }
+
+    /// Where a variable `offset` bytes into the block is relative to the
+    /// thread pointer (`fs` on x86-64, `gs` on i386), which points at the
+    /// aligned end of the block
+    pub(crate) fn thread_pointer_offset(&self, offset: u64) -> i64 {
+        offset as i64 - round_up(self.size(), self.align) as i64
+    }
+}
+
+/// An executable with two PT_LOAD segments, .text and the data segment, and
+/// a PT_TLS segment at the end of the data segment if it has thread-locals
+pub(crate) struct Executable<'a> {
+    /// Write an i386 ELF32 file instead of an x86-64 ELF64 one
+    pub(crate) elf32: bool,
+    pub(crate) entry: u64,
+    pub(crate) text: &'a [u8],
+    pub(crate) text_address: u64,
+    pub(crate) data: &'a [u8],
+    pub(crate) data_address: u64,
+    pub(crate) header: HeaderOptions,
+    /// Placed after the data, in the data segment, and described by PT_TLS
+    pub(crate) tls: Option<ThreadLocalBlock<'a>>,
+    /// Written after the segments, like the debug sections
+    pub(crate) comment: Option<&'a [u8]>,
+    /// Written after the segments, where the loader doesn't map it
+    pub(crate) debug: Option<DebugInfo<'a>>,
+}
+
+impl Executable<'_> {
+    /// File offsets of .text and the data segment, each on its own page after the headers
+    pub(crate) fn offsets(&self) -> (u64, u64) {
+        let headers_size = if self.elf32 {
+            Elf32Header::SIZE + Elf32ProgramHeader::SIZE * self.segment_count()
+        } else {
+            Elf64Header::SIZE + Elf64ProgramHeader::SIZE * self.segment_count()
+        } as u64;
+        let text_offset = round_up(headers_size, PAGE_SIZE);
+        (text_offset, text_offset + round_up(self.text.len() as u64, PAGE_SIZE))
+    }
+
+    /// Program headers: the two PT_LOAD segments, and PT_TLS for thread-locals
+    fn segment_count(&self) -> usize {
+        2 + usize::from(self.tls.is_some())
+    }
+
+    /// Offset of the thread-local block's image from the start of the data segment
+    fn tls_offset(&self) -> u64 {
+        self.tls.map_or(0, |tls| round_up(self.data.len() as u64, tls.align))
+    }
+
+    /// The whole file in one buffer. It starts zeroed, so the padding between
+    /// the page-aligned segments needs no writes of its own.
+    pub(crate) fn image(&self) -> Vec<u8> {
+        let (text_offset, data_offset) = self.offsets();
+        let text_filesz = self.text.len() as u64;
+        let tls_offset = self.tls_offset();
+        let data_filesz = match self.tls {
+            Some(tls) => tls_offset + tls.image.len() as u64,
+            None => self.data.len() as u64,
+        };
+        let data_memsz = round_up(data_filesz, PAGE_SIZE);
+        let mut segments = vec![
+            Segment { p_type: PT_LOAD, flags: PF_R | PF_X, offset: text_offset, address: self.text_address, filesz: text_filesz, memsz: data_offset - text_offset, align: PAGE_SIZE },
+            Segment { p_type: PT_LOAD, flags: PF_R | PF_W, offset: data_offset, address: self.data_address, filesz: data_filesz, memsz: data_memsz, align: PAGE_SIZE },
+        ];
+        if let Some(tls) = self.tls {
+            segments.push(Segment {
+                p_type: PT_TLS, flags: PF_R, offset: data_offset + tls_offset, address: self.data_address + tls_offset,
+                filesz: tls.image.len() as u64, memsz: tls.size(), align: tls.align,
+            });
+        }
+        // Section sizes are capped by max_section_size, so the image fits in memory
+        let mut image = vec![0u8; (data_offset + data_memsz) as usize];
+        let text_start = text_offset as usize;
+        image[text_start..text_start + self.text.len()].copy_from_slice(self.text);
+        let data_start = data_offset as usize;
+        image[data_start..data_start + self.data.len()].copy_from_slice(self.data);
+        if let Some(tls) = self.tls {
+            let tls_start = data_start + tls_offset as usize;
+            image[tls_start..tls_start + tls.image.len()].copy_from_slice(tls.image);
+        }
+        let sections = if self.debug.is_some() || self.comment.is_some() {
+            self.append_sections(&mut image, text_offset, data_offset)
+        } else {
+            SectionTable::default()
+        };
+        let headers = if self.elf32 { self.elf32_headers(&segments, &sections) } else { self.elf64_headers(&segments, &sections) };
+        image[..headers.len()].copy_from_slice(&headers);
+        image
+    }
+
+    /// Contents of .symtab, .strtab, .debug_abbrev, .debug_info and .debug_line
+    fn debug_sections(&self, debug: &DebugInfo) -> [Vec<u8>; 5] {
+        const TEXT_INDEX: u16 = 1;
+        const DATA_INDEX: u16 = 2;
+        const TDATA_INDEX: u16 = 3;
+        const TBSS_INDEX: u16 = 4;
+
+        let mut names = StringTable::new();
+        let mut symtab = Vec::new();
+        let symbol_size = if self.elf32 { Elf32Symbol::SIZE } else { Elf64Symbol::SIZE };
+        symtab.resize(symbol_size, 0);
+        for label in &debug.labels {
+            let st_name = names.add(&label.name);
+            let st_info = STB_GLOBAL << 4 | if label.in_text { STT_FUNC } else { STT_OBJECT };
+            let st_shndx = if label.in_text { TEXT_INDEX } else { DATA_INDEX };
+            if self.elf32 {
+                symtab.extend_from_slice(&record_bytes(&Elf32Symbol {
+                    st_name, st_value: label.address as u32, st_size: label.size as u32, st_info, st_other: 0, st_shndx,
+                }));
+            } else {
+                symtab.extend_from_slice(&record_bytes(&Elf64Symbol {
+                    st_name, st_info, st_other: 0, st_shndx, st_value: label.address, st_size: label.size,
+                }));
+            }
+        }
+        for label in &debug.thread_locals {
+            let st_name = names.add(&label.name);
+            let st_info = STB_GLOBAL << 4 | STT_TLS;
+            let st_shndx = if label.in_text { TDATA_INDEX } else { TBSS_INDEX };
+            if self.elf32 {
+                symtab.extend_from_slice(&record_bytes(&Elf32Symbol {
+                    st_name, st_value: label.address as u32, st_size: label.size as u32, st_info, st_other: 0, st_shndx,
+                }));
+            } else {
+                symtab.extend_from_slice(&record_bytes(&Elf64Symbol {
+                    st_name, st_info, st_other: 0, st_shndx, st_value: label.address, st_size: label.size,
+                }));
             }
         }
-        offset
+        let address_size = if self.elf32 { 4 } else { 8 };
+        let text_end = self.text_address + self.text.len() as u64;
+        let debug_line = dwarf::debug_line(debug.file, debug.lines, text_end, address_size);
+        let (debug_abbrev, debug_info) = dwarf::compile_unit(debug.file, self.text_address..text_end, address_size);
+        [symtab, names.bytes, debug_abbrev, debug_info, debug_line]
     }
 
-    fn create_elf_header(&self) -> Elf64Header {
-        let mut e_ident = [0u8; EI_NIDENT];
-        e_ident[0..4].copy_from_slice(&[0x7F, b'E', b'L', b'F']);
-        e_ident[4] = 2;
-        e_ident[5] = 1;
-        e_ident[6] = 1;
-        e_ident[7] = 0;
-        e_ident[8] = 0;
+    /// Append .symtab, .strtab and the DWARF sections for `-g`, then
+    /// .comment, and the section headers describing them and the segments
+    fn append_sections(&self, image: &mut Vec<u8>, text_offset: u64, data_offset: u64) -> SectionTable {
+        // .tdata and .tbss come before the symbol table when there are thread-locals
+        let symtab_index = if self.tls.is_some() { 5 } else { 3 };
+        let symbol_size = if self.elf32 { Elf32Symbol::SIZE } else { Elf64Symbol::SIZE };
+        let debug_sections = self.debug.as_ref().map(|debug| self.debug_sections(debug));
+
+        let mut section_names = StringTable::new();
+        let mut headers = vec![
+            SectionHeader { name: 0, sh_type: 0, flags: 0, address: 0, offset: 0, size: 0, link: 0, info: 0, align: 0, entsize: 0 },
+            SectionHeader {
+                name: section_names.add(".text"), sh_type: SHT_PROGBITS, flags: SHF_ALLOC | SHF_EXECINSTR,
+                address: self.text_address, offset: text_offset, size: self.text.len() as u64, link: 0, info: 0, align: 16, entsize: 0,
+            },
+            SectionHeader {
+                name: section_names.add(".data"), sh_type: SHT_PROGBITS, flags: SHF_ALLOC | SHF_WRITE,
+                address: self.data_address, offset: data_offset, size: self.data.len() as u64, link: 0, info: 0, align: 4, entsize: 0,
+            },
+        ];
+        if let Some(tls) = self.tls {
+            let tls_offset = self.tls_offset();
+            let tbss_offset = round_up(tls.image.len() as u64, tls.align);
+            headers.push(SectionHeader {
+                name: section_names.add(".tdata"), sh_type: SHT_PROGBITS, flags: SHF_ALLOC | SHF_WRITE | SHF_TLS,
+                address: self.data_address + tls_offset, offset: data_offset + tls_offset, size: tls.image.len() as u64,
+                link: 0, info: 0, align: tls.align, entsize: 0,
+            });
+            headers.push(SectionHeader {
+                name: section_names.add(".tbss"), sh_type: SHT_NOBITS, flags: SHF_ALLOC | SHF_WRITE | SHF_TLS,
+                address: self.data_address + tls_offset + tbss_offset, offset: data_offset + tls_offset + tbss_offset,
+                size: tls.bss_size, link: 0, info: 0, align: tls.align, entsize: 0,
+            });
+        }
+        let mut contents = Vec::new();
+        if let Some([symtab, names, debug_abbrev, debug_info, debug_line]) = &debug_sections {
+            contents.extend([
+                Section { sh_type: SHT_SYMTAB, sh_link: symtab_index + 1, sh_info: 1, sh_addralign: 8, sh_entsize: symbol_size as u64, ..Section::new(".symtab", symtab) },
+                Section { sh_type: SHT_STRTAB, ..Section::new(".strtab", names) },
+                Section { sh_type: SHT_PROGBITS, ..Section::new(".debug_abbrev", debug_abbrev) },
+                Section { sh_type: SHT_PROGBITS, ..Section::new(".debug_info", debug_info) },
+                Section { sh_type: SHT_PROGBITS, ..Section::new(".debug_line", debug_line) },
+            ]);
+        }
+        contents.extend(self.comment.map(comment_section));
+        for section in contents {
+            image.resize(image.len().next_multiple_of(section.sh_addralign as usize), 0);
+            headers.push(SectionHeader {
+                name: section_names.add(section.name), sh_type: section.sh_type, flags: section.sh_flags, address: 0,
+                offset: image.len() as u64, size: section.contents.len() as u64, link: section.sh_link,
+                info: section.sh_info, align: section.sh_addralign, entsize: section.sh_entsize,
+            });
+            image.extend_from_slice(section.contents);
+        }
+        let names_name = section_names.add(".shstrtab");
+        headers.push(SectionHeader {
+            name: names_name, sh_type: SHT_STRTAB, flags: 0, address: 0, offset: image.len() as u64,
+            size: section_names.bytes.len() as u64, link: 0, info: 0, align: 1, entsize: 0,
+        });
+        image.extend_from_slice(&section_names.bytes);
+
+        image.resize(image.len().next_multiple_of(8), 0);
+        let table = SectionTable { offset: image.len() as u64, count: headers.len() as u16, names_index: headers.len() as u16 - 1 };
+        for header in &headers {
+            if self.elf32 {
+                image.extend_from_slice(&record_bytes(&Elf32SectionHeader {
+                    sh_name: header.name,
+                    sh_type: header.sh_type,
+                    sh_flags: header.flags as u32,
+                    sh_addr: header.address as u32,
+                    sh_offset: header.offset as u32,
+                    sh_size: header.size as u32,
+                    sh_link: header.link,
+                    sh_info: header.info,
+                    sh_addralign: header.align as u32,
+                    sh_entsize: header.entsize as u32,
+                }));
+            } else {
+                image.extend_from_slice(&record_bytes(&Elf64SectionHeader {
+                    sh_name: header.name,
+                    sh_type: header.sh_type,
+                    sh_flags: header.flags,
+                    sh_addr: header.address,
+                    sh_offset: header.offset,
+                    sh_size: header.size,
+                    sh_link: header.link,
+                    sh_info: header.info,
+                    sh_addralign: header.align,
+                    sh_entsize: header.entsize,
+                }));
+            }
+        }
+        table
+    }
+
+    /// ELF64 header followed by one program header per segment
+    fn elf64_headers(&self, segments: &[Segment], sections: &SectionTable) -> Vec<u8> {
+        let mut headers = record_bytes(&self.create_elf_header(sections));
+        for segment in segments {
+            headers.extend_from_slice(&record_bytes(&Elf64ProgramHeader {
+                p_type: segment.p_type,
+                p_flags: segment.flags,
+                p_offset: segment.offset,
+                p_vaddr: segment.address,
+                p_paddr: segment.address,
+                p_filesz: segment.filesz,
+                p_memsz: segment.memsz,
+                p_align: segment.align,
+            }));
+        }
+        headers
+    }
+
+    /// ELF32 header followed by one program header per segment. Addresses
+    /// past 4 GiB are truncated; labels there are already rejected when
+    /// relocations are patched.
+    fn elf32_headers(&self, segments: &[Segment], sections: &SectionTable) -> Vec<u8> {
+        let mut headers = record_bytes(&self.create_elf32_header(sections));
+        for segment in segments {
+            headers.extend_from_slice(&record_bytes(&Elf32ProgramHeader {
+                p_type: segment.p_type,
+                p_offset: segment.offset as u32,
+                p_vaddr: segment.address as u32,
+                p_paddr: segment.address as u32,
+                p_filesz: segment.filesz as u32,
+                p_memsz: segment.memsz as u32,
+                p_flags: segment.flags,
+                p_align: segment.align as u32,
+            }));
+        }
+        headers
+    }
+
+    fn create_elf_header(&self, sections: &SectionTable) -> Elf64Header {
         Elf64Header {
-            e_ident,
+            e_ident: elf_ident(ELFCLASS64, &self.header),
             e_type: ET_EXEC,
             e_machine: EM_X86_64,
             e_version: EV_CURRENT as u32,
-            e_entry: self.entry_point,
-            e_phoff: mem::size_of::<Elf64Header>() as u64,
-            e_shoff: 0,
-            e_flags: 0,
-            e_ehsize: mem::size_of::<Elf64Header>() as u16,
-            e_phentsize: mem::size_of::<Elf64ProgramHeader>() as u16,
-            e_phnum: 2,
-            e_shentsize: 0,
-            e_shnum: 0,
-            e_shstrndx: 0,
+            e_entry: self.entry,
+            e_phoff: Elf64Header::SIZE as u64,
+            e_shoff: sections.offset,
+            e_flags: self.header.flags,
+            e_ehsize: Elf64Header::SIZE as u16,
+            e_phentsize: Elf64ProgramHeader::SIZE as u16,
+            e_phnum: self.segment_count() as u16,
+            e_shentsize: if sections.count > 0 { Elf64SectionHeader::SIZE as u16 } else { 0 },
+            e_shnum: sections.count,
+            e_shstrndx: sections.names_index,
+        }
+    }
+
+    fn create_elf32_header(&self, sections: &SectionTable) -> Elf32Header {
+        Elf32Header {
+            e_ident: elf_ident(ELFCLASS32, &self.header),
+            e_type: ET_EXEC,
+            e_machine: EM_386,
+            e_version: EV_CURRENT as u32,
+            e_entry: self.entry as u32,
+            e_phoff: Elf32Header::SIZE as u32,
+            e_shoff: sections.offset as u32,
+            e_flags: self.header.flags,
+            e_ehsize: Elf32Header::SIZE as u16,
+            e_phentsize: Elf32ProgramHeader::SIZE as u16,
+            e_phnum: self.segment_count() as u16,
+            e_shentsize: if sections.count > 0 { Elf32SectionHeader::SIZE as u16 } else { 0 },
+            e_shnum: sections.count,
+            e_shstrndx: sections.names_index,
         }
     }
 }
 
-fn parse_number(num: &str) -> Result<u64, String> {
-    if num.starts_with("0x") || num.starts_with("0X") {
-        u64::from_str_radix(&num[2..], 16).map_err(|e| format!("■ Invalid hex number '{}': {}", num, e))
+/// `e_ident` for a little-endian file of the given class and ABI
+pub(crate) fn elf_ident(class: u8, header: &HeaderOptions) -> [u8; EI_NIDENT] {
+    let mut e_ident = [0u8; EI_NIDENT];
+    e_ident[0..4].copy_from_slice(&[0x7F, b'E', b'L', b'F']);
+    e_ident[4] = class;
+    e_ident[5] = 1;
+    e_ident[6] = EV_CURRENT;
+    e_ident[7] = header.os_abi;
+    e_ident[8] = header.abi_version;
+    e_ident
+}
+
+impl ObjectWriter for ElfGenerator {
+    fn layout_sections(&mut self) -> Result<(), AssemblerError> {
+        self.log("■ Initialized ELF generator".green());
+        self.log("■ Processing AST...".green());
+        Ok(self.layout()?)
+    }
+
+    fn define_symbol(&mut self, name: &str, address: u64) {
+        self.labels.insert(Symbol::intern(name), address);
+        if name == self.entry_symbol { self.entry_point = address; }
+    }
+
+    fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbol_address(name)
+    }
+
+    fn apply_relocations(&mut self) -> Result<(), AssemblerError> {
+        self.resolve_equs()?;
+        self.patch_relocations()?;
+        self.log("■ AST processed".green());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, AssemblerError> {
+        self.check_entry()?;
+        Ok(self.image())
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        self.warnings.iter().map(ToString::to_string).collect()
+    }
+
+    fn size_report(&self, file_size: u64) -> Option<SizeReport> {
+        Some(self.layout_sizes(file_size))
+    }
+
+    fn source_map(&self, file: &str) -> Option<SourceMap> {
+        Some(self.layout_source_map(file))
+    }
+
+    fn code(&self) -> Vec<(&Instruction, &[u8])> {
+        self.text_instructions.iter()
+            .filter_map(|(range, index)| match &self.program.statements[*index] {
+                Statement::Instruction(instruction) => Some((instruction, &self.text_section[range.clone()])),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Split an equ expression like `end - start + 1` into (negated, term) pairs
+fn expression_terms(expression: &str) -> Vec<(bool, &str)> {
+    let mut terms = Vec::new();
+    let mut negative = false;
+    let mut start = 0;
+    for (i, c) in expression.char_indices() {
+        if c == '+' || c == '-' {
+            let term = expression[start..i].trim();
+            if !term.is_empty() {
+                terms.push((negative, term));
+            }
+            negative = c == '-';
+            start = i + 1;
+        }
+    }
+    let term = expression[start..].trim();
+    if !term.is_empty() {
+        terms.push((negative, term));
+    }
+    terms
+}
+
+/// Element size of resb/resw/resd/resq
+/// For .tdata and .tbss, whether the section is .tbss, which takes no space in the file
+fn thread_local_section(section: &str) -> Option<bool> {
+    match section {
+        ".tdata" => Some(false),
+        ".tbss" => Some(true),
+        _ => None,
+    }
+}
+
+/// Bytes of a data directive, and where each label operand's address goes in them
+fn encode_data_directive(dir_name: &str, operands: &[Operand]) -> Result<(Vec<u8>, Vec<Fixup>), ElfError> {
+    let mut bytes = Vec::new();
+    let mut fixups = Vec::new();
+    let size = match dir_name {
+        "db" => 1,
+        "dw" => 2,
+        "dd" => 4,
+        "dq" => 8,
+        _ => return Err(ElfError::UnknownDataDirective(dir_name.to_string())),
+    };
+    for op in operands {
+        match op {
+            Operand::Immediate(val) => {
+                let value = match val.strip_prefix('-') {
+                    Some(digits) => -(parse_number(digits)? as i128),
+                    None => parse_number(val)? as i128,
+                };
+                // The parser already warned about values that don't fit; emit the truncated value
+                let num = fit_immediate(value, size as u32 * 8, false).unwrap_or_else(|overflow| overflow.truncated);
+                bytes.extend_from_slice(&num.to_le_bytes()[..size]);
+            }
+            // A label's address, patched in once it is known
+            Operand::Label(label) => {
+                let kind = match size {
+                    2 => FixupKind::Absolute16,
+                    4 => FixupKind::Absolute32,
+                    8 => FixupKind::Absolute64,
+                    _ => return Err(ElfError::UnsupportedDataOperand(op.to_string())),
+                };
+                fixups.push(Fixup { offset: bytes.len(), kind, label: *label, addend: 0, wrt: None });
+                bytes.resize(bytes.len() + size, 0);
+            }
+            Operand::String(s) => {
+                bytes.extend(s.as_bytes());
+                bytes.push(0);
+            }
+            _ => return Err(ElfError::UnsupportedDataOperand(op.to_string())),
+        }
+    }
+    Ok((bytes, fixups))
+}
+
+/// Bytes `directive` emits at `offset` in its section, as the layout emits
+/// them; 0 for directives that emit nothing or can't be laid out
+pub(crate) fn directive_size(directive: &Directive, offset: u64) -> u64 {
+    if let Some(size) = reserve_element_size(&directive.name) {
+        return match directive.operands.first() {
+            Some(Operand::Immediate(count)) => parse_number(count).map_or(0, |count| count.saturating_mul(size)),
+            _ => 0,
+        };
+    }
+    match directive.name.as_str() {
+        "db" | "dw" | "dd" | "dq" => encode_data_directive(&directive.name, &directive.operands)
+            .map_or(0, |(bytes, _)| bytes.len() as u64),
+        "align" => align_boundary(&directive.operands)
+            .ok()
+            .and_then(|boundary| offset.checked_next_multiple_of(boundary))
+            .map_or(0, |aligned| aligned - offset),
+        _ => 0,
+    }
+}
+
+/// The boundary an `align` directive pads to
+fn align_boundary(operands: &[Operand]) -> Result<u64, ElfError> {
+    match operands.first() {
+        Some(Operand::Immediate(boundary)) => parse_number(boundary),
+        Some(op) => Err(ElfError::UnsupportedDataOperand(op.to_string())),
+        None => Ok(1),
+    }
+}
+
+/// `length` bytes of `fill`, NOPs being encoded for `bits`-bit code
+fn fill_bytes(fill: Fill, length: usize, bits: u16) -> Vec<u8> {
+    match fill {
+        Fill::Nop => nop_padding(length, bits),
+        Fill::Byte(byte) => vec![byte; length],
+    }
+}
+
+fn reserve_element_size(directive: &str) -> Option<u64> {
+    match directive {
+        "resb" => Some(1),
+        "resw" => Some(2),
+        "resd" => Some(4),
+        "resq" => Some(8),
+        _ => None,
+    }
+}
+
+fn parse_number(num: &str) -> Result<u64, ElfError> {
+    let result = if num.starts_with("0x") || num.starts_with("0X") {
+        u64::from_str_radix(&num[2..], 16)
     } else if num.starts_with("0b") || num.starts_with("0B") {
-        u64::from_str_radix(&num[2..], 2).map_err(|e| format!("■ Invalid binary number '{}': {}", num, e))
+        u64::from_str_radix(&num[2..], 2)
     } else if num.starts_with('0') && num.len() > 1 {
-        u64::from_str_radix(&num[1..], 8).map_err(|e| format!("■ Invalid octal number '{}': {}", num, e))
+        u64::from_str_radix(&num[1..], 8)
     } else {
-        num.parse::<u64>().map_err(|e| format!("■ Invalid decimal number '{}': {}", num, e))
-    }
+        num.parse::<u64>()
+    };
+    result.map_err(|source| ElfError::InvalidNumber { literal: num.to_string(), source })
 }
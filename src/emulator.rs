@@ -0,0 +1,1063 @@
+//! Running assembled executables without an x86 processor.
+//!
+//! The [`Emulator`] loads an ELF32 or ELF64 executable and interprets its
+//! machine code with a virtual register file and memory, so programs can be
+//! tested on hosts that can't run them, such as ARM machines and CI runners.
+//! It understands the instructions nasembler encodes: `mov`, `lea`, `add`,
+//! `sub`, `xor`, `cmp`, `inc`, `dec`, `mul`, `imul`, `div`, `idiv`, `not`,
+//! `neg`, the shifts and rotates, `setcc`, `cmovcc`, `movzx`, `movsx`,
+//! `movsxd`, `push`, `pop`, the jumps and `call`/`ret`, `syscall` and `int
+//! 0x80`. The `write`, `exit` and `exit_group` syscalls are carried out; any
+//! other syscall stops the emulation with an error, as does an instruction
+//! outside that set.
+//!
+//! ```no_run
+//! use nasembler::emulator::Emulator;
+//!
+//! let binary = std::fs::read("program").unwrap();
+//! let mut emulator = Emulator::load(&binary).unwrap();
+//! let code = emulator.run(&mut std::io::stdout(), &mut std::io::stderr()).unwrap();
+//! println!("exited with {}", code);
+//! ```
+
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::elf::{read_u16, read_u32, read_u64};
+use crate::relocatable::ET_REL;
+use crate::syscalls;
+
+/// Instructions [`Emulator::run`] executes before giving up on a program
+pub const DEFAULT_STEP_LIMIT: u64 = 10_000_000;
+/// Size of the stack given to the program
+pub const STACK_SIZE: u64 = 1 << 20;
+const STACK_TOP_64: u64 = 0x7FFF_FFFF_F000;
+const STACK_TOP_32: u64 = 0xC000_0000;
+/// Largest segment the emulator maps
+const MAX_SEGMENT_SIZE: u64 = 1 << 30;
+/// `argv[0]` the program sees
+const PROGRAM_NAME: &[u8] = b"program\0";
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const EM_386: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+/// Flag bits of rflags
+pub const CF: u64 = 1 << 0;
+pub const PF: u64 = 1 << 2;
+pub const ZF: u64 = 1 << 6;
+pub const SF: u64 = 1 << 7;
+pub const OF: u64 = 1 << 11;
+/// rflags at startup: the always-set bit 1 and the interrupt flag
+const INITIAL_FLAGS: u64 = 0x202;
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+
+/// Names of the general-purpose registers, in encoding order
+pub const REGISTER_NAMES: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+const RAX: usize = 0;
+const RCX: usize = 1;
+const RDX: usize = 2;
+const RBX: usize = 3;
+const RSP: usize = 4;
+const RSI: usize = 6;
+const RDI: usize = 7;
+const R11: usize = 11;
+
+/// How a memory access went wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+/// Why a program couldn't be loaded or stopped before it exited
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EmulatorError {
+    /// The file isn't an x86 ELF executable the emulator can load
+    InvalidBinary(String),
+    /// The instruction at `address` isn't one the emulator knows
+    UnsupportedInstruction { address: u64, bytes: Vec<u8> },
+    /// The program made a syscall other than `write`, `exit` and `exit_group`
+    UnsupportedSyscall { address: u64, number: u64, bits: u16 },
+    /// The instruction at `rip` accessed memory that isn't mapped for it
+    MemoryFault { rip: u64, address: u64, access: Access },
+    /// The `div` or `idiv` at `rip` divided by zero or had a quotient too
+    /// large for its register
+    DivideError { rip: u64 },
+    /// The program ran for this many instructions without exiting
+    StepLimit(u64),
+    /// Writing the program's output failed
+    Io(io::Error),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::InvalidBinary(reason) => write!(f, "Can't emulate binary: {}", reason),
+            EmulatorError::UnsupportedInstruction { address, bytes } => {
+                let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                write!(f, "The emulator doesn't support the instruction at {:#x} ({})", address, bytes.join(" "))
+            },
+            EmulatorError::UnsupportedSyscall { address, number, bits } => {
+                match syscalls::syscall_name(*number as i64, *bits) {
+                    Some(name) => write!(f, "The emulator doesn't support the '{}' syscall ({})", name, number)?,
+                    None => write!(f, "The emulator doesn't support syscall {}", number)?,
+                }
+                write!(f, " made at {:#x}; only write, exit and exit_group are emulated", address)
+            },
+            EmulatorError::MemoryFault { rip, address, access } => {
+                write!(f, "Segmentation fault: the instruction at {:#x} tried to {} unmapped memory at {:#x}", rip, access, address)
+            },
+            EmulatorError::DivideError { rip } => {
+                write!(f, "Floating point exception: the instruction at {:#x} divided by zero or its quotient didn't fit", rip)
+            },
+            EmulatorError::StepLimit(steps) => write!(f, "The program didn't exit within {} instructions", steps),
+            EmulatorError::Io(_) => write!(f, "Failed to write the program's output"),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmulatorError::Io(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// A mapped range of the program's memory
+#[derive(Debug, Clone)]
+struct Segment {
+    start: u64,
+    bytes: Vec<u8>,
+    writable: bool,
+    executable: bool,
+}
+
+impl Segment {
+    fn contains(&self, address: u64, len: u64) -> bool {
+        address >= self.start && address.saturating_add(len) <= self.start + self.bytes.len() as u64
+    }
+}
+
+/// Where a ModRM-encoded operand is
+#[derive(Debug, Clone, Copy)]
+enum Place {
+    Register(usize),
+    Memory(u64),
+}
+
+/// Bytes of the instruction being decoded
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Cursor<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    /// A little-endian field of `size` bytes
+    fn field(&mut self, size: usize) -> Option<u64> {
+        let bytes = self.bytes.get(self.position..self.position + size)?;
+        self.position += size;
+        Some(bytes.iter().rev().fold(0, |value, &byte| value << 8 | u64::from(byte)))
+    }
+
+    fn signed(&mut self, size: usize) -> Option<i64> {
+        let shift = 64 - size as u32 * 8;
+        self.field(size).map(|value| ((value << shift) as i64) >> shift)
+    }
+
+    /// Step over a ModRM byte and the SIB byte and displacement it calls
+    /// for, for instructions that never use their operand
+    fn skip_modrm(&mut self) -> Option<()> {
+        let modrm = self.byte()?;
+        let (mode, rm) = (modrm >> 6, modrm & 7);
+        let base = if mode != 3 && rm == 4 { self.byte()? & 7 } else { rm };
+        let displacement = match (mode, base) {
+            (0, 5) | (2, _) => 4,
+            (1, _) => 1,
+            _ => 0,
+        };
+        self.field(displacement).map(|_| ())
+    }
+}
+
+/// The state of an emulated program
+#[derive(Debug, Clone)]
+pub struct Emulator {
+    bits: u16,
+    registers: [u64; 16],
+    rip: u64,
+    flags: u64,
+    segments: Vec<Segment>,
+    steps: u64,
+    step_limit: u64,
+    exit_code: Option<i32>,
+}
+
+impl Emulator {
+    /// Map an executable's loadable segments and a stack, ready to run from its entry point
+    pub fn load(binary: &[u8]) -> Result<Self, EmulatorError> {
+        let invalid = |reason: &str| EmulatorError::InvalidBinary(reason.to_string());
+        if binary.get(..4) != Some(b"\x7FELF".as_slice()) {
+            return Err(invalid("not an ELF file; assemble it with -f elf or -f elf32"));
+        }
+        if binary.get(5) != Some(&1) {
+            return Err(invalid("not a little-endian ELF file"));
+        }
+        let bits = match binary.get(4) {
+            Some(1) => 32,
+            Some(2) => 64,
+            _ => return Err(invalid("unknown ELF class")),
+        };
+        let truncated = || invalid("truncated or malformed headers");
+        if read_u16(binary, 16) == Some(ET_REL) {
+            return Err(invalid("it's a relocatable object; link it into an executable first"));
+        }
+        let machine = read_u16(binary, 18).ok_or_else(truncated)?;
+        if machine != if bits == 64 { EM_X86_64 } else { EM_386 } {
+            return Err(invalid("not an x86 executable"));
+        }
+
+        let (entry, phoff, phentsize, phnum) = if bits == 64 {
+            (read_u64(binary, 24), read_u64(binary, 32), read_u16(binary, 54), read_u16(binary, 56))
+        } else {
+            (read_u32(binary, 24).map(u64::from), read_u32(binary, 28).map(u64::from), read_u16(binary, 42), read_u16(binary, 44))
+        };
+        let (entry, phoff) = (entry.ok_or_else(truncated)?, phoff.ok_or_else(truncated)? as usize);
+        let (phentsize, phnum) = (phentsize.ok_or_else(truncated)? as usize, phnum.ok_or_else(truncated)? as usize);
+
+        let mut segments = Vec::new();
+        for index in 0..phnum {
+            let header = phoff + index * phentsize;
+            let (kind, flags, offset, address, file_size, memory_size) = if bits == 64 {
+                (read_u32(binary, header), read_u32(binary, header + 4), read_u64(binary, header + 8),
+                 read_u64(binary, header + 16), read_u64(binary, header + 32), read_u64(binary, header + 40))
+            } else {
+                (read_u32(binary, header), read_u32(binary, header + 24), read_u32(binary, header + 4).map(u64::from),
+                 read_u32(binary, header + 8).map(u64::from), read_u32(binary, header + 16).map(u64::from),
+                 read_u32(binary, header + 20).map(u64::from))
+            };
+            let kind = kind.ok_or_else(truncated)?;
+            if kind == PT_INTERP {
+                return Err(invalid("dynamically linked executables can't be emulated"));
+            }
+            if kind != PT_LOAD {
+                continue;
+            }
+            let (flags, offset, address) = (flags.ok_or_else(truncated)?, offset.ok_or_else(truncated)?, address.ok_or_else(truncated)?);
+            let (file_size, memory_size) = (file_size.ok_or_else(truncated)?, memory_size.ok_or_else(truncated)?);
+            if memory_size > MAX_SEGMENT_SIZE || file_size > memory_size {
+                return Err(invalid("a segment is too large to emulate"));
+            }
+
+            // Bytes past the end of the file part are zero, as in .bss
+            let mut bytes = vec![0; memory_size as usize];
+            let file_part = offset.checked_add(file_size)
+                .and_then(|end| binary.get(offset as usize..end as usize))
+                .ok_or_else(truncated)?;
+            bytes[..file_part.len()].copy_from_slice(file_part);
+            segments.push(Segment { start: address, bytes, writable: flags & PF_W != 0, executable: flags & PF_X != 0 });
+        }
+
+        let mut emulator = Self {
+            bits,
+            registers: [0; 16],
+            rip: entry,
+            flags: INITIAL_FLAGS,
+            segments,
+            steps: 0,
+            step_limit: DEFAULT_STEP_LIMIT,
+            exit_code: None,
+        };
+        emulator.set_up_stack();
+        Ok(emulator)
+    }
+
+    /// Set how many instructions [`run`](Self::run) executes before stopping with
+    /// [`EmulatorError::StepLimit`], which catches programs stuck in a loop
+    pub fn with_step_limit(mut self, step_limit: u64) -> Self {
+        self.step_limit = step_limit;
+        self
+    }
+
+    /// Map the stack and lay out what the kernel passes to a new program:
+    /// argc, argv with just the program name, an empty environment and an
+    /// empty auxiliary vector
+    fn set_up_stack(&mut self) {
+        let top = if self.bits == 64 { STACK_TOP_64 } else { STACK_TOP_32 };
+        let mut bytes = vec![0; STACK_SIZE as usize];
+        let name_address = top - 64;
+        let name_offset = (name_address - (top - STACK_SIZE)) as usize;
+        bytes[name_offset..name_offset + PROGRAM_NAME.len()].copy_from_slice(PROGRAM_NAME);
+        self.segments.push(Segment { start: top - STACK_SIZE, bytes, writable: true, executable: false });
+
+        let word = self.word_size();
+        // argc, argv[0], argv's NULL, envp's NULL, and AT_NULL
+        let entries = [1, name_address, 0, 0, 0, 0];
+        let stack_pointer = (name_address - entries.len() as u64 * word) & !0xF;
+        for (index, &entry) in entries.iter().enumerate() {
+            self.store(stack_pointer + index as u64 * word, word as usize, entry)
+                .expect("the initial stack is mapped");
+        }
+        self.registers[RSP] = stack_pointer;
+    }
+
+    /// Run until the program exits, returning its exit code. Output to file
+    /// descriptors 1 and 2 goes to `stdout` and `stderr`.
+    pub fn run(&mut self, stdout: &mut dyn Write, stderr: &mut dyn Write) -> Result<i32, EmulatorError> {
+        loop {
+            if let Some(code) = self.step(stdout, stderr)? {
+                return Ok(code);
+            }
+        }
+    }
+
+    /// Execute one instruction, returning the exit code once the program has exited
+    pub fn step(&mut self, stdout: &mut dyn Write, stderr: &mut dyn Write) -> Result<Option<i32>, EmulatorError> {
+        if let Some(code) = self.exit_code {
+            return Ok(Some(code));
+        }
+        if self.steps >= self.step_limit {
+            return Err(EmulatorError::StepLimit(self.steps));
+        }
+        self.steps += 1;
+
+        let start = self.rip;
+        let code = self.segments.iter()
+            .find(|segment| segment.executable && segment.contains(start, 1))
+            .map(|segment| &segment.bytes[(start - segment.start) as usize..])
+            .ok_or(EmulatorError::MemoryFault { rip: start, address: start, access: Access::Execute })?;
+        let code = &code[..code.len().min(15)];
+        let unsupported = || EmulatorError::UnsupportedInstruction { address: start, bytes: code.to_vec() };
+        let mut cursor = Cursor { bytes: code, position: 0 };
+
+        let mut operand_size = 4;
+        let mut rex = 0;
+        let opcode = loop {
+            match cursor.byte().ok_or_else(unsupported)? {
+                0x66 => operand_size = 2,
+                prefix @ 0x40..=0x4F if self.bits == 64 => {
+                    rex = prefix;
+                    // REX only applies right before the opcode
+                    break cursor.byte().ok_or_else(unsupported)?;
+                },
+                opcode => break opcode,
+            }
+        };
+        if rex & 0x8 != 0 {
+            operand_size = 8;
+        }
+        let rex_b = usize::from(rex & 0x1 != 0) << 3;
+
+        // Operands are decoded before anything is executed, so `next` is the
+        // address of the following instruction
+        let mut exit = None;
+        match opcode {
+            0x90 => self.rip = start + cursor.position as u64,
+            0xB8..=0xBF => {
+                let value = cursor.field(operand_size).ok_or_else(unsupported)?;
+                self.rip = start + cursor.position as u64;
+                self.set_register(usize::from(opcode - 0xB8) | rex_b, operand_size, value);
+            },
+            0xB0..=0xB7 => {
+                let value = cursor.field(1).ok_or_else(unsupported)?;
+                self.rip = start + cursor.position as u64;
+                self.write_byte(Place::Register(usize::from(opcode - 0xB0) | rex_b), rex, value)?;
+            },
+            // mov between byte registers and memory
+            0x88 | 0x8A => {
+                let (register, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                self.rip = start + cursor.position as u64;
+                let (source, destination) = if opcode == 0x88 { (Place::Register(register), place) } else { (place, Place::Register(register)) };
+                let value = self.read_byte(source, rex)?;
+                self.write_byte(destination, rex, value)?;
+            },
+            0xC6 => {
+                let (extension, place) = self.modrm(&mut cursor, start, rex, 1).ok_or_else(unsupported)?;
+                let value = cursor.byte().ok_or_else(unsupported)?;
+                if extension & 7 != 0 {
+                    return Err(unsupported());
+                }
+                self.rip = start + cursor.position as u64;
+                self.write_byte(place, rex, u64::from(value))?;
+            },
+            0x89 | 0x8B | 0x8D => {
+                let (register, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                self.rip = start + cursor.position as u64;
+                match opcode {
+                    0x89 => {
+                        let value = self.register(register, operand_size);
+                        self.write_place(place, operand_size, value)?;
+                    },
+                    0x8B => {
+                        let value = self.read_place(place, operand_size)?;
+                        self.set_register(register, operand_size, value);
+                    },
+                    _ => match place {
+                        Place::Memory(address) => self.set_register(register, operand_size, address),
+                        Place::Register(_) => return Err(unsupported()),
+                    },
+                }
+            },
+            // add, sub, xor and cmp between a register and a register or memory
+            0x01 | 0x03 | 0x29 | 0x2B | 0x31 | 0x33 | 0x39 | 0x3B => {
+                let (register, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                self.rip = start + cursor.position as u64;
+                let operation = opcode >> 3;
+                let (rm, reg) = (self.read_place(place, operand_size)?, self.register(register, operand_size));
+                // Bit 1 of the opcode makes the register the destination
+                if opcode & 2 == 0 {
+                    let (result, flags) = arithmetic(operation, rm, reg, operand_size).ok_or_else(unsupported)?;
+                    if operation != CMP {
+                        self.write_place(place, operand_size, result)?;
+                    }
+                    self.set_arithmetic_flags(result, flags, operand_size);
+                } else {
+                    let (result, flags) = arithmetic(operation, reg, rm, operand_size).ok_or_else(unsupported)?;
+                    if operation != CMP {
+                        self.set_register(register, operand_size, result);
+                    }
+                    self.set_arithmetic_flags(result, flags, operand_size);
+                }
+            },
+            // The same with an immediate and the accumulator
+            0x05 | 0x2D | 0x35 | 0x3D => {
+                let value = cursor.signed(operand_size.min(4)).ok_or_else(unsupported)? as u64 & mask(operand_size);
+                self.rip = start + cursor.position as u64;
+                let (result, flags) = arithmetic(opcode >> 3, self.register(RAX, operand_size), value, operand_size)
+                    .ok_or_else(unsupported)?;
+                if opcode >> 3 != CMP {
+                    self.set_register(RAX, operand_size, result);
+                }
+                self.set_arithmetic_flags(result, flags, operand_size);
+            },
+            // ... and with an immediate, the operation in ModRM.reg
+            0x81 | 0x83 => {
+                let immediate = if opcode == 0x83 { 1 } else { operand_size.min(4) };
+                let (operation, place) = self.modrm(&mut cursor, start, rex, immediate).ok_or_else(unsupported)?;
+                let value = cursor.signed(immediate).ok_or_else(unsupported)? as u64 & mask(operand_size);
+                self.rip = start + cursor.position as u64;
+                let (result, flags) = arithmetic(operation as u8 & 7, self.read_place(place, operand_size)?, value, operand_size)
+                    .ok_or_else(unsupported)?;
+                if operation as u8 & 7 != CMP {
+                    self.write_place(place, operand_size, result)?;
+                }
+                self.set_arithmetic_flags(result, flags, operand_size);
+            },
+            // imul reg, r/m, imm
+            0x69 | 0x6B => {
+                let immediate = if opcode == 0x6B { 1 } else { operand_size.min(4) };
+                let (register, place) = self.modrm(&mut cursor, start, rex, immediate).ok_or_else(unsupported)?;
+                let value = cursor.signed(immediate).ok_or_else(unsupported)? as u64 & mask(operand_size);
+                self.rip = start + cursor.position as u64;
+                let (result, flags) = multiply(self.read_place(place, operand_size)?, value, operand_size);
+                self.set_register(register, operand_size, result);
+                self.set_arithmetic_flags(result, flags, operand_size);
+            },
+            // inc, dec and push, the operation in ModRM.reg
+            0xFF => {
+                let (operation, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                self.rip = start + cursor.position as u64;
+                match operation & 7 {
+                    operation @ (0 | 1) => {
+                        let value = self.read_place(place, operand_size)?;
+                        let result = self.increment(operation == 1, value, operand_size);
+                        self.write_place(place, operand_size, result)?;
+                    },
+                    6 if operand_size != 2 => {
+                        let value = self.read_place(place, self.word_size() as usize)?;
+                        self.push(value)?;
+                    },
+                    _ => return Err(unsupported()),
+                }
+            },
+            // Stack operations with a stack slot sized operand
+            0x50..=0x5F | 0x68 | 0x6A | 0x8F if operand_size == 2 => return Err(unsupported()),
+            0x50..=0x57 => {
+                self.rip = start + cursor.position as u64;
+                self.push(self.registers[usize::from(opcode - 0x50) | rex_b])?;
+            },
+            0x58..=0x5F => {
+                self.rip = start + cursor.position as u64;
+                let value = self.pop()?;
+                self.set_register(usize::from(opcode - 0x58) | rex_b, self.word_size() as usize, value);
+            },
+            0x68 | 0x6A => {
+                let value = cursor.signed(if opcode == 0x6A { 1 } else { 4 }).ok_or_else(unsupported)?;
+                self.rip = start + cursor.position as u64;
+                self.push(value as u64)?;
+            },
+            0x8F => {
+                let (operation, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                if operation & 7 != 0 {
+                    return Err(unsupported());
+                }
+                self.rip = start + cursor.position as u64;
+                let value = self.pop()?;
+                self.write_place(place, self.word_size() as usize, value)?;
+            },
+            // ... and their one-byte forms outside 64-bit mode, where they are REX
+            0x40..=0x4F => {
+                self.rip = start + cursor.position as u64;
+                let register = usize::from(opcode & 7);
+                let result = self.increment(opcode & 8 != 0, self.register(register, operand_size), operand_size);
+                self.set_register(register, operand_size, result);
+            },
+            // not and neg, and mul, imul, div and idiv of rdx:rax, the
+            // operation in ModRM.reg
+            0xF7 => {
+                let (operation, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                if operation & 7 < 2 {
+                    return Err(unsupported());
+                }
+                self.rip = start + cursor.position as u64;
+                let operand = self.read_place(place, operand_size)?;
+                match operation & 7 {
+                    // not leaves the flags alone
+                    2 => self.write_place(place, operand_size, !operand & mask(operand_size))?,
+                    3 => {
+                        let (result, flags) = arithmetic(5, 0, operand, operand_size).unwrap_or_default();
+                        self.write_place(place, operand_size, result)?;
+                        self.set_arithmetic_flags(result, flags, operand_size);
+                    },
+                    operation => self.multiply_divide(operation as u8, operand, operand_size)
+                        .ok_or(EmulatorError::DivideError { rip: start })?,
+                }
+            },
+            // Shifts and rotates by 1, an imm8 or cl, the operation in ModRM.reg
+            0xC1 | 0xD1 | 0xD3 => {
+                let (operation, place) = self.modrm(&mut cursor, start, rex, usize::from(opcode == 0xC1)).ok_or_else(unsupported)?;
+                let count = match opcode {
+                    0xC1 => cursor.byte().ok_or_else(unsupported)?,
+                    0xD1 => 1,
+                    _ => self.register(RCX, 1) as u8,
+                };
+                // rcl, rcr and the undocumented /6
+                if matches!(operation & 7, 2 | 3 | 6) {
+                    return Err(unsupported());
+                }
+                self.rip = start + cursor.position as u64;
+                let value = self.read_place(place, operand_size)?;
+                let result = self.shift(operation as u8 & 7, value, count, operand_size);
+                self.write_place(place, operand_size, result)?;
+            },
+            // movsxd reg, r/m32
+            0x63 if self.bits == 64 && operand_size == 8 => {
+                let (register, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                self.rip = start + cursor.position as u64;
+                let value = sign_extend(self.read_place(place, 4)?, 4);
+                self.set_register(register, operand_size, value);
+            },
+            // mov r/m, imm32, sign-extended to 64 bits
+            0xC7 => {
+                let immediate = operand_size.min(4);
+                let (extension, place) = self.modrm(&mut cursor, start, rex, immediate).ok_or_else(unsupported)?;
+                let value = cursor.signed(immediate).ok_or_else(unsupported)?;
+                if extension & 7 != 0 {
+                    return Err(unsupported());
+                }
+                self.rip = start + cursor.position as u64;
+                self.write_place(place, operand_size, value as u64)?;
+            },
+            0xEB | 0xE9 | 0xE8 | 0x70..=0x7F => {
+                let size = if matches!(opcode, 0xE9 | 0xE8) { 4 } else { 1 };
+                let displacement = cursor.signed(size).ok_or_else(unsupported)?;
+                let next = start + cursor.position as u64;
+                let target = self.address(next.wrapping_add(displacement as u64));
+                self.rip = next;
+                match opcode {
+                    0xE8 => {
+                        self.push(next)?;
+                        self.rip = target;
+                    },
+                    0x70..=0x7F if !self.condition(opcode - 0x70) => {},
+                    _ => self.rip = target,
+                }
+            },
+            0xC3 => {
+                self.rip = start + 1;
+                self.rip = self.pop()?;
+            },
+            0xCD => {
+                let vector = cursor.byte().ok_or_else(unsupported)?;
+                if vector != 0x80 {
+                    return Err(unsupported());
+                }
+                self.rip = start + cursor.position as u64;
+                exit = self.syscall(start, 32, stdout, stderr)?;
+            },
+            0x0F => match cursor.byte().ok_or_else(unsupported)? {
+                0x05 if self.bits == 64 => {
+                    self.rip = start + cursor.position as u64;
+                    // syscall saves the return address and flags in rcx and r11
+                    self.registers[RCX] = self.rip;
+                    self.registers[R11] = self.flags;
+                    exit = self.syscall(start, 64, stdout, stderr)?;
+                },
+                // imul reg, r/m
+                0xAF => {
+                    let (register, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                    self.rip = start + cursor.position as u64;
+                    let (result, flags) = multiply(self.register(register, operand_size), self.read_place(place, operand_size)?, operand_size);
+                    self.set_register(register, operand_size, result);
+                    self.set_arithmetic_flags(result, flags, operand_size);
+                },
+                // The multi-byte NOPs `align` pads code with
+                0x1F => {
+                    cursor.skip_modrm().ok_or_else(unsupported)?;
+                    self.rip = start + cursor.position as u64;
+                },
+                // cmovcc reg, r/m, which reads the operand even if the
+                // condition doesn't hold and zero-extends a 32-bit register either way
+                condition @ 0x40..=0x4F => {
+                    let (register, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                    self.rip = start + cursor.position as u64;
+                    let value = self.read_place(place, operand_size)?;
+                    let value = if self.condition(condition - 0x40) { value } else { self.register(register, operand_size) };
+                    self.set_register(register, operand_size, value);
+                },
+                // movzx and movsx of a byte or a word
+                extend @ (0xB6 | 0xB7 | 0xBE | 0xBF) => {
+                    let (register, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                    self.rip = start + cursor.position as u64;
+                    let size = if extend & 1 == 0 { 1 } else { 2 };
+                    let value = if size == 1 { self.read_byte(place, rex)? } else { self.read_place(place, size)? };
+                    let value = if extend & 8 == 0 { value } else { sign_extend(value, size) };
+                    self.set_register(register, operand_size, value);
+                },
+                // setcc r/m8
+                condition @ 0x90..=0x9F => {
+                    let (_, place) = self.modrm(&mut cursor, start, rex, 0).ok_or_else(unsupported)?;
+                    self.rip = start + cursor.position as u64;
+                    let value = u64::from(self.condition(condition - 0x90));
+                    self.write_byte(place, rex, value)?;
+                },
+                condition @ 0x80..=0x8F => {
+                    let displacement = cursor.signed(4).ok_or_else(unsupported)?;
+                    let next = start + cursor.position as u64;
+                    self.rip = if self.condition(condition - 0x80) {
+                        self.address(next.wrapping_add(displacement as u64))
+                    } else {
+                        next
+                    };
+                },
+                _ => return Err(unsupported()),
+            },
+            _ => return Err(unsupported()),
+        }
+        self.exit_code = exit;
+        Ok(exit)
+    }
+
+    /// Decode a ModRM byte and the SIB byte and displacement it calls for:
+    /// the register it names and the register or memory operand. A bare
+    /// displacement is relative to the next instruction in 64-bit mode,
+    /// which ends after an `immediate` byte field.
+    fn modrm(&self, cursor: &mut Cursor, start: u64, rex: u8, immediate: usize) -> Option<(usize, Place)> {
+        let extension = |bit: u8| usize::from(rex & bit != 0) << 3;
+        let modrm = cursor.byte()?;
+        let (mode, rm) = (modrm >> 6, modrm & 7);
+        let register = usize::from(modrm >> 3 & 7) | extension(0x4);
+        if mode == 3 {
+            return Some((register, Place::Register(usize::from(rm) | extension(0x1))));
+        }
+
+        let mut address = 0u64;
+        let base = match rm {
+            4 => {
+                let sib = cursor.byte()?;
+                // Index 100 means none
+                let index = usize::from(sib >> 3 & 7) | extension(0x2);
+                if index != RSP {
+                    address = self.registers[index] << (sib >> 6);
+                }
+                // Base 101 with mod 00 means none, only a disp32
+                match (mode, sib & 7) {
+                    (0, 5) => None,
+                    (_, base) => Some(usize::from(base) | extension(0x1)),
+                }
+            },
+            5 if mode == 0 => None,
+            rm => Some(usize::from(rm) | extension(0x1)),
+        };
+        if let Some(base) = base {
+            address = address.wrapping_add(self.registers[base]);
+        }
+        let displacement = match (mode, base) {
+            (1, _) => cursor.signed(1)?,
+            (2, _) | (0, None) => cursor.signed(4)?,
+            _ => 0,
+        };
+        if self.bits == 64 && (mode, rm) == (0, 5) {
+            address = start + (cursor.position + immediate) as u64;
+        }
+        Some((register, Place::Memory(self.address(address.wrapping_add(displacement as u64)))))
+    }
+
+    /// Carry out the syscall in rax, made with the 64-bit ABI (`syscall`) or
+    /// the i386 one (`int 0x80`)
+    fn syscall(&mut self, address: u64, abi: u16, stdout: &mut dyn Write, stderr: &mut dyn Write) -> Result<Option<i32>, EmulatorError> {
+        let (number, arguments) = if abi == 64 {
+            (self.registers[RAX], [self.registers[RDI], self.registers[RSI], self.registers[RDX]])
+        } else {
+            (self.register(RAX, 4), [self.register(RBX, 4), self.register(RCX, 4), self.register(RDX, 4)])
+        };
+        let result = match syscalls::syscall_name(number as i64, abi) {
+            Some("exit" | "exit_group") => return Ok(Some(i32::from(arguments[0] as u8))),
+            Some("write") => {
+                let [fd, buffer, length] = arguments;
+                let output: Option<&mut dyn Write> = match fd {
+                    1 => Some(stdout),
+                    2 => Some(stderr),
+                    _ => None,
+                };
+                match (output, self.slice(buffer, length)) {
+                    (None, _) => -EBADF,
+                    (_, None) => -EFAULT,
+                    (Some(output), Some(bytes)) => {
+                        output.write_all(bytes).and_then(|_| output.flush()).map_err(EmulatorError::Io)?;
+                        length as i64
+                    },
+                }
+            },
+            _ => return Err(EmulatorError::UnsupportedSyscall { address, number, bits: abi }),
+        };
+        let size = if abi == 64 { 8 } else { 4 };
+        self.set_register(RAX, size, result as u64);
+        Ok(None)
+    }
+
+    /// Whether condition code `condition` (the low nibble of a jcc opcode) holds
+    fn condition(&self, condition: u8) -> bool {
+        let flag = |bit: u64| self.flags & bit != 0;
+        let holds = match condition >> 1 {
+            0 => flag(OF),
+            1 => flag(CF),
+            2 => flag(ZF),
+            3 => flag(CF) || flag(ZF),
+            4 => flag(SF),
+            5 => flag(PF),
+            6 => flag(SF) != flag(OF),
+            _ => flag(ZF) || flag(SF) != flag(OF),
+        };
+        // Odd condition codes are the negations of the even ones
+        holds != (condition & 1 != 0)
+    }
+
+    /// `value` plus one, or minus one for `dec`, setting the flags other than CF
+    fn increment(&mut self, dec: bool, value: u64, size: usize) -> u64 {
+        let carry = self.flags & CF;
+        let (result, flags) = arithmetic(if dec { 5 } else { 0 }, value, 1, size).unwrap_or_default();
+        self.set_arithmetic_flags(result, flags & OF | carry, size);
+        result
+    }
+
+    /// `value` shifted or rotated by `count` with operation `operation`, the n
+    /// of its `D3 /n` form, setting the flags it affects. The count is masked
+    /// to 5 bits, or 6 for 64-bit operands, and a count of 0 changes nothing.
+    fn shift(&mut self, operation: u8, value: u64, count: u8, size: usize) -> u64 {
+        let bits = size as u32 * 8;
+        let count = u32::from(count) & if size == 8 { 0x3F } else { 0x1F };
+        if count == 0 {
+            return value;
+        }
+        let sign = |value: u64| value >> (bits - 1) & 1 != 0;
+        let signed = ((value << (64 - bits)) as i64) >> (64 - bits);
+        // The last bit shifted out is CF
+        let (result, carry, overflow) = match operation {
+            0 | 1 => {
+                let rotation = count % bits;
+                let result = if operation == 0 {
+                    (value << rotation | value >> ((bits - rotation) % bits)) & mask(size)
+                } else {
+                    (value >> rotation | value << ((bits - rotation) % bits)) & mask(size)
+                };
+                let carry = if operation == 0 { result & 1 != 0 } else { sign(result) };
+                let overflow = if operation == 0 { sign(result) != carry } else { sign(result) != sign(result << 1) };
+                // Rotates leave ZF, SF and PF alone
+                self.flags &= !(CF | OF);
+                self.flags |= if carry { CF } else { 0 } | if overflow { OF } else { 0 };
+                return result;
+            },
+            4 => {
+                let wide = u128::from(value) << count;
+                let result = wide as u64 & mask(size);
+                let carry = wide >> bits & 1 != 0;
+                (result, carry, sign(result) != carry)
+            },
+            5 => {
+                let result = value.checked_shr(count).unwrap_or(0);
+                (result, value.checked_shr(count - 1).unwrap_or(0) & 1 != 0, sign(value))
+            },
+            _ => {
+                let result = (signed >> count.min(63)) as u64 & mask(size);
+                (result, signed >> (count - 1).min(63) & 1 != 0, false)
+            },
+        };
+        let flags = if carry { CF } else { 0 } | if overflow { OF } else { 0 };
+        self.set_arithmetic_flags(result, flags, size);
+        result
+    }
+
+    /// Carry out `F7 /n` operation `operation`, 4 to 7 for `mul`, `imul`,
+    /// `div` and `idiv`, on the accumulator and `operand`. `None` for a
+    /// division by zero or one whose quotient doesn't fit.
+    fn multiply_divide(&mut self, operation: u8, operand: u64, size: usize) -> Option<()> {
+        let bits = size as u32 * 8;
+        // The value of the low `width` bits of `value` as a signed number
+        let signed = |value: u128, width: u32| ((value << (128 - width)) as i128) >> (128 - width);
+        let (low, high) = (self.register(RAX, size), self.register(RDX, size));
+        let (low, high) = match operation {
+            4 | 5 => {
+                let (product, fits) = if operation == 4 {
+                    let product = u128::from(low) * u128::from(operand);
+                    (product, product >> bits == 0)
+                } else {
+                    let product = signed(low.into(), bits) * signed(operand.into(), bits);
+                    (product as u128, signed(product as u128, bits) == product)
+                };
+                // CF and OF say whether the high half holds part of the product
+                self.flags &= !(CF | OF);
+                if !fits {
+                    self.flags |= CF | OF;
+                }
+                (product as u64 & mask(size), (product >> bits) as u64 & mask(size))
+            },
+            _ => {
+                let dividend = u128::from(high) << bits | u128::from(low);
+                let (quotient, remainder) = if operation == 6 {
+                    let divisor = u128::from(operand);
+                    let quotient = dividend.checked_div(divisor).filter(|&quotient| quotient >> bits == 0)?;
+                    (quotient, dividend % divisor)
+                } else {
+                    let (dividend, divisor) = (signed(dividend, bits * 2), signed(operand.into(), bits));
+                    let quotient = dividend.checked_div(divisor).filter(|&quotient| signed(quotient as u128, bits) == quotient)?;
+                    (quotient as u128, (dividend % divisor) as u128)
+                };
+                (quotient as u64 & mask(size), remainder as u64 & mask(size))
+            },
+        };
+        self.set_register(RAX, size, low);
+        self.set_register(RDX, size, high);
+        Some(())
+    }
+
+    /// Flags after an arithmetic operation: ZF, SF and PF from the result,
+    /// with `carry_overflow` holding CF and OF
+    fn set_arithmetic_flags(&mut self, result: u64, carry_overflow: u64, size: usize) {
+        self.set_logic_flags(result, size);
+        self.flags |= carry_overflow;
+    }
+
+
+    /// Flags after a logical operation: CF and OF cleared, ZF, SF and PF from the result
+    fn set_logic_flags(&mut self, result: u64, size: usize) {
+        self.flags &= !(CF | PF | ZF | SF | OF);
+        if result == 0 {
+            self.flags |= ZF;
+        }
+        if result >> (size * 8 - 1) & 1 != 0 {
+            self.flags |= SF;
+        }
+        if (result as u8).count_ones().is_multiple_of(2) {
+            self.flags |= PF;
+        }
+    }
+
+    /// `size` bytes of register `index`
+    fn register(&self, index: usize, size: usize) -> u64 {
+        self.registers[index] & mask(size)
+    }
+
+    /// Write `size` bytes of register `index`; 32-bit writes clear the upper
+    /// half, 8 and 16-bit ones keep the rest
+    fn set_register(&mut self, index: usize, size: usize, value: u64) {
+        self.registers[index] = match size {
+            1 | 2 => self.registers[index] & !mask(size) | value & mask(size),
+            _ => value & mask(size),
+        };
+    }
+
+    fn read_place(&self, place: Place, size: usize) -> Result<u64, EmulatorError> {
+        match place {
+            Place::Register(index) => Ok(self.register(index, size)),
+            Place::Memory(address) => self.slice(address, size as u64)
+                .map(|bytes| bytes.iter().rev().fold(0, |value, &byte| value << 8 | u64::from(byte)))
+                .ok_or(EmulatorError::MemoryFault { rip: self.rip, address, access: Access::Read }),
+        }
+    }
+
+    fn write_place(&mut self, place: Place, size: usize, value: u64) -> Result<(), EmulatorError> {
+        match place {
+            Place::Register(index) => {
+                self.set_register(index, size, value);
+                Ok(())
+            },
+            Place::Memory(address) => self.store(address, size, value),
+        }
+    }
+
+    /// Read a byte operand; see [`write_byte`](Self::write_byte)
+    fn read_byte(&self, place: Place, rex: u8) -> Result<u64, EmulatorError> {
+        match place {
+            Place::Register(index @ 4..=7) if rex == 0 => Ok(self.registers[index - 4] >> 8 & 0xFF),
+            place => self.read_place(place, 1),
+        }
+    }
+
+    /// Write a byte operand; without a REX prefix, registers 4 to 7 are ah,
+    /// ch, dh and bh rather than spl, bpl, sil and dil
+    fn write_byte(&mut self, place: Place, rex: u8, value: u64) -> Result<(), EmulatorError> {
+        match place {
+            Place::Register(index @ 4..=7) if rex == 0 => {
+                let register = &mut self.registers[index - 4];
+                *register = *register & !0xFF00 | (value & 0xFF) << 8;
+                Ok(())
+            },
+            place => self.write_place(place, 1, value),
+        }
+    }
+
+    fn store(&mut self, address: u64, size: usize, value: u64) -> Result<(), EmulatorError> {
+        let rip = self.rip;
+        let segment = self.segments.iter_mut()
+            .find(|segment| segment.writable && segment.contains(address, size as u64))
+            .ok_or(EmulatorError::MemoryFault { rip, address, access: Access::Write })?;
+        let offset = (address - segment.start) as usize;
+        segment.bytes[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+        Ok(())
+    }
+
+    fn push(&mut self, value: u64) -> Result<(), EmulatorError> {
+        let word = self.word_size();
+        let stack_pointer = self.address(self.registers[RSP].wrapping_sub(word));
+        self.store(stack_pointer, word as usize, value)?;
+        self.registers[RSP] = stack_pointer;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u64, EmulatorError> {
+        let word = self.word_size();
+        let value = self.read_place(Place::Memory(self.registers[RSP]), word as usize)?;
+        self.registers[RSP] = self.address(self.registers[RSP].wrapping_add(word));
+        Ok(value)
+    }
+
+    fn word_size(&self) -> u64 {
+        u64::from(self.bits / 8)
+    }
+
+    /// An address wrapped to the address space of the code mode
+    fn address(&self, address: u64) -> u64 {
+        if self.bits == 64 { address } else { address & 0xFFFF_FFFF }
+    }
+
+    /// `length` bytes of mapped memory at `address`
+    fn slice(&self, address: u64, length: u64) -> Option<&[u8]> {
+        let segment = self.segments.iter().find(|segment| segment.contains(address, length))?;
+        let offset = (address - segment.start) as usize;
+        Some(&segment.bytes[offset..offset + length as usize])
+    }
+
+    /// Code mode of the program: 32 or 64
+    pub fn bits(&self) -> u16 {
+        self.bits
+    }
+
+    /// The general-purpose registers, in the order of [`REGISTER_NAMES`]
+    pub fn registers(&self) -> &[u64; 16] {
+        &self.registers
+    }
+
+    /// Address of the next instruction to execute
+    pub fn rip(&self) -> u64 {
+        self.rip
+    }
+
+    /// The rflags register
+    pub fn flags(&self) -> u64 {
+        self.flags
+    }
+
+    /// Instructions executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// `length` bytes of the program's memory at `address`, if they are all mapped
+    pub fn read_memory(&self, address: u64, length: u64) -> Option<&[u8]> {
+        self.slice(address, length)
+    }
+}
+
+/// Number of `cmp` among the arithmetic operations, a subtraction that only sets the flags
+const CMP: u8 = 7;
+
+/// Result of arithmetic operation `operation` (the n of its `81 /n` form) on
+/// `size`-byte operands, with the CF and OF flags it sets; `None` for the
+/// operations the emulator doesn't know
+fn arithmetic(operation: u8, left: u64, right: u64, size: usize) -> Option<(u64, u64)> {
+    let sign = 1 << (size * 8 - 1);
+    let (result, carry, overflow) = match operation {
+        0 => {
+            let result = left.wrapping_add(right) & mask(size);
+            (result, result < left, (left ^ result) & (right ^ result) & sign != 0)
+        },
+        5 | CMP => {
+            let result = left.wrapping_sub(right) & mask(size);
+            (result, right > left, (left ^ right) & (left ^ result) & sign != 0)
+        },
+        6 => (left ^ right, false, false),
+        _ => return None,
+    };
+    let flags = if carry { CF } else { 0 } | if overflow { OF } else { 0 };
+    Some((result, flags))
+}
+
+/// Low `size` bytes of the signed product of two `size`-byte operands, with
+/// CF and OF set if the product didn't fit
+fn multiply(left: u64, right: u64, size: usize) -> (u64, u64) {
+    let shift = 64 - size as u32 * 8;
+    let signed = |value: u64| i128::from(((value << shift) as i64) >> shift);
+    let product = signed(left) * signed(right);
+    let result = product as u64 & mask(size);
+    let flags = if signed(result) != product { CF | OF } else { 0 };
+    (result, flags)
+}
+
+/// The low `size` bytes of `value`, sign-extended to 64 bits
+fn sign_extend(value: u64, size: usize) -> u64 {
+    let shift = 64 - size as u32 * 8;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+/// Mask of the low `size` bytes
+fn mask(size: usize) -> u64 {
+    if size >= 8 { u64::MAX } else { (1 << (size * 8)) - 1 }
+}
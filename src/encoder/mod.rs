@@ -1,102 +1,1194 @@
-use crate::parser::ast::{Instruction, Operand, MemoryReference};
-use colored::*;
+use std::fmt;
 
-pub struct MachineCodeEncoder;
+use crate::parser::ast::{Instruction, MemoryReference, Operand, SpecialSymbol, Statement};
+use crate::symbol::Symbol;
+use crate::tokenizer::{register_type, TokenType};
+
+/// Encodes instructions for one code mode. `bits` directives in a program
+/// switch the mode for the instructions after them; see
+/// [`encode_statements`](MachineCodeEncoder::encode_statements).
+#[derive(Debug, Clone, Copy)]
+pub struct MachineCodeEncoder {
+    /// Code mode (16, 32 or 64) before any `bits` directive
+    bits: u16,
+    /// Whether jumps to labels take their near form rather than the short one
+    near_branches: bool,
+}
+
+impl Default for MachineCodeEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Statement count from which [`MachineCodeEncoder::encode_statements`]
+/// spreads the work across threads (with the `parallel` feature). Smaller
+/// programs encode faster than the threads take to start.
+pub const PARALLEL_ENCODE_THRESHOLD: usize = 4096;
+
+/// How a label's address is written into an encoded instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixupKind {
+    /// The full 64-bit address (`mov r64, label`)
+    Absolute64,
+    /// A 32-bit address (`mov r32, label`, and memory operands in 32-bit mode)
+    Absolute32,
+    /// A 16-bit address (`mov r16, label`, and memory operands in 16-bit mode)
+    Absolute16,
+    /// Signed 32-bit distance from the end of the instruction (call, jmp, jcc, RIP-relative memory)
+    Relative32,
+    /// Signed 16-bit distance from the end of the instruction (call in 16-bit mode)
+    Relative16,
+    /// Signed 8-bit distance from the end of the instruction (short jumps)
+    Relative8,
+}
+
+impl FixupKind {
+    /// Size of the patched field in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            FixupKind::Absolute64 => 8,
+            FixupKind::Absolute32 | FixupKind::Relative32 => 4,
+            FixupKind::Absolute16 | FixupKind::Relative16 => 2,
+            FixupKind::Relative8 => 1,
+        }
+    }
+}
+
+/// A label reference that has to be patched once addresses are known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixup {
+    /// Offset of the field from the start of the instruction
+    pub offset: usize,
+    pub kind: FixupKind,
+    pub label: Symbol,
+    /// Number added to the label's address, as in `[table+8]`
+    pub addend: i64,
+    /// Special symbol the label is taken relative to, as in `foo wrt ..plt`
+    pub wrt: Option<SpecialSymbol>,
+}
+
+/// Machine code of one instruction together with its label references
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncodedInstruction {
+    pub bytes: Vec<u8>,
+    pub fixups: Vec<Fixup>,
+}
+
+impl EncodedInstruction {
+    /// Append a zero `kind` field, to be patched with the address of `label` plus `addend`
+    fn push_label(&mut self, kind: FixupKind, label: Symbol, addend: i64) {
+        self.fixups.push(Fixup { offset: self.bytes.len(), kind, label, addend, wrt: None });
+        self.bytes.resize(self.bytes.len() + kind.size(), 0);
+    }
+}
+
+impl From<Vec<u8>> for EncodedInstruction {
+    fn from(bytes: Vec<u8>) -> Self {
+        EncodedInstruction { bytes, fixups: Vec::new() }
+    }
+}
+
+/// What goes in the reg field of a ModRM byte
+#[derive(Debug, Clone, Copy)]
+enum RegField {
+    Register(Gpr),
+    /// An opcode extension, such as the 0 in `C7 /0`
+    Extension(u8),
+}
+
+/// How a memory operand is addressed
+struct Address {
+    /// ModRM with mod and rm set, and reg left 0
+    modrm: u8,
+    sib: Option<u8>,
+    /// The displacement, the number added to the label if there is one
+    displacement: i64,
+    /// Size of the displacement field in bytes
+    displacement_size: usize,
+    /// Label patched into the displacement field, and how
+    label: Option<(Symbol, FixupKind)>,
+    base: Option<Gpr>,
+    index: Option<Gpr>,
+    /// Whether the registers need an address size prefix (67) in this mode
+    address_size: bool,
+}
 
 impl MachineCodeEncoder {
     pub fn new() -> Self {
-        MachineCodeEncoder
+        MachineCodeEncoder { bits: 64, near_branches: false }
+    }
+    
+    /// Set the code mode instructions are encoded for: 16, 32 or 64
+    pub fn with_bits(mut self, bits: u16) -> Self {
+        self.bits = bits;
+        self
+    }
+    
+    /// Encode `jmp` and `jcc` to a label in their near form, with a rel16
+    /// or rel32, instead of the short one with a rel8. The ELF generator
+    /// does this for the jumps whose label is out of rel8 range.
+    pub fn with_near_branches(mut self, near_branches: bool) -> Self {
+        self.near_branches = near_branches;
+        self
+    }
+    
+    /// The code mode instructions are encoded for
+    pub fn bits(&self) -> u16 {
+        self.bits
+    }
+    
+    pub fn encode(&self, instruction: &Instruction) -> Result<Vec<u8>, EncodeError> {
+        self.encode_with_fixups(instruction).map(|encoded| encoded.bytes)
     }
     
-    pub fn encode(&self, instruction: &Instruction) -> Vec<u8> {
+    /// Encode an instruction and locate the fields its label operands are patched into
+    pub fn encode_with_fixups(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        // `wrt` only changes how the label is relocated, not the encoding
+        let specials: Vec<_> = instruction.operands.iter()
+            .filter_map(|operand| match operand {
+                Operand::Wrt(label, special) => Some((*label, *special)),
+                Operand::Memory(memory) => Some((memory.displacement_label()?, memory.wrt?)),
+                _ => None,
+            })
+            .collect();
+        if !specials.is_empty() {
+            let mut plain = instruction.clone();
+            for operand in &mut plain.operands {
+                match operand {
+                    Operand::Wrt(label, _) => *operand = Operand::Label(*label),
+                    Operand::Memory(memory) => memory.wrt = None,
+                    _ => {}
+                }
+            }
+            let mut encoded = self.encode_with_fixups(&plain)?;
+            for fixup in &mut encoded.fixups {
+                fixup.wrt = specials.iter().find(|(label, _)| *label == fixup.label).map(|&(_, special)| special);
+            }
+            return Ok(encoded);
+        }
         match instruction.name.as_str() {
             "mov" => self.encode_mov(instruction),
             "lea" => self.encode_lea(instruction),
-            "xor" => self.encode_xor(instruction),
-            "syscall" => self.encode_syscall(),
-            _ => {
-                Vec::new()
-            }
+            "movzx" | "movsx" | "movsxd" => self.encode_extend(instruction),
+            name if arithmetic_extension(name).is_some() => self.encode_arithmetic(instruction),
+            "imul" if instruction.operands.len() == 1 => self.encode_unary(instruction),
+            "imul" => self.encode_imul(instruction),
+            name if unary_opcode(name).is_some() => self.encode_unary(instruction),
+            name if shift_extension(name).is_some() => self.encode_shift(instruction),
+            "push" | "pushq" | "pop" | "popq" => self.encode_stack(instruction),
+            "syscall" => Ok(self.encode_syscall()),
+            "int" => self.encode_int(instruction),
+            "ret" => Ok(vec![0xC3].into()),
+            "nop" => self.encode_nop(instruction),
+            "jmp" | "call" => self.encode_branch(instruction),
+            name if name.strip_prefix('j').and_then(condition_code).is_some() => self.encode_branch(instruction),
+            name if name.strip_prefix("set").and_then(condition_code).is_some() => self.encode_setcc(instruction),
+            name if name.strip_prefix("cmov").and_then(condition_code).is_some() => self.encode_cmov(instruction),
+            _ => Err(EncodeError::UnsupportedInstruction(instruction.name)),
         }
     }
     
-    fn encode_mov(&self, instruction: &Instruction) -> Vec<u8> {
-        if instruction.operands.len() != 2 {
-            return Vec::new();
+    /// Encode every instruction in `statements`, with `None` for statements
+    /// that aren't instructions. Each instruction is encoded for the mode set
+    /// by the last `bits` directive before it. Apart from that, instructions
+    /// encode independently of each other, so large programs are encoded in
+    /// parallel when the `parallel` feature is on; the result is the same
+    /// either way.
+    pub fn encode_statements(&self, statements: &[Statement]) -> Vec<Option<Result<EncodedInstruction, EncodeError>>> {
+        let mut bits = self.bits;
+        let modes: Vec<u16> = statements.iter()
+            .map(|statement| {
+                if let Statement::Directive(directive) = statement {
+                    if let (true, Some(Operand::Immediate(mode))) = (directive.name == "bits", directive.operands.first()) {
+                        bits = mode.parse().unwrap_or(bits);
+                    }
+                }
+                bits
+            })
+            .collect();
+        let encode = |(statement, &bits): (&Statement, &u16)| match statement {
+            Statement::Instruction(instruction) => Some(self.with_bits(bits).encode_with_fixups(instruction)),
+            _ => None,
+        };
+
+        #[cfg(feature = "parallel")]
+        if statements.len() >= PARALLEL_ENCODE_THRESHOLD {
+            use rayon::prelude::*;
+            return statements.par_iter().zip(&modes).map(encode).collect();
         }
-        match (&instruction.operands[0], &instruction.operands[1]) {
-            (Operand::Register(dst), Operand::Immediate(src)) if dst == "rax" => {
-                let imm = parse_immediate(src).unwrap_or(0);
-                let mut code = vec![0x48, 0xB8];
-                code.extend_from_slice(&imm.to_le_bytes());
-                code
-            },
-            (Operand::Register(dst), Operand::Immediate(src)) if dst == "rdi" => {
-                let imm = parse_immediate(src).unwrap_or(0);
-                let mut code = vec![0x48, 0xBF];
-                code.extend_from_slice(&imm.to_le_bytes());
-                code
+
+        statements.iter().zip(&modes).map(encode).collect()
+    }
+    
+    /// `mov` between registers and memory (`89 /r`, `8B /r`) and of an
+    /// immediate: `B8+r` with an immediate of the register's width (`B0+r
+    /// ib` for 8-bit registers), or `C7 /0 id`, sign-extended, when a 64-bit
+    /// value fits. A memory destination takes `C7 /0` (`C6 /0 ib`) and needs
+    /// a size, as in `mov dword [rax], 1`.
+    fn encode_mov(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let [destination, source] = instruction.operands.as_slice() else {
+            return Err(EncodeError::UnsupportedOperands);
+        };
+        match (destination, source) {
+            (Operand::Register(dst), src @ (Operand::Immediate(_) | Operand::Label(_))) => {
+                let register = gpr(dst).ok_or(EncodeError::UnsupportedOperands)?;
+                let width = register.width;
+                // `C7 /0` sign-extends an imm32, 3 bytes shorter than the full imm64
+                if let (64, Operand::Immediate(value)) = (width, src) {
+                    if let Ok(value) = i32::try_from(immediate_number(value)? as i64) {
+                        let mut code = self.encode_modrm(&[0xC7], width, RegField::Extension(0), destination)?;
+                        code.bytes.extend_from_slice(&value.to_le_bytes());
+                        return Ok(code);
+                    }
+                }
+                let mut code = self.prefixes(width, false, None, None, Some(register))?;
+                code.push(if width == 8 { 0xB0 } else { 0xB8 } + (register.number & 7));
+                let mut code = EncodedInstruction::from(code);
+                match src {
+                    // A label's address is patched in by the ELF generator once the layout is known
+                    Operand::Label(_) if width == 8 => return Err(EncodeError::LabelInByte),
+                    Operand::Label(label) => code.push_label(absolute_fixup(width), *label, 0),
+                    _ => {
+                        let imm = match src {
+                            Operand::Immediate(value) => immediate_number(value)? as u64,
+                            _ => 0,
+                        };
+                        code.bytes.extend_from_slice(&imm.to_le_bytes()[..usize::from(width / 8)]);
+                    },
+                }
+                Ok(code)
             },
-            (Operand::Register(dst), Operand::Immediate(src)) if dst == "rdx" => {
-                let imm = parse_immediate(src).unwrap_or(0);
-                let mut code = vec![0x48, 0xBA];
-                code.extend_from_slice(&imm.to_le_bytes());
-                code
+            (Operand::Memory(memory), Operand::Immediate(_) | Operand::Label(_)) => {
+                let width = memory.size.ok_or(EncodeError::OperandSizeNotSpecified)?;
+                let field = width.min(32);
+                let mut code = self.encode_modrm(&[if width == 8 { 0xC6 } else { 0xC7 }], width, RegField::Extension(0), destination)?;
+                match source {
+                    Operand::Label(_) if width == 8 => return Err(EncodeError::LabelInByte),
+                    Operand::Label(label) => code.push_label(absolute_fixup(field), *label, 0),
+                    _ => {
+                        let imm = match source {
+                            Operand::Immediate(value) => immediate_number(value)? as u64,
+                            _ => 0,
+                        };
+                        code.bytes.extend_from_slice(&imm.to_le_bytes()[..usize::from(field / 8)]);
+                    },
+                }
+                Ok(code)
             },
-            (Operand::Register(dst), Operand::Immediate(src)) if dst == "rsi" => {
-                let imm = parse_immediate(src).unwrap_or(0);
-                let mut code = vec![0x48, 0xBE];
-                code.extend_from_slice(&imm.to_le_bytes());
-                code
+            (Operand::Register(_), Operand::Register(src)) => self.encode_register_rm(0x89, src, destination),
+            (Operand::Register(dst), Operand::Memory(_)) => self.encode_register_rm(0x8B, dst, source),
+            (Operand::Memory(_), Operand::Register(src)) => self.encode_register_rm(0x89, src, destination),
+            _ => Err(EncodeError::UnsupportedOperands),
+        }
+    }
+    
+    fn encode_lea(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let [Operand::Register(dst), source] = instruction.operands.as_slice() else {
+            return Err(EncodeError::UnsupportedOperands);
+        };
+        if gpr(dst).is_none_or(|register| register.width == 8) {
+            return Err(EncodeError::UnsupportedOperands);
+        }
+        match source {
+            Operand::Memory(_) => self.encode_register_rm(0x8D, dst, source),
+            // A bare label is taken as `[label]`
+            Operand::Label(label) => self.encode_register_rm(0x8D, dst, &Operand::Memory(MemoryReference::at_label(label))),
+            _ => Err(EncodeError::UnsupportedOperands),
+        }
+    }
+    
+    /// `movzx` and `movsx` of a byte (`0F B6 /r`, `0F BE /r`) or word
+    /// (`0F B7 /r`, `0F BF /r`) into a wider register, and `movsxd` of a
+    /// doubleword into a 64-bit register (`REX.W 63 /r`). The source's size
+    /// is its register's, or the size given to a memory operand, as in
+    /// `movzx eax, byte [rsi]`; `movsxd` needs no size.
+    fn encode_extend(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let [Operand::Register(dst), source] = instruction.operands.as_slice() else {
+            return Err(EncodeError::UnsupportedOperands);
+        };
+        let register = gpr(dst).ok_or(EncodeError::UnsupportedOperands)?;
+        let size = match source {
+            Operand::Register(source) => gpr(source).map(|source| source.width),
+            Operand::Memory(memory) if instruction.name == "movsxd" => Some(memory.size.unwrap_or(32)),
+            Operand::Memory(memory) => Some(memory.size.ok_or(EncodeError::OperandSizeNotSpecified)?),
+            _ => None,
+        };
+        let opcode: &[u8] = match (instruction.name.as_str(), size) {
+            ("movzx", Some(8)) => &[0x0F, 0xB6],
+            ("movzx", Some(16)) => &[0x0F, 0xB7],
+            ("movsx", Some(8)) => &[0x0F, 0xBE],
+            ("movsx", Some(16)) => &[0x0F, 0xBF],
+            ("movsxd", Some(32)) if self.bits == 64 => &[0x63],
+            _ => return Err(EncodeError::UnsupportedOperands),
+        };
+        if size.is_none_or(|size| size >= register.width) {
+            return Err(EncodeError::UnsupportedOperands);
+        }
+        self.encode_modrm(opcode, register.width, RegField::Register(register), source)
+    }
+    
+    /// `add`, `sub`, `xor` and `cmp`, which share one scheme of opcodes. Each is
+    /// numbered by the reg field of its `81 /n` form, and its other forms
+    /// are at 8 times that number: `r/m, reg` (+1), `reg, r/m` (+3) and
+    /// `rax, imm` (+5), each one less with 8-bit operands.
+    fn encode_arithmetic(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let (Some(extension), [destination, source]) = (arithmetic_extension(&instruction.name), instruction.operands.as_slice()) else {
+            return Err(EncodeError::UnsupportedOperands);
+        };
+        let base = extension * 8;
+        match (destination, source) {
+            (Operand::Register(_) | Operand::Memory(_), Operand::Register(src)) => self.encode_register_rm(base + 1, src, destination),
+            (Operand::Register(dst), Operand::Memory(_)) => self.encode_register_rm(base + 3, dst, source),
+            (Operand::Register(_) | Operand::Memory(_), Operand::Immediate(_) | Operand::Label(_)) => {
+                let width = operand_width(destination)?;
+                self.encode_arithmetic_immediate(extension, width, destination, source)
             },
-            (Operand::Register(dst), Operand::Memory(_)) if dst == "rsi" => {
-                vec![0x48, 0x8B, 0x35, 0, 0, 0, 0]
+            _ => Err(EncodeError::UnsupportedOperands),
+        }
+    }
+    
+    /// `op r/m, imm` with a `width`-bit destination: `83 /n ib` when the
+    /// immediate fits a sign-extended imm8, otherwise `81 /n` with an
+    /// immediate of that width, at most an imm32 sign-extended into 64-bit
+    /// operands. al, ax, eax and rax have a shorter form for the latter.
+    fn encode_arithmetic_immediate(&self, extension: u8, width: u16, destination: &Operand, source: &Operand) -> Result<EncodedInstruction, EncodeError> {
+        let field = width.min(32);
+        let value = match source {
+            // A label's address always takes the full field
+            Operand::Label(label) => {
+                if width == 8 {
+                    return Err(EncodeError::LabelInByte);
+                }
+                let mut code = self.encode_modrm(&[0x81], width, RegField::Extension(extension), destination)?;
+                code.push_label(absolute_fixup(field), *label, 0);
+                return Ok(code);
             },
-            _ => {
-                Vec::new()
+            Operand::Immediate(value) => immediate_number(value)?,
+            _ => return Err(EncodeError::UnsupportedOperands),
+        };
+        // Values that don't fit were warned about when parsing and are truncated
+        let truncated = value as u64 & (u64::MAX >> (64 - field));
+        let (opcode, size) = match width {
+            8 => (0x80, 1),
+            _ if i8::try_from(sign_extend(truncated, field)).is_ok() => (0x83, 1),
+            _ => (0x81, usize::from(field / 8)),
+        };
+        let accumulator = matches!(destination, Operand::Register(register) if gpr(register).is_some_and(|register| register.number == 0));
+        let mut code = if accumulator && opcode != 0x83 {
+            let mut code = self.prefixes(width, false, None, None, None)?;
+            code.push(extension * 8 + if width == 8 { 4 } else { 5 });
+            EncodedInstruction::from(code)
+        } else {
+            self.encode_modrm(&[opcode], width, RegField::Extension(extension), destination)?
+        };
+        code.bytes.extend_from_slice(&truncated.to_le_bytes()[..size]);
+        Ok(code)
+    }
+    
+    /// `imul reg, r/m` (`0F AF /r`) and `imul reg, r/m, imm`, which is
+    /// `6B /r ib` when the immediate fits a sign-extended imm8 and `69 /r`
+    /// with an immediate of the register's width (at most a sign-extended
+    /// imm32) otherwise. `imul reg, imm` is short for `imul reg, reg, imm`.
+    /// There are no 8-bit forms.
+    fn encode_imul(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let (dst, rm, immediate) = match instruction.operands.as_slice() {
+            [Operand::Register(dst), rm @ (Operand::Register(_) | Operand::Memory(_))] => (dst, rm, None),
+            [rm @ Operand::Register(dst), Operand::Immediate(value)] => (dst, rm, Some(value)),
+            [Operand::Register(dst), rm @ (Operand::Register(_) | Operand::Memory(_)), Operand::Immediate(value)] => (dst, rm, Some(value)),
+            _ => return Err(EncodeError::UnsupportedOperands),
+        };
+        let register = gpr(dst).filter(|register| register.width != 8).ok_or(EncodeError::UnsupportedOperands)?;
+        if let Operand::Register(rm) = rm {
+            if gpr(rm).is_none_or(|rm| rm.width != register.width) {
+                return Err(EncodeError::UnsupportedOperands);
             }
         }
+        let Some(value) = immediate else {
+            return self.encode_modrm(&[0x0F, 0xAF], register.width, RegField::Register(register), rm);
+        };
+        // Values that don't fit were warned about when parsing and are truncated
+        let field = register.width.min(32);
+        let truncated = immediate_number(value)? as u64 & (u64::MAX >> (64 - field));
+        let (opcode, size) = match i8::try_from(sign_extend(truncated, field)) {
+            Ok(_) => (0x6B, 1),
+            Err(_) => (0x69, usize::from(field / 8)),
+        };
+        let mut code = self.encode_modrm(&[opcode], register.width, RegField::Register(register), rm)?;
+        code.bytes.extend_from_slice(&truncated.to_le_bytes()[..size]);
+        Ok(code)
     }
     
-    fn encode_lea(&self, instruction: &Instruction) -> Vec<u8> {
-        if instruction.operands.len() != 2 {
-            return Vec::new();
+    /// `push` and `pop` of a register (`50+r` and `58+r`) or memory (`FF /6`
+    /// and `8F /0`), and `push` of an immediate, `6A ib` when it fits a
+    /// sign-extended imm8 and `68` with an imm16 or imm32 otherwise. The
+    /// operand is the size of a stack slot or 16 bits; 64-bit mode pushes
+    /// 64-bit operands without REX.W and has no 32-bit push or pop.
+    fn encode_stack(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let [operand] = instruction.operands.as_slice() else {
+            return Err(EncodeError::UnsupportedOperands);
+        };
+        let push = instruction.name.starts_with("push");
+        let slot = self.bits.max(32);
+        let width = match operand {
+            Operand::Register(register) => match gpr(register) {
+                Some(register) if register.width == 16 || register.width == slot => register.width,
+                // 32-bit registers in 16-bit code take a 66 prefix
+                Some(register) if register.width == 32 && self.bits == 16 => 32,
+                Some(register) if register.width == 32 => return Err(EncodeError::NotInMode { bits: self.bits }),
+                _ => return Err(EncodeError::UnsupportedOperands),
+            },
+            _ => if self.bits == 16 { 16 } else { slot },
+        };
+        // The operand size prefix only depends on the width, and REX.W is implied
+        let prefix_width = if width == 64 { 32 } else { width };
+        match operand {
+            Operand::Register(register) => {
+                let register = gpr(register).ok_or(EncodeError::UnsupportedOperands)?;
+                let mut code = self.prefixes(prefix_width, false, None, None, Some(register))?;
+                code.push(if push { 0x50 } else { 0x58 } + (register.number & 7));
+                Ok(code.into())
+            },
+            Operand::Memory(_) if push => self.encode_modrm(&[0xFF], prefix_width, RegField::Extension(6), operand),
+            Operand::Memory(_) => self.encode_modrm(&[0x8F], prefix_width, RegField::Extension(0), operand),
+            Operand::Immediate(value) if push => {
+                let mut code = self.prefixes(prefix_width, false, None, None, None)?;
+                // Values that don't fit were warned about when parsing and are truncated
+                let field = width.min(32);
+                let truncated = immediate_number(value)? as u64 & (u64::MAX >> (64 - field));
+                let size = match i8::try_from(sign_extend(truncated, field)) {
+                    Ok(_) => {
+                        code.push(0x6A);
+                        1
+                    },
+                    Err(_) => {
+                        code.push(0x68);
+                        usize::from(field / 8)
+                    },
+                };
+                code.extend_from_slice(&truncated.to_le_bytes()[..size]);
+                Ok(code.into())
+            },
+            // A label's address always takes the full field
+            Operand::Label(label) if push => {
+                let mut code = EncodedInstruction::from(self.prefixes(prefix_width, false, None, None, None)?);
+                code.bytes.push(0x68);
+                code.push_label(absolute_fixup(width.min(32)), *label, 0);
+                Ok(code)
+            },
+            _ => Err(EncodeError::UnsupportedOperands),
         }
-        match (&instruction.operands[0], &instruction.operands[1]) {
-            (Operand::Register(dst), Operand::Label(label)) if dst == "rsi" => {
-                vec![0x48, 0x8D, 0x35, 0, 0, 0, 0]
+    }
+    
+    /// Instructions with one operand and an opcode extension, as listed in
+    /// [`unary_opcode`]: `mul`, `imul`, `div` and `idiv`, which multiply the
+    /// accumulator or divide dx:ax, edx:eax or rdx:rax, `not`, `neg`, `inc`
+    /// and `dec`.
+    /// The operand is a register or memory given a size. As usual the 8-bit
+    /// form is the opcode before. 16 and 32-bit code has the one-byte `40+r`
+    /// and `48+r` for `inc` and `dec`, which are REX prefixes in 64-bit mode.
+    fn encode_unary(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let (Some((opcode, extension)), [rm]) = (unary_opcode(&instruction.name), instruction.operands.as_slice()) else {
+            return Err(EncodeError::UnsupportedOperands);
+        };
+        let width = operand_width(rm)?;
+        if let (Operand::Register(register), 0xFF) = (rm, opcode) {
+            if self.bits != 64 && width != 8 {
+                let register = gpr(register).ok_or(EncodeError::UnsupportedOperands)?;
+                let mut code = self.prefixes(width, false, None, None, Some(register))?;
+                code.push(0x40 + extension * 8 + register.number);
+                return Ok(code.into());
+            }
+        }
+        let opcode = if width == 8 { opcode - 1 } else { opcode };
+        self.encode_modrm(&[opcode], width, RegField::Extension(extension), rm)
+    }
+    
+    /// Shifts and rotates of a register or sized memory, numbered by the reg
+    /// field as listed in [`shift_extension`]: `D1 /n` by 1, `C1 /n ib` by
+    /// an immediate and `D3 /n` by cl, each one less with 8-bit operands.
+    fn encode_shift(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let (Some(extension), [rm, count]) = (shift_extension(&instruction.name), instruction.operands.as_slice()) else {
+            return Err(EncodeError::UnsupportedOperands);
+        };
+        let width = operand_width(rm)?;
+        let (opcode, immediate) = match count {
+            Operand::Register(count) if *count == "cl" => (0xD3, None),
+            // Counts that don't fit were warned about when parsing and are truncated
+            Operand::Immediate(value) => match immediate_number(value)? as u8 {
+                1 => (0xD1, None),
+                count => (0xC1, Some(count)),
             },
-            _ => {
-                Vec::new()
+            _ => return Err(EncodeError::UnsupportedOperands),
+        };
+        let opcode = if width == 8 { opcode - 1 } else { opcode };
+        let mut code = self.encode_modrm(&[opcode], width, RegField::Extension(extension), rm)?;
+        code.bytes.extend(immediate);
+        Ok(code)
+    }
+    
+    /// `setcc r/m8` (`0F 90+cc /0`), which sets a byte to 1 if the
+    /// condition holds and to 0 otherwise
+    fn encode_setcc(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let (Some(condition), [rm]) = (instruction.name.strip_prefix("set").and_then(condition_code), instruction.operands.as_slice()) else {
+            return Err(EncodeError::UnsupportedOperands);
+        };
+        // The operand is always a byte, so memory needs no size
+        let width = match rm {
+            Operand::Memory(memory) => memory.size.unwrap_or(8),
+            _ => operand_width(rm).unwrap_or(0),
+        };
+        if width != 8 {
+            return Err(EncodeError::UnsupportedOperands);
+        }
+        self.encode_modrm(&[0x0F, 0x90 + condition], 8, RegField::Extension(0), rm)
+    }
+    
+    /// `cmovcc reg, r/m` (`0F 40+cc /r`), which moves a 16, 32 or 64-bit
+    /// operand into a register if the condition holds
+    fn encode_cmov(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let (Some(condition), [Operand::Register(dst), rm @ (Operand::Register(_) | Operand::Memory(_))]) = (instruction.name.strip_prefix("cmov").and_then(condition_code), instruction.operands.as_slice()) else {
+            return Err(EncodeError::UnsupportedOperands);
+        };
+        let register = gpr(dst).filter(|register| register.width != 8).ok_or(EncodeError::UnsupportedOperands)?;
+        if let Operand::Register(rm) = rm {
+            if gpr(rm).is_none_or(|rm| rm.width != register.width) {
+                return Err(EncodeError::UnsupportedOperands);
+            }
+        }
+        self.encode_modrm(&[0x0F, 0x40 + condition], register.width, RegField::Register(register), rm)
+    }
+    
+    /// Prefixes for an instruction with a `width`-bit operand size and these
+    /// registers in ModRM.reg, the SIB index and ModRM.rm (or the SIB base):
+    /// an operand size prefix if `width` isn't the mode's default, an address
+    /// size prefix if asked for, then a REX prefix if the operand size is 64
+    /// bits or a register needs one. An error if that can't be encoded, such
+    /// as r8 outside 64-bit mode or ah alongside a register that needs REX.
+    fn prefixes(&self, width: u16, address_size: bool, reg: Option<Gpr>, index: Option<Gpr>, rm: Option<Gpr>) -> Result<Vec<u8>, EncodeError> {
+        let registers = [reg, index, rm];
+        let extension = |register: Option<Gpr>| register.map_or(0, |register| register.number >> 3);
+        let rex = 0x40
+            | u8::from(width == 64) << 3
+            | extension(reg) << 2
+            | extension(index) << 1
+            | extension(rm);
+        let rex_required = rex != 0x40 || registers.iter().flatten().any(|register| register.needs_rex);
+
+        let mut code = match (self.bits, width) {
+            (16, 32) | (32 | 64, 16) => vec![0x66],
+            _ => Vec::new(),
+        };
+        if address_size {
+            code.push(0x67);
+        }
+        if rex_required {
+            if self.bits != 64 {
+                return Err(EncodeError::NotInMode { bits: self.bits });
             }
+            if let Some(high) = registers.iter().flatten().find(|register| register.high_byte) {
+                return Err(EncodeError::HighByteWithRex(HIGH_BYTE_REGISTERS[usize::from(high.number - 4)]));
+            }
+            code.push(rex);
         }
+        Ok(code)
     }
     
-    fn encode_xor(&self, instruction: &Instruction) -> Vec<u8> {
-        if instruction.operands.len() != 2 {
-            return Vec::new();
+    /// `opcode reg, r/m` for a register `reg` and a register or memory
+    /// operand `rm` of the same width. `opcode` is the form for 16, 32 and
+    /// 64-bit operands; as for every such instruction, the 8-bit form is the
+    /// opcode before it (`88 /r` for `mov r/m8, r8` next to `89 /r`).
+    fn encode_register_rm(&self, opcode: u8, reg: &str, rm: &Operand) -> Result<EncodedInstruction, EncodeError> {
+        let reg = gpr(reg).ok_or(EncodeError::UnsupportedOperands)?;
+        if let Operand::Register(rm) = rm {
+            if gpr(rm).is_none_or(|rm| rm.width != reg.width) {
+                return Err(EncodeError::UnsupportedOperands);
+            }
         }
-        match (&instruction.operands[0], &instruction.operands[1]) {
-            (Operand::Register(dst), Operand::Register(src)) if dst == "rax" && src == "rax" => {
-                vec![0x48, 0x31, 0xC0]
+        let opcode = if reg.width == 8 { opcode - 1 } else { opcode };
+        self.encode_modrm(&[opcode], reg.width, RegField::Register(reg), rm)
+    }
+    
+    /// `opcode` and a ModRM byte with `reg` in its reg field and `rm`, a
+    /// register or memory reference, in its rm field, followed by the SIB
+    /// byte and displacement `rm` needs, after the prefixes an operation of
+    /// `width` bits needs. An error if `rm` can't be encoded in this mode.
+    fn encode_modrm(&self, opcode: &[u8], width: u16, reg: RegField, rm: &Operand) -> Result<EncodedInstruction, EncodeError> {
+        let (reg_register, reg_number) = match reg {
+            RegField::Register(register) => (Some(register), register.number),
+            RegField::Extension(extension) => (None, extension),
+        };
+        match rm {
+            Operand::Register(rm) => {
+                let rm = gpr(rm).ok_or(EncodeError::UnsupportedOperands)?;
+                let mut code = self.prefixes(width, false, reg_register, None, Some(rm))?;
+                code.extend_from_slice(opcode);
+                code.push(modrm(0b11, reg_number, rm.number));
+                Ok(code.into())
             },
-            (Operand::Register(dst), Operand::Register(src)) if dst == "rdi" && src == "rdi" => {
-                vec![0x48, 0x31, 0xFF]
+            Operand::Memory(memory) => {
+                let address = self.address(memory)?;
+                let segment = match memory.segment.map(segment_prefix) {
+                    Some(Some(prefix)) => Some(prefix),
+                    Some(None) => return Err(EncodeError::InvalidAddress),
+                    None => None,
+                };
+                let prefixes = self.prefixes(width, address.address_size, reg_register, address.index, address.base)?;
+                let mut code = EncodedInstruction::from(segment.into_iter().chain(prefixes).collect::<Vec<_>>());
+                code.bytes.extend_from_slice(opcode);
+                code.bytes.push(address.modrm | (reg_number & 7) << 3);
+                code.bytes.extend(address.sib);
+                match address.label {
+                    Some((label, kind)) => code.push_label(kind, label, address.displacement),
+                    None => code.bytes.extend_from_slice(&address.displacement.to_le_bytes()[..address.displacement_size]),
+                }
+                Ok(code)
             },
-            (Operand::Register(dst), Operand::Register(src)) if dst == "rsi" && src == "rsi" => {
-                vec![0x48, 0x31, 0xF6]
+            _ => Err(EncodeError::UnsupportedOperands),
+        }
+    }
+    
+    /// ModRM, SIB and displacement addressing `memory` in this mode. With
+    /// no registers, a label in 64-bit mode is RIP-relative; a segment
+    /// override makes it absolute, which is how code reads the thread
+    /// pointer (`[fs:0]`) and thread-local variables (`[fs:var]`).
+    fn address(&self, memory: &MemoryReference) -> Result<Address, EncodeError> {
+        let register = |name: Option<Symbol>| match name {
+            Some(name) => gpr(&name).map(Some).ok_or(EncodeError::InvalidAddress),
+            None => Ok(None),
+        };
+        // `[rip+8]` spells out RIP-relative addressing
+        let explicit_rip = memory.base.is_some_and(|base| base == "rip");
+        if explicit_rip && (self.bits != 64 || memory.index.is_some()) {
+            return Err(EncodeError::InvalidAddress);
+        }
+        let (base, index) = match (register(memory.base.filter(|_| !explicit_rip))?, register(memory.index)?) {
+            // rsp can't be an index, but unscaled it can swap places with the base
+            (Some(base), Some(index)) if index.number == 4 && memory.scale.is_none_or(|scale| scale == 1) => (Some(index), Some(base)),
+            registers => registers,
+        };
+        let width = match (base, index) {
+            (Some(base), Some(index)) if base.width != index.width => return Err(EncodeError::MixedAddressSizes),
+            (base, index) => base.or(index).map(|register| register.width),
+        };
+        let label = memory.displacement_label();
+        let displacement = memory.displacement_offset()
+            .and_then(|offset| i64::try_from(offset).ok())
+            .ok_or(EncodeError::DisplacementRange)?;
+        let rip_relative = explicit_rip || (self.bits == 64 && width.is_none() && label.is_some() && memory.segment.is_none());
+
+        let (modrm, sib, displacement_size) = match (self.bits, width) {
+            (16, None) => (0b00_000_110, None, 2),
+            (32, None) => (0b00_000_101, None, 4),
+            // mod 00 rm 101 is RIP-relative in 64-bit mode, so an absolute
+            // address takes a SIB byte with no base and no index
+            (_, None) if rip_relative => (0b00_000_101, None, 4),
+            (_, None) => (0b00_000_100, Some(0x25), 4),
+            (16 | 32, Some(16)) => sixteen_bit_address(base, index, memory.scale, label.is_some(), displacement).ok_or(EncodeError::InvalidAddress)?,
+            (16 | 32, Some(32)) | (64, Some(32 | 64)) => sib_address(base, index, memory.scale, label.is_some(), displacement).ok_or(EncodeError::InvalidAddress)?,
+            _ => return Err(EncodeError::NotInMode { bits: self.bits }),
+        };
+        // A 64-bit address's displacement is sign-extended
+        let fits = match displacement_size {
+            0 | 1 => true,
+            2 => (-0x8000..=0xFFFF).contains(&displacement),
+            _ if self.bits == 64 && width != Some(32) => i32::try_from(displacement).is_ok(),
+            _ => (-0x8000_0000..=0xFFFF_FFFF).contains(&displacement),
+        };
+        if !fits {
+            return Err(EncodeError::DisplacementRange);
+        }
+        let kind = match displacement_size {
+            _ if rip_relative && label.is_some() => FixupKind::Relative32,
+            2 => FixupKind::Absolute16,
+            _ => FixupKind::Absolute32,
+        };
+        Ok(Address {
+            modrm,
+            sib,
+            displacement,
+            displacement_size,
+            label: label.map(|label| (label, kind)),
+            base,
+            index,
+            address_size: width.is_some_and(|width| width != self.bits),
+        })
+    }
+    
+    /// `jmp`, `jcc` and `call` to a label, with a displacement the ELF
+    /// generator patches with the distance to the label or leaves to the
+    /// linker in object files. Jumps take the short rel8 form (`EB`, `7x`)
+    /// unless [`with_near_branches`](Self::with_near_branches) is set;
+    /// their near form and `call` have a rel16 in 16-bit mode and a rel32
+    /// otherwise (`E9`, `0F 8x`, `E8`).
+    fn encode_branch(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        let [Operand::Label(label)] = instruction.operands.as_slice() else {
+            return Err(EncodeError::UnsupportedOperands);
+        };
+        let near = if self.bits == 16 { FixupKind::Relative16 } else { FixupKind::Relative32 };
+        let (opcode, kind): (&[u8], _) = match (instruction.name.as_str(), self.near_branches) {
+            ("call", _) => (&[0xE8], near),
+            ("jmp", false) => (&[0xEB], FixupKind::Relative8),
+            ("jmp", true) => (&[0xE9], near),
+            (name, near_branches) => match (name.strip_prefix('j').and_then(condition_code), near_branches) {
+                (Some(condition), false) => (&[0x70 + condition], FixupKind::Relative8),
+                (Some(condition), true) => (&[0x0F, 0x80 + condition], near),
+                (None, _) => return Err(EncodeError::UnsupportedInstruction(instruction.name)),
             },
-            (Operand::Register(dst), Operand::Register(src)) if dst == "rdx" && src == "rdx" => {
-                vec![0x48, 0x31, 0xD2]
+        };
+        let mut code = EncodedInstruction::from(opcode.to_vec());
+        code.push_label(kind, *label, 0);
+        Ok(code)
+    }
+    
+    /// `nop` (`90`), or `nop r/m` (`0F 1F /0`) for the 16, 32 and 64-bit
+    /// multi-byte forms; memory needs a size such as `nop dword [rax]`
+    fn encode_nop(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        match instruction.operands.as_slice() {
+            [] => Ok(vec![0x90].into()),
+            [rm @ (Operand::Register(_) | Operand::Memory(_))] => match operand_width(rm)? {
+                width @ (16 | 32 | 64) => self.encode_modrm(&[0x0F, 0x1F], width, RegField::Extension(0), rm),
+                _ => Err(EncodeError::UnsupportedOperands),
             },
-            _ => {
-                Vec::new()
-            }
+            _ => Err(EncodeError::UnsupportedOperands),
         }
     }
     
-    fn encode_syscall(&self) -> Vec<u8> {
-        vec![0x0F, 0x05]
+    fn encode_syscall(&self) -> EncodedInstruction {
+        vec![0x0F, 0x05].into()
+    }
+    
+    /// `int imm8`, as in the i386 Linux system call `int 0x80`
+    fn encode_int(&self, instruction: &Instruction) -> Result<EncodedInstruction, EncodeError> {
+        match instruction.operands.as_slice() {
+            [Operand::Immediate(vector)] => match immediate_value(vector) {
+                Some(vector @ 0..=0xFF) => Ok(vec![0xCD, vector as u8].into()),
+                Some(value) => Err(EncodeError::ImmediateRange { value, bits: 8 }),
+                None => Err(EncodeError::InvalidImmediate(vector.clone())),
+            },
+            _ => Err(EncodeError::UnsupportedOperands),
+        }
+    }
+}
+
+/// Number of an arithmetic instruction in its `81 /n` form, see
+/// [`MachineCodeEncoder::encode_arithmetic`]
+fn arithmetic_extension(name: &str) -> Option<u8> {
+    match name {
+        "add" => Some(0),
+        "sub" => Some(5),
+        "xor" => Some(6),
+        "cmp" => Some(7),
+        _ => None,
+    }
+}
+
+/// Opcode and ModRM.reg extension of an instruction with one operand, see
+/// [`MachineCodeEncoder::encode_unary`]
+fn unary_opcode(name: &str) -> Option<(u8, u8)> {
+    match name {
+        "inc" => Some((0xFF, 0)),
+        "dec" => Some((0xFF, 1)),
+        "not" => Some((0xF7, 2)),
+        "neg" => Some((0xF7, 3)),
+        "mul" => Some((0xF7, 4)),
+        "imul" => Some((0xF7, 5)),
+        "div" => Some((0xF7, 6)),
+        "idiv" => Some((0xF7, 7)),
+        _ => None,
+    }
+}
+
+/// ModRM.reg extension of a shift or rotate, see
+/// [`MachineCodeEncoder::encode_shift`]
+fn shift_extension(name: &str) -> Option<u8> {
+    match name {
+        "rol" => Some(0),
+        "ror" => Some(1),
+        "shl" | "sal" => Some(4),
+        "shr" => Some(5),
+        "sar" => Some(7),
+        _ => None,
+    }
+}
+
+/// `value`, a `bits` wide field, sign-extended
+fn sign_extend(value: u64, bits: u16) -> i64 {
+    let shift = 64 - u32::from(bits);
+    ((value << shift) as i64) >> shift
+}
+
+/// Kind of field the address of a label goes into in a `width`-bit immediate
+fn absolute_fixup(width: u16) -> FixupKind {
+    match width {
+        64 => FixupKind::Absolute64,
+        32 => FixupKind::Absolute32,
+        _ => FixupKind::Absolute16,
+    }
+}
+
+/// mod, rm and SIB byte of a 32 or 64-bit address and the size of its
+/// displacement, the smallest that holds it unless a label is patched in
+fn sib_address(base: Option<Gpr>, index: Option<Gpr>, scale: Option<u8>, label: bool, displacement: i64) -> Option<(u8, Option<u8>, usize)> {
+    // rsp can't be an index, since index 100 means none (r12 can, with REX.X)
+    if index.is_some_and(|index| index.number == 4) {
+        return None;
+    }
+    let scale = match scale.unwrap_or(1) {
+        1 => 0,
+        2 => 1,
+        4 => 2,
+        8 => 3,
+        _ => return None,
+    };
+    let Some(base) = base else {
+        // An index alone takes base 101, which with mod 00 means a disp32 and no base
+        let index = index?;
+        return Some((0b00_000_100, Some(scale << 6 | (index.number & 7) << 3 | 0b101), 4));
+    };
+    // rbp and r13 have no mod 00 form, since rm (or base) 101 means no base
+    let (mode, size) = if label {
+        (0b10, 4)
+    } else if displacement == 0 && base.number & 7 != 5 {
+        (0b00, 0)
+    } else if i8::try_from(displacement).is_ok() {
+        (0b01, 1)
+    } else {
+        (0b10, 4)
+    };
+    match index {
+        Some(index) => Some((mode << 6 | 0b100, Some(scale << 6 | (index.number & 7) << 3 | (base.number & 7)), size)),
+        // rsp and r12 as the base need a SIB byte, since rm 100 means one follows
+        None if base.number & 7 == 4 => Some((mode << 6 | 0b100, Some(0b00_100_100), size)),
+        None => Some((mode << 6 | (base.number & 7), None, size)),
+    }
+}
+
+/// mod and rm of a 16-bit address and the size of its displacement. 16-bit
+/// addressing has no SIB byte, only these pairs of bx or bp and si or di.
+fn sixteen_bit_address(base: Option<Gpr>, index: Option<Gpr>, scale: Option<u8>, label: bool, displacement: i64) -> Option<(u8, Option<u8>, usize)> {
+    if scale.is_some_and(|scale| scale != 1) {
+        return None;
+    }
+    let registers: Vec<u8> = [base, index].into_iter().flatten().map(|register| register.number).collect();
+    let rm = match registers.as_slice() {
+        [3, 6] | [6, 3] => 0b000,
+        [3, 7] | [7, 3] => 0b001,
+        [5, 6] | [6, 5] => 0b010,
+        [5, 7] | [7, 5] => 0b011,
+        [6] => 0b100,
+        [7] => 0b101,
+        [5] => 0b110,
+        [3] => 0b111,
+        _ => return None,
+    };
+    // bp alone has no mod 00 form, since rm 110 means a disp16 and no registers
+    let (mode, size) = if label {
+        (0b10, 2)
+    } else if displacement == 0 && rm != 0b110 {
+        (0b00, 0)
+    } else if i8::try_from(displacement).is_ok() {
+        (0b01, 1)
+    } else {
+        (0b10, 2)
+    };
+    Some((mode << 6 | rm, None, size))
+}
+
+/// The NOP instructions recommended for each length from 1 to 9 bytes, as
+/// listed in the Intel manuals
+const MULTI_BYTE_NOPS: [&[u8]; 9] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0F, 0x1F, 0x00],
+    &[0x0F, 0x1F, 0x40, 0x00],
+    &[0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+/// `length` bytes of NOP instructions, as few as possible. 16-bit code gets
+/// one-byte NOPs, since the longer forms rely on 32-bit addressing.
+pub(crate) fn nop_padding(length: usize, bits: u16) -> Vec<u8> {
+    if bits == 16 {
+        return vec![0x90; length];
+    }
+    let longest = MULTI_BYTE_NOPS.len();
+    let mut padding = Vec::with_capacity(length);
+    for _ in 0..length / longest {
+        padding.extend_from_slice(MULTI_BYTE_NOPS[longest - 1]);
+    }
+    if !length.is_multiple_of(longest) {
+        padding.extend_from_slice(MULTI_BYTE_NOPS[length % longest - 1]);
+    }
+    padding
+}
+
+/// Override prefix selecting a segment register
+fn segment_prefix(segment: Symbol) -> Option<u8> {
+    match segment.as_str() {
+        "es" => Some(0x26),
+        "cs" => Some(0x2E),
+        "ss" => Some(0x36),
+        "ds" => Some(0x3E),
+        "fs" => Some(0x64),
+        "gs" => Some(0x65),
+        _ => None,
+    }
+}
+
+/// Condition code (the low nibble of the jcc, setcc and cmovcc opcodes) of
+/// a condition, the part of a mnemonic after `j`, `set` or `cmov`
+fn condition_code(condition: &str) -> Option<u8> {
+    match condition {
+        "o" => Some(0x0),
+        "no" => Some(0x1),
+        "b" | "c" | "nae" => Some(0x2),
+        "ae" | "nb" | "nc" => Some(0x3),
+        "e" | "z" => Some(0x4),
+        "ne" | "nz" => Some(0x5),
+        "be" | "na" => Some(0x6),
+        "a" | "nbe" => Some(0x7),
+        "s" => Some(0x8),
+        "ns" => Some(0x9),
+        "p" | "pe" => Some(0xA),
+        "np" | "po" => Some(0xB),
+        "l" | "nge" => Some(0xC),
+        "ge" | "nl" => Some(0xD),
+        "le" | "ng" => Some(0xE),
+        "g" | "nle" => Some(0xF),
+        _ => None,
+    }
+}
+
+/// A general purpose register as the encoder sees it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Gpr {
+    /// Register number from 0 to 15; the low 3 bits go in ModRM or the
+    /// opcode and the 4th in a REX prefix
+    number: u8,
+    width: u16,
+    /// spl, bpl, sil and dil, which share their numbers with ah to bh and
+    /// are told apart by the presence of a REX prefix
+    needs_rex: bool,
+    /// ah, ch, dh and bh, which can't be encoded with a REX prefix
+    high_byte: bool,
+}
+
+/// Names of the eight legacy registers by number, at each width
+const LEGACY_REGISTERS: [[&str; 4]; 8] = [
+    ["rax", "eax", "ax", "al"],
+    ["rcx", "ecx", "cx", "cl"],
+    ["rdx", "edx", "dx", "dl"],
+    ["rbx", "ebx", "bx", "bl"],
+    ["rsp", "esp", "sp", "spl"],
+    ["rbp", "ebp", "bp", "bpl"],
+    ["rsi", "esi", "si", "sil"],
+    ["rdi", "edi", "di", "dil"],
+];
+
+/// ah to bh, numbered 4 to 7 like spl to dil
+const HIGH_BYTE_REGISTERS: [&str; 4] = ["ah", "ch", "dh", "bh"];
+
+/// Number and width of any of the 16 general purpose registers
+fn gpr(name: &str) -> Option<Gpr> {
+    let width = match register_type(name)? {
+        TokenType::Reg64Bit => 64,
+        TokenType::Reg32Bit => 32,
+        TokenType::Reg16Bit => 16,
+        TokenType::Reg8Bit => 8,
+        _ => return None,
+    };
+    let register = |number: usize| Gpr { number: number as u8, width, needs_rex: false, high_byte: false };
+    if let Some(number) = LEGACY_REGISTERS.iter().position(|names| names.contains(&name)) {
+        return Some(Gpr { needs_rex: width == 8 && number >= 4, ..register(number) });
+    }
+    if let Some(number) = HIGH_BYTE_REGISTERS.iter().position(|&high| high == name) {
+        return Some(Gpr { high_byte: true, ..register(number + 4) });
+    }
+    // r8 to r15 with an optional d, w or b suffix
+    let digits = name.strip_prefix('r')?.trim_end_matches(['d', 'w', 'b']);
+    digits.parse().ok().filter(|number| (8..16).contains(number)).map(register)
+}
+
+/// Width of a register, or of a memory operand given a size as in `dword [rax]`
+fn operand_width(operand: &Operand) -> Result<u16, EncodeError> {
+    match operand {
+        Operand::Register(register) => gpr(register).map(|register| register.width).ok_or(EncodeError::UnsupportedOperands),
+        Operand::Memory(memory) => memory.size.ok_or(EncodeError::OperandSizeNotSpecified),
+        _ => Err(EncodeError::UnsupportedOperands),
+    }
+}
+
+/// ModRM byte with `mode` in mod; only the low 3 bits of `reg` and `rm` fit,
+/// the 4th goes in REX.R and REX.B
+fn modrm(mode: u8, reg: u8, rm: u8) -> u8 {
+    mode << 6 | (reg & 7) << 3 | (rm & 7)
+}
+
+/// Why an instruction can't be encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EncodeError {
+    /// The encoder has no forms of the instruction at all
+    UnsupportedInstruction(Symbol),
+    /// The encoder has no form of the instruction for these operands
+    UnsupportedOperands,
+    /// A form the current code mode doesn't have, as `push eax` in 64-bit mode
+    NotInMode { bits: u16 },
+    /// A memory operand without a size where no register gives the width,
+    /// as in `mov [rax], 1`
+    OperandSizeNotSpecified,
+    /// ah, ch, dh or bh in an instruction that needs a REX prefix, which
+    /// turns their numbers into spl to dil
+    HighByteWithRex(&'static str),
+    /// A memory operand the mode can't address, such as rsp as an index
+    InvalidAddress,
+    /// Base and index registers of different sizes, as in `[eax+rbx]`
+    MixedAddressSizes,
+    /// A displacement too large for the address size
+    DisplacementRange,
+    /// An immediate beyond what its field can hold, as in `int 256`
+    ImmediateRange { value: i128, bits: u32 },
+    /// An immediate that isn't a number of at most 64 bits, as in `mov rax, 0x10000000000000000`
+    InvalidImmediate(String),
+    /// A label's address given to an 8-bit field
+    LabelInByte,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::UnsupportedInstruction(name) => write!(f, "the encoder doesn't support '{}' yet", name),
+            EncodeError::UnsupportedOperands => write!(f, "the encoder doesn't support these operands"),
+            EncodeError::NotInMode { bits } => write!(f, "not encodable in {}-bit mode", bits),
+            EncodeError::OperandSizeNotSpecified => write!(f, "operation size not specified"),
+            EncodeError::HighByteWithRex(register) => write!(f, "{} cannot be used with a REX prefix", register),
+            EncodeError::InvalidAddress => write!(f, "invalid effective address"),
+            EncodeError::MixedAddressSizes => write!(f, "mixed address sizes: the base and index registers must be the same size"),
+            EncodeError::DisplacementRange => write!(f, "the displacement doesn't fit the address size"),
+            EncodeError::ImmediateRange { value, bits } => write!(f, "value {} doesn't fit in {} bits", value, bits),
+            EncodeError::InvalidImmediate(literal) => write!(f, "'{}' isn't a number that fits in 64 bits", literal),
+            EncodeError::LabelInByte => write!(f, "a label's address doesn't fit in 8 bits"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// An immediate that doesn't fit the field it is encoded into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImmediateOverflow {
+    /// Value as written in the source
+    pub value: i128,
+    /// Width of the encoded field
+    pub bits: u32,
+    /// What actually gets emitted
+    pub truncated: u64,
+    /// Whether the CPU sign-extends the emitted field
+    pub sign_extended: bool,
+}
+
+impl fmt::Display for ImmediateOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.sign_extended {
+            // Show the value the CPU ends up using after sign extension
+            let shift = 64 - self.bits;
+            let extended = ((self.truncated << shift) as i64) >> shift;
+            write!(f, "value {} doesn't fit in a sign-extended {}-bit immediate and will be read as {} (0x{:x})",
+                   self.value, self.bits, extended, self.truncated)
+        } else {
+            write!(f, "value {} doesn't fit in {} bits and will be truncated to {} (0x{:x})",
+                   self.value, self.bits, self.truncated, self.truncated)
+        }
+    }
+}
+
+/// Truncate `value` to a `bits` wide field.
+///
+/// Fields accept both signed and unsigned values of their width, except
+/// `sign_extended` ones (like the imm32 of `add rax, imm`), which the CPU
+/// widens and so only hold signed values. The 64-bit value they are widened
+/// to may be written either way, so `0xffffffffffffffff` fits as -1.
+pub fn fit_immediate(value: i128, bits: u32, sign_extended: bool) -> Result<u64, ImmediateOverflow> {
+    let min = -(1i128 << (bits - 1));
+    let max = if sign_extended { (1i128 << (bits - 1)) - 1 } else { (1i128 << bits) - 1 };
+    let truncated = (value as u64) & (u64::MAX >> (64 - bits));
+    let signed = if sign_extended && value > i128::from(i64::MAX) { i128::from(value as u64 as i64) } else { value };
+
+    if signed < min || signed > max {
+        Err(ImmediateOverflow { value, bits, truncated, sign_extended })
+    } else {
+        Ok(truncated)
+    }
+}
+
+/// Whether a relative displacement (target minus the address of the next
+/// instruction) fits a signed `bits` wide field such as rel8 or rel32
+pub fn displacement_fits(distance: i64, bits: u32) -> bool {
+    let limit = 1i64 << (bits - 1);
+    (-limit..limit).contains(&distance)
+}
+
+/// Width of the field an instruction's immediate operand is encoded into,
+/// and whether it is sign-extended. `None` when the width isn't known (e.g.
+/// a memory destination with no size).
+pub fn immediate_field(instruction: &Instruction) -> Option<(u32, bool)> {
+    match instruction.name.as_str() {
+        "shl" | "shr" | "sal" | "sar" | "rol" | "ror" => return Some((8, false)),
+        "ret" => return Some((16, false)),
+        "push" | "pushq" => return Some((32, true)),
+        _ => {}
+    }
+
+    let (width, register) = match instruction.operands.first()? {
+        Operand::Register(destination) => match register_type(destination)? {
+            TokenType::Reg8Bit => (8, true),
+            TokenType::Reg16Bit => (16, true),
+            TokenType::Reg32Bit => (32, true),
+            TokenType::Reg64Bit => (64, true),
+            _ => return None,
+        },
+        Operand::Memory(memory) => (memory.size?, false),
+        _ => return None,
+    };
+
+    match width {
+        // Only mov into a register has a full imm64 form, everything else
+        // sign-extends an imm32
+        64 if register && matches!(instruction.name.as_str(), "mov" | "movq" | "movabs") => Some((64, false)),
+        64 => Some((32, true)),
+        width => Some((u32::from(width), false)),
+    }
+}
+
+/// Immediates of `instruction` that don't fit the field they are encoded into
+pub fn immediate_overflows(instruction: &Instruction) -> Vec<ImmediateOverflow> {
+    let Some((bits, sign_extended)) = immediate_field(instruction) else {
+        return Vec::new();
+    };
+
+    instruction.operands.iter()
+        .filter_map(|operand| match operand {
+            Operand::Immediate(literal) => immediate_value(literal),
+            _ => None,
+        })
+        .filter_map(|value| fit_immediate(value, bits, sign_extended).err())
+        .collect()
+}
+
+/// The value of an immediate operand, which must be a number of at most 64 bits
+fn immediate_number(literal: &str) -> Result<i128, EncodeError> {
+    immediate_value(literal).ok_or_else(|| EncodeError::InvalidImmediate(literal.to_string()))
+}
+
+/// Numeric value of an immediate literal (decimal, 0x, 0b or 0o, optionally negative)
+pub fn immediate_value(literal: &str) -> Option<i128> {
+    match literal.strip_prefix('-') {
+        Some(digits) => parse_immediate(digits).map(|value| -(value as i128)),
+        None => parse_immediate(literal).map(|value| value as i128),
     }
 }
 
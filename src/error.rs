@@ -1,10 +1,28 @@
 use std::fmt;
+use std::io;
+use std::num::ParseIntError;
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::PathBuf;
 use colored::*;
 
+use crate::assembler::Format;
+use crate::encoder::EncodeError;
+use crate::parser::opcodes::TableProblem;
+use crate::render::{DiagnosticRenderer, MessageFormat, PrettyRenderer};
+use crate::tokenizer::{Span, Suppression};
+
+/// Names of the warnings that `; nasembler: allow(name)` comments can silence
+pub const WARNING_NAMES: &[&str] = &[
+    "overflow",
+    "address-size",
+    "shadowing",
+    "unused-extern",
+    "undefined-global",
+];
+
 /// Error type for the assembler
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum ErrorType {
     // Tokenization errors
     UnexpectedCharacter,
@@ -94,6 +112,8 @@ pub struct SourceLocation {
     pub line: usize,
     pub column: usize,
     pub line_content: Option<String>,
+    /// Byte range of the offending source text, starting at `column`
+    pub span: Option<Span>,
 }
 
 impl SourceLocation {
@@ -103,13 +123,35 @@ impl SourceLocation {
             line,
             column,
             line_content: None,
+            span: None,
         }
     }
-    
+
     pub fn with_line_content(mut self, content: String) -> Self {
         self.line_content = Some(content);
         self
     }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// Character offset into the line and character width of the region to underline
+    pub(crate) fn underline(&self, line_content: &str) -> (usize, usize) {
+        // Columns count from 1
+        let start = self.column.saturating_sub(1);
+        let width = match self.span {
+            Some(span) => {
+                // Byte offset of the line start, recovered from where the span begins
+                let start_byte = line_content.char_indices().nth(start).map_or(line_content.len(), |(i, _)| i);
+                let end_byte = (span.len() + start_byte).min(line_content.len());
+                line_content.get(start_byte..end_byte).map_or(1, |text| text.chars().count())
+            },
+            None => get_affected_token_length(line_content, start).unwrap_or(1),
+        };
+        (start, width.max(1))
+    }
 }
 
 impl fmt::Display for SourceLocation {
@@ -154,6 +196,8 @@ pub struct Error {
     pub detail: ErrorDetail,
     pub sub_errors: Vec<Error>,
     pub severity: ErrorSeverity,
+    /// For warnings, the name used to silence it (one of `WARNING_NAMES`)
+    pub warning_name: Option<&'static str>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -184,6 +228,7 @@ impl Error {
             detail,
             sub_errors: Vec::new(),
             severity: ErrorSeverity::Error,
+            warning_name: None,
         }
     }
     
@@ -192,6 +237,11 @@ impl Error {
         self
     }
     
+    pub fn with_warning_name(mut self, name: &'static str) -> Self {
+        self.warning_name = Some(name);
+        self
+    }
+    
     pub fn with_sub_error(mut self, error: Error) -> Self {
         self.sub_errors.push(error);
         self
@@ -208,159 +258,7 @@ impl Error {
     
     /// Generate a colorized tree-like display of the error
     pub fn display(&self) -> String {
-        let mut output = String::new();
-        
-        // Error header with error type
-        let error_type_str = format!("{}", self.error_type);
-        let header = format!("{} [{}]: {}", 
-            self.severity, 
-            error_type_str,
-            self.detail.message.white().bold()
-        );
-        output.push_str(&header);
-        output.push('\n');
-        
-        // Location with prettier formatting
-        if let Some(ref location) = self.location {
-            let location_str = format!("  {} {}", 
-                "→".bright_cyan().bold(), 
-                location.to_string().bright_blue().underline()
-            );
-            output.push_str(&location_str);
-            output.push('\n');
-            
-            // Line content if available - with syntax highlighting for assembly
-            if let Some(ref line_content) = location.line_content {
-                // Basic syntax highlighting
-                let highlighted_line = highlight_assembly_line(line_content);
-                output.push_str(&format!("    {}\n", highlighted_line));
-                
-                // Enhanced pointer to the column
-                let mut pointer = String::new();
-                for _ in 0..location.column {
-                    pointer.push(' ');
-                }
-                
-                // Use a caret with color for better visibility
-                pointer.push_str(&"^".bright_red().bold().to_string());
-                
-                // Add a wavy underline for affected text if we can determine it
-                if let Some(affected_length) = get_affected_token_length(line_content, location.column) {
-                    for _ in 0..affected_length.saturating_sub(1) {
-                        pointer.push_str(&"~".bright_red().bold().to_string());
-                    }
-                }
-                
-                output.push_str(&format!("    {}\n", pointer));
-            }
-        }
-        
-        // Help message with nicer formatting
-        if let Some(ref help) = self.detail.help {
-            output.push_str(&format!("  {} {}\n", 
-                "💡".to_string() + &" help:".green().bold().to_string(), 
-                help.bright_green()
-            ));
-        }
-        
-        // Note with nicer formatting
-        if let Some(ref note) = self.detail.note {
-            output.push_str(&format!("  {} {}\n", 
-                "ℹ️".to_string() + &" note:".bright_cyan().bold().to_string(), 
-                note.cyan()
-            ));
-        }
-        
-        // Sub-errors with improved tree formatting
-        if !self.sub_errors.is_empty() {
-            output.push_str(&format!("  {} {}\n", 
-                "⤷".bright_blue().bold(), 
-                "caused by:".bright_blue().underline()
-            ));
-            
-            for (i, error) in self.sub_errors.iter().enumerate() {
-                let is_last = i == self.sub_errors.len() - 1;
-                let prefix = if is_last { "  └─ " } else { "  ├─ " };
-                
-                // First line of sub-error
-                let sub_error_first_line = format!("{}{}: {}", 
-                    prefix.bright_blue(), 
-                    error.severity, 
-                    error.detail.message.white().bold()
-                );
-                output.push_str(&sub_error_first_line);
-                output.push('\n');
-                
-                // Location for sub-error
-                if let Some(ref location) = error.location {
-                    let location_prefix = if is_last { "     " } else { "  │  " };
-                    let location_str = format!("{}{} {}", 
-                        location_prefix.bright_blue(), 
-                        "→".bright_cyan().bold(), 
-                        location.to_string().bright_blue().underline()
-                    );
-                    output.push_str(&location_str);
-                    output.push('\n');
-                    
-                    // Line content if available - with highlighting
-                    if let Some(ref line_content) = location.line_content {
-                        let content_prefix = if is_last { "       " } else { "  │    " };
-                        let highlighted_line = highlight_assembly_line(line_content);
-                        output.push_str(&format!("{}{}\n", content_prefix.bright_blue(), highlighted_line));
-                        
-                        // Enhanced pointer with wavy underline
-                        let mut pointer = String::new();
-                        for _ in 0..location.column {
-                            pointer.push(' ');
-                        }
-                        
-                        pointer.push_str(&"^".bright_red().bold().to_string());
-                        
-                        // Add wavy underline for affected text
-                        if let Some(affected_length) = get_affected_token_length(line_content, location.column) {
-                            for _ in 0..affected_length.saturating_sub(1) {
-                                pointer.push_str(&"~".bright_red().bold().to_string());
-                            }
-                        }
-                        
-                        let pointer_prefix = if is_last { "       " } else { "  │    " };
-                        output.push_str(&format!("{}{}\n", pointer_prefix.bright_blue(), pointer));
-                    }
-                }
-                
-                // Help message for sub-error
-                if let Some(ref help) = error.detail.help {
-                    let help_prefix = if is_last { "     " } else { "  │  " };
-                    output.push_str(&format!("{}{} {}\n", 
-                        help_prefix.bright_blue(), 
-                        "💡 help:".green().bold(), 
-                        help.bright_green()
-                    ));
-                }
-                
-                // Note for sub-error
-                if let Some(ref note) = error.detail.note {
-                    let note_prefix = if is_last { "     " } else { "  │  " };
-                    output.push_str(&format!("{}{} {}\n", 
-                        note_prefix.bright_blue(), 
-                        "ℹ️ note:".bright_cyan().bold(), 
-                        note.cyan()
-                    ));
-                }
-                
-                // Nested sub-errors are not shown directly
-                if !error.sub_errors.is_empty() {
-                    let nested_prefix = if is_last { "     " } else { "  │  " };
-                    output.push_str(&format!("{}{} {} more nested errors not shown\n", 
-                        nested_prefix.bright_blue(), 
-                        "⚠️ note:".bright_cyan().bold(), 
-                        error.sub_errors.len().to_string().yellow().bold()
-                    ));
-                }
-            }
-        }
-        
-        output
+        PrettyRenderer.render_error(self)
     }
 }
 
@@ -370,11 +268,25 @@ impl fmt::Display for Error {
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.sub_errors.first().map(|error| error as &(dyn std::error::Error + 'static))
+    }
+}
+
 /// Error collector for accumulating multiple errors
 #[derive(Debug, Default, Clone)]
 pub struct ErrorCollector {
     errors: Vec<Error>,
     file_contents: HashMap<String, Vec<String>>,
+    /// Warning suppression comments, by file
+    suppressions: HashMap<String, Vec<Suppression>>,
+    warnings_as_errors: bool,
+    /// Source lines shown before and after the offending line
+    context_lines: usize,
+    /// Draw decorations with plain ASCII instead of box-drawing characters and emoji
+    ascii: bool,
+    message_format: MessageFormat,
 }
 
 impl ErrorCollector {
@@ -382,14 +294,48 @@ impl ErrorCollector {
         Self {
             errors: Vec::new(),
             file_contents: HashMap::new(),
+            suppressions: HashMap::new(),
+            warnings_as_errors: false,
+            context_lines: 1,
+            ascii: false,
+            message_format: MessageFormat::Pretty,
         }
     }
     
-    /// Add an error to the collector
-    pub fn add_error(&mut self, error: Error) {
+    /// Set whether warnings added from now on are recorded as errors (`-Werror`)
+    pub fn set_warnings_as_errors(&mut self, warnings_as_errors: bool) {
+        self.warnings_as_errors = warnings_as_errors;
+    }
+    
+    /// Set how many source lines to show before and after each offending line
+    pub fn set_context_lines(&mut self, context_lines: usize) {
+        self.context_lines = context_lines;
+    }
+    
+    /// Add an error to the collector. An error of the same type at the same
+    /// place as one already collected is dropped, since the passes that look
+    /// at a bad line often each report it.
+    pub fn add_error(&mut self, mut error: Error) {
+        if self.is_suppressed(&error) {
+            return;
+        }
+        if self.warnings_as_errors && error.severity == ErrorSeverity::Warning {
+            error.severity = ErrorSeverity::Error;
+            if error.detail.note.is_none() {
+                error.detail.note = Some("warnings are treated as errors (-Werror)".to_string());
+            }
+        }
+        if error.location.is_some() && self.errors.iter().any(|existing| is_duplicate(existing, &error)) {
+            return;
+        }
         self.errors.push(error);
     }
 
+    /// All collected errors and warnings, in the order they were reported
+    pub fn errors(&self) -> &[Error] {
+        &self.errors
+    }
+
     /// Add a simple error with just a message
     pub fn add_simple_error(&mut self, error_type: ErrorType, message: &str) {
         let error = Error::new(
@@ -407,7 +353,100 @@ impl ErrorCollector {
         line: usize,
         column: usize
     ) {
-        // Load file content if needed
+        let location = self.source_location(file, line, column);
+        let error = Error::new(
+            error_type,
+            ErrorDetail::new(message.to_string())
+        ).with_location(location);
+
+        self.add_error(error);
+    }
+
+    /// Add a warning with location. `name` is what `; nasembler: allow(name)` silences it with.
+    pub fn add_warning_with_location(&mut self,
+        name: &'static str,
+        error_type: ErrorType,
+        message: &str,
+        file: &str,
+        line: usize,
+        column: usize
+    ) {
+        let location = self.source_location(file, line, column);
+        let warning = Error::new(
+            error_type,
+            ErrorDetail::new(message.to_string())
+        ).with_location(location).with_severity(ErrorSeverity::Warning).with_warning_name(name);
+
+        self.add_error(warning);
+    }
+
+    /// Register the suppression comments of `file`, warning about names that
+    /// don't match any warning
+    pub fn add_suppressions(&mut self, file: &str, suppressions: &[Suppression]) {
+        for suppression in suppressions {
+            for name in suppression.names.iter().filter(|name| !WARNING_NAMES.contains(&name.as_str())) {
+                let location = self.source_location(file, suppression.first_line, suppression.column);
+                let mut detail = ErrorDetail::new(format!("Unknown warning '{}' in allow comment", name));
+                detail.help = did_you_mean(&similar_names(name, WARNING_NAMES.iter().copied()))
+                    .or_else(|| Some(format!("Known warnings: {}", WARNING_NAMES.join(", "))));
+                self.add_error(Error::new(ErrorType::SyntaxError, detail)
+                    .with_location(location)
+                    .with_severity(ErrorSeverity::Warning));
+            }
+        }
+        self.suppressions.entry(file.to_string()).or_default().extend_from_slice(suppressions);
+    }
+
+    // Whether an allow comment silences this warning
+    fn is_suppressed(&self, error: &Error) -> bool {
+        let (Some(name), Some(location)) = (error.warning_name, &error.location) else {
+            return false;
+        };
+        error.severity == ErrorSeverity::Warning && self.suppressions.get(&location.file)
+            .is_some_and(|suppressions| suppressions.iter().any(|s| s.covers(name, location.line)))
+    }
+
+    /// Add an error underlining `span`, which starts at `line`:`column`
+    pub fn add_error_with_span(&mut self,
+        error_type: ErrorType,
+        message: &str,
+        file: &str,
+        line: usize,
+        column: usize,
+        span: Span
+    ) {
+        let location = self.source_location(file, line, column).with_span(span);
+        let error = Error::new(
+            error_type,
+            ErrorDetail::new(message.to_string())
+        ).with_location(location);
+
+        self.add_error(error);
+    }
+
+    /// Give diagnostics in `file` that have no span the span of the token at
+    /// their location, as looked up by `span_at(line, column)`
+    pub fn attach_spans(&mut self, file: &str, span_at: impl Fn(usize, usize) -> Option<Span>) {
+        for error in &mut self.errors {
+            if let Some(location) = &mut error.location {
+                if location.span.is_none() && location.file == file {
+                    location.span = span_at(location.line, location.column);
+                }
+            }
+        }
+    }
+
+    /// Register the text of `file`, so snippets come from it rather than from
+    /// disk. Needed for sources that aren't files, such as stdin.
+    pub fn add_source(&mut self, file: &str, contents: &str) {
+        let lines = contents.lines().map(|s| s.to_string()).collect();
+        self.file_contents.insert(file.to_string(), lines);
+    }
+
+    /// Build a source location, attaching the line's text when it is known
+    pub fn source_location(&mut self, file: &str, line: usize, column: usize) -> SourceLocation {
+        // Fall back to reading unregistered files (there is no filesystem on wasm)
+        #[cfg(not(target_arch = "wasm32"))]
         if !self.file_contents.contains_key(file) {
             if let Ok(content) = std::fs::read_to_string(file) {
                 let lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
@@ -426,19 +465,11 @@ impl ErrorCollector {
             None
         };
 
-        // Create location
-        let mut location = SourceLocation::new(file.to_string(), line, column);
-        if let Some(content) = line_content {
-            location = location.with_line_content(content);
+        let location = SourceLocation::new(file.to_string(), line, column);
+        match line_content {
+            Some(content) => location.with_line_content(content),
+            None => location,
         }
-
-        // Create and add error
-        let error = Error::new(
-            error_type,
-            ErrorDetail::new(message.to_string())
-        ).with_location(location);
-
-        self.add_error(error);
     }
     
     /// Check if there are any errors (not including warnings)
@@ -461,189 +492,35 @@ impl ErrorCollector {
         self.errors.iter().filter(|e| e.severity == ErrorSeverity::Warning).count()
     }
     
+    /// Set how `display_errors` formats diagnostics
+    pub fn set_message_format(&mut self, message_format: MessageFormat) {
+        self.message_format = message_format;
+    }
+    
+    /// Source lines registered or read for `file`, empty if it isn't known
+    pub fn source_lines(&self, file: &str) -> &[String] {
+        self.file_contents.get(file).map(Vec::as_slice).unwrap_or_default()
+    }
+    
+    /// Source lines shown before and after each offending line
+    pub fn context_lines(&self) -> usize {
+        self.context_lines
+    }
+    
+    /// Set whether diagnostics are drawn with plain ASCII only
+    pub fn set_ascii(&mut self, ascii: bool) {
+        self.ascii = ascii;
+    }
+    
+    /// Whether diagnostics are drawn with plain ASCII only
+    pub fn ascii(&self) -> bool {
+        self.ascii
+    }
+    
     /// Display all errors in a beautifully formatted output
     pub fn display_errors(&self) -> String {
-        if self.errors.is_empty() {
-            return "✓ ".green().bold().to_string() + &"No errors or warnings.".green().to_string();
-        }
-        
-        let mut output = String::new();
-        
-        // Sort errors by severity, then by file, then by line
-        let mut sorted_errors = self.errors.clone();
-        sorted_errors.sort_by(|a, b| {
-            let a_severity = a.severity;
-            let b_severity = b.severity;
-            
-            if a_severity != b_severity {
-                return a_severity.cmp(&b_severity);
-            }
-            
-            // Compare file paths, defaulting to empty string for missing locations
-            let a_file = match &a.location {
-                Some(loc) => &loc.file,
-                None => "",
-            };
-            
-            let b_file = match &b.location {
-                Some(loc) => &loc.file,
-                None => "",
-            };
-            
-            if a_file != b_file {
-                return a_file.cmp(b_file);
-            }
-            
-            let a_line = a.location.as_ref().map(|l| l.line).unwrap_or(0);
-            let b_line = b.location.as_ref().map(|l| l.line).unwrap_or(0);
-            
-            a_line.cmp(&b_line)
-        });
-        
-        // Group errors by file
-        let mut current_file = String::new();
-        let mut error_counter = 1;
-        
-        for error in sorted_errors {
-            let file = if let Some(ref location) = error.location {
-                let path = Path::new(&location.file);
-                path.file_name()
-                    .map(|f| f.to_string_lossy().to_string())
-                    .unwrap_or_else(|| location.file.clone())
-            } else {
-                "unknown".to_string()
-            };
-            
-            // Add file header if we're switching to a new file
-            if file != current_file {
-                if !current_file.is_empty() {
-                    output.push('\n');
-                }
-                
-                output.push_str(&format!("{}{}:{}\n", 
-                    "■ ".bright_blue(),
-                    "FILE".bright_white(),
-                    file.bright_white().bold()
-                ));
-                current_file = file;
-            }
-            
-            // Add error number prefix with colored symbol
-            let (severity_symbol, severity_color) = match error.severity {
-                ErrorSeverity::Fatal => ("✕", "red"),
-                ErrorSeverity::Error => ("×", "red"),
-                ErrorSeverity::Warning => ("!", "yellow"),
-                ErrorSeverity::Info => ("i", "blue"),
-            };
-            
-            let error_number = format!("{}{}", 
-                severity_symbol.color(severity_color).bold(),
-                format!("{:02}", error_counter).bright_white()
-            );
-            error_counter += 1;
-            
-            // Compact error header with error type and message
-            let location_info = if let Some(ref location) = error.location {
-                format!("{}:{}", location.line, location.column).bright_blue().bold().to_string()
-            } else {
-                "".to_string()
-            };
-            
-            // Make the error header more compact - inline all the error info
-            let mut error_header = format!("{} {} {} ", 
-                error_number,
-                location_info,
-                error.error_type
-            );
-            
-            // Truncate message if it's too long for better display
-            let message = error.detail.message.clone();
-            
-            error_header.push_str(&message.white().to_string());
-            
-            output.push_str(&format!("{}\n", error_header));
-            
-            // Add code snippet in a more compact way if available
-            if let Some(ref location) = error.location {
-                if let Some(ref line_content) = location.line_content {
-                    // Highlighted code with pointer on the same line
-                    let highlighted_line = highlight_assembly_line(line_content);
-                    
-                    // Create pointer
-                    let mut pointer = String::new();
-                    for _ in 0..location.column {
-                        pointer.push(' ');
-                    }
-                    
-                    pointer.push_str(&"^".bright_red().bold().to_string());
-                    
-                    if let Some(affected_length) = get_affected_token_length(line_content, location.column) {
-                        for _ in 0..affected_length.saturating_sub(1) {
-                            pointer.push_str(&"~".bright_red().bold().to_string());
-                        }
-                    }
-                    
-                    // More compact code snippet display
-                    output.push_str(&format!("  {}│ {}\n", " ".white(), highlighted_line));
-                    output.push_str(&format!("  {}└→ {}\n", " ".white(), pointer));
-                }
-            }
-            
-            // Add help and note in a compact inline format
-            let mut hints = String::new();
-            
-            if let Some(ref help) = error.detail.help {
-                hints.push_str(&format!("→ {}", help.bright_green()));
-            }
-            
-            if let Some(ref note) = error.detail.note {
-                if !hints.is_empty() {
-                    hints.push_str(" ");
-                }
-                hints.push_str(&format!("ⓘ {}", note.cyan()));
-            }
-            
-            if !hints.is_empty() {
-                output.push_str(&format!("  {}\n", hints));
-            }
-            
-            // Add a minimal separator between errors
-            output.push_str(&format!("  {}\n", "―".repeat(25).bright_blue()));
-        }
-        
-        // Add summary with enhanced styling
-        let error_count = self.error_count();
-        let warning_count = self.warning_count();
-        
-        let mut summary = String::new();
-        
-        if error_count > 0 {
-            summary.push_str(&format!("{} {} {}", 
-                "×".bright_red().bold(), 
-                error_count.to_string().bright_red().bold(), 
-                if error_count == 1 { "err" } else { "errs" }
-            ));
-        }
-        
-        if warning_count > 0 {
-            if !summary.is_empty() {
-                summary.push_str(" + ");
-            }
-            
-            summary.push_str(&format!("{} {} {}", 
-                "!".bright_yellow().bold(), 
-                warning_count.to_string().bright_yellow().bold(), 
-                if warning_count == 1 { "warn" } else { "warns" }
-            ));
-        }
-        
-        if summary.is_empty() {
-            summary.push_str(&format!("{} {}", "✓".green().bold(), "No issues"));
-        }
-        
-        output.push_str(&format!("{}\n{}\n", "═".repeat(30).bright_blue(), summary));
-        
-        output
+        let output = self.message_format.renderer().render(self);
+        if self.ascii { ascii_decorations(&output) } else { output }
     }
     
     /// Return a new collector with the same settings but no errors
@@ -712,147 +589,377 @@ pub fn internal_error(message: String) -> Error {
         .with_severity(ErrorSeverity::Fatal)
 }
 
-/// Custom Result type that uses our Error type
-pub type Result<T> = std::result::Result<T, Error>;
+/// Failure of [`Parser::parse`](crate::parser::Parser::parse)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseError {
+    /// A statement could not be parsed
+    Syntax(String),
+    /// An operand refers to a label that is never defined
+    UndefinedLabel(String),
+    /// An operand refers to an extern symbol, which executables can't resolve
+    UnresolvedExtern(String),
+    /// Errors were reported to the error collector
+    Diagnostics { errors: usize },
+}
 
-// Helper function to highlight assembly syntax
-fn highlight_assembly_line(line: &str) -> String {
-    let parts: Vec<&str> = line.split_whitespace().collect();
-    
-    if parts.is_empty() {
-        return line.to_string();
-    }
-    
-    let mut result = String::new();
-    let trimmed = line.trim_start();
-    
-    // Add original indentation
-    let indent_len = line.len() - trimmed.len();
-    if indent_len > 0 {
-        result.push_str(&line[0..indent_len]);
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Syntax(message) => write!(f, "{}", message),
+            ParseError::UndefinedLabel(label) => write!(f, "Undefined label reference: {}", label),
+            ParseError::UnresolvedExtern(symbol) => {
+                write!(f, "Extern symbol '{}' can't be resolved: dynamic linking is not enabled", symbol)
+            },
+            ParseError::Diagnostics { errors } => write!(f, "Errors occurred during parsing ({} reported)", errors),
+        }
     }
-    
-    // Check for label (ends with :)
-    if parts[0].ends_with(':') {
-        // Label
-        result.push_str(&parts[0].bright_green().bold().to_string());
-        if parts.len() > 1 {
-            result.push(' ');
-            let remainder = trimmed[parts[0].len()..].trim_start();
-            result.push_str(&highlight_assembly_remainder(remainder));
+}
+
+impl std::error::Error for ParseError {}
+
+/// Failure while laying out or writing an ELF image
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ElfError {
+    /// The writer failed
+    Io(io::Error),
+    /// A number literal could not be parsed
+    InvalidNumber { literal: String, source: ParseIntError },
+    /// A `global`/`extern` directive operand is not a symbol
+    InvalidSymbolOperand(String),
+    /// A statement appeared in a section that cannot hold it
+    WrongSection { statement: &'static str, expected: &'static str, found: String, line: usize },
+    /// A data directive other than db/dw/dd/dq
+    UnknownDataDirective(String),
+    /// A data directive operand that is neither a number nor a string
+    UnsupportedDataOperand(String),
+    /// A relocation refers to a symbol that is never defined
+    UndefinedSymbol(String),
+    /// A relocation would patch bytes outside the .text section
+    RelocationOutOfBounds(String),
+    /// An instruction refers to an extern symbol, which executables can't resolve
+    UnresolvedExtern { instruction: String, symbol: String },
+    /// A relative displacement is too far for the field it is encoded into
+    DisplacementOutOfRange { instruction: String, label: String, distance: i64, bits: u32 },
+    /// A label's address doesn't fit the 16- or 32-bit field it is encoded into
+    AddressOutOfRange { instruction: String, label: String, address: u64, bits: u32 },
+    /// A thread-local label used other than as an offset from the thread pointer
+    ThreadLocalReference { instruction: String, label: String },
+    /// `..got` or `..gotpcrel` in an executable, where there is no global offset table
+    GlobalOffsetTable { instruction: String, label: String, special: String },
+    /// A `wrt` special symbol used with a field its relocations can't patch
+    UnsupportedWrt { instruction: String, label: String, special: String },
+    /// Emitting a statement would grow a section past the configured limit
+    SectionTooLarge { section: String, size: u64, limit: u64, line: usize },
+    /// `equ` definitions that depend on each other; the first name is repeated at the end
+    EquCycle { cycle: Vec<String>, line: usize },
+    /// An `equ` expression uses a symbol that is never defined
+    UndefinedEquSymbol { name: String, symbol: String, line: usize },
+    /// The entry symbol of an executable is never defined
+    UndefinedEntry(String),
+    /// An instruction the encoder has no bytes for
+    Unencodable { instruction: String, reason: EncodeError, line: usize },
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Io(_) => write!(f, "Failed to write ELF image"),
+            ElfError::InvalidNumber { literal, .. } => write!(f, "Invalid number '{}'", literal),
+            ElfError::InvalidSymbolOperand(directive) => write!(f, "'{}' directive operand must be a label", directive),
+            ElfError::WrongSection { statement, expected, found, line } => {
+                write!(f, "{} must be in {} section, found in '{}'", statement, expected, found)?;
+                // Programs built directly have no source lines
+                if *line > 0 { write!(f, " (line {})", line)?; }
+                Ok(())
+            },
+            ElfError::UnknownDataDirective(directive) => write!(f, "Unknown data directive '{}'", directive),
+            ElfError::UnsupportedDataOperand(operand) => write!(f, "Unsupported operand '{}' in data directive", operand),
+            ElfError::UndefinedSymbol(label) => write!(f, "Label '{}' not found for relocation", label),
+            ElfError::RelocationOutOfBounds(label) => write!(f, "Relocation for label '{}' is out of bounds", label),
+            ElfError::UnresolvedExtern { instruction, symbol } => {
+                write!(f, "'{}' refers to extern symbol '{}', but dynamic linking is not enabled for executable output",
+                       instruction, symbol)
+            },
+            ElfError::DisplacementOutOfRange { instruction, label, distance, bits } => {
+                write!(f, "'{}' can't reach label '{}': it is {} bytes away, beyond the range of a rel{} displacement",
+                       instruction, label, distance, bits)
+            },
+            ElfError::AddressOutOfRange { instruction, label, address, bits } => {
+                write!(f, "'{}' can't encode the address of label '{}' (0x{:X}): it doesn't fit in {} bits",
+                       instruction, label, address, bits)
+            },
+            ElfError::ThreadLocalReference { instruction, label } => {
+                write!(f, "'{}' can't refer to thread-local '{}' this way: thread-locals are reached through the thread pointer, as in [fs:{}]",
+                       instruction, label, label)
+            },
+            ElfError::GlobalOffsetTable { instruction, label, special } => {
+                write!(f, "'{}' refers to '{} wrt {}', which needs a global offset table; assemble with -f obj and link the object",
+                       instruction, label, special)
+            },
+            ElfError::UnsupportedWrt { instruction, label, special } => {
+                write!(f, "'{}' can't refer to '{} wrt {}' this way: no relocation patches that field relative to {}",
+                       instruction, label, special, special)
+            },
+            ElfError::SectionTooLarge { section, size, limit, line } => {
+                write!(f, "Section '{}' would grow to {} bytes, over the limit of {} bytes", section, size, limit)?;
+                if *line > 0 { write!(f, " (line {})", line)?; }
+                Ok(())
+            },
+            ElfError::EquCycle { cycle, line } => {
+                write!(f, "Cyclic equ definitions: {}", cycle.join(" -> "))?;
+                if *line > 0 { write!(f, " (line {})", line)?; }
+                Ok(())
+            },
+            ElfError::UndefinedEquSymbol { name, symbol, line } => {
+                write!(f, "'{}' equ refers to undefined symbol '{}'", name, symbol)?;
+                if *line > 0 { write!(f, " (line {})", line)?; }
+                Ok(())
+            },
+            ElfError::UndefinedEntry(symbol) => {
+                write!(f, "Entry symbol '{}' is not defined; add a '{}:' label to .text or choose another entry symbol", symbol, symbol)
+            },
+            ElfError::Unencodable { instruction, reason, line } => {
+                write!(f, "Can't encode '{}': {}", instruction, reason)?;
+                if *line > 0 { write!(f, " (line {})", line)?; }
+                Ok(())
+            },
         }
-        return result;
     }
-    
-    // Check for instruction or directive
-    if parts[0].starts_with('.') {
-        // Directive
-        result.push_str(&parts[0].bright_cyan().bold().to_string());
-        if parts.len() > 1 {
-            result.push(' ');
-            let remainder = trimmed[parts[0].len()..].trim_start();
-            result.push_str(&highlight_assembly_remainder(remainder));
+}
+
+impl ElfError {
+    /// Source line the error is about, for those tied to a statement
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            ElfError::WrongSection { line, .. }
+            | ElfError::SectionTooLarge { line, .. }
+            | ElfError::EquCycle { line, .. }
+            | ElfError::UndefinedEquSymbol { line, .. }
+            | ElfError::Unencodable { line, .. } => Some(*line).filter(|&line| line > 0),
+            _ => None,
         }
-    } else {
-        // Instruction
-        result.push_str(&parts[0].bright_yellow().bold().to_string());
-        if parts.len() > 1 {
-            result.push(' ');
-            let remainder = trimmed[parts[0].len()..].trim_start();
-            result.push_str(&highlight_assembly_remainder(remainder));
+    }
+
+    /// The message without the "(line N)" that [`Display`](fmt::Display)
+    /// adds, for diagnostics that point at the line themselves
+    pub(crate) fn located_message(&self) -> String {
+        let message = self.to_string();
+        match self.line() {
+            Some(line) => message.strip_suffix(&format!(" (line {})", line)).map_or(message.clone(), str::to_string),
+            None => message,
         }
     }
-    
-    result
 }
 
-// Helper function to highlight the remainder of an assembly line
-fn highlight_assembly_remainder(remainder: &str) -> String {
-    let mut result = String::new();
-    let mut in_string = false;
-    let mut in_comment = false;
-    let mut current_token = String::new();
-    
-    for c in remainder.chars() {
-        if in_comment {
-            // Everything after ; is a comment
-            result.push_str(&c.to_string().bright_black().to_string());
-            continue;
+impl std::error::Error for ElfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ElfError::Io(source) => Some(source),
+            ElfError::InvalidNumber { source, .. } => Some(source),
+            _ => None,
         }
-        
-        if c == '"' {
-            if in_string {
-                // End of string
-                current_token.push(c);
-                result.push_str(&current_token.green().to_string());
-                current_token.clear();
-                in_string = false;
-            } else {
-                // Start of string
-                if !current_token.is_empty() {
-                    result.push_str(&highlight_assembly_token(&current_token));
-                    current_token.clear();
+    }
+}
+
+impl From<io::Error> for ElfError {
+    fn from(error: io::Error) -> Self {
+        ElfError::Io(error)
+    }
+}
+
+/// Failure loading an [`OpcodeTable`](crate::parser::opcodes::OpcodeTable)
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OpcodeTableError {
+    /// The table file can't be read
+    Io { path: PathBuf, source: io::Error },
+    /// Lines of the table aren't valid; `path` is None for a table given as a string
+    Invalid { path: Option<PathBuf>, problems: Vec<TableProblem> },
+}
+
+impl fmt::Display for OpcodeTableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpcodeTableError::Io { path, .. } => write!(f, "Can't read opcode table '{}'", path.display()),
+            OpcodeTableError::Invalid { path, problems } => {
+                for (index, problem) in problems.iter().enumerate() {
+                    if index > 0 { writeln!(f)?; }
+                    match path {
+                        Some(path) => write!(f, "{}:{}: {}", path.display(), problem.line(), problem.located_message())?,
+                        None => write!(f, "{}", problem)?,
+                    }
                 }
-                current_token.push(c);
-                in_string = true;
-            }
-        } else if in_string {
-            // Inside string
-            current_token.push(c);
-        } else if c == ';' {
-            // Start of comment
-            if !current_token.is_empty() {
-                result.push_str(&highlight_assembly_token(&current_token));
-                current_token.clear();
-            }
-            result.push_str(&c.to_string().bright_black().to_string());
-            in_comment = true;
-        } else if c.is_whitespace() {
-            // Whitespace
-            if !current_token.is_empty() {
-                result.push_str(&highlight_assembly_token(&current_token));
-                current_token.clear();
-            }
-            result.push(c);
-        } else if c == ',' || c == '[' || c == ']' || c == '+' || c == '-' || c == '*' {
-            // Special chars
-            if !current_token.is_empty() {
-                result.push_str(&highlight_assembly_token(&current_token));
-                current_token.clear();
-            }
-            result.push_str(&c.to_string().bright_magenta().to_string());
-        } else {
-            // Part of a token
-            current_token.push(c);
+                Ok(())
+            },
         }
     }
-    
-    // Don't forget any remaining token
-    if !current_token.is_empty() {
-        result.push_str(&highlight_assembly_token(&current_token));
+}
+
+impl OpcodeTableError {
+    /// The table file the error is about, if the table came from one
+    pub fn path(&self) -> Option<&std::path::Path> {
+        match self {
+            OpcodeTableError::Io { path, .. } => Some(path),
+            OpcodeTableError::Invalid { path, .. } => path.as_deref(),
+        }
+    }
+
+    /// Attach the file the table was read from
+    pub(crate) fn in_file(self, file: &std::path::Path) -> Self {
+        match self {
+            OpcodeTableError::Invalid { problems, .. } => OpcodeTableError::Invalid { path: Some(file.to_path_buf()), problems },
+            error => error,
+        }
+    }
+}
+
+impl std::error::Error for OpcodeTableError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OpcodeTableError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Failure of an [`Assembler`](crate::Assembler) run
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum AssemblerError {
+    /// Reading the source or writing the output failed
+    Io { path: String, source: io::Error },
+    /// The source failed to parse
+    Parse(ParseError),
+    /// Errors were reported to the error collector; see `Assembler::errors`
+    Diagnostics { errors: usize },
+    /// ELF generation failed
+    Elf(ElfError),
+    /// The output format has no generator yet
+    UnsupportedFormat(Format),
+    /// An opcode table failed to load
+    OpcodeTable(OpcodeTableError),
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssemblerError::Io { path, .. } => write!(f, "I/O error on '{}'", path),
+            AssemblerError::Parse(_) => write!(f, "Parsing failed"),
+            AssemblerError::Diagnostics { errors } => write!(f, "Errors occurred during assembly ({} reported)", errors),
+            AssemblerError::Elf(_) => write!(f, "ELF generation failed"),
+            AssemblerError::UnsupportedFormat(format) => write!(f, "Output format {:?} is not implemented yet", format),
+            AssemblerError::OpcodeTable(_) => write!(f, "Loading the opcode table failed"),
+        }
+    }
+}
+
+impl std::error::Error for AssemblerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AssemblerError::Io { source, .. } => Some(source),
+            AssemblerError::Parse(source) => Some(source),
+            AssemblerError::Elf(source) => Some(source),
+            AssemblerError::OpcodeTable(source) => Some(source),
+            _ => None,
+        }
     }
-    
-    result
 }
 
-// Helper function to highlight a token based on its content
-fn highlight_assembly_token(token: &str) -> String {
-    if token.starts_with('r') || token == "rax" || token == "rbx" || token == "rcx" || token == "rdx" || 
-       token == "rsi" || token == "rdi" || token == "rbp" || token == "rsp" || 
-       token.starts_with("xmm") || token.starts_with("ymm") || token.starts_with("zmm") {
-        // Register
-        token.bright_blue().to_string()
-    } else if token.starts_with("0x") || token.chars().all(|c| c.is_digit(10)) {
-        // Numeric literal
-        token.bright_cyan().to_string()
-    } else {
-        // Default - likely a label reference or other identifier
-        token.white().to_string()
+impl From<ParseError> for AssemblerError {
+    fn from(error: ParseError) -> Self {
+        AssemblerError::Parse(error)
     }
 }
 
+impl From<ElfError> for AssemblerError {
+    fn from(error: ElfError) -> Self {
+        AssemblerError::Elf(error)
+    }
+}
+
+impl From<OpcodeTableError> for AssemblerError {
+    fn from(error: OpcodeTableError) -> Self {
+        AssemblerError::OpcodeTable(error)
+    }
+}
+
+/// Format an error and its chain of sources as "error: cause: cause"
+pub fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+/// Names from `candidates` close enough to `name` to likely be a typo of it, closest first
+pub fn similar_names<'a, I>(name: &str, candidates: I) -> Vec<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() / 3).max(1);
+
+    let mut matches: Vec<(usize, &str)> = candidates.into_iter()
+        .filter_map(|candidate| {
+            let distance = edit_distance(&name, &candidate.to_lowercase());
+            (distance > 0 && distance <= max_distance).then_some((distance, candidate))
+        })
+        .collect();
+
+    matches.sort();
+    matches.dedup();
+    matches.into_iter().take(3).map(|(_, candidate)| candidate).collect()
+}
+
+/// Format suggestions as "did you mean 'a', 'b' or 'c'?"
+pub fn did_you_mean(suggestions: &[&str]) -> Option<String> {
+    let quoted: Vec<String> = suggestions.iter().map(|name| format!("'{}'", name)).collect();
+    match quoted.split_last() {
+        None => None,
+        Some((last, [])) => Some(format!("did you mean {}?", last)),
+        Some((last, rest)) => Some(format!("did you mean {} or {}?", rest.join(", "), last)),
+    }
+}
+
+/// Edit distance between two strings, counting a swap of adjacent characters as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // distances[i][j] is the distance between the first i chars of a and the first j of b
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+
+    distances[a.len()][b.len()]
+}
+
+/// Custom Result type that uses our Error type
+pub type Result<T> = std::result::Result<T, Error>;
+
 // Helper function to guess the length of the token at the given column
-fn get_affected_token_length(line: &str, column: usize) -> Option<usize> {
+pub(crate) fn get_affected_token_length(line: &str, column: usize) -> Option<usize> {
     if column >= line.len() {
         return None;
     }
@@ -867,3 +974,46 @@ fn get_affected_token_length(line: &str, column: usize) -> Option<usize> {
     
     Some(end - column)
 }
+
+// Whether two diagnostics report the same kind of problem at the same place
+fn is_duplicate(a: &Error, b: &Error) -> bool {
+    a.error_type == b.error_type && a.severity == b.severity && match (&a.location, &b.location) {
+        (Some(a), Some(b)) => a.file == b.file && a.line == b.line && a.column == b.column,
+        _ => false,
+    }
+}
+
+// Whether two diagnostics of the same severity are on the same source line
+pub(crate) fn same_line(a: &Error, b: &Error) -> bool {
+    a.severity == b.severity && match (&a.location, &b.location) {
+        (Some(a), Some(b)) => a.file == b.file && a.line == b.line,
+        _ => false,
+    }
+}
+
+/// Replace the box-drawing characters, arrows and emoji used to decorate
+/// diagnostics with plain ASCII, for terminals and logs that can't show them
+pub fn ascii_decorations(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '→' | '⤷' | '↳' => output.push_str("->"),
+            '─' | '―' => output.push('-'),
+            '═' => output.push('='),
+            '│' | '├' => output.push('|'),
+            '└' => output.push('`'),
+            '■' => output.push('#'),
+            '►' => output.push('>'),
+            '✕' | '✗' => output.push('X'),
+            '×' => output.push('x'),
+            '✓' => output.push('+'),
+            '⚠' => output.push('!'),
+            'ℹ' | 'ⓘ' => output.push('i'),
+            '💡' => output.push('*'),
+            // Emoji presentation selector after ⚠ and ℹ
+            '\u{FE0F}' => {},
+            _ => output.push(ch),
+        }
+    }
+    output
+}
@@ -0,0 +1,210 @@
+//! C bindings for the assembler (`ffi` feature).
+//!
+//! The matching header lives in `include/nasembler.h`. Every result returned
+//! by `nasembler_assemble` must be released with `nasembler_result_free`.
+//! Build the library with `cargo build --profile bindings --features ffi`:
+//! panics are only caught and reported as `NASEMBLER_STATUS_PANIC` when they
+//! unwind, which the release profile's `panic = "abort"` rules out.
+
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::assembler::{Assembler, Format};
+use crate::error::{Error, ErrorSeverity};
+
+/// Output formats, mirroring `NASEMBLER_FORMAT_*` in the header
+pub const NASEMBLER_FORMAT_ELF: c_int = 0;
+pub const NASEMBLER_FORMAT_BIN: c_int = 1;
+pub const NASEMBLER_FORMAT_HEX: c_int = 2;
+pub const NASEMBLER_FORMAT_ELF32: c_int = 3;
+pub const NASEMBLER_FORMAT_OBJ: c_int = 4;
+
+/// Result status codes, mirroring `NASEMBLER_STATUS_*` in the header
+pub const NASEMBLER_STATUS_OK: c_int = 0;
+pub const NASEMBLER_STATUS_ERROR: c_int = 1;
+pub const NASEMBLER_STATUS_INVALID_ARGUMENT: c_int = 2;
+pub const NASEMBLER_STATUS_PANIC: c_int = 3;
+
+/// A single diagnostic (error, warning, ...) reported during assembly
+#[repr(C)]
+pub struct NasemblerDiagnostic {
+    /// 0 = fatal, 1 = error, 2 = warning, 3 = info
+    pub severity: c_int,
+    pub message: *mut c_char,
+    /// NULL when the diagnostic has no source location
+    pub file: *mut c_char,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Output bytes and diagnostics of one `nasembler_assemble` call
+#[repr(C)]
+pub struct NasemblerResult {
+    pub status: c_int,
+    pub output: *mut u8,
+    pub output_len: usize,
+    pub diagnostics: *mut NasemblerDiagnostic,
+    pub diagnostic_count: usize,
+}
+
+impl NasemblerResult {
+    fn new(status: c_int, output: Vec<u8>, errors: &[Error]) -> Self {
+        let output = output.into_boxed_slice();
+        let output_len = output.len();
+        let output = if output_len == 0 { ptr::null_mut() } else { Box::into_raw(output) as *mut u8 };
+
+        let diagnostics: Box<[NasemblerDiagnostic]> = errors.iter().map(to_diagnostic).collect();
+        let diagnostic_count = diagnostics.len();
+        let diagnostics = if diagnostic_count == 0 {
+            ptr::null_mut()
+        } else {
+            Box::into_raw(diagnostics) as *mut NasemblerDiagnostic
+        };
+
+        Self {
+            status,
+            output,
+            output_len,
+            diagnostics,
+            diagnostic_count,
+        }
+    }
+
+    fn failure(status: c_int, message: &str) -> Self {
+        Self {
+            diagnostics: Box::into_raw(Box::new([NasemblerDiagnostic {
+                severity: severity_code(ErrorSeverity::Fatal),
+                message: to_c_string(message),
+                file: ptr::null_mut(),
+                line: 0,
+                column: 0,
+            }])) as *mut NasemblerDiagnostic,
+            diagnostic_count: 1,
+            ..Self::new(status, Vec::new(), &[])
+        }
+    }
+}
+
+fn severity_code(severity: ErrorSeverity) -> c_int {
+    match severity {
+        ErrorSeverity::Fatal => 0,
+        ErrorSeverity::Error => 1,
+        ErrorSeverity::Warning => 2,
+        ErrorSeverity::Info => 3,
+    }
+}
+
+fn to_c_string(value: &str) -> *mut c_char {
+    // Interior NULs can't cross the C boundary; drop them rather than the whole message
+    let value = value.replace('\0', "");
+    CString::new(value).unwrap_or_default().into_raw()
+}
+
+fn to_diagnostic(error: &Error) -> NasemblerDiagnostic {
+    let (file, line, column) = match &error.location {
+        Some(location) => (to_c_string(&location.file), location.line, location.column),
+        None => (ptr::null_mut(), 0, 0),
+    };
+
+    NasemblerDiagnostic {
+        severity: severity_code(error.severity),
+        message: to_c_string(&error.detail.message),
+        file,
+        line,
+        column,
+    }
+}
+
+unsafe fn optional_str<'a>(value: *const c_char) -> Result<Option<&'a str>, ()> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(value).to_str().map(Some).map_err(|_| ())
+}
+
+/// Assemble a NUL-terminated source string.
+///
+/// `file_name` (may be NULL) is only used in diagnostics. Never returns NULL.
+///
+/// # Safety
+///
+/// `source` must be a valid NUL-terminated string and `file_name` either NULL
+/// or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn nasembler_assemble(
+    source: *const c_char,
+    file_name: *const c_char,
+    format: c_int,
+) -> *mut NasemblerResult {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| assemble(source, file_name, format)))
+        .unwrap_or_else(|_| NasemblerResult::failure(NASEMBLER_STATUS_PANIC, "internal error: the assembler panicked"));
+
+    Box::into_raw(Box::new(result))
+}
+
+unsafe fn assemble(source: *const c_char, file_name: *const c_char, format: c_int) -> NasemblerResult {
+    let source = match optional_str(source) {
+        Ok(Some(source)) => source,
+        Ok(None) => return NasemblerResult::failure(NASEMBLER_STATUS_INVALID_ARGUMENT, "source must not be NULL"),
+        Err(()) => return NasemblerResult::failure(NASEMBLER_STATUS_INVALID_ARGUMENT, "source is not valid UTF-8"),
+    };
+
+    let file_name = match optional_str(file_name) {
+        Ok(name) => name.unwrap_or("<memory>"),
+        Err(()) => return NasemblerResult::failure(NASEMBLER_STATUS_INVALID_ARGUMENT, "file_name is not valid UTF-8"),
+    };
+
+    let format = match format {
+        NASEMBLER_FORMAT_ELF => Format::Elf,
+        NASEMBLER_FORMAT_BIN => Format::Bin,
+        NASEMBLER_FORMAT_HEX => Format::Hex,
+        NASEMBLER_FORMAT_ELF32 => Format::Elf32,
+        NASEMBLER_FORMAT_OBJ => Format::Obj,
+        _ => return NasemblerResult::failure(NASEMBLER_STATUS_INVALID_ARGUMENT, "unknown output format"),
+    };
+
+    let mut assembler = Assembler::new()
+        .format(format)
+        .file_name(file_name);
+
+    match assembler.assemble(source) {
+        Ok(bytes) => NasemblerResult::new(NASEMBLER_STATUS_OK, bytes, assembler.errors().errors()),
+        Err(_) => NasemblerResult::new(NASEMBLER_STATUS_ERROR, Vec::new(), assembler.errors().errors()),
+    }
+}
+
+/// Release a result returned by `nasembler_assemble`. Accepts NULL.
+///
+/// # Safety
+///
+/// `result` must be NULL or a pointer returned by `nasembler_assemble` that
+/// has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn nasembler_result_free(result: *mut NasemblerResult) {
+    if result.is_null() {
+        return;
+    }
+
+    let result = Box::from_raw(result);
+
+    if !result.output.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(result.output, result.output_len)));
+    }
+
+    if !result.diagnostics.is_null() {
+        let diagnostics = Box::from_raw(ptr::slice_from_raw_parts_mut(result.diagnostics, result.diagnostic_count));
+        for diagnostic in diagnostics.iter() {
+            drop(CString::from_raw(diagnostic.message));
+            if !diagnostic.file.is_null() {
+                drop(CString::from_raw(diagnostic.file));
+            }
+        }
+    }
+}
+
+/// Version of the library as a static NUL-terminated string
+#[no_mangle]
+pub extern "C" fn nasembler_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
@@ -0,0 +1,48 @@
+//! Entry points for fuzzing the assembler.
+//!
+//! [`fuzz_assemble`] runs arbitrary bytes through the whole pipeline and
+//! returns an error for anything it can't assemble; it must never panic,
+//! overflow or allocate without bound, so a crash while fuzzing it is a bug.
+//! Sections are capped at [`FUZZ_MAX_SECTION_SIZE`] so inputs like
+//! `resb 0xffffffff` fail fast rather than exhausting memory. The cargo-fuzz
+//! targets in `fuzz/` call these functions; `nasembler corpus` output makes a
+//! good seed corpus.
+//!
+//! ```no_run
+//! let result = nasembler::fuzz_assemble(b"section .text\n_start:\n    syscall\n");
+//! assert!(result.is_ok());
+//! ```
+
+use crate::assembler::{Assembler, Format};
+use crate::error::AssemblerError;
+
+/// Largest size in bytes a section may grow to while fuzzing
+pub const FUZZ_MAX_SECTION_SIZE: u64 = 1024 * 1024;
+
+/// Output formats [`fuzz_assemble_any`] picks from with the first input byte
+pub const FUZZ_FORMATS: [Format; 4] = [Format::Elf, Format::Elf32, Format::Bin, Format::Obj];
+
+/// Assemble `bytes` as source into an ELF executable, in memory. Bytes that
+/// aren't valid UTF-8 are replaced with U+FFFD, which the tokenizer rejects.
+pub fn fuzz_assemble(bytes: &[u8]) -> Result<Vec<u8>, AssemblerError> {
+    fuzz_assemble_as(bytes, Format::Elf)
+}
+
+/// Like [`fuzz_assemble`], writing `format` instead of an ELF executable
+pub fn fuzz_assemble_as(bytes: &[u8], format: Format) -> Result<Vec<u8>, AssemblerError> {
+    let source = String::from_utf8_lossy(bytes);
+    Assembler::new()
+        .format(format)
+        .verbose(false)
+        .max_section_size(FUZZ_MAX_SECTION_SIZE)
+        .assemble(&source)
+}
+
+/// Like [`fuzz_assemble`], with the first byte choosing one of
+/// [`FUZZ_FORMATS`] and the rest being the source
+pub fn fuzz_assemble_any(bytes: &[u8]) -> Result<Vec<u8>, AssemblerError> {
+    let Some((&selector, source)) = bytes.split_first() else {
+        return fuzz_assemble(bytes);
+    };
+    fuzz_assemble_as(source, FUZZ_FORMATS[usize::from(selector) % FUZZ_FORMATS.len()])
+}
@@ -0,0 +1,173 @@
+//! Registration with debuggers through the GDB JIT interface.
+//!
+//! Debuggers can't find code that was never loaded from a file, so each
+//! [`Jit`](super::Jit) describes its code with a small in-memory ELF file
+//! whose sections sit at the mapped addresses and whose symbol table names
+//! every label. The file is linked into `__jit_debug_descriptor` and announced
+//! by calling `__jit_debug_register_code`, where gdb and lldb keep a
+//! breakpoint; both symbols are part of the protocol and must keep these
+//! names. Until a debugger is attached, registering costs nothing more than
+//! building the file.
+//!
+//! Other JIT engines define the same two symbols, so this module can't be
+//! linked into a program together with another one that does.
+
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::{Mutex, PoisonError};
+
+use crate::elf::{self, ET_EXEC, HeaderOptions, Record, sized_labels};
+use crate::relocatable::{
+    self, Elf64Symbol, Section, StringTable, SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_PROGBITS, SHT_STRTAB,
+    SHT_SYMTAB, STB_GLOBAL, STT_FUNC, STT_OBJECT,
+};
+use crate::symbol::Symbol;
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+/// Symbol file section indices, in the order `symbol_file` writes them
+const TEXT_INDEX: u16 = 1;
+const DATA_INDEX: u16 = 2;
+const STRTAB_INDEX: u32 = 4;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+/// The list of registered code that debuggers read
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+/// Debuggers break here to read the descriptor after each change
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // Keeps the function from being merged away; its body doesn't matter
+    std::hint::black_box(());
+}
+
+/// Serializes changes to the descriptor, as the protocol requires
+static DESCRIPTOR_LOCK: Mutex<()> = Mutex::new(());
+
+/// Code announced to debuggers, withdrawn again when dropped
+pub(super) struct Registration {
+    entry: Box<JitCodeEntry>,
+    /// Read by the debugger through `entry`
+    _symbol_file: Vec<u8>,
+}
+
+impl Registration {
+    /// Announce the code described by `symbol_file`
+    pub(super) fn new(symbol_file: Vec<u8>) -> Self {
+        let mut entry = Box::new(JitCodeEntry {
+            next_entry: ptr::null_mut(),
+            prev_entry: ptr::null_mut(),
+            symfile_addr: symbol_file.as_ptr(),
+            symfile_size: symbol_file.len() as u64,
+        });
+
+        let _lock = DESCRIPTOR_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            entry.next_entry = (*descriptor).first_entry;
+            let entry_ptr: *mut JitCodeEntry = &mut *entry;
+            if !(*entry_ptr).next_entry.is_null() {
+                (*(*entry_ptr).next_entry).prev_entry = entry_ptr;
+            }
+            (*descriptor).first_entry = entry_ptr;
+            notify(descriptor, entry_ptr, JIT_REGISTER_FN);
+        }
+
+        Self { entry, _symbol_file: symbol_file }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _lock = DESCRIPTOR_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry = &mut *self.entry;
+            if entry.prev_entry.is_null() {
+                (*descriptor).first_entry = entry.next_entry;
+            } else {
+                (*entry.prev_entry).next_entry = entry.next_entry;
+            }
+            if !entry.next_entry.is_null() {
+                (*entry.next_entry).prev_entry = entry.prev_entry;
+            }
+            notify(descriptor, entry, JIT_UNREGISTER_FN);
+        }
+    }
+}
+
+/// Tell an attached debugger that `entry` was added or removed
+///
+/// # Safety
+///
+/// The caller holds `DESCRIPTOR_LOCK`.
+unsafe fn notify(descriptor: *mut JitDescriptor, entry: *mut JitCodeEntry, action: u32) {
+    (*descriptor).relevant_entry = entry;
+    (*descriptor).action_flag = action;
+    __jit_debug_register_code();
+    (*descriptor).action_flag = JIT_NOACTION;
+    (*descriptor).relevant_entry = ptr::null_mut();
+}
+
+/// An ELF file describing code already in memory: `text` and `data` are
+/// copies of the mapped sections at their real addresses, and each symbol
+/// inside them becomes a function or data object.
+pub(super) fn symbol_file(
+    text: &[u8],
+    text_address: u64,
+    data: &[u8],
+    data_address: u64,
+    symbols: &HashMap<Symbol, u64>,
+) -> Vec<u8> {
+    let labels = sized_labels(
+        symbols.iter().map(|(&name, &address)| (name, address)),
+        text_address..text_address + text.len() as u64,
+        data_address..data_address + data.len() as u64,
+    );
+
+    let mut names = StringTable::new();
+    let mut symtab = vec![0u8; Elf64Symbol::SIZE];
+    for label in &labels {
+        symtab.extend_from_slice(&elf::record_bytes(&Elf64Symbol {
+            st_name: names.add(&label.name),
+            st_info: STB_GLOBAL << 4 | if label.in_text { STT_FUNC } else { STT_OBJECT },
+            st_other: 0,
+            st_shndx: if label.in_text { TEXT_INDEX } else { DATA_INDEX },
+            st_value: label.address,
+            st_size: label.size,
+        }));
+    }
+
+    let sections = [
+        Section { sh_type: SHT_PROGBITS, sh_flags: SHF_ALLOC | SHF_EXECINSTR, sh_addr: text_address, sh_addralign: 16, ..Section::new(".text", text) },
+        Section { sh_type: SHT_PROGBITS, sh_flags: SHF_ALLOC | SHF_WRITE, sh_addr: data_address, sh_addralign: 4, ..Section::new(".data", data) },
+        Section { sh_type: SHT_SYMTAB, sh_link: STRTAB_INDEX, sh_info: 1, sh_addralign: 8, sh_entsize: Elf64Symbol::SIZE as u64, ..Section::new(".symtab", &symtab) },
+        Section { sh_type: SHT_STRTAB, ..Section::new(".strtab", &names.bytes) },
+    ];
+    relocatable::write_sections(ET_EXEC, &HeaderOptions::default(), &sections)
+}
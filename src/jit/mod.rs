@@ -0,0 +1,225 @@
+//! Assemble straight into executable memory and call the result.
+//!
+//! Only available on unix targets with the `jit` feature enabled. Compiled
+//! code is registered with gdb and lldb through the GDB JIT interface, so
+//! breakpoints on its labels and backtraces through it work as for code
+//! loaded from a file.
+//!
+//! ```no_run
+//! use nasembler::jit::Jit;
+//!
+//! let jit = Jit::compile("section .text\nanswer:\n    mov rax, 42\n    ret\n").unwrap();
+//! let answer: extern "C" fn() -> u64 = unsafe { jit.function("answer").unwrap() };
+//! assert_eq!(answer(), 42);
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ptr;
+
+use crate::assembler::Assembler;
+use crate::elf::ElfGenerator;
+use crate::error::{AssemblerError, ElfError};
+use crate::parser::ast::Program;
+use crate::symbol::Symbol;
+
+mod gdb;
+
+/// Failure while assembling into executable memory
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum JitError {
+    /// The source failed to assemble
+    Assembly(AssemblerError),
+    /// Laying out the sections failed
+    Layout(ElfError),
+    /// Mapping memory for the code failed
+    Map(io::Error),
+    /// Making the mapped code executable failed
+    Protect(io::Error),
+}
+
+impl fmt::Display for JitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JitError::Assembly(_) => write!(f, "Assembly failed"),
+            JitError::Layout(_) => write!(f, "Section layout failed"),
+            JitError::Map(_) => write!(f, "Failed to map JIT memory"),
+            JitError::Protect(_) => write!(f, "Failed to make JIT memory executable"),
+        }
+    }
+}
+
+impl std::error::Error for JitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JitError::Assembly(source) => Some(source),
+            JitError::Layout(source) => Some(source),
+            JitError::Map(source) | JitError::Protect(source) => Some(source),
+        }
+    }
+}
+
+impl From<AssemblerError> for JitError {
+    fn from(error: AssemblerError) -> Self {
+        JitError::Assembly(error)
+    }
+}
+
+impl From<ElfError> for JitError {
+    fn from(error: ElfError) -> Self {
+        JitError::Layout(error)
+    }
+}
+
+/// Anonymous page-aligned memory mapping
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    /// Map at least `size` bytes of read/write memory
+    fn new(size: usize) -> Result<Self, JitError> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = size.max(1).div_ceil(page_size) * page_size;
+
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(JitError::Map(io::Error::last_os_error()));
+        }
+
+        Ok(Self { ptr: ptr as *mut u8, len })
+    }
+
+    fn address(&self) -> u64 {
+        self.ptr as u64
+    }
+
+    fn copy_from(&mut self, bytes: &[u8]) {
+        debug_assert!(bytes.len() <= self.len);
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr, bytes.len()) };
+    }
+
+    /// Flip the mapping from writable to executable (W^X)
+    fn make_executable(&mut self) -> Result<(), JitError> {
+        let result = unsafe {
+            libc::mprotect(self.ptr as *mut libc::c_void, self.len, libc::PROT_READ | libc::PROT_EXEC)
+        };
+
+        if result != 0 {
+            return Err(JitError::Protect(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// Assembled code living in executable memory
+///
+/// The mappings are released when the `Jit` is dropped, so function pointers
+/// obtained from it must not outlive it.
+pub struct Jit {
+    // Withdrawn from debuggers before the code is unmapped
+    _debug: gdb::Registration,
+    text: Mapping,
+    data: Mapping,
+    symbols: HashMap<Symbol, u64>,
+    entry: u64,
+}
+
+impl Jit {
+    /// Assemble `source` into executable memory
+    pub fn compile(source: &str) -> Result<Self, JitError> {
+        let program = Assembler::new().assemble_program(source)?;
+        Self::from_program(program)
+    }
+
+    /// Load an already parsed or built program into executable memory
+    pub fn from_program(program: Program) -> Result<Self, JitError> {
+        // The first layout only measures the sections; encoding sizes don't
+        // depend on the addresses, so the second layout fits the same mappings
+        let mut sizing = ElfGenerator::new(program).with_verbose(false);
+        sizing.build_sections()?;
+
+        let mut text = Mapping::new(sizing.text_section().len())?;
+        let mut data = Mapping::new(sizing.data_section().len())?;
+
+        // Lay out again at the real addresses so relocations resolve in place
+        let mut generator = ElfGenerator::new(sizing.into_program())
+            .with_verbose(false)
+            .with_addresses(text.address(), data.address());
+        generator.build_sections()?;
+
+        text.copy_from(generator.text_section());
+        data.copy_from(generator.data_section());
+        text.make_executable()?;
+
+        let debug = gdb::Registration::new(gdb::symbol_file(
+            generator.text_section(),
+            text.address(),
+            generator.data_section(),
+            data.address(),
+            generator.symbols(),
+        ));
+
+        Ok(Self {
+            _debug: debug,
+            text,
+            data,
+            symbols: generator.symbols().clone(),
+            entry: generator.entry_point(),
+        })
+    }
+
+    /// Address of a label inside the mapped code or data
+    pub fn symbol(&self, name: &str) -> Option<*const u8> {
+        self.symbols.get(&Symbol::lookup(name)?)
+            .filter(|&&address| self.contains(address))
+            .map(|&address| address as *const u8)
+    }
+
+    /// Address of the entry symbol (`_start`), if the program defines one
+    pub fn entry(&self) -> Option<*const u8> {
+        Some(self.entry)
+            .filter(|&address| self.contains(address))
+            .map(|address| address as *const u8)
+    }
+
+    /// Get a label as a callable function pointer, e.g. `extern "C" fn() -> u64`
+    ///
+    /// # Safety
+    ///
+    /// `F` must be a function pointer type whose signature and calling
+    /// convention match the assembled code at `name`, and it must not be
+    /// called after this `Jit` is dropped.
+    pub unsafe fn function<F: Copy>(&self, name: &str) -> Option<F> {
+        assert_eq!(mem::size_of::<F>(), mem::size_of::<*const u8>(), "F must be a function pointer type");
+        let address = self.symbol(name)?;
+        Some(mem::transmute_copy(&address))
+    }
+
+    fn contains(&self, address: u64) -> bool {
+        let in_text = address >= self.text.address() && address < self.text.address() + self.text.len as u64;
+        let in_data = address >= self.data.address() && address < self.data.address() + self.data.len as u64;
+        in_text || in_data
+    }
+}
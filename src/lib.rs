@@ -0,0 +1,90 @@
+//! nasembler - A modern x86-64 assembler
+//!
+//! The library exposes the same pipeline the command-line tool uses:
+//! tokenizer → parser → encoder → ELF generator. Most users only need
+//! the [`Assembler`] builder:
+//!
+//! ```no_run
+//! use nasembler::{Assembler, Format};
+//!
+//! let mut assembler = Assembler::new()
+//!     .format(Format::Elf)
+//!     .entry("_start");
+//! assembler.assemble_file("hello.asm", "hello").unwrap();
+//! ```
+//!
+//! Nothing on the [`Assembler::assemble`] path touches the filesystem or
+//! spawns processes, apart from reading the files a source names with
+//! `%include`, so the library also builds for `wasm32-unknown-unknown`.
+//!
+//! # Stability
+//!
+//! The crate follows semver (while at 0.x, a minor bump is a breaking
+//! release). The public API is what [`prelude`] re-exports plus the modules
+//! it comes from, [`build`] for Cargo build scripts, and the modules behind
+//! optional features:
+//!
+//! - enums marked `#[non_exhaustive]` (statements, operands, error kinds,
+//!   output formats, targets, token types) may gain variants in any release, so match
+//!   them with a wildcard arm
+//! - any other change to the AST types is a breaking release and bumps
+//!   [`parser::ast::AST_VERSION`], which serialized ASTs can be checked against
+//! - anything `pub(crate)` is internal and may change at any time, as are the
+//!   modules hidden from these docs, which are public only for the
+//!   command-line tool and the benchmarks
+
+#[doc(hidden)]
+pub mod tokenizer;
+pub mod parser;
+#[doc(hidden)]
+pub mod elf;
+pub(crate) mod binary;
+pub(crate) mod relocatable;
+pub mod object;
+#[doc(hidden)]
+pub mod encoder;
+pub mod error;
+pub mod symbol;
+pub mod render;
+pub mod assembler;
+pub mod target;
+#[doc(hidden)]
+pub mod conformance;
+#[doc(hidden)]
+pub mod link;
+#[doc(hidden)]
+pub mod addr2line;
+pub mod build;
+#[doc(hidden)]
+pub mod syscalls;
+pub mod size;
+pub mod source_map;
+#[doc(hidden)]
+pub mod corpus;
+#[doc(hidden)]
+pub mod emulator;
+#[doc(hidden)]
+pub mod debugger;
+mod dwarf;
+mod shellcode;
+mod fuzz;
+pub mod prelude;
+#[cfg(all(unix, feature = "jit"))]
+pub mod jit;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "trace"))]
+pub mod trace;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "sandbox"))]
+pub mod sandbox;
+#[cfg(all(target_os = "linux", target_arch = "x86_64", any(feature = "trace", feature = "sandbox")))]
+mod ptrace;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "python")]
+pub mod python;
+
+pub use assembler::{Assembler, Format};
+pub use target::Target;
+pub use error::AssemblerError;
+pub use fuzz::{fuzz_assemble, fuzz_assemble_any, fuzz_assemble_as, FUZZ_FORMATS, FUZZ_MAX_SECTION_SIZE};
@@ -0,0 +1,185 @@
+//! Static libraries.
+//!
+//! An archive bundles relocatable objects in the `ar` format used by GNU and
+//! BSD toolchains, so C and Rust builds can link assembly routines like any
+//! other `.a` library. It starts with a `/` symbol index mapping each global
+//! symbol to the object defining it, which is what lets a linker pick out
+//! only the objects a program needs. Names longer than 15 bytes go in a `//`
+//! name table. Timestamps and owners are zero, so the same objects always make
+//! the same archive.
+
+use super::builtin::Object;
+use super::LinkError;
+
+const MAGIC: &[u8] = b"!<arch>\n";
+const HEADER_SIZE: usize = 60;
+/// Longest member name stored in the header itself, leaving room for the `/` terminator
+const MAX_SHORT_NAME: usize = 15;
+
+struct Member {
+    name: String,
+    contents: Vec<u8>,
+    symbols: Vec<String>,
+}
+
+/// Builds a static library from relocatable objects
+///
+/// ```no_run
+/// use nasembler::link::Archive;
+///
+/// let mut archive = Archive::new();
+/// archive.add("strlen.o", std::fs::read("strlen.o").unwrap()).unwrap();
+/// archive.add("memcpy.o", std::fs::read("memcpy.o").unwrap()).unwrap();
+/// std::fs::write("libutil.a", archive.finish()).unwrap();
+/// ```
+#[derive(Default)]
+pub struct Archive {
+    members: Vec<Member>,
+}
+
+impl Archive {
+    /// An archive with no members
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the object `contents` as member `name`, indexing the global symbols it defines
+    pub fn add(&mut self, name: &str, contents: Vec<u8>) -> Result<(), LinkError> {
+        let symbols = Object::parse(name, &contents)?.defined_globals().map(str::to_string).collect();
+        self.members.push(Member { name: name.to_string(), contents, symbols });
+        Ok(())
+    }
+
+    /// Number of members added so far
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Whether no members have been added
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// The archive's bytes
+    pub fn finish(&self) -> Vec<u8> {
+        // Long names are referred to by their offset in the name table
+        let mut long_names = Vec::new();
+        let header_names: Vec<String> = self.members.iter()
+            .map(|member| {
+                if member.name.len() <= MAX_SHORT_NAME && !member.name.contains('/') {
+                    format!("{}/", member.name)
+                } else {
+                    let offset = long_names.len();
+                    long_names.extend_from_slice(member.name.as_bytes());
+                    long_names.extend_from_slice(b"/\n");
+                    format!("/{}", offset)
+                }
+            })
+            .collect();
+
+        let symbol_count: usize = self.members.iter().map(|member| member.symbols.len()).sum();
+        let names_size: usize = self.members.iter()
+            .flat_map(|member| &member.symbols)
+            .map(|symbol| symbol.len() + 1)
+            .sum();
+        let index_size = 4 + 4 * symbol_count + names_size;
+
+        // Members follow the index and the name table
+        let mut offset = MAGIC.len() + HEADER_SIZE + padded(index_size);
+        if !long_names.is_empty() {
+            offset += HEADER_SIZE + padded(long_names.len());
+        }
+        let mut member_offsets = Vec::with_capacity(self.members.len());
+        for member in &self.members {
+            member_offsets.push(offset);
+            offset += HEADER_SIZE + padded(member.contents.len());
+        }
+
+        let mut index = Vec::with_capacity(index_size);
+        index.extend_from_slice(&(symbol_count as u32).to_be_bytes());
+        for (member, &member_offset) in self.members.iter().zip(&member_offsets) {
+            for _ in &member.symbols {
+                index.extend_from_slice(&(member_offset as u32).to_be_bytes());
+            }
+        }
+        for symbol in self.members.iter().flat_map(|member| &member.symbols) {
+            index.extend_from_slice(symbol.as_bytes());
+            index.push(0);
+        }
+
+        let mut bytes = Vec::with_capacity(offset);
+        bytes.extend_from_slice(MAGIC);
+        write_member(&mut bytes, "/", &index);
+        if !long_names.is_empty() {
+            write_member(&mut bytes, "//", &long_names);
+        }
+        for (member, name) in self.members.iter().zip(&header_names) {
+            write_member(&mut bytes, name, &member.contents);
+        }
+        bytes
+    }
+}
+
+/// Size of a member's contents once padded to an even length
+fn padded(size: usize) -> usize {
+    size + size % 2
+}
+
+/// Append a member header and its contents
+fn write_member(bytes: &mut Vec<u8>, name: &str, contents: &[u8]) {
+    // Fields are space-padded ASCII: name, date, owner, group, mode, size
+    let header = format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 644, contents.len());
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.extend_from_slice(contents);
+    if contents.len() % 2 == 1 {
+        bytes.push(b'\n');
+    }
+}
+
+/// Whether `bytes` are an archive rather than a single object
+pub(super) fn is_archive(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// The objects in archive `name`, each named like `libutil.a(strlen.o)`
+pub(super) fn read_members<'a>(name: &str, bytes: &'a [u8]) -> Result<Vec<(String, &'a [u8])>, LinkError> {
+    let invalid = |reason: &str| LinkError::InvalidObject { object: name.to_string(), reason: reason.to_string() };
+
+    let mut members = Vec::new();
+    let mut long_names: &[u8] = &[];
+    let mut offset = MAGIC.len();
+    while offset < bytes.len() {
+        let header = bytes.get(offset..offset + HEADER_SIZE).ok_or_else(|| invalid("truncated member header"))?;
+        if &header[58..] != b"`\n" {
+            return Err(invalid("malformed member header"));
+        }
+        let size = std::str::from_utf8(&header[48..58]).ok()
+            .and_then(|size| size.trim_end().parse::<usize>().ok())
+            .ok_or_else(|| invalid("malformed member size"))?;
+        let start = offset + HEADER_SIZE;
+        let contents = start.checked_add(size)
+            .and_then(|end| bytes.get(start..end))
+            .ok_or_else(|| invalid("member contents lie outside the file"))?;
+        offset = start + padded(size);
+
+        let field = String::from_utf8_lossy(&header[..16]);
+        let member = match field.trim_end() {
+            // The symbol index is rebuilt from the members themselves
+            "/" | "/SYM64/" => continue,
+            "//" => {
+                long_names = contents;
+                continue;
+            }
+            long if long.len() > 1 && long.starts_with('/') => {
+                let name = long[1..].parse::<usize>().ok()
+                    .and_then(|start| long_names.get(start..))
+                    .and_then(|tail| tail.split(|&byte| byte == b'\n').next())
+                    .ok_or_else(|| invalid("member name lies outside the name table"))?;
+                String::from_utf8_lossy(name.strip_suffix(b"/").unwrap_or(name)).into_owned()
+            }
+            short => short.strip_suffix('/').unwrap_or(short).to_string(),
+        };
+        members.push((format!("{}({})", name, member), contents));
+    }
+    Ok(members)
+}
@@ -0,0 +1,369 @@
+//! The built-in static linker.
+//!
+//! Combines relocatable objects written with `-f obj` into one static x86-64
+//! executable, without binutils. Every allocated section of every object is
+//! placed in the text segment (executable sections) or the data segment (the
+//! rest), in the order the objects are given. Global symbols are resolved
+//! across objects, and every relocation is applied. There are no shared
+//! libraries, so a symbol no object defines is an error. Static libraries are
+//! searched the way other linkers search them: an archive member is linked in
+//! only when it defines a symbol that is still undefined, which may in turn
+//! pull in further members.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::elf::{
+    read_u16, read_u32, read_u64, round_up, Executable, HeaderOptions, DEFAULT_MAX_SECTION_SIZE, ELFCLASS64, EM_X86_64,
+    PAGE_SIZE,
+};
+use crate::relocatable::{
+    ET_REL, SHF_ALLOC, SHF_EXECINSTR, SHN_ABS, SHN_UNDEF, SHT_RELA, SHT_SYMTAB, STB_LOCAL,
+    R_X86_64_16, R_X86_64_32, R_X86_64_64, R_X86_64_PC16, R_X86_64_PC32, R_X86_64_PC8, R_X86_64_PLT32,
+};
+use super::{archive, LinkError};
+
+const SHT_NOBITS: u32 = 8;
+const R_X86_64_32S: u32 = 11;
+
+/// Address the text segment is loaded at, as for single-file executables
+const TEXT_ADDRESS: u64 = 0x400000;
+
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+/// A section header, with the fields the linker uses
+struct SectionHeader {
+    sh_type: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+}
+
+/// A symbol table entry
+struct ObjectSymbol {
+    name: String,
+    binding: u8,
+    shndx: u16,
+    value: u64,
+}
+
+/// A relocatable object, read from its bytes
+pub(super) struct Object<'a> {
+    name: &'a str,
+    bytes: &'a [u8],
+    sections: Vec<SectionHeader>,
+    symbols: Vec<ObjectSymbol>,
+}
+
+impl<'a> Object<'a> {
+    pub(super) fn parse(name: &'a str, bytes: &'a [u8]) -> Result<Self, LinkError> {
+        let invalid = |reason: &str| LinkError::InvalidObject { object: name.to_string(), reason: reason.to_string() };
+
+        if bytes.get(..4) != Some(b"\x7FELF".as_slice()) {
+            return Err(invalid("not an ELF file"));
+        }
+        if bytes.get(4) != Some(&ELFCLASS64) || read_u16(bytes, 18) != Some(EM_X86_64) {
+            return Err(invalid("not an x86-64 ELF64 file"));
+        }
+        if read_u16(bytes, 16) != Some(ET_REL) {
+            return Err(invalid("not a relocatable object (assemble it with -f obj)"));
+        }
+
+        let truncated = || invalid("truncated or malformed headers");
+        let shoff = read_u64(bytes, 40).ok_or_else(truncated)? as usize;
+        let shnum = read_u16(bytes, 60).ok_or_else(truncated)? as usize;
+        let sections = (0..shnum)
+            .map(|index| {
+                let header = shoff.checked_add(index * SECTION_HEADER_SIZE)?;
+                Some(SectionHeader {
+                    sh_type: read_u32(bytes, header + 4)?,
+                    flags: read_u64(bytes, header + 8)?,
+                    offset: read_u64(bytes, header + 24)?,
+                    size: read_u64(bytes, header + 32)?,
+                    link: read_u32(bytes, header + 40)?,
+                    info: read_u32(bytes, header + 44)?,
+                    align: read_u64(bytes, header + 48)?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(truncated)?;
+
+        let mut object = Object { name, bytes, sections, symbols: Vec::new() };
+        if let Some(symtab) = object.sections.iter().position(|section| section.sh_type == SHT_SYMTAB) {
+            object.symbols = object.read_symbols(symtab).ok_or_else(truncated)?;
+        }
+        Ok(object)
+    }
+
+    /// Global symbols the object defines, which other objects may refer to
+    pub(super) fn defined_globals(&self) -> impl Iterator<Item = &str> {
+        self.symbols.iter()
+            .filter(|symbol| symbol.binding != STB_LOCAL && symbol.shndx != SHN_UNDEF && !symbol.name.is_empty())
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    /// Global symbols the object refers to without defining
+    fn undefined_globals(&self) -> impl Iterator<Item = &str> {
+        self.symbols.iter()
+            .filter(|symbol| symbol.binding != STB_LOCAL && symbol.shndx == SHN_UNDEF && !symbol.name.is_empty())
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    /// Contents of a section that occupies space in the file
+    fn contents(&self, section: &SectionHeader) -> Option<&'a [u8]> {
+        let start = section.offset as usize;
+        self.bytes.get(start..start.checked_add(section.size as usize)?)
+    }
+
+    /// The NUL-terminated string at `offset` in string table `table`
+    fn string(&self, table: u32, offset: u32) -> Option<String> {
+        let strings = self.contents(self.sections.get(table as usize)?)?;
+        let tail = strings.get(offset as usize..)?;
+        let end = tail.iter().position(|&byte| byte == 0)?;
+        Some(String::from_utf8_lossy(&tail[..end]).into_owned())
+    }
+
+    fn read_symbols(&self, symtab: usize) -> Option<Vec<ObjectSymbol>> {
+        let section = &self.sections[symtab];
+        let table = self.contents(section)?;
+        table.chunks_exact(SYMBOL_SIZE)
+            .map(|entry| Some(ObjectSymbol {
+                name: self.string(section.link, read_u32(entry, 0)?)?,
+                binding: entry[4] >> 4,
+                shndx: read_u16(entry, 6)?,
+                value: read_u64(entry, 8)?,
+            }))
+            .collect()
+    }
+}
+
+/// Where one input section ended up
+#[derive(Clone, Copy)]
+struct Placement {
+    in_text: bool,
+    /// Offset within the output segment
+    offset: u64,
+}
+
+/// Links relocatable objects into a static executable
+///
+/// ```no_run
+/// use nasembler::link::StaticLinker;
+///
+/// let main = std::fs::read("main.o").unwrap();
+/// let util = std::fs::read("util.o").unwrap();
+/// let executable = StaticLinker::new()
+///     .link(&[("main.o", main.as_slice()), ("util.o", util.as_slice())])
+///     .unwrap();
+/// std::fs::write("program", executable).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct StaticLinker {
+    entry: String,
+}
+
+impl Default for StaticLinker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StaticLinker {
+    /// A linker starting programs at `_start`
+    pub fn new() -> Self {
+        Self { entry: "_start".to_string() }
+    }
+
+    /// Set the symbol whose address becomes the entry point
+    pub fn with_entry(mut self, symbol: &str) -> Self {
+        self.entry = symbol.to_string();
+        self
+    }
+
+    /// Link `(name, contents)` pairs of objects and archives into the bytes of an executable
+    pub fn link(&self, inputs: &[(&str, &[u8])]) -> Result<Vec<u8>, LinkError> {
+        let mut members = Vec::new();
+        for (name, bytes) in inputs {
+            if archive::is_archive(bytes) {
+                members.extend(archive::read_members(name, bytes)?);
+            }
+        }
+        let mut objects = inputs.iter()
+            .filter(|(_, bytes)| !archive::is_archive(bytes))
+            .map(|(name, bytes)| Object::parse(name, bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut library = members.iter()
+            .map(|(name, bytes)| Object::parse(name, bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Pull in archive members while one defines a symbol still undefined
+        loop {
+            let defined: HashSet<&str> = objects.iter().flat_map(Object::defined_globals).collect();
+            let undefined: HashSet<&str> = objects.iter()
+                .flat_map(Object::undefined_globals)
+                .chain([self.entry.as_str()])
+                .filter(|symbol| !defined.contains(symbol))
+                .collect();
+            let Some(position) = library.iter()
+                .position(|member| member.defined_globals().any(|symbol| undefined.contains(symbol)))
+            else {
+                break;
+            };
+            objects.push(library.remove(position));
+        }
+
+        // Place every allocated section, keeping each one's alignment
+        let mut text = Vec::new();
+        let mut data = Vec::new();
+        let mut placements: Vec<HashMap<usize, Placement>> = Vec::new();
+        for object in &objects {
+            let mut placed = HashMap::new();
+            for (index, section) in object.sections.iter().enumerate() {
+                if section.flags & SHF_ALLOC == 0 {
+                    continue;
+                }
+                let in_text = section.flags & SHF_EXECINSTR != 0;
+                let segment = if in_text { &mut text } else { &mut data };
+                if (segment.len() as u64).saturating_add(section.size) > DEFAULT_MAX_SECTION_SIZE {
+                    return Err(LinkError::InvalidObject {
+                        object: object.name.to_string(),
+                        reason: format!("sections grow past the limit of {} bytes", DEFAULT_MAX_SECTION_SIZE),
+                    });
+                }
+                let offset = round_up(segment.len() as u64, section.align.max(1));
+                segment.resize(offset as usize, 0);
+                if section.sh_type == SHT_NOBITS {
+                    segment.resize(segment.len() + section.size as usize, 0);
+                } else {
+                    let contents = object.contents(section).ok_or_else(|| LinkError::InvalidObject {
+                        object: object.name.to_string(),
+                        reason: "section contents lie outside the file".to_string(),
+                    })?;
+                    segment.extend_from_slice(contents);
+                }
+                placed.insert(index, Placement { in_text, offset });
+            }
+            placements.push(placed);
+        }
+
+        let data_address = round_up(TEXT_ADDRESS + text.len() as u64, PAGE_SIZE);
+        let address = |placement: Placement| {
+            placement.offset + if placement.in_text { TEXT_ADDRESS } else { data_address }
+        };
+
+        // Global symbols, by the object defining them
+        let mut globals: HashMap<&str, (u64, &str)> = HashMap::new();
+        for (object, placed) in objects.iter().zip(&placements) {
+            for symbol in &object.symbols {
+                if symbol.binding == STB_LOCAL || symbol.shndx == SHN_UNDEF {
+                    continue;
+                }
+                let value = match symbol.shndx {
+                    SHN_ABS => symbol.value,
+                    shndx => match placed.get(&(shndx as usize)) {
+                        Some(&placement) => address(placement) + symbol.value,
+                        None => continue,
+                    },
+                };
+                if let Some((_, first)) = globals.insert(&symbol.name, (value, object.name)) {
+                    return Err(LinkError::DuplicateSymbol {
+                        symbol: symbol.name.clone(),
+                        first: first.to_string(),
+                        second: object.name.to_string(),
+                    });
+                }
+            }
+        }
+
+        for (object, placed) in objects.iter().zip(&placements) {
+            let symbol_value = |index: usize| -> Result<u64, LinkError> {
+                let symbol = object.symbols.get(index).ok_or_else(|| LinkError::InvalidObject {
+                    object: object.name.to_string(),
+                    reason: format!("relocation refers to symbol {}, which doesn't exist", index),
+                })?;
+                match symbol.shndx {
+                    SHN_UNDEF => globals.get(symbol.name.as_str()).map(|&(value, _)| value).ok_or_else(|| {
+                        LinkError::UndefinedSymbol { symbol: symbol.name.clone(), object: object.name.to_string() }
+                    }),
+                    SHN_ABS => Ok(symbol.value),
+                    shndx => placed.get(&(shndx as usize))
+                        .map(|&placement| address(placement) + symbol.value)
+                        .ok_or_else(|| LinkError::InvalidObject {
+                            object: object.name.to_string(),
+                            reason: format!("symbol '{}' is in a section that isn't loaded", symbol.name),
+                        }),
+                }
+            };
+
+            for section in object.sections.iter().filter(|section| section.sh_type == SHT_RELA) {
+                let Some(&target) = placed.get(&(section.info as usize)) else { continue };
+                let entries = object.contents(section).ok_or_else(|| LinkError::InvalidObject {
+                    object: object.name.to_string(),
+                    reason: "relocations lie outside the file".to_string(),
+                })?;
+                for entry in entries.chunks_exact(RELA_SIZE) {
+                    // Entries are exactly RELA_SIZE bytes, so the reads can't fail
+                    let field = |offset| read_u64(entry, offset).unwrap_or_default();
+                    let (r_offset, r_info, r_addend) = (field(0), field(8), field(16));
+                    let kind = r_info as u32;
+                    let symbol = (r_info >> 32) as usize;
+                    let name = object.symbols.get(symbol).map(|symbol| symbol.name.clone()).unwrap_or_default();
+                    let value = symbol_value(symbol)?.wrapping_add(r_addend);
+                    let place = address(target) + r_offset;
+
+                    let out_of_range = || LinkError::RelocationOutOfRange { symbol: name.clone(), object: object.name.to_string() };
+                    let bytes = match kind {
+                        R_X86_64_64 => value.to_le_bytes().to_vec(),
+                        R_X86_64_32 => u32::try_from(value).map_err(|_| out_of_range())?.to_le_bytes().to_vec(),
+                        R_X86_64_32S => i32::try_from(value as i64).map_err(|_| out_of_range())?.to_le_bytes().to_vec(),
+                        R_X86_64_16 => u16::try_from(value).map_err(|_| out_of_range())?.to_le_bytes().to_vec(),
+                        R_X86_64_PC32 | R_X86_64_PLT32 => {
+                            let distance = value.wrapping_sub(place) as i64;
+                            i32::try_from(distance).map_err(|_| out_of_range())?.to_le_bytes().to_vec()
+                        },
+                        R_X86_64_PC16 => {
+                            let distance = value.wrapping_sub(place) as i64;
+                            i16::try_from(distance).map_err(|_| out_of_range())?.to_le_bytes().to_vec()
+                        },
+                        R_X86_64_PC8 => {
+                            let distance = value.wrapping_sub(place) as i64;
+                            i8::try_from(distance).map_err(|_| out_of_range())?.to_le_bytes().to_vec()
+                        },
+                        _ => return Err(LinkError::UnsupportedRelocation { kind, object: object.name.to_string() }),
+                    };
+
+                    let segment = if target.in_text { &mut text } else { &mut data };
+                    let patched = usize::try_from(target.offset + r_offset).ok()
+                        .and_then(|start| segment.get_mut(start..start.checked_add(bytes.len())?))
+                        .ok_or_else(|| LinkError::InvalidObject {
+                            object: object.name.to_string(),
+                            reason: format!("relocation for '{}' lies outside its section", name),
+                        })?;
+                    patched.copy_from_slice(&bytes);
+                }
+            }
+        }
+
+        let entry = globals.get(self.entry.as_str())
+            .map(|&(value, _)| value)
+            .ok_or_else(|| LinkError::MissingEntry(self.entry.clone()))?;
+
+        let executable = Executable {
+            elf32: false,
+            entry,
+            text: &text,
+            text_address: TEXT_ADDRESS,
+            data: &data,
+            data_address,
+            header: HeaderOptions::default(),
+            tls: None,
+            comment: None,
+            debug: None,
+        };
+        Ok(executable.image())
+    }
+}
@@ -0,0 +1,187 @@
+//! Linking relocatable objects into executables.
+//!
+//! [`StaticLinker`] links objects and static libraries written by nasembler itself, with no other
+//! tools. [`Archive`] bundles objects into a static library for other
+//! toolchains to link. [`Linker`] runs the system linker instead, which is needed to link
+//! against C libraries. The system linker is either a compiler driver such as `cc`, which finds the C
+//! library and the dynamic loader on its own, or `ld` itself. Programs that
+//! define `_start` are linked without the C runtime's startup files; programs
+//! that define `main` keep them, so the C runtime calls `main`.
+//!
+//! ```no_run
+//! use nasembler::link::Linker;
+//!
+//! Linker::new("cc")
+//!     .with_library("c")
+//!     .link(&["hello.o".into()], "hello".as_ref())
+//!     .unwrap();
+//! ```
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+mod archive;
+mod builtin;
+
+pub use archive::Archive;
+pub use builtin::StaticLinker;
+
+/// Linker used when none is given
+pub const DEFAULT_LINKER: &str = "cc";
+
+/// Dynamic loader `ld` is pointed at when libraries are linked in; its own
+/// default (`/lib/ld64.so.1`) doesn't exist on most distributions
+const DYNAMIC_LINKER: &str = "/lib64/ld-linux-x86-64.so.2";
+
+/// Failure while linking
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LinkError {
+    /// The system linker couldn't be started
+    Spawn { linker: String, source: io::Error },
+    /// The system linker ran and reported errors
+    Failed { linker: String, status: ExitStatus, stderr: String },
+    /// An input isn't a well-formed x86-64 relocatable object
+    InvalidObject { object: String, reason: String },
+    /// A symbol is referenced but no object defines it
+    UndefinedSymbol { symbol: String, object: String },
+    /// Two objects define the same global symbol
+    DuplicateSymbol { symbol: String, first: String, second: String },
+    /// A relocation type the built-in linker doesn't handle
+    UnsupportedRelocation { kind: u32, object: String },
+    /// A symbol's address doesn't fit the field referring to it
+    RelocationOutOfRange { symbol: String, object: String },
+    /// No object defines the entry symbol as a global
+    MissingEntry(String),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::Spawn { linker, .. } => write!(f, "Failed to run linker '{}'", linker),
+            LinkError::Failed { linker, status, stderr } => {
+                write!(f, "Linker '{}' failed ({})", linker, status)?;
+                if !stderr.trim().is_empty() { write!(f, ":\n{}", stderr.trim_end())?; }
+                Ok(())
+            },
+            LinkError::InvalidObject { object, reason } => write!(f, "Invalid object '{}': {}", object, reason),
+            LinkError::UndefinedSymbol { symbol, object } => {
+                write!(f, "Undefined symbol '{}', referenced from '{}'", symbol, object)
+            },
+            LinkError::DuplicateSymbol { symbol, first, second } => {
+                write!(f, "Symbol '{}' is defined in both '{}' and '{}'", symbol, first, second)
+            },
+            LinkError::UnsupportedRelocation { kind, object } => {
+                write!(f, "Relocation type {} in '{}' isn't supported by the built-in linker", kind, object)
+            },
+            LinkError::RelocationOutOfRange { symbol, object } => {
+                write!(f, "The address of '{}' doesn't fit the field referring to it in '{}'", symbol, object)
+            },
+            LinkError::MissingEntry(symbol) => {
+                write!(f, "Entry symbol '{}' isn't defined; declare it with 'global {}' in one of the objects", symbol, symbol)
+            },
+        }
+    }
+}
+
+impl std::error::Error for LinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LinkError::Spawn { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Builder for a system linker invocation
+#[derive(Debug, Clone)]
+pub struct Linker {
+    program: String,
+    libraries: Vec<String>,
+    library_paths: Vec<PathBuf>,
+    startup_files: bool,
+}
+
+impl Default for Linker {
+    fn default() -> Self {
+        Self::new(DEFAULT_LINKER)
+    }
+}
+
+impl Linker {
+    /// Link with `program`, a compiler driver (`cc`, `gcc`, `clang`) or `ld`
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.to_string(),
+            libraries: Vec::new(),
+            library_paths: Vec::new(),
+            startup_files: false,
+        }
+    }
+
+    /// Link against a library, as with `-l<name>`
+    pub fn with_library(mut self, name: &str) -> Self {
+        self.libraries.push(name.to_string());
+        self
+    }
+
+    /// Add a directory to search for libraries, as with `-L<dir>`
+    pub fn with_library_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.library_paths.push(path.into());
+        self
+    }
+
+    /// Set whether a compiler driver adds the C runtime's startup files,
+    /// which call `main` instead of starting at `_start`
+    pub fn with_startup_files(mut self, startup_files: bool) -> Self {
+        self.startup_files = startup_files;
+        self
+    }
+
+    /// Whether the linker is `ld` itself (`ld`, `ld.lld`, `x86_64-linux-gnu-ld`, ...)
+    /// rather than a compiler driver
+    pub fn is_ld(&self) -> bool {
+        let name = Path::new(&self.program).file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        name == "ld" || name.starts_with("ld.") || name.ends_with("-ld")
+    }
+
+    /// The command that links `objects` into `output`
+    pub fn command(&self, objects: &[PathBuf], output: &Path) -> Command {
+        let mut command = Command::new(&self.program);
+        command.arg("-o").arg(output).args(objects);
+        if self.is_ld() {
+            if !self.libraries.is_empty() {
+                command.args(["-dynamic-linker", DYNAMIC_LINKER]);
+            }
+        } else {
+            // The code uses absolute addresses, which a position-independent executable can't
+            command.arg("-no-pie");
+            if !self.startup_files {
+                command.arg("-nostartfiles");
+            }
+        }
+        for path in &self.library_paths {
+            command.arg("-L").arg(path);
+        }
+        for library in &self.libraries {
+            command.arg(format!("-l{}", library));
+        }
+        command
+    }
+
+    /// Link `objects` into the executable `output`
+    pub fn link(&self, objects: &[PathBuf], output: &Path) -> Result<(), LinkError> {
+        let result = self.command(objects, output).output()
+            .map_err(|source| LinkError::Spawn { linker: self.program.clone(), source })?;
+        if !result.status.success() {
+            return Err(LinkError::Failed {
+                linker: self.program.clone(),
+                status: result.status,
+                stderr: String::from_utf8_lossy(&result.stderr).into_owned(),
+            });
+        }
+        Ok(())
+    }
+}
@@ -1,30 +1,35 @@
-use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::time::Instant;
-use clap::{Parser as ClapParser, ArgGroup, ValueEnum};
+use clap::{Parser as ClapParser, ArgGroup, Subcommand};
 use colored::*;
 
-mod tokenizer;
-mod parser;
-mod elf;
-mod encoder;
-mod error;
-
-use tokenizer::Tokenizer;
-use parser::Parser;
-use parser::ast::Program;
-use elf::ElfGenerator;
-use error::{ErrorCollector, Error, ErrorType, ErrorDetail, ErrorSeverity};
+use nasembler::{Assembler, Format, Target};
+use nasembler::error::{ascii_decorations, error_chain, ErrorCollector};
+use nasembler::parser::ast::Program;
+use nasembler::elf::{self, Fill, DEFAULT_MAX_SECTION_SIZE, INT3};
+use nasembler::render::MessageFormat;
+use nasembler::conformance::{self, Conformance, Outcome};
+use nasembler::corpus;
+use nasembler::link::{Archive, Linker, StaticLinker, DEFAULT_LINKER};
+use nasembler::addr2line;
+use nasembler::emulator::{Emulator, DEFAULT_STEP_LIMIT};
+use nasembler::debugger::Debugger;
+use nasembler::parser::{insns, opcodes};
+use nasembler::parser::opcodes::OpcodeTable;
 
 /// nasembler - A modern x86-64 assembler
 #[derive(ClapParser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 #[clap(group(ArgGroup::new("output_mode").args(&["parse_only", "tokenize_only", "dump_tokens", "dump_ast"])))]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input file to assemble (required)
     #[arg(index = 1, required = true)]
-    file: String,
+    file: Option<String>,
     
     /// Output file for the assembled code
     #[arg(short, long)]
@@ -49,9 +54,35 @@ struct Args {
     /// Print verbose information during compilation
     #[arg(short, long)]
     verbose: bool,
-    /// Output format for the compiled binary [default: elf]
-    #[arg(short = 'f', long, value_enum, default_value_t = OutputFormat::Elf)]
-    format: OutputFormat,
+    /// Output format for the compiled binary [default: elf, or the target's format]
+    #[arg(short = 'f', long, value_enum)]
+    format: Option<Format>,
+    
+    /// What the program runs on; sets the code mode, load address and output format
+    #[arg(long, value_enum, value_name = "TARGET")]
+    target: Option<Target>,
+    
+    /// OS/ABI byte of ELF output: sysv (default), gnu, freebsd, netbsd, openbsd, solaris, standalone or a number
+    #[arg(long, value_name = "ABI", value_parser = parse_os_abi, default_value = "sysv")]
+    os_abi: u8,
+    
+    /// ABI version byte of ELF output
+    #[arg(long, value_name = "VERSION", default_value_t = 0)]
+    abi_version: u8,
+    
+    /// e_flags field of ELF output, in hexadecimal with or without 0x
+    #[arg(long, value_name = "FLAGS", value_parser = parse_flags, default_value = "0")]
+    elf_flags: u32,
+    
+    /// Record symbols and source line numbers in ELF executables, for
+    /// debuggers and `nasembler addr2line`
+    #[arg(short = 'g')]
+    debug_info: bool,
+    
+    /// Leave out the .comment section naming the assembler, for reproducible
+    /// or minimal ELF output
+    #[arg(long)]
+    no_comment: bool,
     
     /// Stop on first error instead of collecting all errors
     #[arg(short = 's', long)]
@@ -61,57 +92,394 @@ struct Args {
     #[arg(long)]
     silent: bool,
     
+    /// Treat data in .bss and instructions outside .text as errors
+    #[arg(long)]
+    strict_sections: bool,
+    
+    /// Require mnemonics, registers and directives to be lowercase
+    #[arg(long)]
+    strict_case: bool,
+    
+    /// Directory to search for %include files that aren't next to the
+    /// including file; may be repeated, searched in order
+    #[arg(short = 'I', long = "include", value_name = "DIR")]
+    include_paths: Vec<PathBuf>,
+    
+    /// Opcode table to check operands against, merged over the built-in one;
+    /// may be repeated, with later tables taking precedence. A table has to
+    /// pass `opcodes validate` to load
+    #[arg(short = 'p', long = "opcodes", value_name = "PATH")]
+    opcode_tables: Vec<PathBuf>,
+
+    /// What `align` pads .text with when it names no fill: nop, int3 or a
+    /// hexadecimal byte
+    #[arg(long, value_name = "FILL", value_parser = parse_fill, default_value = "nop")]
+    fill: Fill,
+    
+    /// What `align` pads other sections with when it names no fill: nop,
+    /// int3 or a hexadecimal byte
+    #[arg(long, value_name = "FILL", value_parser = parse_fill, default_value = "00")]
+    data_fill: Fill,
+    
+    /// Largest size in bytes a section may grow to
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_SECTION_SIZE)]
+    max_section_size: u64,
+    
+    /// Hexadecimal byte values no instruction may contain, e.g. 00,0a for shellcode
+    #[arg(long, value_name = "BYTES", value_delimiter = ',', value_parser = parse_byte)]
+    forbid_bytes: Vec<u8>,
+    
+    /// Print how many bytes of the output each section and label accounts for
+    #[arg(long)]
+    size_report: bool,
+    
+    /// Write a JSON map from each range of output bytes to the source line it came from
+    #[arg(long, value_name = "PATH")]
+    source_map: Option<PathBuf>,
+    
+    /// Source lines to show before and after each line with a diagnostic
+    #[arg(long, value_name = "LINES", default_value_t = 1)]
+    context: usize,
+    
+    /// How diagnostics are printed
+    #[arg(long, value_enum, default_value_t = MessageFormat::Pretty)]
+    message_format: MessageFormat,
+    
+    /// Draw diagnostics with plain ASCII instead of box-drawing characters
+    /// (the default when the locale isn't UTF-8)
+    #[arg(long)]
+    ascii_diagnostics: bool,
+    
+    /// Treat warnings as errors (-Werror)
+    #[arg(short = 'W', value_name = "KIND", value_parser = ["error"])]
+    warnings: Option<String>,
+    
+    /// Assemble an object and link it into an executable with the system linker
+    #[arg(long, conflicts_with = "format")]
+    link: bool,
+    
+    /// Linker used by --link: a compiler driver such as cc, or ld
+    #[arg(long, value_name = "PROGRAM", default_value = DEFAULT_LINKER, requires = "link")]
+    linker: String,
+    
+    /// Library to link against with --link (-lc links the C library)
+    #[arg(short = 'l', value_name = "LIB", requires = "link")]
+    libraries: Vec<String>,
+    
+    /// Directory the linker searches for -l libraries
+    #[arg(short = 'L', value_name = "DIR", requires = "link")]
+    library_paths: Vec<PathBuf>,
+    
     /// Execute the compiled binary after successful assembly
     #[arg(short = 'x', long)]
     execute: bool,
     
+    /// With -x, log each syscall the program makes, with its arguments and result
+    #[arg(long, requires = "execute")]
+    trace: bool,
+    
+    /// With -x, run the program without network access or the ability to start
+    /// other programs, and with limits on CPU time, memory and open files
+    #[arg(long, requires = "execute", conflicts_with = "trace")]
+    sandbox: bool,
+    
+    /// With -x, run the program in the built-in x86-64 emulator instead of on the CPU
+    #[arg(long, requires = "execute", conflicts_with_all = ["trace", "sandbox"])]
+    emulate: bool,
+    
     /// Make the output file executable (chmod +x)
     #[arg(short = 'e', long)]
     make_executable: bool,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
-enum OutputFormat {
-    /// Raw binary output
-    Bin,
-    /// Intel hex format
-    Hex,
-    /// ELF executable (default)
-    Elf,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Assemble each .asm file in a directory with nasembler and nasm and compare the output
+    Conformance {
+        /// Directory of test cases
+        dir: PathBuf,
+
+        /// Reference assembler to compare against [default: nasm, when installed]
+        #[arg(long, value_name = "PATH")]
+        nasm: Option<String>,
+    },
+    /// Link objects written with -f obj into a static executable, without a system linker
+    Link {
+        /// Objects to link, laid out in the order given, and static libraries to search
+        #[arg(required = true)]
+        objects: Vec<PathBuf>,
+
+        /// Executable to write
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Symbol the program starts at
+        #[arg(long, value_name = "SYMBOL", default_value = "_start")]
+        entry: String,
+    },
+    /// Bundle objects written with -f obj into a static library (.a) with a symbol index
+    Archive {
+        /// Objects to add, in the order given
+        #[arg(required = true)]
+        objects: Vec<PathBuf>,
+
+        /// Library to write
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Show the label and source line of addresses in a binary
+    Addr2line {
+        /// Executable or object to look in
+        binary: PathBuf,
+
+        /// Addresses, in hexadecimal with or without 0x
+        #[arg(required = true, value_parser = parse_address)]
+        addresses: Vec<u64>,
+    },
+    /// Run an ELF executable in the built-in x86-64 emulator and exit with its exit code
+    Emulate {
+        /// Executable to run, written with -f elf or -f elf32
+        binary: PathBuf,
+
+        /// Instructions to execute before stopping a program that doesn't exit
+        #[arg(long, value_name = "COUNT", default_value_t = DEFAULT_STEP_LIMIT)]
+        max_steps: u64,
+    },
+    /// Print an assembly line and its expected bytes for every instruction form the encoder supports
+    Corpus {
+        /// Only generate vectors for this code mode (16, 32 or 64)
+        #[arg(long, value_parser = parse_bits)]
+        bits: Option<u16>,
+
+        /// Write the corpus to this file instead of stdout
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+
+        /// Reassemble the vectors in a saved corpus and report those whose bytes changed
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["bits", "output"])]
+        check: Option<PathBuf>,
+    },
+    /// Step through an ELF executable in the emulator; assemble it with -g for source lines
+    Debug {
+        /// Executable to debug, written with -f elf or -f elf32
+        binary: PathBuf,
+
+        /// Label or hexadecimal address to stop at; may be repeated
+        #[arg(short, long = "break", value_name = "LOCATION")]
+        breakpoints: Vec<String>,
+    },
+    /// Work with opcode table files
+    Opcodes {
+        #[command(subcommand)]
+        command: OpcodesCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum OpcodesCommand {
+    /// Check an opcode table for malformed lines, duplicate forms and mnemonics the tokenizer doesn't know
+    Validate {
+        /// Opcode table to check
+        file: PathBuf,
+    },
+    /// Convert NASM's instruction database (x86/insns.dat) to an opcode table
+    ImportNasm {
+        /// insns.dat from the NASM sources
+        insns: PathBuf,
+
+        /// Write the table to this file instead of stdout
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+
+        /// Only keep mnemonics the tokenizer recognizes
+        #[arg(long)]
+        known_only: bool,
+
+        /// List each line that wasn't imported and why
+        #[arg(long)]
+        list_skipped: bool,
+    },
+}
+
+/// Parse a hexadecimal address, as binutils' addr2line does
+fn parse_address(text: &str) -> Result<u64, String> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u64::from_str_radix(digits, 16).map_err(|_| format!("'{}' isn't a hexadecimal address", text))
+}
+
+/// Parse an OS/ABI name or number for `--os-abi`
+fn parse_os_abi(text: &str) -> Result<u8, String> {
+    elf::os_abi(text).or_else(|| text.parse().ok())
+        .ok_or_else(|| format!("'{}' isn't an OS/ABI name or a number from 0 to 255", text))
+}
+
+/// Parse hexadecimal `e_flags` for `--elf-flags`, with or without 0x
+fn parse_flags(text: &str) -> Result<u32, String> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u32::from_str_radix(digits, 16).map_err(|_| format!("'{}' isn't a hexadecimal 32-bit value", text))
+}
+
+/// Parse a code mode for `corpus --bits`
+fn parse_bits(text: &str) -> Result<u16, String> {
+    text.parse().ok().filter(|bits| corpus::MODES.contains(bits))
+        .ok_or_else(|| format!("'{}' isn't a code mode; use 16, 32 or 64", text))
+}
+
+/// Parse a hexadecimal byte for `--forbid-bytes`, with or without `0x`
+fn parse_byte(text: &str) -> Result<u8, String> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u8::from_str_radix(digits, 16).map_err(|_| format!("'{}' isn't a hexadecimal byte such as 00 or 0a", text))
+}
+
+/// Parse an `align` fill for `--fill` and `--data-fill`
+fn parse_fill(text: &str) -> Result<Fill, String> {
+    match text.to_lowercase().as_str() {
+        "nop" => Ok(Fill::Nop),
+        "int3" => Ok(Fill::Byte(INT3)),
+        _ => parse_byte(text).map(Fill::Byte)
+            .map_err(|_| format!("'{}' isn't a fill; use nop, int3 or a hexadecimal byte such as cc", text)),
+    }
+}
+
+/// `println!` that draws decorations in plain ASCII when `ascii` is set
+macro_rules! print_line {
+    ($ascii:expr, $($arg:tt)*) => {
+        println!("{}", decorations($ascii, format!($($arg)*)))
+    };
+}
+
+/// `eprintln!` that draws decorations in plain ASCII when `ascii` is set
+macro_rules! eprint_line {
+    ($ascii:expr, $($arg:tt)*) => {
+        eprintln!("{}", decorations($ascii, format!($($arg)*)))
+    };
+}
+
+fn decorations(ascii: bool, text: String) -> String {
+    if ascii { ascii_decorations(&text) } else { text }
+}
+
+/// Print the collected diagnostics, if the message format shows any
+fn print_diagnostics(errors: &ErrorCollector, ascii: bool) {
+    let text = errors.display_errors();
+    if !text.is_empty() {
+        print_line!(ascii, "{}", text);
+    }
+}
+
+/// Whether the locale asks for UTF-8 output. A locale that is set but isn't
+/// UTF-8 (such as `C`) can't show box-drawing characters; an unset one is
+/// assumed to be fine.
+fn locale_is_utf8() -> bool {
+    ["LC_ALL", "LC_CTYPE", "LANG"].iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .is_none_or(|locale| {
+            let locale = locale.to_lowercase();
+            locale.contains("utf-8") || locale.contains("utf8")
+        })
 }
 
 fn main() -> Result<(), String> {
     let args = Args::parse();
+    let ascii = args.ascii_diagnostics || !locale_is_utf8();
     
-    // Create error collector
-    let mut error_collector = ErrorCollector::new();
+    if let Some(Command::Conformance { dir, nasm }) = &args.command {
+        run_conformance(dir, nasm.as_deref(), ascii);
+    }
+    if let Some(Command::Link { objects, output, entry }) = &args.command {
+        run_link(objects, output, entry, ascii);
+    }
+    if let Some(Command::Archive { objects, output }) = &args.command {
+        run_archive(objects, output, ascii);
+    }
+    if let Some(Command::Addr2line { binary, addresses }) = &args.command {
+        run_addr2line(binary, addresses, ascii);
+    }
+    if let Some(Command::Emulate { binary, max_steps }) = &args.command {
+        process::exit(emulate(binary, *max_steps, ascii));
+    }
+    if let Some(Command::Corpus { bits, output, check }) = &args.command {
+        run_corpus(*bits, output.as_deref(), check.as_deref(), ascii);
+    }
+    if let Some(Command::Debug { binary, breakpoints }) = &args.command {
+        run_debugger(binary, breakpoints, ascii);
+    }
+    if let Some(Command::Opcodes { command: OpcodesCommand::Validate { file } }) = &args.command {
+        run_opcodes_validate(file, ascii);
+    }
+    if let Some(Command::Opcodes { command: OpcodesCommand::ImportNasm { insns, output, known_only, list_skipped } }) = &args.command {
+        run_opcodes_import(insns, output.as_deref(), *known_only, *list_skipped, ascii);
+    }
+    // Required unless a subcommand was given
+    let file = args.file.clone().unwrap_or_default();
     
-    // Header message
-    if !args.silent {
-        println!("{}", "─".repeat(60).bright_blue());
-        println!("{} {}", "nasembler".bright_white().bold(), "- x86-64 Assembler".bright_blue());
-        println!("{}", "─".repeat(60).bright_blue());
+    let mut assembler = Assembler::new();
+    if let Some(target) = args.target {
+        assembler = assembler.target(target);
+    }
+    if let Some(format) = args.format {
+        assembler = assembler.format(format);
+    }
+    if args.link {
+        if let Some(target) = assembler.get_target().filter(|target| target.bits() != 64) {
+            eprint_line!(ascii, "{} --link only links x86-64 programs, not {} ones", "✗".bright_red().bold(), target);
+            process::exit(2);
+        }
+        assembler = assembler.format(Format::Obj);
+    }
+    if !args.opcode_tables.is_empty() {
+        match OpcodeTable::layered(&args.opcode_tables) {
+            Ok(table) => assembler = assembler.opcode_table(table),
+            Err(err) => {
+                eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), error_chain(&err).bright_red());
+                process::exit(1);
+            },
+        }
+    }
+    let mut assembler = args.include_paths.iter()
+        .fold(assembler, |assembler, path| assembler.include_path(path))
+        .continue_on_errors(!args.stop_on_first_error)
+        .strict_sections(args.strict_sections)
+        .strict_case(args.strict_case)
+        .max_section_size(args.max_section_size)
+        .code_fill(args.fill)
+        .data_fill(args.data_fill)
+        .os_abi(args.os_abi)
+        .abi_version(args.abi_version)
+        .elf_flags(args.elf_flags)
+        .debug_info(args.debug_info)
+        .comment(!args.no_comment)
+        .forbid_bytes(&args.forbid_bytes)
+        .report_sizes(args.size_report)
+        .map_source(args.source_map.is_some())
+        .warnings_as_errors(args.warnings.is_some())
+        .context_lines(args.context)
+        .message_format(args.message_format)
+        .ascii_diagnostics(ascii)
+        // Progress lines would break up compact and quiet output
+        .verbose(args.message_format == MessageFormat::Pretty);
+    
+    // Header message, only alongside pretty diagnostics
+    if !args.silent && args.message_format == MessageFormat::Pretty {
+        print_line!(ascii, "{}", "─".repeat(60).bright_blue());
+        print_line!(ascii, "{} {}", "nasembler".bright_white().bold(), "- x86-64 Assembler".bright_blue());
+        print_line!(ascii, "{}", "─".repeat(60).bright_blue());
     }
     
     // Load file content
     let start_time = Instant::now();
-    let file_content = match fs::read_to_string(&args.file) {
+    let file_content = match assembler.read_source(&file) {
         Ok(content) => content,
-        Err(e) => {
-            let file_error = error::file_error(
-                format!("Failed to read input file: {}", e),
-                &args.file
-            );
-            error_collector.add_error(file_error);
-            
+        Err(_) => {
             // If we can't even read the file, we can't proceed
-            println!("{}", error_collector.display_errors());
+            print_diagnostics(assembler.errors(), ascii);
             process::exit(1);
         }
     };
     
     if args.verbose {
-        println!("{} {} in {:.2?}",
+        print_line!(ascii, "{} {} in {:.2?}",
             "→".bright_blue(),
             "File loaded".bright_white().bold(),
             start_time.elapsed());
@@ -119,12 +487,11 @@ fn main() -> Result<(), String> {
     
     // Tokenize the file
     let start = Instant::now();
-    let mut tokenizer = Tokenizer::new(&file_content);
-    let tokens = tokenizer.tokenize();
+    let tokens = assembler.tokenize(&file_content);
     let tokenize_time = start.elapsed();
     
     if args.verbose {
-        println!("{} {} in {:.2?} ({} tokens)",
+        print_line!(ascii, "{} {} in {:.2?} ({} tokens)",
             "→".bright_blue(),
             "Tokenization completed".bright_white().bold(),
             tokenize_time,
@@ -133,45 +500,33 @@ fn main() -> Result<(), String> {
     
     // If tokenize_only or dump_tokens flag is set, show tokens and stop
     if args.tokenize_only || args.dump_tokens {
-        println!("\n{}", "Tokens:".bright_white().bold().underline());
+        print_line!(ascii, "\n{}", "Tokens:".bright_white().bold().underline());
         for (i, token) in tokens.iter().enumerate() {
-            println!("  {}. {:?}", i+1, token);
+            print_line!(ascii, "  {}. {:?}", i+1, token);
+        }
+        print_line!(ascii, "\n{} {} tokens", "✓".green().bold(), tokens.len());
+        if assembler.errors().has_errors() {
+            print_diagnostics(assembler.errors(), ascii);
+            process::exit(1);
         }
-        println!("\n{} {} tokens", "✓".green().bold(), tokens.len());
         return Ok(());
     }
     
     // Parse tokens
     let start = Instant::now();
-    let mut parser = Parser::new(tokens.clone())
-        .with_error_collector(error_collector.clone())
-        .with_file_name(args.file.clone())
-        .with_continue_on_errors(!args.stop_on_first_error);
-    
-    // Parse the program
-    let program = match parser.parse() {
+    let program = match assembler.parse(tokens) {
         Ok(prog) => prog,
-        Err(err_msg) => {
-            // If we're continuing on errors, use an empty program, otherwise exit
-            if !args.stop_on_first_error {
-                Program::new()
-            } else {
-                // Get the error collector from the parser before exiting
-                error_collector = parser.get_error_collector().unwrap_or(error_collector);
-                println!("{}", error_collector.display_errors());
-                eprintln!("{} {}", "✗".bright_red().bold(), err_msg.bright_red());
-                process::exit(1);
-            }
+        Err(err) => {
+            print_diagnostics(assembler.errors(), ascii);
+            eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), error_chain(&err).bright_red());
+            process::exit(1);
         }
     };
     
-    // Update the error collector with any errors collected during parsing
-    error_collector = parser.get_error_collector().unwrap_or(error_collector);
-    
     let parse_time = start.elapsed();
     
     if args.verbose {
-        println!("{} {} in {:.2?} ({} statements)",
+        print_line!(ascii, "{} {} in {:.2?} ({} statements)",
             "→".bright_blue(),
             "Parsing completed".bright_white().bold(),
             parse_time,
@@ -180,21 +535,23 @@ fn main() -> Result<(), String> {
     
     // Dump AST if requested
     if args.dump_ast {
-        println!("\n{}", "Abstract Syntax Tree:".bright_white().bold().underline());
+        print_line!(ascii, "\n{}", "Abstract Syntax Tree:".bright_white().bold().underline());
         dump_ast(&program);
         return Ok(());
     }
     
+    let error_collector = assembler.errors();
+    
     // If parse_only flag is set, stop here
     if args.parse_only {
         // If we have errors, display them
         if error_collector.has_errors() || (error_collector.warning_count() > 0 && !args.silent) {
-            println!("{}", error_collector.display_errors());
+            print_diagnostics(error_collector, ascii);
             if error_collector.has_fatal_errors() || error_collector.error_count() > 0 {
                 process::exit(1);
             }
-        } else {
-            println!("\n{} {}", "✓".green().bold(), "Parsing completed successfully with no errors".green());
+        } else if args.message_format == MessageFormat::Pretty {
+            print_line!(ascii, "\n{} {}", "✓".green().bold(), "Parsing completed successfully with no errors".green());
         }
         return Ok(());
     }
@@ -202,149 +559,425 @@ fn main() -> Result<(), String> {
     // Define output path
     let output_path = match args.output {
         Some(path) => path,
-        None => {
-            let path = PathBuf::from(&args.file);
-            let stem = path.file_stem().unwrap_or_default();
-            let extension = match args.format {
-                OutputFormat::Bin => "bin",
-                OutputFormat::Hex => "hex",
-                OutputFormat::Elf => "",  // No extension for ELF executables by default
-            };
-            if extension.is_empty() {
-                format!("{}", stem.to_string_lossy())
-            } else {
-                format!("{}.{}", stem.to_string_lossy(), extension)
-            }
-        },
+        // Linked programs are executables, named like ELF output
+        None if args.link => Path::new(&file).file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+        None => assembler.default_output_path(&file),
     };
+    // With --link the object is only an intermediate file
+    let object_path = if args.link {
+        std::env::temp_dir().join(format!("nasembler-{}-{}.o", process::id(), output_path.replace('/', "_")))
+    } else {
+        PathBuf::from(&output_path)
+    };
+    // Programs with a `main` are started by the C runtime
+    let startup_files = program.labels.keys().any(|label| *label == "main")
+        && !program.labels.keys().any(|label| *label == "_start");
+    
+    // Display any errors collected during processing
+    if error_collector.has_errors() {
+        print_diagnostics(error_collector, ascii);
+        process::exit(1);
+    }
     
     // Generate output based on format
     let generation_start = Instant::now();
-    let mut output_successful = false;
-    // Display any errors collected during processing
-    if error_collector.has_errors() || (error_collector.warning_count() > 0 && !args.silent) {
-        println!("{}", error_collector.display_errors());
-        
-        if error_collector.has_fatal_errors() || error_collector.has_errors() {
+    if assembler.generate(program, &object_path.to_string_lossy()).is_err() {
+        print_diagnostics(assembler.errors(), ascii);
+        process::exit(1);
+    }
+    
+    // Warnings are shown once generation has added its own
+    if assembler.errors().warning_count() > 0 && !args.silent {
+        print_diagnostics(assembler.errors(), ascii);
+    }
+    
+    // With --link the map gives the object's section offsets, not final addresses
+    if let (Some(path), Some(map)) = (&args.source_map, assembler.source_map()) {
+        if let Err(err) = std::fs::write(path, map.to_json()) {
+            eprint_line!(ascii, "{} Failed to write source map {}: {}", "✗".bright_red().bold(), path.display(), err);
             process::exit(1);
         }
     }
     
-    if args.format == OutputFormat::Elf {
-        let mut elf_generator = ElfGenerator::new(program);
-        
-        match elf_generator.generate(&output_path) {
-            Ok(_) => {
-                output_successful = true;
-                
-                // Make executable if requested
-                if args.make_executable {
-                    if let Err(err) = std::process::Command::new("chmod")
-                        .args(&["+x", &output_path])
-                        .output() {
-                        eprintln!("{} Failed to make output file executable: {}", 
-                            "⚠".yellow().bold(), 
-                            err);
-                    }
-                }
-                
-                if args.verbose {
-                    println!("{} {} in {:.2?}",
-                        "→".bright_blue(),
-                        "ELF generation completed".bright_white().bold(),
-                        generation_start.elapsed());
-                }
-            },
-            Err(err_msg) => {
-                // Convert the string error to our Error type
-                let elf_error = Error::new(
-                    ErrorType::ElfWriteError,
-                    ErrorDetail::new(err_msg.clone())
-                ).with_severity(ErrorSeverity::Error);
-                
-                error_collector.add_error(elf_error);
-            }
+    if args.link {
+        let link_start = Instant::now();
+        let mut linker = Linker::new(&args.linker).with_startup_files(startup_files);
+        for library in &args.libraries {
+            linker = linker.with_library(library);
+        }
+        for path in &args.library_paths {
+            linker = linker.with_library_path(path);
+        }
+        let result = linker.link(std::slice::from_ref(&object_path), Path::new(&output_path));
+        let _ = std::fs::remove_file(&object_path);
+        if let Err(err) = result {
+            eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), error_chain(&err).bright_red());
+            process::exit(1);
         }
-    } else if args.format == OutputFormat::Bin || args.format == OutputFormat::Hex {
-        // Placeholder for binary and hex output formats
-        let error = Error::new(
-            ErrorType::Other,
-            ErrorDetail::new("Binary and hex output formats not implemented yet".to_string())
-        ).with_severity(ErrorSeverity::Error);
         
-        error_collector.add_error(error);
+        if args.verbose {
+            print_line!(ascii, "{} {} in {:.2?}",
+                "→".bright_blue(),
+                "Linking completed".bright_white().bold(),
+                link_start.elapsed());
+        }
     }
     
+    // Make executable if requested
+    if args.make_executable {
+        if let Err(err) = std::process::Command::new("chmod")
+            .args(["+x", &output_path])
+            .output() {
+            eprint_line!(ascii, "{} Failed to make output file executable: {}", 
+                "⚠".yellow().bold(), 
+                err);
+        }
+    }
     
-    // Show summary if compilation was successful
-    if output_successful {
-        let canonical_path = std::fs::canonicalize(&output_path).unwrap_or_else(|_| PathBuf::from(&output_path));
-        
-        println!("\n{} {}", "✓".green().bold(), "Assembly completed successfully".green().bold());
-        println!("{} Output binary at: {}", 
+    if args.verbose {
+        print_line!(ascii, "{} {} in {:.2?}",
+            "→".bright_blue(),
+            "ELF generation completed".bright_white().bold(),
+            generation_start.elapsed());
+    }
+    
+    // Show summary since compilation was successful
+    let canonical_path = std::fs::canonicalize(&output_path).unwrap_or_else(|_| PathBuf::from(&output_path));
+    
+    // Success lines, like the header, only alongside pretty diagnostics
+    if args.message_format == MessageFormat::Pretty {
+        print_line!(ascii, "\n{} {}", "✓".green().bold(), "Assembly completed successfully".green().bold());
+        print_line!(ascii, "{} Output binary at: {}", 
             "→".bright_blue().bold(), 
             canonical_path.display().to_string().bright_white().bold().underline());
+    }
+    
+    if args.verbose {
+        print_line!(ascii, "{} Total time: {:.2?}", 
+            "→".bright_blue().bold(), 
+            start_time.elapsed());
+    }
+    
+    if let Some(report) = assembler.size_report() {
+        // With --link the report describes the object that was linked
+        let subject = if args.link { "Object size" } else { "Output size" };
+        print_line!(ascii, "\n{} {}", "■".bright_blue().bold(), subject.bright_white().bold());
+        print!("{}", report);
+    }
+    
+    // Execute the binary if requested
+    if args.execute {
+        print_line!(ascii, "\n{} {}", "►".bright_green().bold(), "Executing output binary:".bright_green());
+        print_line!(ascii, "{}", "─".repeat(60).bright_blue());
         
-        if args.verbose {
-            println!("{} Total time: {:.2?}", 
-                "→".bright_blue().bold(), 
-                start_time.elapsed());
-        }
+        let code = if args.emulate {
+            emulate(&canonical_path, DEFAULT_STEP_LIMIT, ascii)
+        } else {
+            let status = if args.trace {
+                run_traced(&canonical_path, ascii)
+            } else if args.sandbox {
+                run_sandboxed(&canonical_path, ascii)
+            } else {
+                std::process::Command::new(canonical_path)
+                    .status()
+                    .unwrap_or_else(|e| {
+                        eprint_line!(ascii, "{} Failed to execute binary: {}", "✗".bright_red().bold(), e);
+                        process::exit(1);
+                    })
+            };
+            status.code().unwrap_or(-1)
+        };
         
-        // Execute the binary if requested
-        if args.execute {
-            println!("\n{} {}", "►".bright_green().bold(), "Executing output binary:".bright_green());
-            println!("{}", "─".repeat(60).bright_blue());
-            
-            let status = std::process::Command::new(canonical_path)
-                .status()
-                .unwrap_or_else(|e| {
-                    eprintln!("{} Failed to execute binary: {}", "✗".bright_red().bold(), e);
-                    process::exit(1);
-                });
-            
-            println!("{}", "─".repeat(60).bright_blue());
-            println!("{} Exit code: {}", 
-                "→".bright_blue().bold(), 
-                status.code().unwrap_or(-1));
+        print_line!(ascii, "{}", "─".repeat(60).bright_blue());
+        print_line!(ascii, "{} Exit code: {}", 
+            "→".bright_blue().bold(), 
+            code);
+    }
+    
+    Ok(())
+}
+
+/// Run `program`, logging its syscalls to stderr as it makes them
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "trace"))]
+fn run_traced(program: &Path, ascii: bool) -> process::ExitStatus {
+    let mut command = std::process::Command::new(program);
+    nasembler::trace::trace(&mut command, |event| {
+        eprint_line!(ascii, "{} {}", "│".bright_black(), event.to_string().bright_black());
+    })
+    .unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), error_chain(&err).bright_red());
+        process::exit(1);
+    })
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64", feature = "trace")))]
+fn run_traced(_program: &Path, ascii: bool) -> process::ExitStatus {
+    eprint_line!(ascii, "{} --trace needs a Linux x86-64 build with the 'trace' feature", "✗".bright_red().bold());
+    process::exit(1);
+}
+
+/// Run `program` in the sandbox, reporting a rule it broke
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "sandbox"))]
+fn run_sandboxed(program: &Path, ascii: bool) -> process::ExitStatus {
+    let outcome = nasembler::sandbox::Sandbox::new().run(program, &[] as &[&str]).unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), error_chain(&err).bright_red());
+        process::exit(1);
+    });
+    if let Some(violation) = &outcome.violation {
+        eprint_line!(ascii, "{} {}", "✗".bright_red().bold(),
+            format!("The sandbox stopped the program: it {}", violation).bright_red());
+    }
+    outcome.status
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64", feature = "sandbox")))]
+fn run_sandboxed(_program: &Path, ascii: bool) -> process::ExitStatus {
+    eprint_line!(ascii, "{} --sandbox needs a Linux x86-64 build with the 'sandbox' feature", "✗".bright_red().bold());
+    process::exit(1);
+}
+
+/// Run the conformance cases in `dir` and exit, with status 1 if any failed
+fn run_conformance(dir: &Path, nasm: Option<&str>, ascii: bool) -> ! {
+    let reference = conformance::find_reference(nasm.unwrap_or(conformance::DEFAULT_REFERENCE));
+    if reference.is_none() {
+        if let Some(nasm) = nasm {
+            eprint_line!(ascii, "{} Failed to run reference assembler '{}'", "✗".bright_red().bold(), nasm);
+            process::exit(1);
         }
-    } else if !error_collector.has_errors() {
-        // This should not happen, but just in case
-        eprintln!("{} {}", "✗".bright_red().bold(), "Failed to generate output for unknown reason".bright_red());
+    }
+    
+    let conformance = Conformance::new(reference);
+    let results = conformance.run_dir(dir).unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} Failed to read {}: {}", "✗".bright_red().bold(), dir.display(), err);
         process::exit(1);
+    });
+    
+    for result in &results {
+        let path = result.path.display();
+        match &result.outcome {
+            Outcome::Match => print_line!(ascii, "{} {}", "✓".green().bold(), path),
+            Outcome::Assembled => print_line!(ascii, "{} {} (assembled, not compared)", "✓".green().bold(), path),
+            Outcome::Diverged { offset, ours, reference } => {
+                let byte = |byte: &Option<u8>| byte.map_or("end of output".to_string(), |byte| format!("{:02x}", byte));
+                print_line!(ascii, "{} {}: first difference at byte {:#x}: nasembler {}, nasm {}",
+                    "✗".bright_red().bold(), path, offset, byte(ours), byte(reference));
+            },
+            Outcome::Failed(message) => print_line!(ascii, "{} {}: nasembler: {}", "✗".bright_red().bold(), path, message),
+            Outcome::ReferenceFailed(message) => print_line!(ascii, "{} {}: nasm: {}", "✗".bright_red().bold(), path, message),
+            _ => print_line!(ascii, "{} {}", "?".yellow().bold(), path),
+        }
     }
     
-    Ok(())
+    let passed = results.iter().filter(|result| result.outcome.passed()).count();
+    let failed = results.len() - passed;
+    print_line!(ascii, "\n{} passed, {} failed", passed.to_string().green().bold(), failed.to_string().bright_red().bold());
+    if conformance.reference().is_none() {
+        print_line!(ascii, "{} nasm not found; only checked that each file assembles", "⚠".yellow().bold());
+    }
+    process::exit(if failed > 0 { 1 } else { 0 });
+}
+
+/// Link `objects` into `output` with the built-in linker and exit
+fn run_link(objects: &[PathBuf], output: &Path, entry: &str, ascii: bool) -> ! {
+    let mut inputs = Vec::with_capacity(objects.len());
+    for path in objects {
+        match std::fs::read(path) {
+            Ok(bytes) => inputs.push((path.display().to_string(), bytes)),
+            Err(err) => {
+                eprint_line!(ascii, "{} Failed to read {}: {}", "✗".bright_red().bold(), path.display(), err);
+                process::exit(1);
+            }
+        }
+    }
+    let inputs: Vec<(&str, &[u8])> = inputs.iter().map(|(name, bytes)| (name.as_str(), bytes.as_slice())).collect();
+    
+    let image = StaticLinker::new().with_entry(entry).link(&inputs).unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), error_chain(&err).bright_red());
+        process::exit(1);
+    });
+    if let Err(err) = write_executable(output, &image) {
+        eprint_line!(ascii, "{} Failed to write {}: {}", "✗".bright_red().bold(), output.display(), err);
+        process::exit(1);
+    }
+    
+    print_line!(ascii, "{} Linked {} object{} into {}", "✓".green().bold(),
+        objects.len(), if objects.len() == 1 { "" } else { "s" }, output.display());
+    process::exit(0);
+}
+
+/// Bundle `objects` into the static library `output` and exit
+fn run_archive(objects: &[PathBuf], output: &Path, ascii: bool) -> ! {
+    let mut archive = Archive::new();
+    for path in objects {
+        let contents = std::fs::read(path).unwrap_or_else(|err| {
+            eprint_line!(ascii, "{} Failed to read {}: {}", "✗".bright_red().bold(), path.display(), err);
+            process::exit(1);
+        });
+        // Members are named like ar names them, without the directory
+        let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy();
+        if let Err(err) = archive.add(&name, contents) {
+            eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), error_chain(&err).bright_red());
+            process::exit(1);
+        }
+    }
+    if let Err(err) = std::fs::write(output, archive.finish()) {
+        eprint_line!(ascii, "{} Failed to write {}: {}", "✗".bright_red().bold(), output.display(), err);
+        process::exit(1);
+    }
+    
+    print_line!(ascii, "{} Archived {} object{} into {}", "✓".green().bold(),
+        archive.len(), if archive.len() == 1 { "" } else { "s" }, output.display());
+    process::exit(0);
+}
+
+/// Print where each of `addresses` is in `binary` and exit
+fn run_addr2line(binary: &Path, addresses: &[u64], ascii: bool) -> ! {
+    let bytes = std::fs::read(binary).unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} Failed to read {}: {}", "✗".bright_red().bold(), binary.display(), err);
+        process::exit(1);
+    });
+    
+    for &address in addresses {
+        match addr2line::lookup(&bytes, address) {
+            Ok(location) => println!("{:#x}: {}", address, location),
+            Err(err) => {
+                eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), err.to_string().bright_red());
+                process::exit(1);
+            }
+        }
+    }
+    process::exit(0);
+}
+
+/// Run `binary` in the emulator, returning its exit code
+fn emulate(binary: &Path, max_steps: u64, ascii: bool) -> i32 {
+    let bytes = std::fs::read(binary).unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} Failed to read {}: {}", "✗".bright_red().bold(), binary.display(), err);
+        process::exit(1);
+    });
+    
+    let result = Emulator::load(&bytes)
+        .and_then(|emulator| emulator.with_step_limit(max_steps).run(&mut std::io::stdout(), &mut std::io::stderr()));
+    result.unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), error_chain(&err).bright_red());
+        process::exit(1);
+    })
+}
+
+/// Write the encoder's test vectors, or check a saved corpus against the encoder, and exit
+fn run_corpus(bits: Option<u16>, output: Option<&Path>, check: Option<&Path>, ascii: bool) -> ! {
+    let Some(golden) = check else {
+        let text = corpus::to_text(&corpus::generate(bits));
+        match output {
+            Some(path) => if let Err(err) = std::fs::write(path, text) {
+                eprint_line!(ascii, "{} Failed to write {}: {}", "✗".bright_red().bold(), path.display(), err);
+                process::exit(1);
+            },
+            None => print!("{}", text),
+        }
+        process::exit(0);
+    };
+    
+    let vectors = std::fs::read_to_string(golden)
+        .map_err(|err| format!("Failed to read {}: {}", golden.display(), err))
+        .and_then(|text| corpus::read(&text).map_err(|err| format!("{}: {}", golden.display(), err)))
+        .unwrap_or_else(|message| {
+            eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), message.bright_red());
+            process::exit(1);
+        });
+    let mismatches = corpus::check(&vectors);
+    for mismatch in &mismatches {
+        print_line!(ascii, "{} {}", "✗".bright_red().bold(), mismatch);
+    }
+    print_line!(ascii, "\n{} passed, {} failed",
+        (vectors.len() - mismatches.len()).to_string().green().bold(),
+        mismatches.len().to_string().bright_red().bold());
+    process::exit(if mismatches.is_empty() { 0 } else { 1 });
 }
 
-/// Print a summary of the AST
-fn print_ast_summary(program: &Program) {
-    // Count of different types of statements
-    let mut instruction_count = 0;
-    let mut label_count = 0;
-    let mut directive_count = 0;
-    let mut section_count = 0;
-    let mut comment_count = 0;
-    let mut empty_count = 0;
-    
-    for statement in &program.statements {
-        match statement {
-            parser::ast::Statement::Instruction(_) => instruction_count += 1,
-            parser::ast::Statement::Label(_) => label_count += 1,
-            parser::ast::Statement::Directive(_) => directive_count += 1,
-            parser::ast::Statement::Section(_) => section_count += 1,
-            parser::ast::Statement::Comment(_) => comment_count += 1,
-            parser::ast::Statement::Empty => empty_count += 1,
+/// Report every problem in the opcode table at `path`
+fn run_opcodes_validate(path: &Path, ascii: bool) -> ! {
+    let content = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} Failed to read {}: {}", "✗".bright_red().bold(), path.display(), err);
+        process::exit(1);
+    });
+    let (forms, problems) = opcodes::check(&content);
+    for problem in &problems {
+        print_line!(ascii, "{} {}: {}", "✗".bright_red().bold(), path.display(), problem);
+    }
+    if problems.is_empty() {
+        print_line!(ascii, "{} {}: {} forms, no problems", "✓".green().bold(), path.display(), forms);
+    } else {
+        let noun = if problems.len() == 1 { "problem" } else { "problems" };
+        print_line!(ascii, "\n{} forms, {} {}", forms.to_string().green().bold(), problems.len().to_string().bright_red().bold(), noun);
+    }
+    process::exit(if problems.is_empty() { 0 } else { 1 });
+}
+
+/// Convert the insns.dat at `path` to an opcode table, reporting on stderr
+/// so the table can go to stdout
+fn run_opcodes_import(path: &Path, output: Option<&Path>, known_only: bool, list_skipped: bool, ascii: bool) -> ! {
+    let content = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} Failed to read {}: {}", "✗".bright_red().bold(), path.display(), err);
+        process::exit(1);
+    });
+    let import = insns::import(&content, known_only);
+    if list_skipped {
+        for skipped in &import.skipped {
+            eprint_line!(ascii, "{} {}: {}", "-".yellow().bold(), path.display(), skipped);
         }
     }
+    match output {
+        Some(output) => if let Err(err) = std::fs::write(output, &import.table) {
+            eprint_line!(ascii, "{} Failed to write {}: {}", "✗".bright_red().bold(), output.display(), err);
+            process::exit(1);
+        },
+        None => print!("{}", import.table),
+    }
+    eprint_line!(ascii, "{} Imported {} forms, skipped {} lines", "✓".green().bold(),
+        import.forms.to_string().green().bold(), import.skipped.len());
+    process::exit(0);
+}
+
+/// Debug `binary` with commands read from stdin
+fn run_debugger(binary: &Path, breakpoints: &[String], ascii: bool) -> ! {
+    let bytes = std::fs::read(binary).unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} Failed to read {}: {}", "✗".bright_red().bold(), binary.display(), err);
+        process::exit(1);
+    });
+    let debugger = Debugger::new(&bytes).unwrap_or_else(|err| {
+        eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), error_chain(&err).bright_red());
+        process::exit(1);
+    });
+    let mut debugger = match binary.parent() {
+        Some(dir) => debugger.with_source_dir(dir),
+        None => debugger,
+    };
+    for location in breakpoints {
+        let Some(address) = debugger.resolve(location) else {
+            eprint_line!(ascii, "{} No label '{}' in {}", "✗".bright_red().bold(), location, binary.display());
+            process::exit(1);
+        };
+        debugger.add_breakpoint(address);
+    }
     
-    println!("  AST Summary:");
-    println!("    Instructions: {}", instruction_count);
-    println!("    Labels: {}", label_count);
-    println!("    Directives: {}", directive_count);
-    println!("    Sections: {}", section_count);
-    println!("    Comments: {}", comment_count);
-    println!("    Empty statements: {}", empty_count);
+    let stdin = std::io::stdin();
+    if let Err(err) = debugger.run(&mut stdin.lock(), &mut std::io::stdout(), &mut std::io::stderr()) {
+        eprint_line!(ascii, "{} {}", "✗".bright_red().bold(), err.to_string().bright_red());
+        process::exit(1);
+    }
+    process::exit(0);
+}
+
+/// Write `bytes` to `path`, executable by everyone who can read it
+fn write_executable(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, bytes)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
 }
 
 /// Dump the AST in a slightly pretty format
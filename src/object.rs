@@ -0,0 +1,77 @@
+//! Object writer backends.
+//!
+//! Every output format is produced by an [`ObjectWriter`]: the backend owns
+//! the program, lays its sections out, resolves relocations against the
+//! symbols it knows and finally serializes everything into bytes. The ELF
+//! generator, the flat `BinaryWriter` and the `RelocatableWriter` are the
+//! backends today; Mach-O or PE writers plug in by implementing the same
+//! trait and being selected in
+//! [`Assembler::object_writer`](crate::Assembler::object_writer).
+//!
+//! ```no_run
+//! use nasembler::{Assembler, Format};
+//!
+//! let mut assembler = Assembler::new().format(Format::Elf);
+//! let program = assembler.assemble_program("section .text\n_start:\n    syscall\n").unwrap();
+//!
+//! let mut writer = assembler.object_writer(program).unwrap();
+//! writer.layout_sections().unwrap();
+//! writer.define_symbol("answer", 42);
+//! writer.apply_relocations().unwrap();
+//! let bytes = writer.finish().unwrap();
+//! ```
+
+use crate::error::AssemblerError;
+use crate::parser::ast::Instruction;
+use crate::size::SizeReport;
+use crate::source_map::SourceMap;
+
+/// A backend turning a program into an object or executable file
+///
+/// The steps are called in order: [`layout_sections`](Self::layout_sections),
+/// any number of [`define_symbol`](Self::define_symbol) calls,
+/// [`apply_relocations`](Self::apply_relocations) and [`finish`](Self::finish).
+pub trait ObjectWriter {
+    /// Encode every statement into its section and assign label addresses
+    fn layout_sections(&mut self) -> Result<(), AssemblerError>;
+
+    /// Define (or override) a symbol's address, e.g. an absolute constant
+    fn define_symbol(&mut self, name: &str, address: u64);
+
+    /// Address of a symbol defined by the layout or by `define_symbol`
+    fn symbol(&self, name: &str) -> Option<u64>;
+
+    /// Patch every symbol reference now that all addresses are known
+    fn apply_relocations(&mut self) -> Result<(), AssemblerError>;
+
+    /// Serialize the laid out sections into the final file contents
+    fn finish(&mut self) -> Result<Vec<u8>, AssemblerError>;
+
+    /// Problems found so far that didn't stop generation
+    fn warnings(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Each instruction in the code section with its bytes, which are final
+    /// once relocations have been applied
+    fn code(&self) -> Vec<(&Instruction, &[u8])> {
+        Vec::new()
+    }
+
+    /// What the sections and labels of the `file_size`-byte output account for
+    fn size_report(&self, _file_size: u64) -> Option<SizeReport> {
+        None
+    }
+
+    /// Where each statement's bytes were placed, with its line in `file`
+    fn source_map(&self, _file: &str) -> Option<SourceMap> {
+        None
+    }
+
+    /// Run all steps with no extra symbols
+    fn write_object(&mut self) -> Result<Vec<u8>, AssemblerError> {
+        self.layout_sections()?;
+        self.apply_relocations()?;
+        self.finish()
+    }
+}
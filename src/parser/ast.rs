@@ -1,26 +1,38 @@
 use std::fmt;
 use std::collections::HashMap;
 
+use crate::encoder::immediate_value;
+use crate::symbol::Symbol;
+use crate::tokenizer::Span;
+
+/// Version of the AST layout, bumped whenever a change to these types
+/// alters their shape in a way downstream code (or serialized ASTs) can notice
+pub const AST_VERSION: u32 = 5;
+
 /// Abstract Syntax Tree (AST) structures for the assembly parser
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum Statement {
     Instruction(Instruction),
     Directive(Directive),
-    Label(String),
+    Label(Symbol),
     Comment(String),
     Empty,
     Section(Section),
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Instruction {
-    pub name: String,
+    pub name: Symbol,
     pub operands: Vec<Operand>,
     pub machine_code: Vec<u8>,
     pub line: usize,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Directive {
     pub name: String,
     pub operands: Vec<Operand>,
@@ -28,36 +40,129 @@ pub struct Directive {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum Operand {
-    Register(String),
+    Register(Symbol),
     Immediate(String),
     Memory(MemoryReference),
-    Label(String),
+    Label(Symbol),
     String(String),
+    /// A label relative to one of NASM's special symbols, as in `foo wrt ..plt`
+    Wrt(Symbol, SpecialSymbol),
+}
+
+/// Special symbols a label can be taken relative to with `wrt`, which ask the
+/// linker for position-independent access to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum SpecialSymbol {
+    /// `..got`: the label's entry in the global offset table
+    Got,
+    /// `..plt`: the label's procedure linkage table entry
+    Plt,
+    /// `..gotpcrel`: the label's global offset table entry, relative to the instruction
+    GotPcRel,
+}
+
+impl SpecialSymbol {
+    /// The special symbol called `name`, e.g. `..plt`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "..got" => Some(SpecialSymbol::Got),
+            "..plt" => Some(SpecialSymbol::Plt),
+            "..gotpcrel" => Some(SpecialSymbol::GotPcRel),
+            _ => None,
+        }
+    }
+    
+    pub fn name(self) -> &'static str {
+        match self {
+            SpecialSymbol::Got => "..got",
+            SpecialSymbol::Plt => "..plt",
+            SpecialSymbol::GotPcRel => "..gotpcrel",
+        }
+    }
+}
+
+impl fmt::Display for SpecialSymbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MemoryReference {
-    pub base: Option<String>,
-    pub index: Option<String>,
+    /// Segment register overriding the default segment, as in `[fs:var]`
+    pub segment: Option<Symbol>,
+    pub base: Option<Symbol>,
+    pub index: Option<Symbol>,
     pub scale: Option<u8>,
+    /// A number, a label, or a label plus or minus a number: `8`, `-0x10`,
+    /// `table` or `table+8`
     pub displacement: Option<String>,
+    /// Size in bits of the operand, when it is given as in `byte [rsi]`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub size: Option<u16>,
+    /// Special symbol the label is taken relative to, as in `[rel foo wrt ..gotpcrel]`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub wrt: Option<SpecialSymbol>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Program {
     pub statements: Vec<Statement>,
     pub sections: HashMap<String, SectionInfo>,
-    pub labels: HashMap<String, LabelInfo>,
+    pub labels: HashMap<Symbol, LabelInfo>,
+    /// Where each statement came from, by index into `statements`. Programs
+    /// built in code may have fewer spans than statements.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub spans: Vec<NodeSpan>,
+}
+
+/// Where a statement is in the source and, once laid out, the bytes it emitted
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NodeSpan {
+    /// Source file, empty for statements that weren't parsed from one
+    pub file: Symbol,
+    pub line: usize,
+    /// Byte range from the start of the statement's first token to the end of its last
+    pub source: Span,
+    /// Set by layout for statements that emit bytes
+    pub bytes: Option<ByteRange>,
+}
+
+impl Default for NodeSpan {
+    fn default() -> Self {
+        NodeSpan { file: Symbol::intern(""), line: 0, source: Span::default(), bytes: None }
+    }
+}
+
+/// Bytes a statement emitted into the output
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ByteRange {
+    pub section: String,
+    /// Virtual address of the first byte; an offset within the section in relocatable objects
+    pub address: u64,
+    /// Offset of the first byte from the start of .text or the data segment
+    pub offset: u64,
+    pub size: u64,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SectionInfo {
     pub size: usize,
     pub statements: Vec<usize>, // Indices into the statements vec
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LabelInfo {
     pub offset: u64,
     pub section: Option<String>,
@@ -69,6 +174,7 @@ impl Program {
             statements: Vec::new(),
             sections: HashMap::new(),
             labels: HashMap::new(),
+            spans: Vec::new(),
         }
     }
     
@@ -76,6 +182,19 @@ impl Program {
         self.statements.push(statement);
     }
     
+    /// Append a statement along with where it came from
+    pub fn add_statement_at(&mut self, statement: Statement, span: NodeSpan) {
+        // Statements added without a span get a default one, keeping the indices in step
+        self.spans.resize(self.statements.len(), NodeSpan::default());
+        self.statements.push(statement);
+        self.spans.push(span);
+    }
+    
+    /// Where the statement at `index` came from, if known
+    pub fn span(&self, index: usize) -> Option<&NodeSpan> {
+        self.spans.get(index)
+    }
+    
     pub fn add_section(&mut self, name: String, size: usize) {
         self.sections.insert(name, SectionInfo {
             size,
@@ -83,16 +202,246 @@ impl Program {
         });
     }
     
-    pub fn add_label(&mut self, name: String, offset: u64, section: Option<String>) {
+    pub fn add_label(&mut self, name: Symbol, offset: u64, section: Option<String>) {
         self.labels.insert(name, LabelInfo {
             offset,
             section,
         });
     }
+    
+    /// Append a `section <name>` switch
+    pub fn push_section(&mut self, name: &str) -> &mut Self {
+        let line = self.next_line();
+        self.add_statement(Statement::Section(Section {
+            name: name.to_string(),
+            line,
+        }));
+        self
+    }
+    
+    /// Append a label definition
+    pub fn push_label(&mut self, name: &str) -> &mut Self {
+        self.add_statement(Statement::Label(Symbol::intern(name)));
+        self
+    }
+    
+    /// Append an instruction, numbering its line if it has none
+    pub fn push_instruction(&mut self, mut instruction: Instruction) -> &mut Self {
+        if instruction.line == 0 {
+            instruction.line = self.next_line();
+        }
+        self.add_statement(Statement::Instruction(instruction));
+        self
+    }
+    
+    /// Append a directive, numbering its line if it has none
+    pub fn push_directive(&mut self, mut directive: Directive) -> &mut Self {
+        if directive.line == 0 {
+            directive.line = self.next_line();
+        }
+        self.add_statement(Statement::Directive(directive));
+        self
+    }
+    
+    /// Append a labelled data definition, e.g. `msg db "hi", 10`
+    pub fn push_data(&mut self, label: &str, directive: Directive) -> &mut Self {
+        self.push_label(label);
+        self.push_directive(directive)
+    }
+    
+    /// Synthetic line number for built statements, so diagnostics stay ordered
+    fn next_line(&self) -> usize {
+        self.statements.len() + 1
+    }
+}
+
+impl Instruction {
+    /// Start building an instruction with no operands
+    pub fn new(name: &str) -> Self {
+        Instruction {
+            name: Symbol::intern(&name.to_lowercase()),
+            operands: Vec::new(),
+            machine_code: Vec::new(),
+            line: 0,
+        }
+    }
+    
+    /// Append an arbitrary operand
+    pub fn operand(mut self, operand: Operand) -> Self {
+        self.operands.push(operand);
+        self
+    }
+    
+    /// Append a register operand
+    pub fn reg(self, register: &str) -> Self {
+        self.operand(Operand::Register(Symbol::intern(&register.to_lowercase())))
+    }
+    
+    /// Append an immediate operand
+    pub fn imm(self, value: i64) -> Self {
+        self.operand(Operand::Immediate(value.to_string()))
+    }
+    
+    /// Append a label reference operand
+    pub fn label(self, name: &str) -> Self {
+        self.operand(Operand::Label(Symbol::intern(name)))
+    }
+    
+    /// Append a label operand taken relative to a special symbol, as in `foo wrt ..plt`
+    pub fn label_wrt(self, name: &str, special: SpecialSymbol) -> Self {
+        self.operand(Operand::Wrt(Symbol::intern(name), special))
+    }
+    
+    /// Append a memory operand
+    pub fn mem(self, memory: MemoryReference) -> Self {
+        self.operand(Operand::Memory(memory))
+    }
+    
+    /// Set the source line reported in diagnostics
+    pub fn at_line(mut self, line: usize) -> Self {
+        self.line = line;
+        self
+    }
+}
+
+impl Directive {
+    /// Start building a directive with no operands
+    pub fn new(name: &str) -> Self {
+        Directive {
+            name: name.to_lowercase(),
+            operands: Vec::new(),
+            line: 0,
+        }
+    }
+    
+    /// Append an arbitrary operand
+    pub fn operand(mut self, operand: Operand) -> Self {
+        self.operands.push(operand);
+        self
+    }
+    
+    /// Append an immediate operand
+    pub fn imm(self, value: i64) -> Self {
+        self.operand(Operand::Immediate(value.to_string()))
+    }
+    
+    /// Append a string operand
+    pub fn string(self, value: &str) -> Self {
+        self.operand(Operand::String(value.to_string()))
+    }
+    
+    /// Append a label reference operand
+    pub fn label(self, name: &str) -> Self {
+        self.operand(Operand::Label(Symbol::intern(name)))
+    }
+    
+    /// Set the source line reported in diagnostics
+    pub fn at_line(mut self, line: usize) -> Self {
+        self.line = line;
+        self
+    }
+}
+
+impl MemoryReference {
+    /// `[base]`
+    pub fn base(register: &str) -> Self {
+        MemoryReference {
+            segment: None,
+            base: Some(Symbol::intern(&register.to_lowercase())),
+            index: None,
+            scale: None,
+            displacement: None,
+            size: None,
+            wrt: None,
+        }
+    }
+    
+    /// Add an index register with a scale factor: `[base + index*scale]`
+    pub fn index(mut self, register: &str, scale: u8) -> Self {
+        self.index = Some(Symbol::intern(&register.to_lowercase()));
+        self.scale = Some(scale);
+        self
+    }
+    
+    /// Add a displacement: `[base + disp]`
+    pub fn displacement(mut self, displacement: i64) -> Self {
+        self.displacement = Some(displacement.to_string());
+        self
+    }
+    
+    /// `[label]`, RIP-relative in 64-bit code
+    pub fn at_label(label: &str) -> Self {
+        MemoryReference {
+            segment: None,
+            base: None,
+            index: None,
+            scale: None,
+            displacement: Some(label.to_string()),
+            size: None,
+            wrt: None,
+        }
+    }
+    
+    /// The label in the displacement, as in `[rbx+table]` or `[counter+8]`
+    pub fn displacement_label(&self) -> Option<Symbol> {
+        let displacement = self.displacement.as_deref()?;
+        if immediate_value(displacement).is_some() {
+            return None;
+        }
+        let end = displacement.find(['+', '-']).unwrap_or(displacement.len());
+        Some(Symbol::intern(&displacement[..end]))
+    }
+    
+    /// The number in the displacement, 0 if there is none: `-8` for both
+    /// `[rbp-8]` and `[counter-8]`. `None` if it isn't a valid number.
+    pub fn displacement_offset(&self) -> Option<i128> {
+        let Some(displacement) = self.displacement.as_deref() else {
+            return Some(0);
+        };
+        if let Some(value) = immediate_value(displacement) {
+            return Some(value);
+        }
+        match displacement.find(['+', '-']) {
+            Some(sign) => immediate_value(displacement[sign..].trim_start_matches('+')),
+            None => Some(0),
+        }
+    }
+    
+    /// `[segment:label]`, e.g. `[fs:counter]` for a thread-local variable
+    pub fn segment_label(segment: &str, label: &str) -> Self {
+        MemoryReference {
+            segment: Some(Symbol::intern(&segment.to_lowercase())),
+            base: None,
+            index: None,
+            scale: None,
+            displacement: Some(label.to_string()),
+            size: None,
+            wrt: None,
+        }
+    }
+    
+    /// Override the segment: `[segment:base]`
+    pub fn segment(mut self, segment: &str) -> Self {
+        self.segment = Some(Symbol::intern(&segment.to_lowercase()));
+        self
+    }
+    
+    /// Give the operand's size in bits: `byte [base]` for 8
+    pub fn size(mut self, bits: u16) -> Self {
+        self.size = Some(bits);
+        self
+    }
+    
+    /// Take the label relative to a special symbol: `[label wrt ..gotpcrel]`
+    pub fn wrt(mut self, special: SpecialSymbol) -> Self {
+        self.wrt = Some(special);
+        self
+    }
 }
 
 /// Represents a section in the assembly
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Section {
     /// Name of the section (e.g., ".text", ".data")
     pub name: String,
@@ -102,6 +451,7 @@ pub struct Section {
 
 /// Represents a label in the assembly
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Label {
     /// Name of the label
     pub name: String,
@@ -111,6 +461,7 @@ pub struct Label {
 
 /// Represents a data value for db, dw, dd, etc. directives
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DataValue {
     /// A numeric value
     Number(String),
@@ -122,6 +473,28 @@ pub enum DataValue {
     Label(String),
 }
 
+/// Writes the instruction as it would be written in source, e.g. `mov rax, 1`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_statement(f, &self.name, &self.operands)
+    }
+}
+
+/// Writes the directive as it would be written in source, e.g. `db "hi", 10`
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_statement(f, &self.name, &self.operands)
+    }
+}
+
+fn write_statement(f: &mut fmt::Formatter, name: &dyn fmt::Display, operands: &[Operand]) -> fmt::Result {
+    write!(f, "{}", name)?;
+    for (index, operand) in operands.iter().enumerate() {
+        write!(f, "{}{}", if index == 0 { " " } else { ", " }, operand)?;
+    }
+    Ok(())
+}
+
 // Implement Display for better error messages and debugging
 impl fmt::Display for Operand {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -129,8 +502,15 @@ impl fmt::Display for Operand {
             Operand::Register(reg) => write!(f, "{}", reg),
             Operand::Immediate(imm) => write!(f, "{}", imm),
             Operand::Memory(mem) => {
+                if let Some(keyword) = mem.size.and_then(size_keyword) {
+                    write!(f, "{} ", keyword)?;
+                }
                 write!(f, "[")?;
                 
+                if let Some(segment) = &mem.segment {
+                    write!(f, "{}:", segment)?;
+                }
+                
                 if let Some(base) = &mem.base {
                     write!(f, "{}", base)?;
                 }
@@ -159,10 +539,26 @@ impl fmt::Display for Operand {
                     }
                 }
                 
+                if let Some(special) = mem.wrt {
+                    write!(f, " wrt {}", special)?;
+                }
+                
                 write!(f, "]")
             },
             Operand::String(str) => write!(f, "\"{}\"", str),
             Operand::Label(label) => write!(f, "{}", label),
+            Operand::Wrt(label, special) => write!(f, "{} wrt {}", label, special),
         }
     }
-} 
\ No newline at end of file
+}
+
+/// Size keyword for operands of `bits` bits, as in `byte [rsi]`
+pub fn size_keyword(bits: u16) -> Option<&'static str> {
+    match bits {
+        8 => Some("byte"),
+        16 => Some("word"),
+        32 => Some("dword"),
+        64 => Some("qword"),
+        _ => None,
+    }
+}
@@ -1,7 +1,9 @@
+use crate::encoder::{fit_immediate, immediate_value};
 use crate::parser::ast::{Statement, Directive, Operand};
-use crate::tokenizer::TokenType;
+use crate::tokenizer::{Token, TokenType};
 use crate::parser::Parser;
 use crate::error::ErrorType;
+use crate::symbol::Symbol;
 
 /// Parse a directive statement
 pub fn parse_directive(parser: &mut Parser) -> Result<Statement, String> {
@@ -28,43 +30,17 @@ pub fn parse_directive(parser: &mut Parser) -> Result<Statement, String> {
                 
                 match token.token_type {
                     TokenType::StringLiteral => {
-                        // Store token info before borrowing
-                        let token_clone = token.clone();
-                        let file_name = parser.file_name.clone();
-                        
-                        // Better check for unclosed string - if it's the last token or followed by EOF/newline
-                        let is_last_token = parser.current == parser.tokens.len() - 1;
-                        let next_is_newline_or_eof = parser.current + 1 < parser.tokens.len() && 
-                            (parser.tokens[parser.current + 1].0.token_type == TokenType::NewLine ||
-                             parser.tokens[parser.current + 1].0.token_type == TokenType::EOF);
-                        
-                        // For unclosed strings, look for trailing newline within the value
-                        let str_value = token.value.clone();
-                        let appears_unclosed = str_value.ends_with('\n') || str_value.ends_with('\r');
-                        
-                        if is_last_token || next_is_newline_or_eof || appears_unclosed {
-                            if let Some(collector) = &mut parser.error_collector {
-                                collector.add_error_with_location(
-                                    ErrorType::UnclosedString,
-                                    "Unclosed string literal. String literals must be properly terminated with matching quotes.",
-                                    &file_name,
-                                    token_clone.line,
-                                    token_clone.column
-                                );
-                            }
-                        }
-                        
-                        values.push(Operand::String(token.value.clone()));
+                        values.push(Operand::String(token.value.to_string()));
                         parser.next_token();
                     },
                     TokenType::Immediate => {
-                        let value = token.value.clone();
+                        let value = token.value.to_string();
+                        warn_data_overflow(parser, &directive_name, &token);
                         values.push(Operand::Immediate(value));
                         parser.next_token();
                     },
-                    TokenType::LabelRef => {
-                        let value = token.value.clone();
-                        values.push(Operand::Label(value));
+                    TokenType::LabelRef | TokenType::Identifier => {
+                        values.push(Operand::Label(token.symbol()));
                         parser.next_token();
                     },
                     TokenType::NewLine | TokenType::EOF => {
@@ -81,7 +57,7 @@ pub fn parse_directive(parser: &mut Parser) -> Result<Statement, String> {
                     },
                     _ => {
                         let token_type = token.token_type.clone();
-                        let token_value = token.value.clone();
+                        let token_value = token.value.to_string();
                         
                         if let Some(collector) = &mut parser.error_collector {
                             let file_name = parser.file_name.clone();
@@ -138,13 +114,37 @@ pub fn parse_directive(parser: &mut Parser) -> Result<Statement, String> {
                 return Err(format!("Failed to parse equ directive at line {}. Equ directives should be in the format: symbol equ value", line));
             }
         },
+        "bits" | "use16" | "use32" | "use64" => {
+            parse_bits_directive(parser, &directive_name, &token)?
+        },
+        "align" => parse_align_directive(parser, &token)?,
+        "resb" | "resw" | "resd" | "resq" => {
+            let count = parser.current_token();
+            if count.token_type != TokenType::Immediate {
+                if let Some(collector) = &mut parser.error_collector {
+                    let file_name = parser.file_name.clone();
+                    
+                    collector.add_error_with_location(
+                        ErrorType::InvalidOperand,
+                        &format!("'{}' expects the number of elements to reserve, e.g. '{} 64'", directive_name, directive_name),
+                        &file_name,
+                        line,
+                        token.column
+                    );
+                }
+                
+                return Err(format!("Missing count for {} directive at line {}", directive_name, line));
+            }
+            parser.next_token();
+            vec![Operand::Immediate(count.value.into_owned())]
+        },
         _ => {
             if let Some(collector) = &mut parser.error_collector {
                 let file_name = parser.file_name.clone();
                 
                 collector.add_error_with_location(
                     ErrorType::UnknownDirective,
-                    &format!("Unknown directive: '{}'. Common directives include: section, db, dw, dd, dq, global, extern, equ", directive_name),
+                    &format!("Unknown directive: '{}'. Common directives include: section, db, dw, dd, dq, resb, global, extern, equ", directive_name),
                     &file_name,
                     line,
                     token.column
@@ -155,18 +155,47 @@ pub fn parse_directive(parser: &mut Parser) -> Result<Statement, String> {
         }
     };
     
+    // use16/use32/use64 are spellings of bits
+    let name = if directive_name.starts_with("use") { "bits".to_string() } else { directive_name };
+    
     Ok(Statement::Directive(Directive {
-        name: directive_name,
+        name,
         operands,
         line,
     }))
 }
 
+/// Warn when a data value doesn't fit the directive's element size
+fn warn_data_overflow(parser: &mut Parser, directive: &str, token: &Token) {
+    let bits = match directive {
+        "db" => 8,
+        "dw" => 16,
+        "dd" => 32,
+        _ => 64,
+    };
+    
+    let Some(overflow) = immediate_value(&token.value).and_then(|value| fit_immediate(value, bits, false).err()) else {
+        return;
+    };
+    
+    let file_name = parser.file_name.clone();
+    if let Some(collector) = &mut parser.error_collector {
+        collector.add_warning_with_location(
+            "overflow",
+            ErrorType::InvalidOperand,
+            &format!("Immediate out of range in {} directive: {}", directive, overflow),
+            &file_name,
+            token.line,
+            token.column
+        );
+    }
+}
+
 /// Parse a section directive
 fn parse_section_directive(parser: &mut Parser, line: usize) -> Result<Statement, String> {
     let token = parser.current_token();
     
-    if token.token_type != TokenType::LabelRef {
+    if token.token_type != TokenType::LabelRef && token.token_type != TokenType::Identifier {
         if let Some(collector) = &mut parser.error_collector {
             let file_name = parser.file_name.clone();
             
@@ -182,7 +211,7 @@ fn parse_section_directive(parser: &mut Parser, line: usize) -> Result<Statement
         return Err(format!("Expected section name after section directive, got {:?} at line {}", token.token_type, token.line));
     }
     
-    let section_name = token.value.clone();
+    let section_name = token.symbol();
     
     // Advance past the section name
     parser.next_token();
@@ -214,7 +243,7 @@ fn parse_global_directive(parser: &mut Parser, line: usize) -> Result<Statement,
         return Err(format!("Expected symbol name after global directive, got {:?} at line {}", token.token_type, token.line));
     }
     
-    let symbol_name = token.value.clone();
+    let symbol_name = token.symbol();
     
     // Advance past the symbol name
     parser.next_token();
@@ -230,7 +259,7 @@ fn parse_global_directive(parser: &mut Parser, line: usize) -> Result<Statement,
 fn parse_extern_directive(parser: &mut Parser, line: usize) -> Result<Statement, String> {
     let token = parser.current_token();
     
-    if token.token_type != TokenType::LabelRef {
+    if token.token_type != TokenType::LabelRef && token.token_type != TokenType::Identifier {
         if let Some(collector) = &mut parser.error_collector {
             let file_name = parser.file_name.clone();
             
@@ -246,7 +275,7 @@ fn parse_extern_directive(parser: &mut Parser, line: usize) -> Result<Statement,
         return Err(format!("Expected symbol name after extern directive, got {:?} at line {}", token.token_type, token.line));
     }
     
-    let symbol_name = token.value.clone();
+    let symbol_name = token.symbol();
     
     // Advance past the symbol name
     parser.next_token();
@@ -258,78 +287,148 @@ fn parse_extern_directive(parser: &mut Parser, line: usize) -> Result<Statement,
     }))
 }
 
-/// Parse an equ directive, which can use $ syntax
-fn parse_equ_directive(parser: &mut Parser, line: usize) -> Result<Statement, String> {
-    let mut operands = Vec::new();
+/// Parse `bits 16|32|64` (or `use16`/`use32`/`use64`) and switch the parser's code mode
+fn parse_bits_directive(parser: &mut Parser, directive: &str, token: &Token) -> Result<Vec<Operand>, String> {
+    let bits = match directive.strip_prefix("use") {
+        Some(bits) => bits.to_string(),
+        None => {
+            let value = parser.current_token();
+            if value.token_type == TokenType::Immediate {
+                parser.next_token();
+            }
+            value.value.into_owned()
+        }
+    };
     
-    // For equ, we need to handle the special case of $ - label
-    // This is commonly used to calculate the size of data
-    let token = parser.current_token();
+    if !matches!(bits.as_str(), "16" | "32" | "64") {
+        if let Some(collector) = &mut parser.error_collector {
+            let file_name = parser.file_name.clone();
+            
+            collector.add_error_with_location(
+                ErrorType::InvalidOperand,
+                &format!("Invalid mode '{}' for bits directive. Use bits 16, bits 32 or bits 64", bits.trim()),
+                &file_name,
+                token.line,
+                token.column
+            );
+        }
+        
+        return Err(format!("Invalid bits mode at line {}", token.line));
+    }
     
-    if token.token_type == TokenType::Immediate && token.value == "$" {
-        // This is a current location counter reference
-        parser.next_token(); // Consume $
+    parser.bits = bits.parse().unwrap_or(64);
+    Ok(vec![Operand::Immediate(bits)])
+}
+
+/// Parse `align N` or `align N, fill`, where N is a power of two and the
+/// fill is a byte value, `nop` for multi-byte NOPs or `int3`
+fn parse_align_directive(parser: &mut Parser, token: &Token) -> Result<Vec<Operand>, String> {
+    let boundary = parser.current_token();
+    let valid_boundary = boundary.token_type == TokenType::Immediate
+        && immediate_value(&boundary.value).is_some_and(|value| value > 0 && (value as u64).is_power_of_two());
+    if !valid_boundary {
+        return Err(align_error(parser, token, &format!(
+            "'align' expects a power of two to align to, got '{}'. Example: align 16 or align 16, 0xcc", boundary.value)));
+    }
+    parser.next_token();
+    let mut operands = vec![Operand::Immediate(boundary.value.into_owned())];
+
+    if parser.check(TokenType::Comma) {
+        parser.next_token();
+        let fill = parser.current_token();
+        let operand = match fill.token_type {
+            TokenType::Immediate if immediate_value(&fill.value).is_some_and(|value| (0..=0xFF).contains(&value)) =>
+                Operand::Immediate(fill.value.to_string()),
+            // `nop` tokenizes as an instruction, so any token type is accepted
+            _ if matches!(fill.value.to_lowercase().as_str(), "nop" | "int3") =>
+                Operand::Label(Symbol::intern(&fill.value.to_lowercase())),
+            _ => return Err(align_error(parser, token, &format!(
+                "'align' fills with a byte value from 0 to 0xff, 'nop' or 'int3', got '{}'", fill.value))),
+        };
+        parser.next_token();
+        operands.push(operand);
+    }
+    Ok(operands)
+}
+
+fn align_error(parser: &mut Parser, token: &Token, message: &str) -> String {
+    if let Some(collector) = &mut parser.error_collector {
+        let file_name = parser.file_name.clone();
         
-        // Check for minus operation
-        if parser.check(TokenType::Minus) {
-            parser.next_token(); // Consume minus
-            
-            // Check for label or another $ reference
-            let next_token = parser.current_token();
-            if next_token.token_type == TokenType::LabelRef {
-                let label = next_token.value.clone();
-                parser.next_token(); // Consume label
-                
-                // For now, we'll just add a placeholder value
-                // In a real implementation, this would be resolved during assembly
-                operands.push(Operand::Immediate("0".to_string()));
-                
-                // Handle further operations if needed (like -1)
-                if parser.check(TokenType::Minus) {
-                    parser.next_token(); // Consume minus
-                    
-                    let value_token = parser.current_token();
-                    if value_token.token_type == TokenType::Immediate {
-                        // Add another placeholder
-                        operands.push(Operand::Immediate(value_token.value.clone()));
-                        parser.next_token(); // Consume immediate
-                    }
-                }
-            } else {
-                if let Some(collector) = &mut parser.error_collector {
-                    let file_name = parser.file_name.clone();
-                    
-                    collector.add_error_with_location(
-                        ErrorType::InvalidOperand,
-                        &format!("Expected label after '$ -' in equ directive. The '$ - label' format is used to calculate the size of a data block. Example: size equ $ - data_start"),
-                        &file_name,
-                        next_token.line,
-                        next_token.column
-                    );
-                }
-                
-                return Err(format!("Expected label after $ - at line {}", line));
-            }
+        collector.add_error_with_location(
+            ErrorType::InvalidOperand,
+            message,
+            &file_name,
+            token.line,
+            token.column
+        );
+    }
+    format!("Invalid align directive at line {}", token.line)
+}
+
+/// Parse an equ directive: a `+`/`-` expression over numbers, symbols and `$`.
+///
+/// The expression is kept as text in a single immediate operand and evaluated
+/// once layout has given every label its address, so it may use labels that
+/// are defined further down.
+fn parse_equ_directive(parser: &mut Parser, line: usize) -> Result<Statement, String> {
+    let mut expression = Vec::new();
+    let mut expect_term = true;
+
+    while !parser.check(TokenType::NewLine) && !parser.check(TokenType::EOF) && !parser.check(TokenType::Comment) {
+        let token = parser.current_token();
+        let is_term = matches!(token.token_type, TokenType::Immediate | TokenType::Identifier | TokenType::LabelRef)
+            || token.value == "$";
+        let is_operator = matches!(token.token_type, TokenType::Plus | TokenType::Minus);
+        // A leading '-' negates the first term
+        let valid = if expect_term {
+            is_term || (expression.is_empty() && token.token_type == TokenType::Minus)
         } else {
-            // Just the $ by itself
-            operands.push(Operand::Immediate("0".to_string()));
+            is_operator
+        };
+
+        if !valid {
+            if let Some(collector) = &mut parser.error_collector {
+                let file_name = parser.file_name.clone();
+
+                collector.add_error_with_location(
+                    ErrorType::InvalidOperand,
+                    &format!("Unexpected '{}' in equ expression. Equ values are numbers, symbols and '$' joined with '+' or '-'. Example: size equ $ - data_start", token.value),
+                    &file_name,
+                    token.line,
+                    token.column
+                );
+            }
+
+            return Err(format!("Invalid equ expression at line {}", line));
         }
-    } else {
-        // Regular immediate value or other operand
-        operands.push(Operand::Immediate(token.value.clone()));
+
+        expect_term = !is_term;
+        expression.push(token.value);
         parser.next_token();
     }
-    
+
+    if expect_term {
+        let token = parser.current_token();
+        if let Some(collector) = &mut parser.error_collector {
+            let file_name = parser.file_name.clone();
+
+            collector.add_error_with_location(
+                ErrorType::InvalidOperand,
+                "Incomplete equ expression. Equ directives should be in the format: symbol equ value",
+                &file_name,
+                token.line,
+                token.column
+            );
+        }
+
+        return Err(format!("Incomplete equ expression at line {}", line));
+    }
+
     Ok(Statement::Directive(Directive {
         name: "equ".to_string(),
-        operands,
+        operands: vec![Operand::Immediate(expression.join(" "))],
         line,
     }))
 }
-
-// Helper function to check if we're at the end of file
-fn is_at_end_of_file(parser: &Parser) -> bool {
-    parser.current >= parser.tokens.len() || 
-    (parser.current < parser.tokens.len() && 
-     parser.tokens[parser.current].0.token_type == TokenType::EOF)
-} 
\ No newline at end of file
+ 
\ No newline at end of file
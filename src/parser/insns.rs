@@ -0,0 +1,338 @@
+//! Converting NASM's instruction database to an opcode table.
+//!
+//! NASM lists every instruction form it assembles in `x86/insns.dat`, one per
+//! line: the mnemonic, the operands, the encoding in brackets and the CPU
+//! flags.
+//!
+//! ```text
+//! ADD     rm64,reg64      [mr:  hle o64 01 /r]     X86_64,LONG,SM,LOCK
+//! ```
+//!
+//! [`import`] rewrites each form as a line of nasembler's opcode table, so
+//! the line above becomes `add | arith | r/m64, r64 | REX.W 01 /r | MR`.
+//! `Jcc`, `CMOVcc` and `SETcc` are expanded into one form per condition.
+//! Forms nasembler's table can't describe are skipped with a reason: operands
+//! such as segment, FPU, MMX and ZMM registers, EVEX and XOP encodings, far
+//! pointers, and forms that aren't valid in 64-bit mode. Later forms with the
+//! same name and operands as an earlier one are alternative encodings and
+//! are left out too.
+//!
+//! ```no_run
+//! use nasembler::parser::insns;
+//!
+//! let insns = std::fs::read_to_string("insns.dat").unwrap();
+//! let import = insns::import(&insns, true);
+//! std::fs::write("nasm_opcodes.txt", &import.table).unwrap();
+//! ```
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::parser::opcodes::{self, EncodingTemplate, OpcodeTable, OperandSpec};
+use crate::tokenizer;
+
+/// First line of the tables [`import`] writes
+pub const HEADER: &str = "# Generated from NASM's insns.dat by `nasembler opcodes import-nasm`";
+
+/// Condition codes and the suffixes NASM accepts for each, for `Jcc` and friends
+const CONDITIONS: [(u8, &[&str]); 16] = [
+    (0x0, &["o"]), (0x1, &["no"]), (0x2, &["b", "c", "nae"]), (0x3, &["ae", "nb", "nc"]),
+    (0x4, &["e", "z"]), (0x5, &["ne", "nz"]), (0x6, &["be", "na"]), (0x7, &["a", "nbe"]),
+    (0x8, &["s"]), (0x9, &["ns"]), (0xA, &["p", "pe"]), (0xB, &["np", "po"]),
+    (0xC, &["l", "nge"]), (0xD, &["ge", "nl"]), (0xE, &["le", "ng"]), (0xF, &["g", "nle"]),
+];
+
+/// Encoding codes that only matter to NASM's own encoder or disassembler,
+/// such as which prefixes a form tolerates
+const IGNORED_CODES: [&str; 21] = [
+    "odf", "o64nw", "adf", "np", "nw", "hle", "hlexr", "hlenl", "hlex", "nohi", "nof3", "norep",
+    "norexb", "norexx", "norexr", "norexw", "bnd", "nobnd", "jcc8", "jmp8", "jlen",
+];
+
+/// An insns.dat line that wasn't imported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub line: usize,
+    pub mnemonic: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}: {}", self.line, self.mnemonic, self.reason)
+    }
+}
+
+/// An opcode table converted from insns.dat
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    /// The table, in the format [`OpcodeTable::from_string`] reads
+    pub table: String,
+    /// Number of forms in the table
+    pub forms: usize,
+    pub skipped: Vec<Skipped>,
+}
+
+/// Convert insns.dat to an opcode table; with `known_only`, only forms of
+/// mnemonics the tokenizer recognizes are kept
+pub fn import(insns: &str, known_only: bool) -> Import {
+    let known: HashSet<&str> = tokenizer::instruction_names().collect();
+    let mut seen: HashSet<(String, Vec<OperandSpec>)> = HashSet::new();
+    let mut table = format!("{}\n", HEADER);
+    let mut forms = 0;
+    let mut skipped = Vec::new();
+    // Written before the first form of the section, so empty sections are left out
+    let mut section: Option<String> = None;
+
+    for (index, text) in insns.lines().enumerate() {
+        let line = index + 1;
+        let text = text.trim();
+        // ";#" starts a section heading, other ";" lines are comments
+        if let Some(heading) = text.strip_prefix(";#") {
+            section = Some(heading.trim().to_string());
+            continue;
+        }
+        if text.is_empty() || text.starts_with(';') {
+            continue;
+        }
+
+        let Some(mnemonic) = text.split_whitespace().next() else { continue };
+        let converted = match convert(text) {
+            Ok(converted) => converted,
+            Err(reason) => {
+                skipped.push(Skipped { line, mnemonic: mnemonic.to_string(), reason });
+                continue;
+            },
+        };
+
+        for form in converted {
+            if known_only && !known.contains(form.name.as_str()) {
+                continue;
+            }
+            let parsed = match opcodes::parse_line(&form.to_string()) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => continue,
+                Err(reason) => {
+                    skipped.push(Skipped { line, mnemonic: form.name.clone(), reason });
+                    continue;
+                },
+            };
+            if let Some(reason) = parsed.iter().find_map(opcodes::inconsistency) {
+                skipped.push(Skipped { line, mnemonic: form.name.clone(), reason });
+                continue;
+            }
+            if parsed.iter().any(|info| !seen.insert((info.name.clone(), info.operand_specs.clone()))) {
+                // An alternative encoding of a form already in the table
+                continue;
+            }
+            forms += 1;
+            if let Some(heading) = section.take() {
+                table.push_str(&format!("\n# {}\n", heading));
+            }
+            table.push_str(&format!("{}\n", form));
+        }
+    }
+
+    Import { table, forms, skipped }
+}
+
+/// A form converted from insns.dat, one line of the opcode table
+struct Form {
+    name: String,
+    category: String,
+    operands: Vec<String>,
+    machine_code: String,
+    encoding: String,
+}
+
+impl fmt::Display for Form {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operands = self.operands.join(", ");
+        let separator = if operands.is_empty() { "" } else { " " };
+        write!(f, "{:<8}| {} | {}{}| {} | {}", self.name, self.category, operands, separator, self.machine_code, self.encoding)
+    }
+}
+
+/// The forms an insns.dat line stands for, more than one for `Jcc` and friends
+fn convert(text: &str) -> Result<Vec<Form>, String> {
+    let (mnemonic, rest) = split_field(text);
+    let (operands, rest) = split_field(rest);
+    let Some(rest) = rest.strip_prefix('[') else {
+        return Err("the encoding isn't in the bracketed form".to_string());
+    };
+    let Some((encoding, flags)) = rest.split_once(']') else {
+        return Err("the encoding has no closing bracket".to_string());
+    };
+    let flags: Vec<&str> = flags.trim().split(',').map(str::trim).collect();
+    if flags.contains(&"NOLONG") {
+        return Err("not valid in 64-bit mode".to_string());
+    }
+
+    // "rm: 66 0f 6f /r", or "rvm:fv: evex..." with an EVEX tuple type
+    let (roles, codes) = match encoding.split_once(':') {
+        Some((roles, codes)) => (roles.trim(), codes.rsplit(':').next().unwrap_or(codes)),
+        None => ("", encoding),
+    };
+    let codes: Vec<&str> = codes.split_whitespace().collect();
+    let relative = codes.iter().any(|code| matches!(*code, "rel" | "rel8"));
+
+    let operands: Vec<&str> = match operands {
+        "void" => Vec::new(),
+        operands => operands.split(',').collect(),
+    };
+    let operands = operands.iter()
+        .map(|operand| operand_kind(operand, relative))
+        .collect::<Result<Vec<_>, _>>()?;
+    let encoding = operand_encoding(roles, &codes, &operands)?;
+
+    let name = mnemonic.to_lowercase();
+    let conditions: Vec<(u8, &str)> = match name.strip_suffix("cc") {
+        Some(_) if codes.iter().any(|code| code.ends_with("+c")) => CONDITIONS.iter()
+            .flat_map(|(code, suffixes)| suffixes.iter().map(move |suffix| (*code, *suffix)))
+            .collect(),
+        _ => vec![(0, "")],
+    };
+
+    let names: Vec<(u8, String)> = conditions.into_iter()
+        .map(|(condition, suffix)| match suffix {
+            "" => (condition, name.clone()),
+            suffix => (condition, format!("{}{}", &name[..name.len() - 2], suffix)),
+        })
+        .collect();
+    // Every condition gets the category of whichever the built-in table has
+    let category = names.iter()
+        .find_map(|(_, name)| OpcodeTable::builtin().get_info(name))
+        .map(|info| info.category.clone())
+        .unwrap_or_else(|| guess_category(&flags));
+
+    names.into_iter()
+        .map(|(condition, name)| Ok(Form {
+            name,
+            category: category.clone(),
+            operands: operands.clone(),
+            machine_code: machine_code(&codes, condition)?,
+            encoding: encoding.clone(),
+        }))
+        .collect()
+}
+
+/// The first whitespace-separated field and the rest of the line
+fn split_field(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+/// The opcode table operand kind for an insns.dat operand such as `rm64`,
+/// `xmmrm128` or `imm|near`
+fn operand_kind(operand: &str, relative: bool) -> Result<String, String> {
+    // "xmmreg*" may be left out, which the table has no way to say
+    let operand = operand.trim_end_matches('*');
+    let mut modifiers = operand.split('|');
+    let base = modifiers.next().unwrap_or_default();
+    if let Some(modifier) = modifiers.find(|modifier| !matches!(*modifier, "near" | "short")) {
+        return Err(format!("the '{}' operand modifier isn't supported", modifier));
+    }
+
+    let width = |prefix: &str| base.strip_prefix(prefix).filter(|width| matches!(*width, "8" | "16" | "32" | "64"));
+    let kind = match base {
+        "imm" if relative => "rel".to_string(),
+        "imm" => "imm".to_string(),
+        "mem" => "m".to_string(),
+        "sbyteword" | "sbyteword16" | "sbytedword" | "sbytedword32" | "sbytedword64" => "imm8".to_string(),
+        "udword" | "udword64" | "sdword" | "sdword64" => "imm32".to_string(),
+        "xmmreg" => "xmm".to_string(),
+        "ymmreg" => "ymm".to_string(),
+        "xmmrm" | "xmmrm8" | "xmmrm16" | "xmmrm32" | "xmmrm64" | "xmmrm128" => "xmm/m".to_string(),
+        "ymmrm" | "ymmrm256" => "ymm/m".to_string(),
+        _ => if let Some(width) = width("reg") {
+            format!("r{}", width)
+        } else if let Some(width) = width("rm") {
+            format!("r/m{}", width)
+        } else if let Some(width) = width("imm") {
+            format!("imm{}", width)
+        } else if base.starts_with("mem") && base[3..].parse::<u16>().is_ok() {
+            "m".to_string()
+        } else if let Some(register) = base.strip_prefix("reg_").or(Some(base)).filter(|name| tokenizer::register_type(name).is_some()) {
+            register.to_string()
+        } else {
+            return Err(format!("the '{}' operand isn't supported", base));
+        },
+    };
+    Ok(kind)
+}
+
+/// The operand encoding column for the insns.dat operand roles, such as `mr`
+fn operand_encoding(roles: &str, codes: &[&str], operands: &[String]) -> Result<String, String> {
+    if operands.is_empty() {
+        return Ok("ZO".to_string());
+    }
+    // Forms without roles only have operands implied by the opcode, such as `ret`'s registers
+    if roles.is_empty() {
+        return Ok("-".repeat(operands.len()));
+    }
+    if roles.len() != operands.len() {
+        return Err(format!("the roles '{}' don't give one letter per operand", roles));
+    }
+    let in_opcode = codes.iter().any(|code| code.ends_with("+r"));
+    roles.chars()
+        .map(|role| match role {
+            'r' if in_opcode => Ok('O'),
+            'r' => Ok('R'),
+            'm' => Ok('M'),
+            'i' if operands.iter().any(|operand| operand == "rel") => Ok('D'),
+            'i' => Ok('I'),
+            'v' => Ok('V'),
+            '-' => Ok('-'),
+            role => Err(format!("the '{}' operand role isn't supported", role)),
+        })
+        .collect()
+}
+
+/// The machine code column for insns.dat codes such as `o64 81 /0 id,s`,
+/// adding `condition` to a `+c` opcode byte
+fn machine_code(codes: &[&str], condition: u8) -> Result<String, String> {
+    let mut tokens = Vec::new();
+    for &code in codes {
+        let byte = |text: &str| match text.len() {
+            2 => u8::from_str_radix(text, 16).ok(),
+            _ => None,
+        };
+        let token = match code {
+            code if IGNORED_CODES.contains(&code) => continue,
+            "o16" | "o32" => code.to_string(),
+            "o64" => "REX.W".to_string(),
+            "f2i" | "mustrepne" => "F2".to_string(),
+            "f3i" | "mustrep" => "F3".to_string(),
+            "wait" => "9B".to_string(),
+            "/r" | "/0" | "/1" | "/2" | "/3" | "/4" | "/5" | "/6" | "/7" => code.to_string(),
+            "ib" | "ib,s" | "ib,u" => "ib".to_string(),
+            "iw" => "iw".to_string(),
+            "id" | "id,s" => "id".to_string(),
+            "iq" => "iq".to_string(),
+            "rel8" => "cb".to_string(),
+            "rel" => "cd".to_string(),
+            code if code.starts_with("vex.") => code.to_uppercase(),
+            code => if let Some(value) = code.strip_suffix("+r").and_then(byte) {
+                format!("{:02X}+r", value)
+            } else if let Some(value) = code.strip_suffix("+c").and_then(byte) {
+                format!("{:02X}", value + condition)
+            } else if let Some(value) = byte(code) {
+                format!("{:02X}", value)
+            } else {
+                return Err(format!("the '{}' encoding code isn't supported", code));
+            },
+        };
+        tokens.push(token);
+    }
+    // Written back in canonical form, e.g. without VEX.NDS
+    EncodingTemplate::parse(&tokens.join(" ")).map(|template| template.to_string())
+}
+
+/// A category for mnemonics the built-in table doesn't have, from the CPU flags
+fn guess_category(flags: &[&str]) -> String {
+    let simd = flags.iter().any(|flag| ["SSE", "AVX", "MMX"].iter().any(|prefix| flag.starts_with(prefix)));
+    if simd { "simd" } else { "other" }.to_string()
+}
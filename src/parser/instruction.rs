@@ -1,7 +1,10 @@
-use crate::parser::ast::{Statement, Instruction, Operand, MemoryReference};
-use crate::tokenizer::TokenType;
+use crate::encoder::{immediate_overflows, immediate_value};
+use crate::parser::ast::{Statement, Instruction, Operand, MemoryReference, SpecialSymbol, size_keyword};
+use crate::parser::opcodes::{OperandMismatch, OperandSpec};
+use crate::tokenizer::{self, Span, Token, TokenType};
 use crate::parser::Parser;
 use crate::error::ErrorType;
+use crate::symbol::Symbol;
 
 /// Parse an instruction statement (e.g., "mov eax, ebx")
 pub fn parse_instruction(parser: &mut Parser) -> Result<Statement, String> {
@@ -13,6 +16,9 @@ pub fn parse_instruction(parser: &mut Parser) -> Result<Statement, String> {
     // Advance past the instruction token
     parser.next_token();
     
+    let errors_before = error_count(parser);
+    let operands_start = parser.current;
+    
     // Parse operands
     let operands = match parse_operands(parser) {
         Ok(ops) => ops,
@@ -34,7 +40,7 @@ pub fn parse_instruction(parser: &mut Parser) -> Result<Statement, String> {
             
             if parser.continue_on_errors {
                 // Skip to next line
-                while parser.check(TokenType::NewLine) == false && !parser.is_at_end() {
+                while !parser.check(TokenType::NewLine) && !parser.is_at_end() {
                     parser.advance();
                 }
                 Vec::new() // Return empty operands to continue
@@ -44,12 +50,229 @@ pub fn parse_instruction(parser: &mut Parser) -> Result<Statement, String> {
         }
     };
     
-    Ok(Statement::Instruction(Instruction {
-        name: instruction_name,
+    // Only check operand kinds if parsing them didn't already report a problem
+    if error_count(parser) == errors_before {
+        let spans = operand_spans(&parser.tokens[operands_start..parser.current]);
+        validate_operands(parser, &token, &instruction_name, &operands, &spans)?;
+        validate_mode(parser, &token, &instruction_name, &operands)?;
+    }
+    
+    let instruction = Instruction {
+        name: Symbol::intern(&instruction_name),
         operands,
         machine_code: Vec::new(), // Machine code will be filled in later
         line,
-    }))
+    };
+    
+    warn_immediate_overflows(parser, &token, &instruction);
+    
+    Ok(Statement::Instruction(instruction))
+}
+
+/// Warn about immediates that get truncated when the instruction is encoded
+fn warn_immediate_overflows(parser: &mut Parser, token: &Token, instruction: &Instruction) {
+    let file_name = parser.file_name.clone();
+    if let Some(collector) = &mut parser.error_collector {
+        for overflow in immediate_overflows(instruction) {
+            collector.add_warning_with_location(
+                "overflow",
+                ErrorType::InvalidOperand,
+                &format!("Immediate out of range for '{}' instruction: {}", instruction.name, overflow),
+                &file_name,
+                token.line,
+                token.column
+            );
+        }
+    }
+}
+
+/// Number of diagnostics collected so far
+fn error_count(parser: &Parser) -> usize {
+    parser.error_collector.as_ref().map_or(0, |collector| collector.errors().len())
+}
+
+/// First token and full span of each operand in `tokens`, split at commas
+fn operand_spans<'src>(tokens: &[(Token<'src>, usize)]) -> Vec<(Token<'src>, Span)> {
+    let mut operands: Vec<(Token, Span)> = Vec::new();
+    let mut new_operand = true;
+    for (token, _) in tokens {
+        match token.token_type {
+            TokenType::Comma => new_operand = true,
+            TokenType::Comment | TokenType::NewLine | TokenType::EOF => break,
+            _ if new_operand => {
+                operands.push((token.clone(), token.span));
+                new_operand = false;
+            },
+            _ => if let Some((_, span)) = operands.last_mut() {
+                *span = span.to(token.span);
+            },
+        }
+    }
+    operands
+}
+
+/// Check operands against the instruction's forms in the opcode table.
+///
+/// A wrong operand is underlined in full (`[rbx + 8]`, not just `[`); a wrong
+/// operand count underlines all operands.
+fn validate_operands(
+    parser: &mut Parser,
+    token: &Token,
+    instruction: &str,
+    operands: &[Operand],
+    spans: &[(Token, Span)],
+) -> Result<(), String> {
+    let (message, target) = match parser.opcode_table().validate(instruction, operands) {
+        Ok(()) => return Ok(()),
+        Err(OperandMismatch::Count { expected, found }) => {
+            let target = spans.first().zip(spans.last())
+                .map(|((first, start), (_, end))| (first.clone(), start.to(*end)));
+            (operand_count_message(instruction, &expected, found), target)
+        },
+        Err(OperandMismatch::Kind { position, expected }) => {
+            let expected: Vec<&str> = expected.iter().map(OperandSpec::describe).collect();
+            let message = format!("Invalid {} operand '{}' for '{}' instruction: expected {}. {}",
+                    ordinal(position), operands[position], instruction,
+                    join_alternatives(&expected), get_example_operands(instruction));
+            (message, spans.get(position).cloned())
+        },
+        Err(OperandMismatch::Size { position, widths }) => {
+            let keywords: Vec<&str> = widths.iter().filter_map(|&width| size_keyword(width)).collect();
+            let message = format!("Operation size not specified for '{}': write {} before the memory operand, as in '{} {}'",
+                    instruction, join_alternatives(&keywords), keywords.first().copied().unwrap_or("qword"), operands[position]);
+            (message, spans.get(position).cloned())
+        },
+    };
+    
+    let file_name = parser.file_name.clone();
+    if let Some(collector) = &mut parser.error_collector {
+        match target {
+            Some((first, span)) => collector.add_error_with_span(
+                ErrorType::InvalidOperand,
+                &message,
+                &file_name,
+                first.line,
+                first.column,
+                span
+            ),
+            None => collector.add_error_with_location(
+                ErrorType::InvalidOperand,
+                &message,
+                &file_name,
+                token.line,
+                token.column
+            ),
+        }
+    }
+    
+    if parser.continue_on_errors {
+        Ok(())
+    } else {
+        Err(message)
+    }
+}
+
+/// Check operands against the code mode selected with `bits`
+fn validate_mode(parser: &mut Parser, token: &Token, instruction: &str, operands: &[Operand]) -> Result<(), String> {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    
+    // 64-bit mode has no 32-bit push or pop, only 64 and 16-bit ones
+    if let (64, "push" | "pop", [Operand::Register(register)]) = (parser.bits, instruction, operands) {
+        if tokenizer::register_type(register) == Some(TokenType::Reg32Bit) {
+            let wide = register.strip_prefix('e').map_or_else(|| register.trim_end_matches('d').to_string(), |rest| format!("r{}", rest));
+            errors.push(format!("'{} {}' is not encodable in 64-bit mode: the stack takes 64-bit or 16-bit registers, as in '{} {}'",
+                                instruction, register, instruction, wide));
+        }
+    }
+    
+    if parser.bits != 64 {
+        for operand in operands {
+            match operand {
+                Operand::Register(register) if requires_long_mode(register) => {
+                    errors.push(format!("Register '{}' is not available in {}-bit mode", register, parser.bits));
+                },
+                Operand::Memory(memory) => {
+                    for register in [&memory.base, &memory.index].into_iter().flatten() {
+                        if register == "rip" {
+                            errors.push("RIP-relative addressing is only available in 64-bit mode".to_string());
+                        } else if requires_long_mode(register) {
+                            errors.push(format!("Register '{}' is not available in {}-bit mode", register, parser.bits));
+                        } else if parser.bits == 16 && tokenizer::register_type(register) == Some(TokenType::Reg32Bit) {
+                            warnings.push(format!("32-bit address register '{}' in 16-bit mode needs an address-size prefix (0x67)", register));
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+    
+    let file_name = parser.file_name.clone();
+    if let Some(collector) = &mut parser.error_collector {
+        for warning in &warnings {
+            collector.add_warning_with_location("address-size", ErrorType::InvalidOperand, warning, &file_name, token.line, token.column);
+        }
+        for error in &errors {
+            collector.add_error_with_location(ErrorType::InvalidOperand, error, &file_name, token.line, token.column);
+        }
+    }
+    
+    match errors.into_iter().next() {
+        Some(error) if !parser.continue_on_errors => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Registers that can only be encoded in 64-bit mode: 64-bit GPRs and the ones needing a REX prefix
+fn requires_long_mode(register: &str) -> bool {
+    let extended = register.strip_prefix('r').is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()));
+    extended
+        || matches!(register, "spl" | "bpl" | "sil" | "dil" | "rip")
+        || tokenizer::register_type(register) == Some(TokenType::Reg64Bit)
+}
+
+/// Describe a wrong operand count, given the counts the instruction's forms accept
+fn operand_count_message(instruction: &str, expected: &[usize], found: usize) -> String {
+    let min = expected.first().copied().unwrap_or(0);
+    let max = expected.last().copied().unwrap_or(0);
+    let counts: Vec<String> = expected.iter().map(|count| count.to_string()).collect();
+    let counts: Vec<&str> = counts.iter().map(String::as_str).collect();
+    let operand_examples = get_example_operands(instruction);
+    
+    if max == 0 {
+        format!("Instruction '{}' doesn't take operands, but found {}. Remove the operands.", instruction, found)
+    } else if found == 0 {
+        format!("Instruction '{}' requires {} but none were provided. {}",
+                instruction, if min == 1 { "an operand" } else { "operands" }, operand_examples)
+    } else if found < min {
+        format!("Instruction '{}' requires {} operands, but found {}. {}",
+                instruction, join_alternatives(&counts), found, operand_examples)
+    } else if found > max {
+        format!("Instruction '{}' requires {} {} operands, but found {}. Remove extra operands.",
+                instruction, if expected.len() == 1 { "exactly" } else { "at most" }, max, found)
+    } else {
+        format!("Instruction '{}' takes {} operands, but found {}. {}",
+                instruction, join_alternatives(&counts), found, operand_examples)
+    }
+}
+
+/// "a", "a or b", "a, b or c"
+fn join_alternatives(items: &[&str]) -> String {
+    match items {
+        [] => String::new(),
+        [only] => only.to_string(),
+        [rest @ .., last] => format!("{} or {}", rest.join(", "), last),
+    }
+}
+
+fn ordinal(position: usize) -> &'static str {
+    match position {
+        0 => "first",
+        1 => "second",
+        2 => "third",
+        _ => "next",
+    }
 }
 
 /// Parse operands for an instruction
@@ -58,38 +281,6 @@ fn parse_operands(parser: &mut Parser) -> Result<Vec<Operand>, String> {
     
     // Check if we have any operands at all
     if parser.check(TokenType::NewLine) || parser.check(TokenType::EOF) {
-        // For instructions that require operands (like mov), this is an error
-        let instruction = parser.tokens[parser.current - 1].0.value.to_lowercase();
-        if requires_operands(&instruction) {
-            let token = &parser.tokens[parser.current - 1].0;
-            let file_name = parser.file_name.clone();
-            
-            if let Some(collector) = &mut parser.error_collector {
-                let operand_examples = get_example_operands(&instruction);
-                let msg = format!(
-                    "Instruction '{}' requires {} but none were provided. {}",
-                    instruction,
-                    if required_operand_count(&instruction).unwrap_or(1) == 1 {
-                        "an operand"
-                    } else {
-                        "operands"
-                    },
-                    operand_examples
-                );
-                
-                collector.add_error_with_location(
-                    ErrorType::InvalidOperand,
-                    &msg,
-                    &file_name,
-                    token.line,
-                    token.column
-                );
-            }
-            
-            if !parser.continue_on_errors {
-                return Err(format!("Missing operand for instruction '{}'", instruction));
-            }
-        }
         return Ok(operands);
     }
     
@@ -166,39 +357,6 @@ fn parse_operands(parser: &mut Parser) -> Result<Vec<Operand>, String> {
         }
     }
     
-    // Check if instruction requires specific number of operands
-    let instruction = parser.tokens[parser.current - operands.len() - 1].0.value.to_lowercase();
-    if let Some(required) = required_operand_count(&instruction) {
-        if operands.len() != required {
-            let token = &parser.tokens[parser.current - operands.len() - 1].0;
-            let file_name = parser.file_name.clone();
-            
-            if let Some(collector) = &mut parser.error_collector {
-                let operand_examples = get_example_operands(&instruction);
-                let message = if operands.len() < required {
-                    format!("Instruction '{}' requires {} operands, but found {}. {}", 
-                            instruction, required, operands.len(), operand_examples)
-                } else {
-                    format!("Instruction '{}' requires exactly {} operands, but found {}. Remove extra operands.", 
-                            instruction, required, operands.len())
-                };
-                
-                collector.add_error_with_location(
-                    ErrorType::InvalidOperand,
-                    &message,
-                    &file_name,
-                    token.line,
-                    token.column
-                );
-            }
-            
-            if !parser.continue_on_errors {
-                return Err(format!("Instruction '{}' requires {} operands, found {}",
-                                  instruction, required, operands.len()));
-            }
-        }
-    }
-    
     Ok(operands)
 }
 
@@ -235,6 +393,9 @@ fn get_example_operands(instruction: &str) -> &'static str {
                 "jle" => "Example: jle label",
                 _ => "Example: jXX label", // Should never happen
             },
+        // The rest of the conditional jumps
+        name if name.starts_with('j') => "Example: jXX label",
+        name if name.starts_with("set") => "Example: sete al",
         "call" => "Example: call function_name",
         "lea" => "Example: lea rax, [rbx + 8]",
         "shl" | "shr" => 
@@ -245,33 +406,11 @@ fn get_example_operands(instruction: &str) -> &'static str {
             },
         "ret" => "This instruction doesn't need any operands",
         "syscall" => "This instruction doesn't need any operands",
-        "nop" => "This instruction doesn't need any operands",
+        "nop" => "Example: nop or nop dword [rax]",
         _ => "Check the x86-64 assembly manual for correct syntax",
     }
 }
 
-/// Determine if an instruction requires operands
-fn requires_operands(instruction: &str) -> bool {
-    match instruction {
-        "mov" | "add" | "sub" | "mul" | "div" | "and" | "or" | "xor" | "cmp" |
-        "shl" | "shr" | "jmp" | "je" | "jne" | "jg" | "jge" | "jl" | "jle" |
-        "call" | "lea" => true,
-        _ => false,
-    }
-}
-
-/// Determine the required number of operands for an instruction
-fn required_operand_count(instruction: &str) -> Option<usize> {
-    match instruction {
-        "mov" | "add" | "sub" | "and" | "or" | "xor" | "cmp" |
-        "shl" | "shr" | "lea" => Some(2),  // Two operands
-        "mul" | "div" | "jmp" | "je" | "jne" | "jg" | "jge" | "jl" | "jle" |
-        "call" | "push" | "pop" => Some(1),  // One operand
-        "ret" | "syscall" | "nop" => Some(0),  // No operands
-        _ => None,  // Unknown instruction
-    }
-}
-
 /// Parse a single operand
 fn parse_operand(parser: &mut Parser) -> Result<Operand, String> {
     let token = parser.current_token();
@@ -280,126 +419,217 @@ fn parse_operand(parser: &mut Parser) -> Result<Operand, String> {
         TokenType::Register | TokenType::Reg64Bit | TokenType::Reg32Bit | 
         TokenType::Reg16Bit | TokenType::Reg8Bit | TokenType::RegXMM | 
         TokenType::RegYMM | TokenType::RegZMM | TokenType::RegSpecial => {
-            let register = token.value.to_lowercase();
+            let register = Symbol::intern(&token.value.to_lowercase());
             parser.next_token();
             Ok(Operand::Register(register))
         },
         TokenType::Immediate => {
-            let immediate = token.value.clone();
+            let immediate = token.value.to_string();
             parser.next_token();
             Ok(Operand::Immediate(immediate))
         },
-        TokenType::LabelRef => {
-            let label = token.value.clone();
+        // A size keyword in front of a memory reference, as in `byte [rsi]`
+        TokenType::Identifier if parser.peek_ahead(1).is_some_and(|(next, _)| next.token_type == TokenType::OpenBracket) => {
+            let Some(size) = size_bits(&token.value) else {
+                return Err(format!("'{}' isn't an operand size. Write byte, word, dword or qword before a memory reference, as in byte [rsi]", token.value));
+            };
+            parser.next_token();
+            match parse_memory_reference(parser)? {
+                Operand::Memory(memory) => Ok(Operand::Memory(memory.size(size))),
+                operand => Err(format!("'{}' can't be given a size", operand)),
+            }
+        },
+        TokenType::LabelRef | TokenType::Identifier => {
+            let label = token.symbol();
             parser.next_token();
-            Ok(Operand::Label(label))
+            parse_wrt(parser, label)
         },
         TokenType::OpenBracket => {
             // This is a memory reference
             parse_memory_reference(parser)
         },
+        TokenType::OpenParen | TokenType::CloseParen | TokenType::ShiftLeft | TokenType::ShiftRight
+        | TokenType::Ampersand | TokenType::Pipe | TokenType::Caret | TokenType::Tilde => {
+            Err(format!("Constant expressions using '{}' aren't supported in operands yet. Compute the value, or define it with equ", token.value))
+        },
         _ => {
             Err(format!("Unexpected token in operand: {:?}. Expected a register, immediate value, or memory reference", token.token_type))
         }
     }
 }
 
+/// The label operand `label`, taken relative to a special symbol if `wrt` follows it
+fn parse_wrt(parser: &mut Parser, label: Symbol) -> Result<Operand, String> {
+    Ok(match parse_special(parser, label)? {
+        Some(special) => Operand::Wrt(label, special),
+        None => Operand::Label(label),
+    })
+}
+
+/// The special symbol after `label wrt`, if `wrt` follows the label
+fn parse_special(parser: &mut Parser, label: Symbol) -> Result<Option<SpecialSymbol>, String> {
+    let token = parser.current_token();
+    if token.token_type != TokenType::Identifier || !token.value.eq_ignore_ascii_case("wrt") {
+        return Ok(None);
+    }
+    parser.next_token();
+    let special = parser.current_token();
+    let Some(special) = SpecialSymbol::from_name(&special.value) else {
+        return Err(format!("Expected a special symbol after 'wrt', found '{}'. Use {} wrt ..got, ..plt or ..gotpcrel", special.value, label));
+    };
+    parser.next_token();
+    Ok(Some(special))
+}
+
+/// Size in bits named by a size keyword such as `dword`
+fn size_bits(keyword: &str) -> Option<u16> {
+    match keyword.to_lowercase().as_str() {
+        "byte" => Some(8),
+        "word" => Some(16),
+        "dword" => Some(32),
+        "qword" => Some(64),
+        _ => None,
+    }
+}
+
+/// Whether `name` is a segment register, which can prefix a memory reference
+fn is_segment_register(name: &str) -> bool {
+    matches!(name.to_lowercase().as_str(), "cs" | "ds" | "es" | "fs" | "gs" | "ss")
+}
+
 /// Parse a memory reference (e.g., [rax], [rbx+4], [rcx+rdx*2+8])
 fn parse_memory_reference(parser: &mut Parser) -> Result<Operand, String> {
     // Skip the opening bracket
     parser.next_token();
     
-    let token = parser.current_token();
+    let mut token = parser.current_token();
     
-    // Check for register or label
-    let base = if token.token_type == TokenType::Register || 
-               token.token_type == TokenType::Reg64Bit || 
-               token.token_type == TokenType::Reg32Bit || 
-               token.token_type == TokenType::Reg16Bit || 
-               token.token_type == TokenType::Reg8Bit {
-        let register = token.value.to_lowercase();
-        parser.next_token();
-        Some(register)
-    } else if token.token_type == TokenType::LabelRef || token.token_type == TokenType::Identifier {
-        let label = token.value.clone();
+    // A segment override comes first: [fs:var], [gs:0], [fs:rax]
+    let segment = match parser.peek_ahead(1) {
+        Some((next, _)) if next.token_type == TokenType::Colon && is_segment_register(&token.value) => {
+            let segment = Symbol::intern(&token.value.to_lowercase());
+            parser.next_token();
+            parser.next_token();
+            token = parser.current_token();
+            Some(segment)
+        },
+        _ => None,
+    };
+    // `rel` asks for RIP-relative addressing, which 64-bit label references use anyway
+    if token.value.eq_ignore_ascii_case("rel") && parser.peek_ahead(1).is_some_and(|(next, _)| next.token_type != TokenType::CloseBracket) {
         parser.next_token();
-        
-        // For simplicity, treat labels as special case and return early
-        if parser.check(TokenType::CloseBracket) {
-            parser.next_token(); // Skip closing bracket
-            return Ok(Operand::Label(label));
-        } else {
-            // Check for displacement operations like [label-1]
-            if parser.check(TokenType::Minus) || parser.check(TokenType::Plus) {
-                // Skip the operator and parse the rest
-                parser.next_token(); 
-                // For now, we'll ignore the displacement and just return the label
-                // In a real implementation, we'd handle the displacement properly
-                
-                // Skip any immediate values
-                if parser.current_token().token_type == TokenType::Immediate {
-                    parser.next_token();
-                }
-                
-                // Skip to the closing bracket
-                if parser.check(TokenType::CloseBracket) {
-                    parser.next_token();
-                    return Ok(Operand::Label(label));
+    }
+    
+    let mut memory = MemoryReference { segment, base: None, index: None, scale: None, displacement: None, size: None, wrt: None };
+    let mut label: Option<Symbol> = None;
+    let mut offset: i128 = 0;
+    let mut offsets = Vec::new();
+    let mut negative = false;
+    loop {
+        let token = parser.current_token();
+        if is_general_register(&token.token_type) || token.value.eq_ignore_ascii_case("rip") {
+            let register = Symbol::intern(&token.value.to_lowercase());
+            parser.next_token();
+            if negative {
+                return Err(format!("Registers can't be subtracted in a memory reference, as in '-{}'. Valid forms: [reg], [reg+offset], [reg+reg*scale]", register));
+            }
+            let scale = if parser.check(TokenType::Asterisk) {
+                parser.next_token();
+                Some(parse_scale(parser)?)
+            } else {
+                None
+            };
+            add_register(&mut memory, register, scale)?;
+        } else if token.token_type == TokenType::Immediate {
+            parser.next_token();
+            // `4*rcx` is the index written with its scale first
+            if parser.check(TokenType::Asterisk) {
+                parser.next_token();
+                let register = parser.current_token();
+                if negative || !is_general_register(&register.token_type) {
+                    return Err(format!("Expected an index register after '{}*' in memory reference, as in [rbx+{}*rcx]", token.value, token.value));
                 }
+                parser.next_token();
+                let register = Symbol::intern(&register.value.to_lowercase());
+                add_register(&mut memory, register, Some(scale_factor(&token.value)?))?;
+            } else {
+                let Some(value) = immediate_value(&token.value) else {
+                    return Err(format!("'{}' isn't a valid displacement in memory reference", token.value));
+                };
+                offset += if negative { -value } else { value };
+                offsets.push((negative, token.value.into_owned()));
             }
-            
-            return Err(format!("Expected closing bracket ']' after label in memory reference. Memory references with labels should be in the form [label] or [label+offset]"))
-        }
-    } else {
-        None
-    };
-    
-    // Check for the rest of the components
-    let index = None; // We're simplifying for now
-    let scale = None;
-    let displacement = None;
-    
-    // Handle operators and additional components
-    if parser.check(TokenType::Plus) || parser.check(TokenType::Minus) {
-        // Get operator type for better error messages
-        let operator = parser.current_token().token_type.clone();
-        parser.next_token(); // Skip the operator
-        
-        // Check for the next token
-        let next_token = parser.current_token();
-        
-        // If it's an unexpected token type, provide a better error message
-        if next_token.token_type != TokenType::Register && 
-           next_token.token_type != TokenType::Reg64Bit && 
-           next_token.token_type != TokenType::Reg32Bit && 
-           next_token.token_type != TokenType::Reg16Bit && 
-           next_token.token_type != TokenType::Reg8Bit && 
-           next_token.token_type != TokenType::Immediate {
-            
-            return Err(format!("Invalid expression in memory reference after '{}'. Expected a register or immediate value, found {:?}. Valid forms: [reg], [reg+offset], [reg+reg*scale]", 
-                              if operator == TokenType::Plus { "+" } else { "-" }, 
-                              next_token.token_type));
+        } else if matches!(token.token_type, TokenType::LabelRef | TokenType::Identifier) {
+            if label.is_some() || negative {
+                return Err(format!("A memory reference can only add one label, found '{}'. Valid forms: [label], [label+offset], [reg+label]", token.value));
+            }
+            label = Some(token.symbol());
+            parser.next_token();
+            // `[rel label wrt ..gotpcrel]` loads the label's address from the global offset table
+            memory.wrt = parse_special(parser, token.symbol())?;
+        } else {
+            return Err(format!("Invalid expression in memory reference: expected a register, number or label, found {:?}. Valid forms: [reg], [reg+offset], [reg+reg*scale], [label]",
+                               token.token_type));
         }
         
-        // Skip to the closing bracket even if we have an error, to continue parsing
-        while !parser.check(TokenType::CloseBracket) && !parser.is_at_end() {
+        if parser.check(TokenType::Plus) || parser.check(TokenType::Minus) {
+            negative = parser.check(TokenType::Minus);
+            parser.next_token();
+        } else if parser.check(TokenType::CloseBracket) {
             parser.next_token();
+            break;
+        } else {
+            return Err("Expected closing bracket ']' in memory reference. Memory references should be in the form [register], [register+offset], or [label]".to_string());
         }
     }
     
-    // Skip to the closing bracket
-    if !parser.check(TokenType::CloseBracket) {
-        return Err(format!("Expected closing bracket ']' in memory reference. Memory references should be in the form [register], [register+offset], or [label]"))
+    // A single number is kept as written, several are added up
+    let number = match offsets.as_slice() {
+        [] => None,
+        [(false, text)] => Some(text.clone()),
+        _ => Some(offset.to_string()),
+    };
+    memory.displacement = match (label, number) {
+        (Some(label), Some(number)) if number.starts_with('-') => Some(format!("{}{}", label, number)),
+        (Some(label), Some(number)) => Some(format!("{}+{}", label, number)),
+        (Some(label), None) => Some(label.to_string()),
+        (None, number) => number,
+    };
+    Ok(Operand::Memory(memory))
+}
+
+fn is_general_register(token_type: &TokenType) -> bool {
+    matches!(token_type, TokenType::Register | TokenType::Reg64Bit | TokenType::Reg32Bit | TokenType::Reg16Bit | TokenType::Reg8Bit)
+}
+
+/// The scale factor after `index*`
+fn parse_scale(parser: &mut Parser) -> Result<u8, String> {
+    let token = parser.current_token();
+    if token.token_type != TokenType::Immediate {
+        return Err(format!("Expected a scale factor after '*' in memory reference, found '{}'. The scale is 1, 2, 4 or 8", token.value));
     }
-    
-    // Skip the closing bracket
     parser.next_token();
-    
-    Ok(Operand::Memory(MemoryReference {
-        base,
-        index,
-        scale,
-        displacement,
-    }))
+    scale_factor(&token.value)
+}
+
+fn scale_factor(text: &str) -> Result<u8, String> {
+    match immediate_value(text) {
+        Some(scale @ (1 | 2 | 4 | 8)) => Ok(scale as u8),
+        _ => Err(format!("Invalid scale factor '{}' in memory reference. The scale is 1, 2, 4 or 8", text)),
+    }
+}
+
+/// Add `register` to a memory reference: the base, unless it is scaled or
+/// there already is one, otherwise the index
+fn add_register(memory: &mut MemoryReference, register: Symbol, scale: Option<u8>) -> Result<(), String> {
+    if scale.is_none() && memory.base.is_none() {
+        memory.base = Some(register);
+    } else if memory.index.is_none() {
+        memory.index = Some(register);
+        memory.scale = scale;
+    } else {
+        return Err(format!("Too many registers in memory reference at '{}'. A memory reference has at most a base and an index register", register));
+    }
+    Ok(())
 }
 
@@ -1,31 +1,52 @@
-use std::collections::HashMap;
-use crate::tokenizer::{Token, TokenType};
-use crate::encoder::MachineCodeEncoder;
-use crate::error::{ErrorCollector, ErrorType};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use crate::tokenizer::{self, Span, Token, TokenType};
+use crate::elf;
+use crate::encoder::{EncodeError, MachineCodeEncoder};
+use crate::symbol::Symbol;
+use crate::syscalls;
+use crate::error::{did_you_mean, similar_names, Error, ErrorCollector, ErrorDetail, ErrorSeverity, ErrorType, ParseError};
 
 pub mod ast;
-pub mod directive;
-pub mod instruction;
-pub mod section;
-pub mod label;
+pub(crate) mod directive;
+pub(crate) mod instruction;
+pub mod opcodes;
+pub mod insns;
 
-pub struct Parser {
-    tokens: Vec<(Token, usize)>,
+pub struct Parser<'src> {
+    tokens: Vec<(Token<'src>, usize)>,
     current: usize,
-    labels: HashMap<String, usize>,
-    label_offsets: HashMap<String, u64>,
+    labels: HashMap<Symbol, usize>,
+    label_offsets: HashMap<Symbol, u64>,
+    /// Section each label was defined in
+    label_sections: HashMap<Symbol, String>,
     current_section: String,
+    /// Code mode selected with `bits` (16, 32 or 64)
+    bits: u16,
+    /// Code mode before any `bits` directive
+    default_bits: u16,
     text_offset: u64,
     data_offset: u64,
     bss_offset: u64,
     error_collector: Option<ErrorCollector>,
     file_name: String,
     continue_on_errors: bool,
+    /// Whether the output is a relocatable object, whose extern symbols the linker resolves
+    relocatable: bool,
+    /// Whether `%use syscalls` appeared
+    use_syscalls: bool,
+    /// Constants from `%use` packages the program refers to, with the line of the `%use`
+    package_constants: Vec<(Symbol, i64, usize)>,
+    /// Forms operands are checked against, the built-in table unless set
+    opcode_table: Option<Arc<opcodes::OpcodeTable>>,
+    /// Largest size in bytes a section may grow to
+    max_section_size: u64,
 }
 
-impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        let tokens_with_index: Vec<(Token, usize)> = tokens.into_iter()
+impl<'src> Parser<'src> {
+    pub fn new(tokens: Vec<Token<'src>>) -> Self {
+        let tokens_with_index: Vec<(Token<'src>, usize)> = tokens.into_iter()
             .enumerate()
             .map(|(i, token)| (token, i))
             .collect();
@@ -35,13 +56,21 @@ impl Parser {
             current: 0,
             labels: HashMap::new(),
             label_offsets: HashMap::new(),
+            label_sections: HashMap::new(),
             current_section: ".text".to_string(),
+            bits: 64,
+            default_bits: 64,
             text_offset: 0x400000,
             data_offset: 0x600000,
             bss_offset: 0x800000,
             error_collector: None,
             file_name: "unknown".to_string(),
             continue_on_errors: false,
+            relocatable: false,
+            use_syscalls: false,
+            package_constants: Vec::new(),
+            opcode_table: None,
+            max_section_size: elf::DEFAULT_MAX_SECTION_SIZE,
         }
     }
     
@@ -63,17 +92,43 @@ impl Parser {
         self
     }
     
-    /// Add an error to the collector
-    fn add_error(&mut self, error_type: ErrorType, message: &str, token: &Token) {
-        if let Some(collector) = &mut self.error_collector {
-            collector.add_error_with_location(
-                error_type,
-                message,
-                &self.file_name,
-                token.line,
-                token.column
-            );
+    /// Set the code mode (16, 32 or 64) the program starts in
+    pub fn with_bits(mut self, bits: u16) -> Self {
+        self.bits = bits;
+        self.default_bits = bits;
+        self
+    }
+    
+    /// Set whether the output is a relocatable object, so references to
+    /// extern symbols are left for the linker instead of being errors
+    pub fn with_relocatable(mut self, relocatable: bool) -> Self {
+        self.relocatable = relocatable;
+        self
+    }
+    
+    /// Set the largest size in bytes a section may grow to
+    pub fn with_max_section_size(mut self, bytes: u64) -> Self {
+        self.max_section_size = bytes;
+        self
+    }
+    
+    /// Predefine absolute symbols (name → value) before parsing
+    pub fn with_defines(mut self, defines: &HashMap<String, i64>) -> Self {
+        for (name, value) in defines {
+            self.label_offsets.insert(Symbol::intern(name), *value as u64);
         }
+        self
+    }
+    
+    /// Check operands against `table` instead of the built-in opcode table
+    pub fn with_opcode_table(mut self, table: Arc<opcodes::OpcodeTable>) -> Self {
+        self.opcode_table = Some(table);
+        self
+    }
+    
+    /// The table instruction operands are checked against
+    pub(crate) fn opcode_table(&self) -> &opcodes::OpcodeTable {
+        self.opcode_table.as_deref().unwrap_or_else(|| opcodes::OpcodeTable::builtin())
     }
     
     /// Check if there are any errors
@@ -85,33 +140,29 @@ impl Parser {
         }
     }
     
-    pub fn parse(&mut self) -> Result<ast::Program, String> {
+    pub fn parse(&mut self) -> Result<ast::Program, ParseError> {
         let mut program = ast::Program::new();
         
+        self.resolve_shadowing_names();
+        
         // First pass: collect labels and track sections
         match self.collect_labels_and_sections() {
             Ok(_) => {},
             Err(err) => {
                 if !self.continue_on_errors {
-                    return Err(err);
+                    return Err(ParseError::Syntax(err));
                 }
                 // Otherwise continue with what we've collected
             }
         }
         
+        self.use_packages();
+        
         // Populate labels and sections in the Program
         for (label_name, offset) in &self.label_offsets {
-            let section = if *offset >= self.text_offset && *offset < self.data_offset {
-                Some(".text".to_string())
-            } else if *offset >= self.data_offset && *offset < self.bss_offset {
-                Some(".data".to_string())
-            } else if *offset >= self.bss_offset {
-                Some(".bss".to_string())
-            } else {
-                None
-            };
-            
-            program.add_label(label_name.clone(), *offset, section);
+            // Predefined symbols are absolute and belong to no section
+            let section = self.label_sections.get(label_name).cloned();
+            program.add_label(*label_name, *offset, section);
         }
         
         // Add default sections with sizes
@@ -131,30 +182,45 @@ impl Parser {
                 }
             }
             
+            // The tokenizer reported the line's bad character already, and
+            // parsing around it would only report it again
+            if self.has_reported_character() {
+                self.skip_line();
+                continue;
+            }
+            
+            let start = self.current;
             match self.parse_statement() {
                 Ok(statement) => {
-                    program.add_statement(statement);
+                    let span = self.statement_span(start, self.current);
+                    program.add_statement_at(statement, span);
                 },
                 Err(error) => {
                     // If we have an error collector, add the error to it and continue
                     // Otherwise, return the error immediately
                     if self.error_collector.is_some() && self.continue_on_errors {
-                        // Skip to the next line to continue parsing
-                        while !self.is_at_end() && !self.check(TokenType::NewLine) {
-                            self.advance();
-                        }
-                        
-                        // Skip the newline if present
-                        if self.check(TokenType::NewLine) {
-                            self.advance();
-                        }
+                        self.skip_line();
                     } else {
-                        return Err(error);
+                        return Err(ParseError::Syntax(error));
                     }
                 }
             }
         }
         
+        // Package constants behave like `name equ value` lines at the `%use`
+        for &(name, value, line) in &self.package_constants {
+            let span = ast::NodeSpan { file: Symbol::intern(&self.file_name), line, ..Default::default() };
+            program.add_statement_at(ast::Statement::Label(name), span.clone());
+            program.add_statement_at(ast::Statement::Directive(ast::Directive {
+                name: "equ".to_string(),
+                operands: vec![ast::Operand::Immediate(value.to_string())],
+                line,
+            }), span);
+        }
+        
+        // Check global/extern declarations against the labels actually defined
+        self.check_symbol_declarations(&program);
+        
         // Third pass: encode instructions with machine code
         match self.encode_instructions(&mut program) {
             Ok(_) => {},
@@ -165,31 +231,101 @@ impl Parser {
                 // Otherwise continue with what we've encoded
             }
         }
+        if let Err(err) = self.measure_label_offsets(&mut program) {
+            if !self.continue_on_errors || self.error_collector.is_none() {
+                return Err(err);
+            }
+        }
         
         // If we have errors but we're not continuing on errors, return the error
         if self.has_errors() && !self.continue_on_errors {
-            return Err("Errors occurred during parsing".to_string());
+            let errors = self.error_collector.as_ref().map_or(0, |collector| collector.error_count());
+            return Err(ParseError::Diagnostics { errors });
         }
         
         Ok(program)
     }
     
+    /// Turn registers and mnemonics used as label or variable names into
+    /// labels, warning about each one.
+    ///
+    /// `rax:` and `add db 1` are unambiguous definitions. References to a
+    /// shadowing mnemonic (`jmp add`) are read as the label too, but a
+    /// register name in an operand always means the register.
+    fn resolve_shadowing_names(&mut self) {
+        let mut shadowed = HashSet::new();
+        let mut warnings = Vec::new();
+
+        for i in 0..self.tokens.len().saturating_sub(1) {
+            if i > 0 && self.tokens[i - 1].0.token_type != TokenType::NewLine {
+                continue;
+            }
+            let token = &self.tokens[i].0;
+            let next = &self.tokens[i + 1].0;
+            let Some(kind) = shadowed_kind(&token.token_type) else { continue };
+
+            let definition = if next.token_type == TokenType::Colon {
+                TokenType::Label
+            } else if next.token_type == TokenType::Directive && is_data_definition(&next.value) {
+                TokenType::Identifier
+            } else {
+                continue;
+            };
+
+            let consequence = if kind == "register" {
+                "operands naming it will still refer to the register"
+            } else {
+                shadowed.insert(token.value.to_lowercase());
+                "operands naming it refer to the label"
+            };
+            warnings.push((
+                format!("Label '{}' has the same name as the {} '{}'; {}. Consider renaming it",
+                    token.value, kind, token.value, consequence),
+                token.line,
+                token.column,
+            ));
+            self.tokens[i].0.token_type = definition;
+        }
+
+        if !shadowed.is_empty() {
+            for i in 1..self.tokens.len() {
+                let previous = &self.tokens[i - 1].0.token_type;
+                let token = &self.tokens[i].0;
+                if *previous != TokenType::NewLine
+                    && shadowed_kind(&token.token_type) == Some("instruction")
+                    && shadowed.contains(&token.value.to_lowercase())
+                {
+                    self.tokens[i].0.token_type = TokenType::Identifier;
+                }
+            }
+        }
+
+        let file_name = self.file_name.clone();
+        if let Some(collector) = &mut self.error_collector {
+            for (message, line, column) in warnings {
+                collector.add_warning_with_location("shadowing", ErrorType::SemanticError, &message, &file_name, line, column);
+            }
+        }
+    }
+    
     // Enhanced label collection method that also tracks sections
     fn collect_labels_and_sections(&mut self) -> Result<(), String> {
         let mut statement_index = 0;
-        let mut current_offset = self.text_offset; // Start at text base
+        // Labels get the start of their section here, and their offset in it
+        // once instructions are encoded; see `measure_label_offsets`
+        let mut current_offset = self.text_offset;
         
         while !self.is_at_end() {
             let token_info = match self.peek() {
-                Some((token, _)) => (token.clone(), token.token_type.clone(), token.value.clone(), token.line, token.column),
+                Some((token, _)) => (token.token_type.clone(), token.value.clone(), token.line, token.column),
                 None => break,
             };
             
-            let (token, token_type, token_value, token_line, token_column) = token_info;
+            let (token_type, token_value, token_line, token_column) = token_info;
             
             match token_type {
                 TokenType::Label => {
-                    let label = token_value;
+                    let label = Symbol::intern(&token_value);
                     
                     // Check for duplicate labels
                     if self.labels.contains_key(&label) {
@@ -222,7 +358,8 @@ impl Parser {
                         }
                     }
                     
-                    self.labels.insert(label.clone(), statement_index);
+                    self.labels.insert(label, statement_index);
+                    self.label_sections.insert(label, self.current_section.clone());
                     
                     // Store actual memory offset for this label
                     self.label_offsets.insert(label, current_offset);
@@ -235,14 +372,14 @@ impl Parser {
                     }
                 },
                 TokenType::Directive => {
-                    if token_value == "section" {
+                    if token_value.eq_ignore_ascii_case("section") {
                         self.advance(); // Consume directive
                         
                         // Get section name
                         let section_info = match self.peek() {
                             Some((section_token, _)) => {
                                 (section_token.clone(), section_token.token_type.clone(), 
-                                 section_token.value.clone(), section_token.line, section_token.column)
+                                 section_token.value.to_string(), section_token.line, section_token.column)
                             },
                             None => {
                                 let error_msg = "Missing section name after section directive".to_string();
@@ -308,7 +445,7 @@ impl Parser {
                             continue;
                         } else {
                             // Invalid section name
-                            let error_msg = format!("Invalid section name, expected identifier or label reference");
+                            let error_msg = "Invalid section name, expected identifier or label reference".to_string();
                             
                             if let Some(collector) = &mut self.error_collector {
                                 collector.add_error_with_location(
@@ -350,11 +487,21 @@ impl Parser {
                         }
                         
                         statement_index += 1;
-                        // Estimate offset increase for directives (approx)
-                        current_offset += 8;
                         continue;
                     }
                 },
+                // Variable declarations (e.g., "msg db 'hi', 0") define a label too
+                TokenType::Identifier if self.peek_ahead(1).is_some_and(|(next, _)| next.token_type == TokenType::Directive) => {
+                    let label = Symbol::intern(&token_value);
+                    if let Entry::Vacant(entry) = self.labels.entry(label) {
+                        entry.insert(statement_index);
+                        self.label_sections.insert(label, self.current_section.clone());
+                        self.label_offsets.insert(label, current_offset);
+                    }
+                    
+                    // The directive itself is handled on the next iteration
+                    self.advance();
+                },
                 TokenType::NewLine => {
                     self.advance();
                     // Skip empty lines when counting statements
@@ -365,9 +512,6 @@ impl Parser {
                 },
                 TokenType::Instruction | TokenType::InstrData | TokenType::InstrArith 
                 | TokenType::InstrLogic | TokenType::InstrJump | TokenType::InstrSIMD => {
-                    // For instructions, estimate size (approx. 8 bytes per instruction)
-                    current_offset += 8;
-                    
                     // Count non-empty, non-label statements
                     statement_index += 1;
                     
@@ -404,6 +548,70 @@ impl Parser {
         Ok(())
     }
     
+    /// Handle `%use` lines, defining the package constants the program refers
+    /// to that it doesn't define itself
+    fn use_packages(&mut self) {
+        let mut use_line = None;
+        for i in 0..self.tokens.len() {
+            let token = &self.tokens[i].0;
+            if token.token_type != TokenType::Preprocessor || !token.value.eq_ignore_ascii_case("%use") {
+                continue;
+            }
+            let (line, column) = (token.line, token.column);
+            let package = self.tokens.get(i + 1)
+                .map(|(next, _)| next)
+                .filter(|next| next.token_type != TokenType::NewLine && next.token_type != TokenType::EOF);
+            let message = match package {
+                Some(package) if package.value.trim_matches(['\'', '"']).eq_ignore_ascii_case(syscalls::PACKAGE) => {
+                    use_line.get_or_insert(line);
+                    continue;
+                },
+                Some(package) => format!("Unknown package '{}' for %use. The available package is '{}'", package.value, syscalls::PACKAGE),
+                None => format!("Missing package name after %use, e.g. '%use {}'", syscalls::PACKAGE),
+            };
+            if let Some(collector) = &mut self.error_collector {
+                collector.add_error_with_location(ErrorType::UnknownDirective, &message, &self.file_name, line, column);
+            }
+        }
+
+        let Some(line) = use_line else { return };
+        self.use_syscalls = true;
+        for (token, _) in &self.tokens {
+            if token.token_type != TokenType::Identifier {
+                continue;
+            }
+            let name = Symbol::intern(&token.value);
+            if self.label_offsets.contains_key(&name) {
+                continue;
+            }
+            if let Some(value) = syscalls::constant(&token.value, self.default_bits) {
+                self.label_offsets.insert(name, value as u64);
+                self.package_constants.push((name, value, line));
+            }
+        }
+    }
+    
+    /// An error suggesting the instructions or directives `token` looks like a typo of
+    fn misspelled_keyword(&mut self, token: &Token) -> Option<Error> {
+        let instructions = similar_names(&token.value, tokenizer::instruction_names());
+        let directives = similar_names(&token.value, tokenizer::directive_names());
+        let (error_type, kind) = match (instructions.is_empty(), directives.is_empty()) {
+            (true, true) => return None,
+            (false, true) => (ErrorType::UnknownInstruction, "x86-64 instruction"),
+            (true, false) => (ErrorType::UnknownDirective, "directive"),
+            (false, false) => (ErrorType::UnknownInstruction, "instruction or directive"),
+        };
+        
+        let suggestions: Vec<&str> = directives.into_iter().chain(instructions).take(3).collect();
+        let help = did_you_mean(&suggestions)?;
+        let collector = self.error_collector.as_mut()?;
+        let location = collector.source_location(&self.file_name, token.line, token.column);
+        Some(Error::new(
+            error_type,
+            ErrorDetail::new(format!("Unknown {} '{}'", kind, token.value)).with_help(help)
+        ).with_location(location))
+    }
+    
     /// Get a string with examples of common x86-64 instructions
     fn get_common_instruction_examples() -> &'static str {
         "Common x86-64 instructions include: mov, add, sub, mul, div, push, pop, call, ret, jmp, je, jne, cmp, and, or, xor, shl, shr, lea"
@@ -420,15 +628,13 @@ impl Parser {
                     },
                     TokenType::Directive => {
                         // Special handling for section directives
-                        if token.value == "section" {
-                            // Get a copy of the directive token before advancing
-                            let directive_token = token.clone();
+                        if token.value.eq_ignore_ascii_case("section") {
                             self.advance(); // consume the directive
                             
                             // Check for the section name
                             if let Some((section_token, _)) = self.peek() {
                                 if section_token.token_type == TokenType::Identifier || section_token.token_type == TokenType::LabelRef {
-                                    let section_name = section_token.value.clone();
+                                    let section_name = section_token.value.to_string();
                                     let section_line = section_token.line;
                                     self.advance(); // consume the section name
                                     
@@ -448,7 +654,7 @@ impl Parser {
                         directive::parse_directive(self)
                     },
                     TokenType::Label => {
-                        let label = token.value.clone();
+                        let label = token.symbol();
                         self.advance();
                         
                         // Check if there's a colon after the label and consume it
@@ -461,7 +667,7 @@ impl Parser {
                         Ok(ast::Statement::Label(label))
                     },
                     TokenType::Comment => {
-                        let comment = token.value.clone();
+                        let comment = token.value.to_string();
                         self.advance();
                         Ok(ast::Statement::Comment(comment))
                     },
@@ -482,7 +688,7 @@ impl Parser {
                             let next_token_clone = next_token.clone();
                             
                             if next_token_clone.token_type == TokenType::Colon {
-                                let label = current_token.value.clone();
+                                let label = current_token.symbol();
                                 self.advance(); // Consume the identifier
                                 self.advance(); // Consume the colon
                                 return Ok(ast::Statement::Label(label));
@@ -490,8 +696,7 @@ impl Parser {
                             // Check if it's followed by a directive like 'db', 'dw', etc. - then it's a variable declaration
                             else if next_token_clone.token_type == TokenType::Directive {
                                 // This is a variable declaration (e.g., hello db 'Hello, World!', 0)
-                                let var_name = current_token.value.clone();
-                                let directive_name = next_token_clone.value.clone();
+                                let var_name = current_token.symbol();
                                 
                                 // Advance past the identifier
                                 self.advance();
@@ -511,16 +716,16 @@ impl Parser {
                                    next_token_clone.token_type == TokenType::OpenBracket {
                                     
                                 if let Some(collector) = &mut self.error_collector {
-                                    let recognized_instructions = Self::get_common_instruction_examples();
+                                    let suggestions = similar_names(&current_token.value, tokenizer::instruction_names());
+                                    let help = did_you_mean(&suggestions)
+                                        .unwrap_or_else(|| Self::get_common_instruction_examples().to_string());
                                     
-                                    collector.add_error_with_location(
+                                    let location = collector.source_location(&self.file_name, current_token.line, current_token.column);
+                                    let error = Error::new(
                                         ErrorType::UnknownInstruction,
-                                        &format!("Unknown x86-64 instruction '{}'. {}",
-                                                current_token.value, recognized_instructions),
-                                        &self.file_name,
-                                        current_token.line,
-                                        current_token.column
-                                    );
+                                        ErrorDetail::new(format!("Unknown x86-64 instruction '{}'", current_token.value)).with_help(help)
+                                    ).with_location(location);
+                                    collector.add_error(error);
                                 }
                                 
                                 if self.continue_on_errors {
@@ -541,6 +746,14 @@ impl Parser {
                             }
                         }
                         
+                        // A misspelled mnemonic or directive (`syscal`, `sectoin .text`)
+                        if let Some(error) = self.misspelled_keyword(&current_token) {
+                            if let Some(collector) = &mut self.error_collector {
+                                collector.add_error(error);
+                            }
+                            return Err(format!("Unknown instruction or directive '{}' at line {}", current_token.value, current_token.line));
+                        }
+                        
                         // Otherwise, it's an unexpected token
                         if let Some(collector) = &mut self.error_collector {
                             collector.add_error_with_location(
@@ -556,9 +769,33 @@ impl Parser {
                         Err(format!("Unexpected token type {:?} at line {}. In x86-64 assembly, lines typically start with a label, instruction, or directive.", 
                                     current_token.token_type, current_token.line))
                     },
+                    // `%use` lines were handled before this pass
+                    TokenType::Preprocessor if token.value.eq_ignore_ascii_case("%use") => {
+                        while !self.is_at_end() && !self.check(TokenType::NewLine) {
+                            self.advance();
+                        }
+                        Ok(ast::Statement::Empty)
+                    },
+                    TokenType::Preprocessor | TokenType::MacroLocal | TokenType::MacroExpansion => {
+                        let token_value = token.value.to_string();
+                        let token_line = token.line;
+                        let token_column = token.column;
+                        
+                        if let Some(collector) = &mut self.error_collector {
+                            collector.add_error_with_location(
+                                ErrorType::UnknownDirective,
+                                &format!("Preprocessor syntax like '{}' isn't supported yet. Define constants with equ instead, e.g. 'size equ 64'", token_value),
+                                &self.file_name,
+                                token_line,
+                                token_column
+                            );
+                        }
+                        
+                        Err(format!("Unsupported preprocessor syntax '{}' at line {}", token_value, token_line))
+                    },
                     _ => {
                         // Store the token information before borrowing
-                        let token_value = token.value.clone();
+                        let token_value = token.value.to_string();
                         let token_type = token.token_type.clone();
                         let token_line = token.line;
                         let token_column = token.column;
@@ -582,45 +819,284 @@ impl Parser {
         }
     }
     
-    // Enhanced encoding method that resolves label references
-    fn encode_instructions(&self, program: &mut ast::Program) -> Result<(), String> {
-        let encoder = MachineCodeEncoder::new();
+    // Encode instructions, checking that each one has an encoding and that
+    // every label they reference exists. Labels stay symbolic: their
+    // addresses are only known after the ELF layout, which patches them in.
+    fn encode_instructions(&mut self, program: &mut ast::Program) -> Result<(), ParseError> {
+        let encoder = MachineCodeEncoder::new().with_bits(self.default_bits);
+        let externs = extern_symbols(program);
+        let mut first_error = None;
         
-        for statement in &mut program.statements {
-            if let ast::Statement::Instruction(ref mut instruction) = statement {
-                // For LEA instructions, don't try to resolve the label
-                if instruction.name.to_lowercase() == "lea" {
-                    instruction.machine_code = encoder.encode(instruction);
-                    continue;
-                }
-                
-                // Check for and resolve label references in operands
-                for operand in &mut instruction.operands {
-                    if let ast::Operand::Label(label) = operand {
-                        if let Some(offset) = self.label_offsets.get(label) {
-                            // Replace label with resolved address
-                            *operand = ast::Operand::Immediate(format!("0x{:x}", offset));
+        for statement in &program.statements {
+            if let ast::Statement::Instruction(instruction) = statement {
+                for operand in &instruction.operands {
+                    let label = match operand {
+                        ast::Operand::Label(label) | ast::Operand::Wrt(label, _) => Some(*label),
+                        ast::Operand::Memory(memory) => memory.displacement_label(),
+                        _ => None,
+                    };
+                    if let Some(label) = label {
+                        if self.label_offsets.contains_key(&label) || (self.relocatable && externs.contains(&label)) {
+                            continue;
+                        }
+                        
+                        let error = if externs.contains(&label) {
+                            self.report_extern_reference(&label, instruction);
+                            ParseError::UnresolvedExtern(label.to_string())
                         } else {
-                            return Err(format!("Undefined label reference: {}", label));
+                            self.report_undefined_label(&label, instruction.line);
+                            ParseError::UndefinedLabel(label.to_string())
+                        };
+                        if !self.continue_on_errors {
+                            return Err(error);
                         }
+                        first_error.get_or_insert(error);
                     }
                 }
-                
-                // Now encode with resolved operands
-                instruction.machine_code = encoder.encode(instruction);
             }
         }
         
+        // The opcode table accepts some forms the encoder can't produce
+        let encoded = encoder.encode_statements(&program.statements);
+        for (statement, encoded) in program.statements.iter_mut().zip(encoded) {
+            match (statement, encoded) {
+                (ast::Statement::Instruction(instruction), Some(Ok(encoded))) => instruction.machine_code = encoded.bytes,
+                // Invalid operands were reported already and don't encode either
+                (ast::Statement::Instruction(instruction), Some(Err(_))) if self.line_has_error(instruction.line) => {},
+                (ast::Statement::Instruction(instruction), Some(Err(reason))) => {
+                    self.report_unencodable(instruction, &reason);
+                    let error = ParseError::Syntax(format!("Can't encode '{}': {}", instruction, reason));
+                    if !self.continue_on_errors {
+                        return Err(error);
+                    }
+                    first_error.get_or_insert(error);
+                },
+                _ => {},
+            }
+        }
+        
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+    
+    /// Set each label's offset from the size of the statements before it in
+    /// its section, now that instructions are encoded. Jumps count in their
+    /// short form; output addresses come from the ELF generator's layout,
+    /// which lengthens the jumps that don't reach. A section growing past
+    /// the size limit is reported at the statement that takes it there.
+    fn measure_label_offsets(&mut self, program: &mut ast::Program) -> Result<(), ParseError> {
+        let mut offsets: HashMap<&str, u64> = HashMap::new();
+        let mut section = ".text";
+        for (index, statement) in program.statements.iter().enumerate() {
+            let base = match section {
+                ".data" => self.data_offset,
+                ".bss" => self.bss_offset,
+                _ => self.text_offset,
+            };
+            let offset = offsets.entry(section).or_insert(base);
+            let (size, line) = match statement {
+                ast::Statement::Section(new_section) => {
+                    section = &new_section.name;
+                    continue;
+                },
+                ast::Statement::Label(label) => {
+                    // An equ's value isn't an offset
+                    let equ = matches!(program.statements.get(index + 1), Some(ast::Statement::Directive(directive)) if directive.name == "equ");
+                    if let (false, Some(info)) = (equ, program.labels.get_mut(label)) {
+                        info.offset = *offset;
+                    }
+                    continue;
+                },
+                ast::Statement::Instruction(instruction) => (instruction.machine_code.len() as u64, instruction.line),
+                ast::Statement::Directive(directive) => (elf::directive_size(directive, *offset - base), directive.line),
+                ast::Statement::Comment(_) | ast::Statement::Empty => continue,
+            };
+            match offset.checked_add(size).filter(|&end| end - base <= self.max_section_size) {
+                Some(end) => *offset = end,
+                None => {
+                    let message = format!("Section '{}' would grow past the limit of {} bytes. Reserve less, or raise the limit with --max-section-size",
+                                          section, self.max_section_size);
+                    let file_name = self.file_name.clone();
+                    if let Some(collector) = &mut self.error_collector {
+                        collector.add_error_with_location(ErrorType::SectionError, &message, &file_name, line, 1);
+                    }
+                    return Err(ParseError::Syntax(message));
+                },
+            }
+        }
         Ok(())
     }
     
+    /// Cross-check `global` and `extern` declarations against the labels the program defines and uses
+    fn check_symbol_declarations(&mut self, program: &ast::Program) {
+        let mut defined = HashSet::new();
+        let mut used = HashSet::new();
+        let mut declarations = Vec::new();
+        
+        for statement in &program.statements {
+            match statement {
+                ast::Statement::Label(label) => {
+                    defined.insert(label.as_str());
+                },
+                ast::Statement::Directive(directive) if directive.name == "global" || directive.name == "extern" => {
+                    if let Some(ast::Operand::Label(symbol)) = directive.operands.first() {
+                        declarations.push((directive.name.as_str(), symbol.as_str(), directive.line));
+                    }
+                },
+                ast::Statement::Directive(directive) => used.extend(referenced_symbols(&directive.operands)),
+                ast::Statement::Instruction(instruction) => used.extend(referenced_symbols(&instruction.operands)),
+                _ => {}
+            }
+        }
+        
+        for (kind, symbol, line) in declarations {
+            let column = self.reference_column(symbol, line);
+            let file_name = self.file_name.clone();
+            let Some(collector) = &mut self.error_collector else {
+                continue;
+            };
+            
+            match kind {
+                "extern" if defined.contains(symbol) => collector.add_error_with_location(
+                    ErrorType::SemanticError,
+                    &format!("Symbol '{}' is declared extern but also defined in this file. Remove the extern declaration, or rename the local label", symbol),
+                    &file_name,
+                    line,
+                    column
+                ),
+                "extern" if !used.contains(symbol) => collector.add_warning_with_location(
+                    "unused-extern",
+                    ErrorType::SemanticError,
+                    &format!("Extern symbol '{}' is declared but never used", symbol),
+                    &file_name,
+                    line,
+                    column
+                ),
+                "global" if !defined.contains(symbol) => collector.add_warning_with_location(
+                    "undefined-global",
+                    ErrorType::UndefinedLabel,
+                    &format!("Global symbol '{}' is never defined. Add a '{}:' label, or remove the global declaration", symbol, symbol),
+                    &file_name,
+                    line,
+                    column
+                ),
+                _ => {}
+            }
+        }
+    }
+    
+    /// Column of `name` on the given source line, or 1 when it can't be found
+    fn reference_column(&self, name: &str, line: usize) -> usize {
+        self.tokens.iter()
+            .map(|(token, _)| token)
+            .find(|token| token.line == line && token.value == name)
+            .map_or(1, |token| token.column)
+    }
+    
+    /// Report a reference to an unknown label, suggesting similarly named ones
+    fn report_undefined_label(&mut self, label: &str, line: usize) {
+        // Point at the reference itself rather than the start of the line
+        let column = self.reference_column(label, line);
+        
+        // Misspelled registers (`rbz`) end up as label references too
+        let package_names: Vec<String> = if self.use_syscalls { syscalls::names(self.default_bits).collect() } else { Vec::new() };
+        let candidates = self.label_offsets.keys().map(|label| label.as_str())
+            .chain(tokenizer::register_names())
+            .chain(package_names.iter().map(String::as_str));
+        let suggestions = similar_names(label, candidates);
+        let help = if !self.use_syscalls && syscalls::constant(label, self.default_bits).is_some() {
+            format!("'{}' is a Linux constant; add '%use {}' at the top of the file to define it", label, syscalls::PACKAGE)
+        } else {
+            did_you_mean(&suggestions).unwrap_or_else(|| format!("Define '{}' as a label in this file", label))
+        };
+        
+        let file_name = self.file_name.clone();
+        if let Some(collector) = &mut self.error_collector {
+            let location = collector.source_location(&file_name, line, column);
+            let error = Error::new(
+                ErrorType::UndefinedLabel,
+                ErrorDetail::new(format!("Undefined label '{}'", label)).with_help(help)
+            ).with_location(location);
+            collector.add_error(error);
+        }
+    }
+    
+    /// Report a reference to an extern symbol, which an executable has no way to resolve
+    fn report_extern_reference(&mut self, symbol: &str, instruction: &ast::Instruction) {
+        let column = self.reference_column(symbol, instruction.line);
+        
+        let file_name = self.file_name.clone();
+        if let Some(collector) = &mut self.error_collector {
+            let location = collector.source_location(&file_name, instruction.line, column);
+            let error = Error::new(
+                ErrorType::UndefinedLabel,
+                ErrorDetail::new(format!("'{}' refers to extern symbol '{}', but dynamic linking is not enabled for executable output",
+                                         instruction.name, symbol))
+                    .with_help(format!("Executables can only use symbols defined in the same file. Build a relocatable object with -f obj, or link in one step with --link, to have a linker resolve '{}'", symbol))
+            ).with_location(location);
+            collector.add_error(error);
+        }
+    }
+    
+    /// Whether the line starting at the current token has an unknown token
+    /// the collector holds an error for
+    fn has_reported_character(&self) -> bool {
+        let Some(collector) = &self.error_collector else {
+            return false;
+        };
+        self.tokens[self.current..].iter()
+            .map(|(token, _)| token)
+            .take_while(|token| !matches!(token.token_type, TokenType::NewLine | TokenType::EOF))
+            .filter(|token| token.token_type == TokenType::Unknown)
+            .any(|token| collector.errors().iter().any(|error| error.location.as_ref().is_some_and(|location| {
+                location.line == token.line && location.column == token.column && location.file == self.file_name
+            })))
+    }
+    
+    /// Skip to the start of the next line, to continue parsing after an error
+    fn skip_line(&mut self) {
+        while !self.is_at_end() && !self.check(TokenType::NewLine) {
+            self.advance();
+        }
+        
+        // Skip the newline if present
+        if self.check(TokenType::NewLine) {
+            self.advance();
+        }
+    }
+    
+    /// Whether an error was reported on `line` of the file being parsed
+    fn line_has_error(&self, line: usize) -> bool {
+        self.error_collector.as_ref().is_some_and(|collector| collector.errors().iter().any(|error| {
+            error.severity <= ErrorSeverity::Error
+                && error.location.as_ref().is_some_and(|location| location.line == line && location.file == self.file_name)
+        }))
+    }
+    
+    /// Report an instruction that passed operand validation but has no encoding
+    fn report_unencodable(&mut self, instruction: &ast::Instruction, reason: &EncodeError) {
+        let column = self.reference_column(&instruction.name, instruction.line);
+        
+        let file_name = self.file_name.clone();
+        if let Some(collector) = &mut self.error_collector {
+            let location = collector.source_location(&file_name, instruction.line, column);
+            let error = Error::new(
+                ErrorType::EncodingError,
+                ErrorDetail::new(format!("Can't encode '{}': {}", instruction, reason))
+            ).with_location(location);
+            collector.add_error(error);
+        }
+    }
+    
     // Helper method to check if we are at the end of the tokens
     fn is_at_end(&self) -> bool {
         self.current >= self.tokens.len()
     }
     
     // Helper method to peek at the current token without consuming it
-    pub fn peek(&self) -> Option<&(Token, usize)> {
+    pub(crate) fn peek(&self) -> Option<&(Token<'src>, usize)> {
         if self.is_at_end() {
             None
         } else {
@@ -629,20 +1105,14 @@ impl Parser {
     }
     
     // Helper method to advance to the next token
-    pub fn advance(&mut self) -> &(Token, usize) {
+    pub(crate) fn advance(&mut self) {
         if !self.is_at_end() {
             self.current += 1;
         }
-        self.previous()
-    }
-    
-    // Helper method to get the previous token
-    fn previous(&self) -> &(Token, usize) {
-        &self.tokens[self.current - 1]
     }
     
     // Helper method to check if the current token has the expected type
-    pub fn check(&self, token_type: TokenType) -> bool {
+    pub(crate) fn check(&self, token_type: TokenType) -> bool {
         if let Some((token, _)) = self.peek() {
             token.token_type == token_type
         } else {
@@ -650,32 +1120,18 @@ impl Parser {
         }
     }
     
-    // Helper method to check if the current token has the expected type and value
-    pub fn check_value(&self, token_type: TokenType, value: &str) -> bool {
-        if let Some((token, _)) = self.peek() {
-            token.token_type == token_type && token.value == value
-        } else {
-            false
-        }
-    }
-    
     // Helper method to get the current token
-    pub fn current_token(&self) -> Token {
+    pub(crate) fn current_token(&self) -> Token<'src> {
         if let Some((token, _)) = self.peek() {
             token.clone()
         } else {
             // Return an EOF token if we're at the end
-            Token {
-                token_type: TokenType::EOF,
-                value: "".to_string(),
-                line: 0,
-                column: 0,
-            }
+            Token::new(TokenType::EOF, "".to_string(), 0, 0)
         }
     }
     
     // Helper method to advance to the next token and return the current token
-    pub fn next_token(&mut self) -> Token {
+    pub(crate) fn next_token(&mut self) -> Token<'src> {
         let current = self.current_token();
         self.advance();
         current
@@ -683,7 +1139,7 @@ impl Parser {
     
     // Added: Get label offset for a given label name
     pub fn get_label_offset(&self, label: &str) -> Option<u64> {
-        self.label_offsets.get(label).cloned()
+        self.label_offsets.get(&Symbol::lookup(label)?).cloned()
     }
     
     // Added: Get current section name
@@ -692,7 +1148,7 @@ impl Parser {
     }
     
     // Helper method to peek at a token n positions ahead without consuming it
-    pub fn peek_ahead(&self, n: usize) -> Option<&(Token, usize)> {
+    pub(crate) fn peek_ahead(&self, n: usize) -> Option<&(Token<'src>, usize)> {
         if self.current + n >= self.tokens.len() {
             None
         } else {
@@ -702,6 +1158,88 @@ impl Parser {
     
     /// Get the current error collector
     pub fn get_error_collector(&self) -> Option<ErrorCollector> {
-        self.error_collector.clone()
+        let mut collector = self.error_collector.clone()?;
+        collector.attach_spans(&self.file_name, |line, column| self.token_span_at(line, column));
+        Some(collector)
+    }
+    
+    /// Like `get_error_collector`, but hands the collector over instead of copying it
+    pub fn take_error_collector(&mut self) -> Option<ErrorCollector> {
+        let mut collector = self.error_collector.take()?;
+        collector.attach_spans(&self.file_name, |line, column| self.token_span_at(line, column));
+        Some(collector)
+    }
+    
+    /// Where the statement made of the tokens `start..end` is in the source,
+    /// leaving out the line break that ends it
+    fn statement_span(&self, start: usize, end: usize) -> ast::NodeSpan {
+        let tokens = &self.tokens[start..end.min(self.tokens.len())];
+        let line = tokens.first().map_or(0, |(token, _)| token.line);
+        let mut content = tokens.iter()
+            .map(|(token, _)| token)
+            .filter(|token| !matches!(token.token_type, TokenType::NewLine | TokenType::EOF) && !token.span.is_empty());
+        let source = match (content.next(), content.next_back()) {
+            (Some(first), last) => first.span.to(last.unwrap_or(first).span),
+            (None, _) => tokens.first().map_or(Span::default(), |(token, _)| Span::new(token.span.start, token.span.start)),
+        };
+        ast::NodeSpan { file: Symbol::intern(&self.file_name), line, source, bytes: None }
+    }
+    
+    /// Span of the token starting at `line`:`column`
+    fn token_span_at(&self, line: usize, column: usize) -> Option<Span> {
+        let index = self.tokens.partition_point(|(token, _)| (token.line, token.column) < (line, column));
+        self.tokens.get(index)
+            .map(|(token, _)| token)
+            .filter(|token| token.line == line && token.column == column && !token.span.is_empty())
+            .map(|token| token.span)
+    }
+} 
+
+/// Symbols declared with `extern`
+fn extern_symbols(program: &ast::Program) -> HashSet<Symbol> {
+    program.statements.iter()
+        .filter_map(|statement| match statement {
+            ast::Statement::Directive(directive) if directive.name == "extern" => match directive.operands.first() {
+                Some(ast::Operand::Label(symbol)) => Some(*symbol),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// Label names referenced by operands, including those used inside memory references
+fn referenced_symbols(operands: &[ast::Operand]) -> Vec<&str> {
+    let mut symbols = Vec::new();
+    for operand in operands {
+        match operand {
+            ast::Operand::Label(label) | ast::Operand::Wrt(label, _) => symbols.push(label.as_str()),
+            ast::Operand::Memory(memory) => {
+                symbols.extend(memory.base.as_deref());
+                symbols.extend(memory.displacement_label().map(|label| label.as_str()));
+            },
+            _ => {}
+        }
     }
-} 
\ No newline at end of file
+    symbols
+}
+
+/// "register" or "instruction" for tokens a label name can shadow
+fn shadowed_kind(token_type: &TokenType) -> Option<&'static str> {
+    match token_type {
+        TokenType::Register | TokenType::Reg64Bit | TokenType::Reg32Bit | TokenType::Reg16Bit
+        | TokenType::Reg8Bit | TokenType::RegXMM | TokenType::RegYMM | TokenType::RegZMM
+        | TokenType::RegSpecial => Some("register"),
+        TokenType::Instruction | TokenType::InstrData | TokenType::InstrArith
+        | TokenType::InstrLogic | TokenType::InstrJump | TokenType::InstrSIMD => Some("instruction"),
+        _ => None,
+    }
+}
+
+/// Directives that define the name in front of them (`name db ...`)
+fn is_data_definition(directive: &str) -> bool {
+    matches!(
+        directive.to_lowercase().as_str(),
+        "db" | "dw" | "dd" | "dq" | "dt" | "equ" | "times" | "resb" | "resw" | "resd" | "resq"
+    )
+}
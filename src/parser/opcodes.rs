@@ -1,7 +1,22 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, Read};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
 use std::path::Path;
+use once_cell::sync::Lazy;
+
+use crate::error::OpcodeTableError;
+use crate::parser::ast::Operand;
+use crate::tokenizer::{self, TokenType};
+
+/// Instruction forms shipped with the assembler, see the file for the format
+const BUILTIN_OPCODES: &str = include_str!("opcode_forms.txt");
+
+static BUILTIN_TABLE: Lazy<OpcodeTable> = Lazy::new(|| {
+    OpcodeTable::from_string(BUILTIN_OPCODES).expect("built-in opcode table is valid")
+});
+
+/// Widths an `N` in an operand kind expands to
+const EXPANDED_WIDTHS: [u16; 4] = [8, 16, 32, 64];
 
 /// Information about an opcode
 #[derive(Debug, Clone)]
@@ -9,12 +24,541 @@ pub struct OpcodeInfo {
     pub name: String,
     pub category: String,
     pub operands: Vec<String>,
+    pub operand_specs: Vec<OperandSpec>,
     pub machine_code: Option<String>,
     pub encoding: Option<String>,
+    /// The machine code column, parsed
+    pub template: Option<EncodingTemplate>,
+    /// The operand encoding column, parsed; empty if the column is
+    pub operand_encoding: Vec<OperandEncoding>,
 }
 
-/// Table of opcodes loaded from OPCODES.txt
-#[derive(Debug, Clone)]
+/// Kind of operand an instruction form accepts
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OperandSpec {
+    /// General purpose register of the given width
+    Reg(u16),
+    /// General purpose register of the given width or a memory reference
+    RegOrMem(u16),
+    /// Memory reference
+    Mem,
+    /// Immediate of the given width (0 = any width)
+    Imm(u16),
+    /// Branch target
+    Rel,
+    /// XMM register
+    Xmm,
+    /// XMM register or memory reference
+    XmmOrMem,
+    /// YMM register
+    Ymm,
+    /// YMM register or memory reference
+    YmmOrMem,
+    /// One specific register
+    Exact(String),
+}
+
+impl OperandSpec {
+    /// Parse an operand kind such as `r/m64`, `imm` or `cl`
+    pub fn parse(spec: &str) -> Option<Self> {
+        let spec = spec.trim();
+        let width = |prefix: &str| spec.strip_prefix(prefix).and_then(|w| w.parse::<u16>().ok());
+
+        match spec {
+            "m" => return Some(OperandSpec::Mem),
+            "imm" => return Some(OperandSpec::Imm(0)),
+            "rel" => return Some(OperandSpec::Rel),
+            "xmm" => return Some(OperandSpec::Xmm),
+            "xmm/m" => return Some(OperandSpec::XmmOrMem),
+            "ymm" => return Some(OperandSpec::Ymm),
+            "ymm/m" => return Some(OperandSpec::YmmOrMem),
+            _ => {}
+        }
+
+        if let Some(width) = width("r/m") {
+            Some(OperandSpec::RegOrMem(width))
+        } else if let Some(width) = width("imm") {
+            Some(OperandSpec::Imm(width))
+        } else if let Some(width) = width("r").filter(|width| matches!(width, 8 | 16 | 32 | 64)) {
+            // "r8" is the register width, not the register r8
+            Some(OperandSpec::Reg(width))
+        } else if tokenizer::register_type(spec).is_some() {
+            Some(OperandSpec::Exact(spec.to_string()))
+        } else {
+            None
+        }
+    }
+
+    /// Check whether an operand is acceptable for this kind
+    pub fn matches(&self, operand: &Operand) -> bool {
+        match operand {
+            Operand::Register(name) => {
+                let name = name.to_lowercase();
+                match self {
+                    OperandSpec::Reg(width) | OperandSpec::RegOrMem(width) => register_width(&name) == Some(*width),
+                    OperandSpec::Xmm | OperandSpec::XmmOrMem => tokenizer::register_type(&name) == Some(TokenType::RegXMM),
+                    OperandSpec::Ymm | OperandSpec::YmmOrMem => tokenizer::register_type(&name) == Some(TokenType::RegYMM),
+                    OperandSpec::Exact(register) => *register == name,
+                    _ => false,
+                }
+            },
+            // A memory operand with a size only fits forms of that width
+            Operand::Memory(memory) => match self {
+                OperandSpec::RegOrMem(width) => memory.size.is_none_or(|size| size == *width),
+                OperandSpec::Mem | OperandSpec::XmmOrMem | OperandSpec::YmmOrMem => true,
+                _ => false,
+            },
+            // A bare label is its address as an immediate, or RIP-relative memory for lea
+            Operand::Label(_) | Operand::Wrt(..) => matches!(self, OperandSpec::Imm(_) | OperandSpec::Rel | OperandSpec::Mem),
+            Operand::Immediate(_) => matches!(self, OperandSpec::Imm(_) | OperandSpec::Rel),
+            // Character constants are immediates
+            Operand::String(_) => matches!(self, OperandSpec::Imm(_)),
+        }
+    }
+
+    /// Whether every operand accepted by `other` is also accepted by this kind
+    fn covers(&self, other: &OperandSpec) -> bool {
+        match (self, other) {
+            (OperandSpec::RegOrMem(width), OperandSpec::Reg(other_width)) => width == other_width,
+            (OperandSpec::RegOrMem(_), OperandSpec::Mem) => true,
+            (OperandSpec::XmmOrMem, OperandSpec::Xmm | OperandSpec::Mem) => true,
+            (OperandSpec::YmmOrMem, OperandSpec::Ymm | OperandSpec::Mem) => true,
+            (OperandSpec::Imm(0), OperandSpec::Imm(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Human readable name of the kind, for diagnostics
+    pub fn describe(&self) -> &str {
+        match self {
+            OperandSpec::Reg(8) => "an 8-bit register",
+            OperandSpec::Reg(16) => "a 16-bit register",
+            OperandSpec::Reg(32) => "a 32-bit register",
+            OperandSpec::Reg(64) => "a 64-bit register",
+            OperandSpec::Reg(_) => "a register",
+            OperandSpec::RegOrMem(8) => "an 8-bit register/memory operand",
+            OperandSpec::RegOrMem(16) => "a 16-bit register/memory operand",
+            OperandSpec::RegOrMem(32) => "a 32-bit register/memory operand",
+            OperandSpec::RegOrMem(64) => "a 64-bit register/memory operand",
+            OperandSpec::RegOrMem(_) => "a register/memory operand",
+            OperandSpec::Mem => "a memory reference",
+            OperandSpec::Imm(_) => "an immediate",
+            OperandSpec::Rel => "a label",
+            OperandSpec::Xmm => "an XMM register",
+            OperandSpec::XmmOrMem => "an XMM register/memory operand",
+            OperandSpec::Ymm => "a YMM register",
+            OperandSpec::YmmOrMem => "a YMM register/memory operand",
+            OperandSpec::Exact(register) => register,
+        }
+    }
+}
+
+impl fmt::Display for OperandSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OperandSpec::Reg(width) => write!(f, "r{}", width),
+            OperandSpec::RegOrMem(width) => write!(f, "r/m{}", width),
+            OperandSpec::Mem => write!(f, "m"),
+            OperandSpec::Imm(0) => write!(f, "imm"),
+            OperandSpec::Imm(width) => write!(f, "imm{}", width),
+            OperandSpec::Rel => write!(f, "rel"),
+            OperandSpec::Xmm => write!(f, "xmm"),
+            OperandSpec::XmmOrMem => write!(f, "xmm/m"),
+            OperandSpec::Ymm => write!(f, "ymm"),
+            OperandSpec::YmmOrMem => write!(f, "ymm/m"),
+            OperandSpec::Exact(register) => write!(f, "{}", register),
+        }
+    }
+}
+
+/// Legacy prefixes that may precede the opcode in a machine code template
+const LEGACY_PREFIXES: [u8; 11] = [0x66, 0x67, 0xF0, 0xF2, 0xF3, 0x26, 0x2E, 0x36, 0x3E, 0x64, 0x65];
+
+/// Machine code of an instruction form, written as in the Intel manuals:
+/// prefixes, opcode bytes, the ModRM field, then immediates, as in
+/// `REX.W 89 /r`, `B8+r id` or `VEX.128.66.0F.WIG 6F /r`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EncodingTemplate {
+    /// Operand size the form is for (`o16`, `o32` or `o64`), which takes a
+    /// 66 prefix or REX.W depending on the code mode
+    pub operand_size: Option<u16>,
+    /// Legacy and mandatory prefixes, e.g. F3 in `F3 0F 6F /r`
+    pub prefixes: Vec<u8>,
+    /// A REX prefix is required, e.g. for `spl` in `REX 88 /r`
+    pub rex: bool,
+    /// REX.W is set
+    pub rex_w: bool,
+    pub vex: Option<VexPrefix>,
+    pub opcode: Vec<u8>,
+    /// The register operand is added to the last opcode byte (`+r`)
+    pub register_in_opcode: bool,
+    pub modrm: Option<ModRm>,
+    /// Sizes in bytes of the immediates that follow (`ib`, `iw`, `id`, `iq`)
+    pub immediates: Vec<u8>,
+    /// Size in bytes of a branch displacement (`cb`, `cw`, `cd`)
+    pub relative: Option<u8>,
+}
+
+/// How an instruction form uses the ModRM byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModRm {
+    /// `/r`: reg holds a register operand
+    Register,
+    /// `/0` to `/7`: reg holds this opcode extension
+    Digit(u8),
+}
+
+/// A VEX prefix, e.g. `VEX.256.66.0F38.W0`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VexPrefix {
+    /// Vector length in bits, or None if ignored (`LIG`)
+    pub length: Option<u16>,
+    /// Implied 66, F2 or F3 prefix
+    pub prefix: Option<u8>,
+    /// Opcode map: 0x0F, 0x0F38 or 0x0F3A
+    pub map: u16,
+    /// VEX.W, or None if ignored (`WIG`)
+    pub w: Option<bool>,
+}
+
+impl VexPrefix {
+    /// Parse the dotted fields after `VEX.`
+    fn parse(fields: &str) -> Result<Self, String> {
+        let (mut length, mut prefix, mut map, mut w) = (None, None, None, None);
+        for field in fields.split('.') {
+            match field.to_ascii_uppercase().as_str() {
+                // Which operand VEX.vvvv encodes is given by the operand encoding column
+                "NDS" | "NDD" | "DDS" => {},
+                "128" | "L0" | "LZ" => length = Some(128),
+                "256" | "L1" => length = Some(256),
+                "LIG" => length = None,
+                "66" => prefix = Some(0x66),
+                "F2" => prefix = Some(0xF2),
+                "F3" => prefix = Some(0xF3),
+                "NP" => prefix = None,
+                "0F" => map = Some(0x0F),
+                "0F38" => map = Some(0x0F38),
+                "0F3A" => map = Some(0x0F3A),
+                "W0" => w = Some(false),
+                "W1" => w = Some(true),
+                "WIG" => w = None,
+                _ => return Err(format!("'{}' isn't a VEX field", field)),
+            }
+        }
+        let map = map.ok_or_else(|| "the VEX prefix doesn't name an opcode map (0F, 0F38 or 0F3A)".to_string())?;
+        Ok(Self { length, prefix, map, w })
+    }
+}
+
+impl fmt::Display for VexPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.length {
+            Some(length) => write!(f, "VEX.{}", length)?,
+            None => write!(f, "VEX.LIG")?,
+        }
+        if let Some(prefix) = self.prefix {
+            write!(f, ".{:02X}", prefix)?;
+        }
+        write!(f, ".{:02X}", self.map)?;
+        match self.w {
+            Some(w) => write!(f, ".W{}", u8::from(w)),
+            None => write!(f, ".WIG"),
+        }
+    }
+}
+
+/// One token of a machine code template
+enum TemplatePart {
+    OperandSize(u16),
+    Rex { w: bool },
+    Vex(VexPrefix),
+    Byte(u8),
+    RegisterInByte(u8),
+    ModRm(ModRm),
+    Immediate(u8),
+    Relative(u8),
+}
+
+impl TemplatePart {
+    fn parse(token: &str) -> Result<Self, String> {
+        let upper = token.to_ascii_uppercase();
+        let byte = |text: &str| match text.len() {
+            2 => u8::from_str_radix(text, 16).ok(),
+            _ => None,
+        };
+        let part = match upper.as_str() {
+            "O16" => TemplatePart::OperandSize(16),
+            "O32" => TemplatePart::OperandSize(32),
+            "O64" => TemplatePart::OperandSize(64),
+            "REX" => TemplatePart::Rex { w: false },
+            "REX.W" => TemplatePart::Rex { w: true },
+            "/R" => TemplatePart::ModRm(ModRm::Register),
+            "IB" => TemplatePart::Immediate(1),
+            "IW" => TemplatePart::Immediate(2),
+            "ID" => TemplatePart::Immediate(4),
+            "IQ" => TemplatePart::Immediate(8),
+            // Lowercase, like the manuals, to tell them from the bytes CB and CD
+            "CB" if token == "cb" => TemplatePart::Relative(1),
+            "CW" if token == "cw" => TemplatePart::Relative(2),
+            "CD" if token == "cd" => TemplatePart::Relative(4),
+            _ => if let Some(fields) = upper.strip_prefix("VEX.") {
+                TemplatePart::Vex(VexPrefix::parse(fields)?)
+            } else if let Some(digit) = upper.strip_prefix('/').and_then(|digit| digit.parse().ok()).filter(|&digit| digit < 8) {
+                TemplatePart::ModRm(ModRm::Digit(digit))
+            } else if let Some(value) = upper.strip_suffix("+R").and_then(byte) {
+                TemplatePart::RegisterInByte(value)
+            } else if let Some(value) = byte(&upper) {
+                TemplatePart::Byte(value)
+            } else {
+                return Err(format!("'{}' isn't a hexadecimal byte or a machine code token", token));
+            },
+        };
+        Ok(part)
+    }
+
+    /// Which of prefixes (0), opcode (1), ModRM (2) and immediates (3) the part belongs to
+    fn stage(&self) -> u8 {
+        match self {
+            TemplatePart::OperandSize(_) | TemplatePart::Rex { .. } | TemplatePart::Vex(_) => 0,
+            TemplatePart::Byte(_) | TemplatePart::RegisterInByte(_) => 1,
+            TemplatePart::ModRm(_) => 2,
+            TemplatePart::Immediate(_) | TemplatePart::Relative(_) => 3,
+        }
+    }
+}
+
+impl EncodingTemplate {
+    /// Parse machine code such as `o32 0F AF /r` or `REX.W C7 /0 id`
+    ///
+    /// A hexadecimal byte is a prefix if it is a legacy prefix and another
+    /// opcode byte or REX follows it, so `66 0F 6F /r` has a 66 prefix and
+    /// the opcode 0F 6F.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens: Vec<&str> = text.split_whitespace().collect();
+        let parts = tokens.iter().map(|token| TemplatePart::parse(token)).collect::<Result<Vec<_>, _>>()?;
+        if parts.is_empty() {
+            return Err("the machine code is empty".to_string());
+        }
+
+        let mut template = Self::default();
+        let mut stage = 0;
+        for (index, part) in parts.iter().enumerate() {
+            let token = tokens[index];
+            let part_stage = match part {
+                TemplatePart::Byte(value) if stage == 0 && LEGACY_PREFIXES.contains(value)
+                    && matches!(parts.get(index + 1), Some(TemplatePart::Byte(_) | TemplatePart::RegisterInByte(_) | TemplatePart::Rex { .. })) => 0,
+                part => part.stage(),
+            };
+            if part_stage < stage {
+                return Err(format!("'{}' is out of place; write prefixes, opcode bytes, ModRM, then immediates", token));
+            }
+            stage = part_stage;
+
+            match *part {
+                TemplatePart::Byte(value) if part_stage == 0 => {
+                    if template.vex.is_some() {
+                        return Err(format!("the {:02X} prefix can't be combined with a VEX prefix", value));
+                    } else if template.rex || template.rex_w {
+                        return Err(format!("the {:02X} prefix has to come before REX", value));
+                    }
+                    template.prefixes.push(value);
+                },
+                TemplatePart::OperandSize(_) | TemplatePart::Rex { .. } if template.vex.is_some() => {
+                    return Err(format!("'{}' can't be combined with a VEX prefix", token));
+                },
+                TemplatePart::OperandSize(size) => match template.operand_size {
+                    Some(_) => return Err("the operand size is given twice".to_string()),
+                    None => template.operand_size = Some(size),
+                },
+                TemplatePart::Rex { w } => {
+                    if template.rex || template.rex_w {
+                        return Err("REX is given twice".to_string());
+                    }
+                    template.rex = !w;
+                    template.rex_w = w;
+                },
+                TemplatePart::Vex(vex) => {
+                    if template.vex.is_some() || template.operand_size.is_some() || template.rex || template.rex_w || !template.prefixes.is_empty() {
+                        return Err("a VEX prefix replaces legacy prefixes and REX, so it has to come alone".to_string());
+                    }
+                    template.vex = Some(vex);
+                },
+                TemplatePart::Byte(value) | TemplatePart::RegisterInByte(value) => {
+                    if template.register_in_opcode {
+                        return Err("'+r' has to be on the last opcode byte".to_string());
+                    }
+                    if let TemplatePart::RegisterInByte(_) = part {
+                        if value & 7 != 0 {
+                            return Err(format!("'{}' adds the register to the low 3 bits, which have to be clear", token));
+                        }
+                        template.register_in_opcode = true;
+                    }
+                    template.opcode.push(value);
+                },
+                TemplatePart::ModRm(modrm) => match template.modrm {
+                    Some(_) => return Err("the ModRM field is given twice".to_string()),
+                    None => template.modrm = Some(modrm),
+                },
+                TemplatePart::Immediate(size) => template.immediates.push(size),
+                TemplatePart::Relative(size) => match template.relative {
+                    Some(_) => return Err("the branch displacement is given twice".to_string()),
+                    None => template.relative = Some(size),
+                },
+            }
+        }
+
+        let most = if template.vex.is_some() { 1 } else { 3 };
+        if template.opcode.is_empty() {
+            return Err("the machine code has no opcode byte".to_string());
+        } else if template.opcode.len() > most {
+            return Err(format!("the opcode is {} bytes long; at most {} are allowed here", template.opcode.len(), most));
+        } else if template.register_in_opcode && template.modrm.is_some() {
+            return Err("'+r' and a ModRM field can't both encode the register".to_string());
+        }
+        Ok(template)
+    }
+}
+
+impl fmt::Display for EncodingTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut tokens = Vec::new();
+        if let Some(size) = self.operand_size {
+            tokens.push(format!("o{}", size));
+        }
+        tokens.extend(self.prefixes.iter().map(|prefix| format!("{:02X}", prefix)));
+        if self.rex_w {
+            tokens.push("REX.W".to_string());
+        } else if self.rex {
+            tokens.push("REX".to_string());
+        }
+        if let Some(vex) = self.vex {
+            tokens.push(vex.to_string());
+        }
+        for (index, byte) in self.opcode.iter().enumerate() {
+            let plus_register = self.register_in_opcode && index + 1 == self.opcode.len();
+            tokens.push(format!("{:02X}{}", byte, if plus_register { "+r" } else { "" }));
+        }
+        match self.modrm {
+            Some(ModRm::Register) => tokens.push("/r".to_string()),
+            Some(ModRm::Digit(digit)) => tokens.push(format!("/{}", digit)),
+            None => {},
+        }
+        tokens.extend(self.immediates.iter().map(|size| match size {
+            1 => "ib", 2 => "iw", 4 => "id", _ => "iq",
+        }.to_string()));
+        if let Some(size) = self.relative {
+            tokens.push(match size { 1 => "cb", 2 => "cw", _ => "cd" }.to_string());
+        }
+        write!(f, "{}", tokens.join(" "))
+    }
+}
+
+/// Where an operand goes in the machine code, one letter of the operand
+/// encoding column as in the Intel manuals' Op/En column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandEncoding {
+    /// `R`: ModRM.reg
+    Reg,
+    /// `M`: ModRM.rm, a register or memory reference
+    Rm,
+    /// `O`: the low 3 bits of the last opcode byte (`+r`)
+    Opcode,
+    /// `I`: an immediate
+    Immediate,
+    /// `D`: a branch displacement
+    Relative,
+    /// `V`: VEX.vvvv
+    Vex,
+    /// `-`: implied by the opcode, such as `cl` in `shl r/m8, cl`
+    Implicit,
+}
+
+impl OperandEncoding {
+    /// Parse an operand encoding column such as `MR`, `OI` or `RVM`; `ZO`
+    /// or `NP` means the form has no operands
+    pub fn parse_all(text: &str) -> Result<Vec<Self>, String> {
+        let text = text.trim();
+        if text.eq_ignore_ascii_case("ZO") || text.eq_ignore_ascii_case("NP") {
+            return Ok(Vec::new());
+        }
+        text.chars()
+            .map(|letter| match letter.to_ascii_uppercase() {
+                'R' => Ok(OperandEncoding::Reg),
+                'M' => Ok(OperandEncoding::Rm),
+                'O' => Ok(OperandEncoding::Opcode),
+                'I' => Ok(OperandEncoding::Immediate),
+                'D' => Ok(OperandEncoding::Relative),
+                'V' => Ok(OperandEncoding::Vex),
+                '-' => Ok(OperandEncoding::Implicit),
+                _ => Err(format!("'{}' in '{}' isn't an operand encoding (R, M, O, I, D, V or -)", letter, text)),
+            })
+            .collect()
+    }
+
+    fn letter(&self) -> char {
+        match self {
+            OperandEncoding::Reg => 'R',
+            OperandEncoding::Rm => 'M',
+            OperandEncoding::Opcode => 'O',
+            OperandEncoding::Immediate => 'I',
+            OperandEncoding::Relative => 'D',
+            OperandEncoding::Vex => 'V',
+            OperandEncoding::Implicit => '-',
+        }
+    }
+}
+
+impl fmt::Display for OperandEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.letter())
+    }
+}
+
+/// Width in bits of a general purpose register
+fn register_width(name: &str) -> Option<u16> {
+    match tokenizer::register_type(name)? {
+        TokenType::Reg64Bit => Some(64),
+        TokenType::Reg32Bit => Some(32),
+        TokenType::Reg16Bit => Some(16),
+        TokenType::Reg8Bit => Some(8),
+        _ => None,
+    }
+}
+
+/// A memory operand without a size whose width the accepted `forms` don't
+/// agree on, and the widths they take for it
+fn unsized_memory(forms: &[&OpcodeInfo], operands: &[Operand]) -> Option<(usize, Vec<u16>)> {
+    operands.iter().enumerate()
+        .filter(|(_, operand)| matches!(operand, Operand::Memory(memory) if memory.size.is_none()))
+        .find_map(|(position, _)| {
+            let mut widths = Vec::new();
+            for form in forms {
+                match form.operand_specs[position] {
+                    OperandSpec::RegOrMem(width) => widths.push(width),
+                    // A form that takes memory of any size settles it
+                    _ => return None,
+                }
+            }
+            widths.sort_unstable();
+            widths.dedup();
+            Some((position, widths)).filter(|(_, widths)| widths.len() > 1)
+        })
+}
+
+/// Why operands don't fit any form of an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OperandMismatch {
+    /// No form takes this many operands
+    Count { expected: Vec<usize>, found: usize },
+    /// The operand at `position` (0-based) has the wrong kind
+    Kind { position: usize, expected: Vec<OperandSpec> },
+    /// The memory operand at `position` has no size, and the forms it fits
+    /// take these different widths
+    Size { position: usize, widths: Vec<u16> },
+}
+
+/// Table of instruction forms, either built in or loaded from a file
+#[derive(Debug, Clone, Default)]
 pub struct OpcodeTable {
     opcodes: Vec<OpcodeInfo>,
     opcode_map: HashMap<String, Vec<usize>>,
@@ -30,6 +574,7 @@ pub enum InstructionCategory {
     Logic,
     Shift,
     String,
+    Simd,
     IO,
     Other,
 }
@@ -37,90 +582,198 @@ pub enum InstructionCategory {
 impl OpcodeTable {
     /// Create a new, empty opcode table
     pub fn new() -> Self {
-        Self {
-            opcodes: Vec::new(),
-            opcode_map: HashMap::new(),
-        }
+        Self::default()
+    }
+
+    /// The instruction forms built into the assembler
+    pub fn builtin() -> &'static OpcodeTable {
+        &BUILTIN_TABLE
     }
-    
+
     /// Load opcodes from a file
-    pub fn from_file(path: &Path) -> Result<Self, String> {
-        let file = File::open(path)
-            .map_err(|e| format!("Failed to open opcode file: {}", e))?;
-        let reader = BufReader::new(file);
-        
-        let mut content = String::new();
-        reader.buffer().read_to_string(&mut content)
-            .map_err(|e| format!("Failed to read opcode file: {}", e))?;
-        
-        Self::from_string(&content)
-    }
-    
+    pub fn from_file(path: &Path) -> Result<Self, OpcodeTableError> {
+        let content = fs::read_to_string(path)
+            .map_err(|source| OpcodeTableError::Io { path: path.to_path_buf(), source })?;
+
+        Self::from_string(&content).map_err(|error| error.in_file(path))
+    }
+
+    /// The built-in table with the tables in `paths` merged over it in order,
+    /// so later files take precedence, see [`merge`](Self::merge)
+    pub fn layered<P: AsRef<Path>>(paths: &[P]) -> Result<Self, OpcodeTableError> {
+        let mut table = Self::builtin().clone();
+        for path in paths {
+            table.merge(&Self::from_file(path.as_ref())?);
+        }
+        Ok(table)
+    }
+
+    /// Add the forms of `other`, replacing forms with the same name and
+    /// operands, so a table of overrides and extensions can be laid over a
+    /// base table
+    pub fn merge(&mut self, other: &OpcodeTable) {
+        for form in &other.opcodes {
+            let existing = self.lookup(&form.name)
+                .and_then(|indices| indices.iter().copied().find(|&index| self.opcodes[index].operand_specs == form.operand_specs));
+            match existing {
+                Some(index) => self.opcodes[index] = form.clone(),
+                None => {
+                    let index = self.opcodes.len();
+                    self.opcode_map.entry(form.name.clone())
+                        .or_default()
+                        .push(index);
+                    self.opcodes.push(form.clone());
+                },
+            }
+        }
+    }
+
     /// Parse and load opcodes from a string
-    pub fn from_string(content: &str) -> Result<Self, String> {
+    ///
+    /// Fails with every problem [`check`] finds, so a table loads only if
+    /// `opcodes validate` accepts it.
+    pub fn from_string(content: &str) -> Result<Self, OpcodeTableError> {
+        let (table, problems) = Self::load(content);
+        if problems.is_empty() {
+            Ok(table)
+        } else {
+            Err(OpcodeTableError::Invalid { path: None, problems })
+        }
+    }
+
+    /// The forms of the lines that parse, and the problems with every line
+    fn load(content: &str) -> (Self, Vec<TableProblem>) {
+        let known: HashSet<&str> = tokenizer::instruction_names().collect();
         let mut table = Self::new();
-        
-        for line in content.lines() {
-            let line = line.trim();
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-            
-            let parts: Vec<&str> = line.split('|').collect();
-            if parts.len() < 3 {
-                continue;
-            }
-            
-            let name = parts[0].trim().to_lowercase();
-            let category = parts[1].trim().to_string();
-            let operands = parts[2].trim()
-                .split(',')
-                .map(|s| s.trim().to_string())
-                .collect();
-                
-            let machine_code = if parts.len() > 3 && !parts[3].trim().is_empty() {
-                Some(parts[3].trim().to_string())
-            } else {
-                None
-            };
-            
-            let encoding = if parts.len() > 4 && !parts[4].trim().is_empty() {
-                Some(parts[4].trim().to_string())
-            } else {
-                None
-            };
-            
-            let opcode_info = OpcodeInfo {
-                name: name.clone(),
-                category,
-                operands,
-                machine_code,
-                encoding,
+        let mut problems = Vec::new();
+        let mut first_lines: HashMap<(String, Vec<OperandSpec>), usize> = HashMap::new();
+        let mut unknown: HashSet<String> = HashSet::new();
+
+        for (index, text) in content.lines().enumerate() {
+            let line = index + 1;
+            let forms = match parse_line(text) {
+                Ok(Some(forms)) => forms,
+                Ok(None) if text.trim().is_empty() || text.trim().starts_with('#') => continue,
+                Ok(None) => {
+                    problems.push(TableProblem::Malformed { line, message: "expected name | category | operands [| machine code | encoding]".to_string() });
+                    continue;
+                },
+                Err(message) => {
+                    problems.push(TableProblem::Malformed { line, message });
+                    continue;
+                },
             };
-            
-            let index = table.opcodes.len();
-            table.opcodes.push(opcode_info);
-            
-            table.opcode_map.entry(name)
-                .or_insert_with(Vec::new)
-                .push(index);
-        }
-        
-        Ok(table)
+
+            for form in forms {
+                if !known.contains(form.name.as_str()) && unknown.insert(form.name.clone()) {
+                    problems.push(TableProblem::UnknownMnemonic { line, name: form.name.clone() });
+                }
+                if let Some(message) = inconsistency(&form) {
+                    problems.push(TableProblem::Inconsistent { line, name: form.name.clone(), message });
+                }
+                let key = (form.name.clone(), form.operand_specs.clone());
+                match first_lines.get(&key) {
+                    Some(&first_line) => problems.push(TableProblem::Duplicate {
+                        line,
+                        first_line,
+                        name: form.name.clone(),
+                        operands: form.operands.join(", "),
+                    }),
+                    None => { first_lines.insert(key, line); },
+                }
+
+                let index = table.opcodes.len();
+                table.opcode_map.entry(form.name.clone())
+                    .or_default()
+                    .push(index);
+                table.opcodes.push(form);
+            }
+        }
+        (table, problems)
     }
-    
+
     /// Lookup opcodes for an instruction
     pub fn lookup(&self, name: &str) -> Option<&[usize]> {
         self.opcode_map.get(name).map(|v| v.as_slice())
     }
-    
+
     /// Get information about an instruction
     pub fn get_info(&self, name: &str) -> Option<&OpcodeInfo> {
         self.lookup(name)
             .and_then(|indices| indices.first())
             .map(|&index| &self.opcodes[index])
     }
-    
+
+    /// All forms of an instruction
+    pub fn forms(&self, name: &str) -> Vec<&OpcodeInfo> {
+        self.lookup(name)
+            .map(|indices| indices.iter().map(|&index| &self.opcodes[index]).collect())
+            .unwrap_or_default()
+    }
+
+    /// Check operands against every form of `name`
+    ///
+    /// Instructions missing from the table are accepted as they are. When no
+    /// form fits, the mismatch describes the operand that got furthest: the
+    /// position where the best-matching forms first disagree with the input.
+    /// A memory operand without a size fits r/m forms of any width, so it
+    /// needs one unless the other operands leave a single width.
+    pub fn validate(&self, name: &str, operands: &[Operand]) -> Result<(), OperandMismatch> {
+        let forms = self.forms(name);
+        if forms.is_empty() {
+            return Ok(());
+        }
+
+        let same_count: Vec<&OpcodeInfo> = forms.iter()
+            .filter(|form| form.operand_specs.len() == operands.len())
+            .copied()
+            .collect();
+
+        if same_count.is_empty() {
+            let mut expected: Vec<usize> = forms.iter().map(|form| form.operand_specs.len()).collect();
+            expected.sort_unstable();
+            expected.dedup();
+            return Err(OperandMismatch::Count { expected, found: operands.len() });
+        }
+
+        // Index of the first operand each form rejects (None = accepted)
+        let mut best_position = 0;
+        let mut failures = Vec::new();
+        let mut accepted = Vec::new();
+        for form in same_count {
+            match form.operand_specs.iter().zip(operands).position(|(spec, operand)| !spec.matches(operand)) {
+                None => accepted.push(form),
+                Some(position) => {
+                    best_position = best_position.max(position);
+                    failures.push((position, form));
+                }
+            }
+        }
+        if !accepted.is_empty() {
+            return match unsized_memory(&accepted, operands) {
+                Some((position, widths)) => Err(OperandMismatch::Size { position, widths }),
+                None => Ok(()),
+            };
+        }
+
+        let mut expected: Vec<OperandSpec> = Vec::new();
+        for (position, form) in failures {
+            let spec = &form.operand_specs[position];
+            if position == best_position && !expected.contains(spec) {
+                expected.push(spec.clone());
+            }
+        }
+
+        // "a 64-bit register/memory operand" already covers "a 64-bit register"
+        let covered: Vec<bool> = expected.iter()
+            .map(|spec| expected.iter().any(|other| other != spec && other.covers(spec)))
+            .collect();
+        let mut covered = covered.into_iter();
+        expected.retain(|_| !covered.next().unwrap_or(false));
+
+        Err(OperandMismatch::Kind { position: best_position, expected })
+    }
+
     /// Get all instructions in a category
     pub fn get_category(&self, category: &InstructionCategory) -> Option<Vec<String>> {
         let category_str = match category {
@@ -132,19 +785,181 @@ impl OpcodeTable {
             InstructionCategory::Logic => "logic",
             InstructionCategory::Shift => "shift",
             InstructionCategory::String => "string",
+            InstructionCategory::Simd => "simd",
             InstructionCategory::IO => "io",
             InstructionCategory::Other => "other",
         };
-        
-        let opcodes = self.opcodes.iter()
+
+        let mut opcodes = self.opcodes.iter()
             .filter(|o| o.category.eq_ignore_ascii_case(category_str))
             .map(|o| o.name.clone())
             .collect::<Vec<_>>();
-            
+        opcodes.dedup();
+
         if opcodes.is_empty() {
             None
         } else {
             Some(opcodes)
         }
     }
-} 
\ No newline at end of file
+}
+
+/// The forms a table line stands for, or None for blank lines, comments and
+/// lines without the name, category and operand columns
+pub(crate) fn parse_line(line: &str) -> Result<Option<Vec<OpcodeInfo>>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let parts: Vec<&str> = line.split('|').collect();
+    if parts.len() < 3 {
+        return Ok(None);
+    }
+
+    let name = parts[0].trim().to_lowercase();
+    let category = parts[1].trim().to_string();
+    let operands = parts[2].trim();
+    let column = |index: usize| parts.get(index).map(|part| part.trim()).filter(|part| !part.is_empty());
+
+    let machine_code = column(3).map(str::to_string);
+    let encoding = column(4).map(str::to_string);
+    let template = machine_code.as_deref().map(EncodingTemplate::parse).transpose()
+        .map_err(|message| format!("Machine code '{}' for '{}': {}", machine_code.as_deref().unwrap_or_default(), name, message))?;
+    let operand_encoding = encoding.as_deref().map(OperandEncoding::parse_all).transpose()?.unwrap_or_default();
+
+    // "r/mN, rN" is shorthand for the same form at every width
+    let expanded: Vec<String> = if operands.contains('N') {
+        EXPANDED_WIDTHS.iter().map(|width| operands.replace('N', &width.to_string())).collect()
+    } else {
+        vec![operands.to_string()]
+    };
+
+    let mut forms = Vec::new();
+    for operands in expanded {
+        let operands: Vec<String> = operands.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        let operand_specs = operands.iter()
+            .map(|spec| OperandSpec::parse(spec)
+                .ok_or_else(|| format!("Unknown operand kind '{}' for '{}'", spec, name)))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        forms.push(OpcodeInfo {
+            name: name.clone(),
+            category: category.clone(),
+            operands,
+            operand_specs,
+            machine_code: machine_code.clone(),
+            encoding: encoding.clone(),
+            template: template.clone(),
+            operand_encoding: operand_encoding.clone(),
+        });
+    }
+    Ok(Some(forms))
+}
+
+/// Something wrong with a line of an opcode table, as reported by [`check`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TableProblem {
+    /// The line can't be parsed: missing columns, an unknown operand kind,
+    /// or machine code or an operand encoding that isn't understood
+    Malformed { line: usize, message: String },
+    /// The operand encoding doesn't fit the operands or the machine code
+    Inconsistent { line: usize, name: String, message: String },
+    /// An earlier line already has a form with the same name and operands
+    Duplicate { line: usize, first_line: usize, name: String, operands: String },
+    /// The tokenizer doesn't know the mnemonic, so source can't use the form
+    UnknownMnemonic { line: usize, name: String },
+}
+
+impl fmt::Display for TableProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableProblem::Malformed { line, message } => write!(f, "Line {}: {}", line, message),
+            TableProblem::Inconsistent { line, name, message } => write!(f, "Line {}: '{}': {}", line, name, message),
+            TableProblem::Duplicate { line, first_line, name, operands } if operands.is_empty() =>
+                write!(f, "Line {}: '{}' without operands is already defined on line {}", line, name, first_line),
+            TableProblem::Duplicate { line, first_line, name, operands } =>
+                write!(f, "Line {}: '{} {}' is already defined on line {}", line, name, operands, first_line),
+            TableProblem::UnknownMnemonic { line, name } =>
+                write!(f, "Line {}: '{}' isn't an instruction the tokenizer knows, so no source can use it", line, name),
+        }
+    }
+}
+
+impl TableProblem {
+    /// Line of the table the problem is on
+    pub fn line(&self) -> usize {
+        match self {
+            TableProblem::Malformed { line, .. }
+            | TableProblem::Inconsistent { line, .. }
+            | TableProblem::Duplicate { line, .. }
+            | TableProblem::UnknownMnemonic { line, .. } => *line,
+        }
+    }
+
+    /// The message without the "Line N: " that [`Display`](fmt::Display)
+    /// adds, for messages that give the line themselves
+    pub(crate) fn located_message(&self) -> String {
+        let message = self.to_string();
+        message.strip_prefix(&format!("Line {}: ", self.line())).map_or(message.clone(), str::to_string)
+    }
+}
+
+impl std::error::Error for TableProblem {}
+
+/// Check an opcode table for lines that don't parse, duplicate forms,
+/// operand encodings that don't fit, and mnemonics the tokenizer doesn't know
+///
+/// Returns the number of forms the table defines and the problems found.
+/// Unlike [`OpcodeTable::from_string`], which fails if there are any, the
+/// forms of the lines that do parse are counted.
+pub fn check(content: &str) -> (usize, Vec<TableProblem>) {
+    let (table, problems) = OpcodeTable::load(content);
+    (table.opcodes.len(), problems)
+}
+
+/// Why a form's operand encoding doesn't fit its operands or machine code
+pub(crate) fn inconsistency(form: &OpcodeInfo) -> Option<String> {
+    let encoding = form.encoding.as_deref()?;
+    let roles = &form.operand_encoding;
+    if roles.len() != form.operand_specs.len() {
+        return Some(format!("the operand encoding '{}' doesn't have one letter for each operand of '{}'",
+            encoding, form.operands.join(", ")));
+    }
+
+    for (role, spec) in roles.iter().zip(&form.operand_specs) {
+        let fits = match role {
+            OperandEncoding::Reg => matches!(spec, OperandSpec::Reg(_) | OperandSpec::Xmm | OperandSpec::Ymm),
+            OperandEncoding::Opcode => matches!(spec, OperandSpec::Reg(_)),
+            OperandEncoding::Immediate => matches!(spec, OperandSpec::Imm(_)),
+            OperandEncoding::Relative => matches!(spec, OperandSpec::Rel),
+            _ => true,
+        };
+        if !fits {
+            return Some(format!("{} can't be encoded as '{}'", spec, role));
+        }
+    }
+
+    let template = form.template.as_ref()?;
+    let needs = |role: OperandEncoding| roles.contains(&role);
+    if needs(OperandEncoding::Reg) && template.modrm != Some(ModRm::Register) {
+        Some("an operand is encoded in ModRM.reg (R) but the machine code has no /r".to_string())
+    } else if needs(OperandEncoding::Rm) && template.modrm.is_none() {
+        Some("an operand is encoded in ModRM.rm (M) but the machine code has no ModRM field".to_string())
+    } else if needs(OperandEncoding::Opcode) && !template.register_in_opcode {
+        Some("an operand is encoded in the opcode (O) but the machine code has no +r".to_string())
+    } else if needs(OperandEncoding::Immediate) && template.immediates.is_empty() {
+        Some("an operand is an immediate (I) but the machine code has no ib, iw, id or iq".to_string())
+    } else if needs(OperandEncoding::Relative) && template.relative.is_none() {
+        Some("an operand is a branch displacement (D) but the machine code has no cb, cw or cd".to_string())
+    } else if needs(OperandEncoding::Vex) && template.vex.is_none() {
+        Some("an operand is encoded in VEX.vvvv (V) but the machine code has no VEX prefix".to_string())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,21 @@
+//! The types most users of the library need, in one import.
+//!
+//! ```no_run
+//! use nasembler::prelude::*;
+//!
+//! let mut assembler = Assembler::new().format(Format::Elf);
+//! let program: Program = assembler.assemble_program("section .text\n_start:\n    syscall\n").unwrap();
+//! ```
+//!
+//! Everything re-exported here is covered by the semver guarantees described
+//! in the crate documentation.
+
+pub use crate::assembler::{Assembler, Format};
+pub use crate::target::Target;
+pub use crate::object::ObjectWriter;
+pub use crate::render::{DiagnosticRenderer, MessageFormat};
+pub use crate::error::{AssemblerError, ElfError, Error, ErrorCollector, ErrorSeverity, ErrorType, OpcodeTableError, ParseError, SourceLocation};
+pub use crate::symbol::Symbol;
+pub use crate::size::SizeReport;
+pub use crate::source_map::SourceMap;
+pub use crate::parser::ast::{Directive, Instruction, MemoryReference, Operand, Program, Section, SpecialSymbol, Statement, AST_VERSION};
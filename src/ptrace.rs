@@ -0,0 +1,56 @@
+//! The ptrace and wait calls shared by [`crate::trace`] and [`crate::sandbox`].
+
+use std::io;
+use std::ptr;
+
+use libc::{c_int, c_void, pid_t};
+
+/// Code segment selector of 32-bit processes on x86-64 Linux
+pub(crate) const CS_32: u64 = 0x23;
+
+/// A ptrace or wait call that failed
+pub(crate) struct RequestError {
+    pub(crate) request: &'static str,
+    pub(crate) source: io::Error,
+}
+
+/// Make a ptrace request that takes no address, only an option mask or signal number
+pub(crate) fn request(name: &'static str, pid: pid_t, request: libc::c_uint, data: usize) -> Result<(), RequestError> {
+    // SAFETY: these requests take no pointers
+    if unsafe { libc::ptrace(request, pid, ptr::null_mut::<c_void>(), data as *mut c_void) } == -1 {
+        return Err(RequestError { request: name, source: io::Error::last_os_error() });
+    }
+    Ok(())
+}
+
+/// Wait for the next change in `pid`'s state, returning the wait status
+pub(crate) fn wait(pid: pid_t) -> Result<c_int, RequestError> {
+    let mut status = 0;
+    loop {
+        // SAFETY: `status` is a valid out pointer
+        if unsafe { libc::waitpid(pid, &mut status, 0) } != -1 {
+            return Ok(status);
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(RequestError { request: "waitpid", source: error });
+        }
+    }
+}
+
+/// The registers of a stopped tracee
+pub(crate) fn registers(pid: pid_t) -> Result<libc::user_regs_struct, RequestError> {
+    // SAFETY: user_regs_struct is plain integers, and PTRACE_GETREGS fills all of it
+    unsafe {
+        let mut registers: libc::user_regs_struct = std::mem::zeroed();
+        if libc::ptrace(libc::PTRACE_GETREGS, pid, ptr::null_mut::<c_void>(), &mut registers as *mut _ as *mut c_void) == -1 {
+            return Err(RequestError { request: "PTRACE_GETREGS", source: io::Error::last_os_error() });
+        }
+        Ok(registers)
+    }
+}
+
+/// The syscall number the tracee at a syscall stop is making, and the mode of its code
+pub(crate) fn syscall_number(registers: &libc::user_regs_struct) -> (u64, u16) {
+    (registers.orig_rax, if registers.cs == CS_32 { 32 } else { 64 })
+}
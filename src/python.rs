@@ -0,0 +1,141 @@
+//! Python bindings (`python` feature).
+//!
+//! Build and install the extension module with maturin:
+//!
+//! ```text
+//! maturin develop
+//! ```
+//!
+//! pyproject.toml builds with the `bindings` profile, the release profile
+//! with unwinding panics, so a panic raises `PanicException` in Python
+//! rather than aborting the interpreter.
+//!
+//! ```python
+//! import nasembler
+//!
+//! elf = nasembler.assemble("section .text\n_start:\n    mov rax, 60\n    syscall\n")
+//! tokens = nasembler.tokenize("mov rax, 1")
+//! statements = nasembler.parse("_start:\n    xor rdi, rdi\n")
+//! ```
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList};
+
+use crate::assembler::{Assembler, Format};
+use crate::error::{self, AssemblerError as Failure, ErrorCollector};
+use crate::parser::ast::Statement;
+
+create_exception!(nasembler, AssemblerError, PyException, "Raised when the source fails to assemble");
+
+/// Turn the collected diagnostics into an `AssemblerError`, one line per diagnostic
+fn assembler_error(errors: &ErrorCollector, failure: Failure) -> PyErr {
+    let lines: Vec<String> = errors.errors().iter()
+        .map(|error| match &error.location {
+            Some(location) => format!("{}: {}", location, error.detail.message),
+            None => error.detail.message.clone(),
+        })
+        .collect();
+
+    if lines.is_empty() {
+        AssemblerError::new_err(error::error_chain(&failure))
+    } else {
+        AssemblerError::new_err(lines.join("\n"))
+    }
+}
+
+fn parse_format(format: &str) -> PyResult<Format> {
+    match format {
+        "elf" => Ok(Format::Elf),
+        "bin" => Ok(Format::Bin),
+        "hex" => Ok(Format::Hex),
+        "elf32" => Ok(Format::Elf32),
+        "obj" => Ok(Format::Obj),
+        _ => Err(PyValueError::new_err(format!("unknown output format '{}' (expected elf, elf32, obj, bin or hex)", format))),
+    }
+}
+
+/// Assemble `source` and return the output as bytes
+#[pyfunction]
+#[pyo3(signature = (source, format = "elf", entry = "_start"))]
+fn assemble<'py>(py: Python<'py>, source: &str, format: &str, entry: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let mut assembler = Assembler::new()
+        .format(parse_format(format)?)
+        .entry(entry)
+        .file_name("<input>");
+
+    match assembler.assemble(source) {
+        Ok(bytes) => Ok(PyBytes::new(py, &bytes)),
+        Err(err) => Err(assembler_error(assembler.errors(), err)),
+    }
+}
+
+/// Tokenize `source` into `(type, value, line, column)` tuples
+#[pyfunction]
+fn tokenize(source: &str) -> Vec<(String, String, usize, usize)> {
+    Assembler::new().tokenize(source)
+        .into_iter()
+        .map(|token| (token.token_type.to_string(), token.value.into_owned(), token.line, token.column))
+        .collect()
+}
+
+/// Parse `source` into a list of statement dicts
+///
+/// Every dict has a `kind` key ("instruction", "directive", "label",
+/// "section", "comment" or "empty"); the other keys depend on the kind.
+#[pyfunction]
+fn parse<'py>(py: Python<'py>, source: &str) -> PyResult<Bound<'py, PyList>> {
+    let mut assembler = Assembler::new().file_name("<input>");
+    let program = assembler.assemble_program(source)
+        .map_err(|err| assembler_error(assembler.errors(), err))?;
+
+    let statements = PyList::empty(py);
+    for statement in &program.statements {
+        let dict = PyDict::new(py);
+        match statement {
+            Statement::Instruction(instruction) => {
+                dict.set_item("kind", "instruction")?;
+                dict.set_item("name", instruction.name.as_str())?;
+                dict.set_item("operands", instruction.operands.iter().map(|op| op.to_string()).collect::<Vec<_>>())?;
+                dict.set_item("machine_code", PyBytes::new(py, &instruction.machine_code))?;
+                dict.set_item("line", instruction.line)?;
+            },
+            Statement::Directive(directive) => {
+                dict.set_item("kind", "directive")?;
+                dict.set_item("name", &directive.name)?;
+                dict.set_item("operands", directive.operands.iter().map(|op| op.to_string()).collect::<Vec<_>>())?;
+                dict.set_item("line", directive.line)?;
+            },
+            Statement::Label(name) => {
+                dict.set_item("kind", "label")?;
+                dict.set_item("name", name.as_str())?;
+            },
+            Statement::Section(section) => {
+                dict.set_item("kind", "section")?;
+                dict.set_item("name", &section.name)?;
+                dict.set_item("line", section.line)?;
+            },
+            Statement::Comment(text) => {
+                dict.set_item("kind", "comment")?;
+                dict.set_item("text", text)?;
+            },
+            Statement::Empty => {
+                dict.set_item("kind", "empty")?;
+            },
+        }
+        statements.append(dict)?;
+    }
+
+    Ok(statements)
+}
+
+#[pymodule]
+fn nasembler(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(assemble, m)?)?;
+    m.add_function(wrap_pyfunction!(tokenize, m)?)?;
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add("AssemblerError", m.py().get_type::<AssemblerError>())?;
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    Ok(())
+}
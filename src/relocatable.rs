@@ -0,0 +1,488 @@
+//! Relocatable ELF64 objects.
+//!
+//! A relocatable object (`ET_REL`, a `.o` file) leaves addresses to the
+//! linker: .text and .data both start at 0, and every reference the assembler
+//! can't resolve on its own becomes a relocation entry. That is what lets a
+//! program call functions from other files and from C libraries. References
+//! within one section that are relative, and references to absolute symbols
+//! such as `equ` constants, are still patched here.
+
+use std::collections::{BTreeSet, HashSet};
+use std::mem;
+
+use crate::elf::{self, write_fields, ElfGenerator, Elf64Header, HeaderOptions, Record, ELFCLASS64, EM_X86_64, EV_CURRENT};
+use crate::encoder::FixupKind;
+use crate::error::{AssemblerError, ElfError};
+use crate::object::ObjectWriter;
+use crate::parser::ast::{Instruction, Operand, SpecialSymbol, Statement};
+use crate::size::SizeReport;
+use crate::source_map::SourceMap;
+use crate::symbol::Symbol;
+
+pub(crate) const ET_REL: u16 = 1;
+
+pub(crate) const SHT_PROGBITS: u32 = 1;
+pub(crate) const SHT_SYMTAB: u32 = 2;
+pub(crate) const SHT_STRTAB: u32 = 3;
+pub(crate) const SHT_RELA: u32 = 4;
+pub(crate) const SHT_NOBITS: u32 = 8;
+
+pub(crate) const SHF_WRITE: u64 = 1;
+pub(crate) const SHF_ALLOC: u64 = 2;
+pub(crate) const SHF_EXECINSTR: u64 = 4;
+pub(crate) const SHF_MERGE: u64 = 0x10;
+pub(crate) const SHF_STRINGS: u64 = 0x20;
+pub(crate) const SHF_INFO_LINK: u64 = 0x40;
+pub(crate) const SHF_TLS: u64 = 0x400;
+
+pub(crate) const SHN_UNDEF: u16 = 0;
+pub(crate) const SHN_ABS: u16 = 0xFFF1;
+
+pub(crate) const STB_LOCAL: u8 = 0;
+pub(crate) const STB_GLOBAL: u8 = 1;
+pub(crate) const STT_NOTYPE: u8 = 0;
+pub(crate) const STT_OBJECT: u8 = 1;
+pub(crate) const STT_FUNC: u8 = 2;
+pub(crate) const STT_SECTION: u8 = 3;
+pub(crate) const STT_TLS: u8 = 6;
+
+pub(crate) const R_X86_64_64: u32 = 1;
+pub(crate) const R_X86_64_PC32: u32 = 2;
+pub(crate) const R_X86_64_GOT32: u32 = 3;
+pub(crate) const R_X86_64_PLT32: u32 = 4;
+pub(crate) const R_X86_64_GOTPCREL: u32 = 9;
+pub(crate) const R_X86_64_32: u32 = 10;
+pub(crate) const R_X86_64_16: u32 = 12;
+pub(crate) const R_X86_64_PC16: u32 = 13;
+pub(crate) const R_X86_64_PC8: u32 = 15;
+pub(crate) const R_X86_64_TPOFF64: u32 = 18;
+pub(crate) const R_X86_64_TPOFF32: u32 = 23;
+pub(crate) const R_X86_64_GOT64: u32 = 27;
+pub(crate) const R_X86_64_GOTPCREL64: u32 = 28;
+
+/// Section header indices; every object has the same sections, in the order
+/// `finish` writes them
+const TEXT_INDEX: u16 = 1;
+const DATA_INDEX: u16 = 2;
+const SYMTAB_INDEX: u32 = 5;
+const STRTAB_INDEX: u32 = 6;
+/// .tdata and .tbss follow the string table, in objects with thread-locals
+const TDATA_INDEX: u16 = 7;
+const TBSS_INDEX: u16 = 8;
+
+pub(crate) struct Elf64SectionHeader {
+    pub(crate) sh_name: u32,
+    pub(crate) sh_type: u32,
+    pub(crate) sh_flags: u64,
+    pub(crate) sh_addr: u64,
+    pub(crate) sh_offset: u64,
+    pub(crate) sh_size: u64,
+    pub(crate) sh_link: u32,
+    pub(crate) sh_info: u32,
+    pub(crate) sh_addralign: u64,
+    pub(crate) sh_entsize: u64,
+}
+
+pub(crate) struct Elf64Symbol {
+    pub(crate) st_name: u32,
+    pub(crate) st_info: u8,
+    pub(crate) st_other: u8,
+    pub(crate) st_shndx: u16,
+    pub(crate) st_value: u64,
+    pub(crate) st_size: u64,
+}
+
+struct Elf64Rela {
+    r_offset: u64,
+    r_info: u64,
+    r_addend: i64,
+}
+
+impl Record for Elf64SectionHeader {
+    const SIZE: usize = 64;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        write_fields!(out, self, sh_name, sh_type, sh_flags, sh_addr, sh_offset, sh_size, sh_link, sh_info,
+            sh_addralign, sh_entsize);
+    }
+}
+
+impl Record for Elf64Symbol {
+    const SIZE: usize = 24;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        write_fields!(out, self, st_name, st_info, st_other, st_shndx, st_value, st_size);
+    }
+}
+
+impl Record for Elf64Rela {
+    const SIZE: usize = 24;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        write_fields!(out, self, r_offset, r_info, r_addend);
+    }
+}
+
+/// A relocation entry, before symbols have their table indices
+struct Rela {
+    in_text: bool,
+    offset: u64,
+    /// The symbol the entry refers to; `None` for the target's section symbol
+    symbol: Option<Symbol>,
+    /// Whether the target is in .text, for references through a section symbol
+    target_in_text: bool,
+    kind: u32,
+    addend: i64,
+}
+
+/// A section's contents and the header fields that don't depend on where it is written
+pub(crate) struct Section<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) sh_type: u32,
+    pub(crate) sh_flags: u64,
+    pub(crate) sh_addr: u64,
+    pub(crate) sh_link: u32,
+    pub(crate) sh_info: u32,
+    pub(crate) sh_addralign: u64,
+    pub(crate) sh_entsize: u64,
+    pub(crate) contents: &'a [u8],
+    /// Size of an `SHT_NOBITS` section, which has no contents in the file
+    pub(crate) nobits_size: u64,
+}
+
+impl<'a> Section<'a> {
+    pub(crate) fn new(name: &'a str, contents: &'a [u8]) -> Self {
+        Self { name, sh_type: 0, sh_flags: 0, sh_addr: 0, sh_link: 0, sh_info: 0, sh_addralign: 1, sh_entsize: 0, contents, nobits_size: 0 }
+    }
+}
+
+/// A .comment section identifying the assembler, holding NUL-terminated
+/// strings that linkers merge with those of other objects
+pub(crate) fn comment_section(contents: &[u8]) -> Section<'_> {
+    Section { sh_type: SHT_PROGBITS, sh_flags: SHF_MERGE | SHF_STRINGS, sh_entsize: 1, ..Section::new(".comment", contents) }
+}
+
+/// A string table under construction
+pub(crate) struct StringTable {
+    pub(crate) bytes: Vec<u8>,
+}
+
+impl StringTable {
+    pub(crate) fn new() -> Self {
+        // Offset 0 is the empty name
+        Self { bytes: vec![0] }
+    }
+
+    pub(crate) fn add(&mut self, name: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(name.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+/// [`ObjectWriter`] producing an x86-64 relocatable ELF object for `ld` or `cc`
+pub struct RelocatableWriter {
+    generator: ElfGenerator,
+    /// Symbols given absolute values with `define_symbol`
+    absolute: HashSet<Symbol>,
+    relas: Vec<Rela>,
+}
+
+impl RelocatableWriter {
+    /// Wrap a configured generator; its addresses are replaced by 0, since the linker assigns them
+    pub fn new(generator: ElfGenerator) -> Self {
+        Self { generator: generator.with_addresses(0, 0), absolute: HashSet::new(), relas: Vec::new() }
+    }
+
+    /// Symbols named by `global` or `extern` directives
+    fn declared(&self, directive: &str) -> BTreeSet<Symbol> {
+        self.generator.program().statements.iter()
+            .filter_map(|statement| match statement {
+                Statement::Directive(dir) if dir.name == directive => match dir.operands.first() {
+                    Some(Operand::Label(symbol)) => Some(*symbol),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether `name` has a fixed value rather than an address in a section
+    fn is_absolute(&self, name: Symbol) -> bool {
+        self.generator.is_equ(name) || self.absolute.contains(&name)
+    }
+
+    /// Whether `name` is in .text (`Some(false)` for .data), or `None` if it
+    /// isn't an address in this object
+    fn section_of(&self, name: Symbol) -> Option<bool> {
+        if self.is_absolute(name) { None } else { self.generator.label_in_text(name) }
+    }
+
+    /// Symbol table entries (locals first, as ELF requires) and the index of the first global
+    fn symbol_table(&self, strings: &mut StringTable) -> (Vec<(Symbol, Elf64Symbol)>, u32) {
+        let globals: BTreeSet<Symbol> = self.declared("global").into_iter().chain(self.declared("extern")).collect();
+        let mut locals: Vec<Symbol> = self.generator.symbols().keys().copied()
+            .filter(|name| !globals.contains(name))
+            .collect();
+        locals.sort();
+
+        let mut entries: Vec<(Symbol, Elf64Symbol)> = Vec::new();
+        let bindings = locals.iter().map(|name| (STB_LOCAL, *name)).chain(globals.iter().map(|name| (STB_GLOBAL, *name)));
+        for (binding, name) in bindings {
+            let (shndx, value) = match (self.section_of(name), self.generator.symbol_address(&name)) {
+                (Some(true), Some(value)) => (TEXT_INDEX, value),
+                (Some(false), Some(value)) => (DATA_INDEX, value),
+                (None, Some(value)) if self.is_absolute(name) => (SHN_ABS, value),
+                _ => match self.generator.thread_local(name) {
+                    Some((nobits, offset)) => (if nobits { TBSS_INDEX } else { TDATA_INDEX }, offset),
+                    None => (SHN_UNDEF, 0),
+                },
+            };
+            let symbol_type = if self.generator.thread_local(name).is_some() { STT_TLS } else { STT_NOTYPE };
+            entries.push((name, Elf64Symbol {
+                st_name: strings.add(&name),
+                st_info: binding << 4 | symbol_type,
+                st_other: 0,
+                st_shndx: shndx,
+                st_value: value,
+                st_size: 0,
+            }));
+        }
+        // The null symbol and the two section symbols come first
+        (entries, 3 + locals.len() as u32)
+    }
+}
+
+/// An ELF64 file of type `e_type` holding `sections` in order, after the null
+/// section and followed by the section name table
+pub(crate) fn write_sections(e_type: u16, header: &HeaderOptions, sections: &[Section]) -> Vec<u8> {
+    let mut section_names = StringTable::new();
+    let name_offsets: Vec<u32> = sections.iter().map(|section| section.name)
+        .chain([".shstrtab"])
+        .map(|name| section_names.add(name))
+        .collect();
+    let shstrtab = Section { sh_type: SHT_STRTAB, ..Section::new(".shstrtab", &section_names.bytes) };
+
+    let mut image = vec![0u8; Elf64Header::SIZE];
+    let mut headers = vec![0u8; Elf64SectionHeader::SIZE];
+    for (section, sh_name) in sections.iter().chain([&shstrtab]).zip(name_offsets) {
+        image.resize(image.len().next_multiple_of(section.sh_addralign as usize), 0);
+        headers.extend_from_slice(&elf::record_bytes(&Elf64SectionHeader {
+            sh_name,
+            sh_type: section.sh_type,
+            sh_flags: section.sh_flags,
+            sh_addr: section.sh_addr,
+            sh_offset: image.len() as u64,
+            sh_size: if section.sh_type == SHT_NOBITS { section.nobits_size } else { section.contents.len() as u64 },
+            sh_link: section.sh_link,
+            sh_info: section.sh_info,
+            sh_addralign: section.sh_addralign,
+            sh_entsize: section.sh_entsize,
+        }));
+        image.extend_from_slice(section.contents);
+    }
+
+    image.resize(image.len().next_multiple_of(8), 0);
+    let header = Elf64Header {
+        e_ident: elf::elf_ident(ELFCLASS64, header),
+        e_type,
+        e_machine: EM_X86_64,
+        e_version: EV_CURRENT as u32,
+        e_entry: 0,
+        e_phoff: 0,
+        e_shoff: image.len() as u64,
+        e_flags: header.flags,
+        e_ehsize: Elf64Header::SIZE as u16,
+        e_phentsize: 0,
+        e_phnum: 0,
+        e_shentsize: Elf64SectionHeader::SIZE as u16,
+        e_shnum: sections.len() as u16 + 2,
+        e_shstrndx: sections.len() as u16 + 1,
+    };
+    image[..Elf64Header::SIZE].copy_from_slice(&elf::record_bytes(&header));
+    image.extend_from_slice(&headers);
+    image
+}
+
+/// ELF relocation type for a field written by `instruction`
+fn rela_type(kind: FixupKind, instruction: Symbol) -> u32 {
+    match kind {
+        FixupKind::Absolute64 => R_X86_64_64,
+        FixupKind::Absolute32 => R_X86_64_32,
+        FixupKind::Absolute16 => R_X86_64_16,
+        // Calls and jumps may land in a shared library, through its PLT
+        FixupKind::Relative32 if instruction == "call" || instruction == "jmp" => R_X86_64_PLT32,
+        FixupKind::Relative32 => R_X86_64_PC32,
+        FixupKind::Relative16 => R_X86_64_PC16,
+        FixupKind::Relative8 => R_X86_64_PC8,
+    }
+}
+
+/// ELF relocation type for a field referring to a label `wrt` a special symbol
+fn special_rela_type(kind: FixupKind, special: SpecialSymbol) -> Option<u32> {
+    match (special, kind) {
+        (SpecialSymbol::Plt, FixupKind::Relative32) => Some(R_X86_64_PLT32),
+        // A RIP-relative `[rel foo wrt ..got]` loads the entry, as NASM encodes it
+        (SpecialSymbol::GotPcRel | SpecialSymbol::Got, FixupKind::Relative32) => Some(R_X86_64_GOTPCREL),
+        (SpecialSymbol::GotPcRel, FixupKind::Absolute64) => Some(R_X86_64_GOTPCREL64),
+        (SpecialSymbol::Got, FixupKind::Absolute32) => Some(R_X86_64_GOT32),
+        (SpecialSymbol::Got, FixupKind::Absolute64) => Some(R_X86_64_GOT64),
+        _ => None,
+    }
+}
+
+fn is_relative(kind: FixupKind) -> bool {
+    matches!(kind, FixupKind::Relative32 | FixupKind::Relative16 | FixupKind::Relative8)
+}
+
+impl ObjectWriter for RelocatableWriter {
+    fn layout_sections(&mut self) -> Result<(), AssemblerError> {
+        Ok(self.generator.layout()?)
+    }
+
+    fn define_symbol(&mut self, name: &str, address: u64) {
+        self.absolute.insert(Symbol::intern(name));
+        self.generator.define_symbol(name, address);
+    }
+
+    fn symbol(&self, name: &str) -> Option<u64> {
+        self.generator.symbol(name)
+    }
+
+    fn apply_relocations(&mut self) -> Result<(), AssemblerError> {
+        self.generator.resolve_equs()?;
+
+        let mut local = Vec::new();
+        for relocation in mem::take(self.generator.relocations_mut()) {
+            let label = relocation.label;
+            // Offsets from the thread pointer are only known once the linker
+            // has laid out the thread-local block
+            if self.generator.thread_local(label).is_some() {
+                let kind = match (relocation.kind, relocation.wrt) {
+                    (FixupKind::Absolute64, None) => R_X86_64_TPOFF64,
+                    (FixupKind::Absolute32, None) => R_X86_64_TPOFF32,
+                    _ => return Err(ElfError::ThreadLocalReference {
+                        instruction: relocation.instruction.to_string(), label: label.to_string(),
+                    }.into()),
+                };
+                self.relas.push(Rela { in_text: relocation.in_text, offset: relocation.offset as u64, symbol: Some(label),
+                                       target_in_text: false, kind, addend: relocation.addend });
+                continue;
+            }
+            // PLT and GOT entries belong to the symbol, wherever it is defined,
+            // so these are never resolved here
+            if let Some(special) = relocation.wrt {
+                let Some(kind) = special_rela_type(relocation.kind, special) else {
+                    return Err(ElfError::UnsupportedWrt {
+                        instruction: relocation.instruction.to_string(), label: label.to_string(), special: special.to_string(),
+                    }.into());
+                };
+                if self.section_of(label).is_none() && !self.declared("extern").contains(&label) && !self.declared("global").contains(&label) {
+                    return Err(ElfError::UndefinedSymbol(label.to_string()).into());
+                }
+                let pc_bias = if is_relative(relocation.kind) { (relocation.end - relocation.offset) as i64 } else { 0 };
+                let addend = relocation.addend - pc_bias;
+                self.relas.push(Rela { in_text: relocation.in_text, offset: relocation.offset as u64, symbol: Some(label),
+                                       target_in_text: false, kind, addend });
+                continue;
+            }
+            let section = self.section_of(label);
+            // The distance between two places in one section doesn't depend
+            // on where the linker puts it, and absolute values don't move
+            let patch_here = if self.is_absolute(label) {
+                !is_relative(relocation.kind)
+            } else {
+                section == Some(relocation.in_text) && is_relative(relocation.kind)
+            };
+            if patch_here {
+                local.push(relocation);
+                continue;
+            }
+
+            // Relative fields count from the end of the instruction, not the field itself
+            let pc_bias = if is_relative(relocation.kind) { (relocation.end - relocation.offset) as i64 } else { 0 };
+            let rela = match section {
+                Some(target_in_text) => {
+                    let address = self.generator.symbol_address(&label).unwrap_or(0);
+                    Rela { in_text: relocation.in_text, offset: relocation.offset as u64, symbol: None, target_in_text,
+                           kind: rela_type(relocation.kind, relocation.instruction), addend: address as i64 + relocation.addend - pc_bias }
+                },
+                None if self.is_absolute(label) || self.declared("extern").contains(&label) || self.declared("global").contains(&label) => {
+                    Rela { in_text: relocation.in_text, offset: relocation.offset as u64, symbol: Some(label), target_in_text: false,
+                           kind: rela_type(relocation.kind, relocation.instruction), addend: relocation.addend - pc_bias }
+                },
+                None => return Err(ElfError::UndefinedSymbol(label.to_string()).into()),
+            };
+            self.relas.push(rela);
+        }
+
+        *self.generator.relocations_mut() = local;
+        Ok(self.generator.patch_relocations()?)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, AssemblerError> {
+        let mut names = StringTable::new();
+        let (symbols, first_global) = self.symbol_table(&mut names);
+
+        let mut symtab = vec![0u8; Elf64Symbol::SIZE];
+        for shndx in [TEXT_INDEX, DATA_INDEX] {
+            symtab.extend_from_slice(&elf::record_bytes(&Elf64Symbol {
+                st_name: 0, st_info: STB_LOCAL << 4 | STT_SECTION, st_other: 0, st_shndx: shndx, st_value: 0, st_size: 0,
+            }));
+        }
+        for (_, symbol) in &symbols {
+            symtab.extend_from_slice(&elf::record_bytes(symbol));
+        }
+
+        let mut rela_sections = [Vec::new(), Vec::new()];
+        for rela in &self.relas {
+            let index = match rela.symbol {
+                Some(name) => 3 + symbols.iter().position(|(symbol, _)| *symbol == name).unwrap_or_default() as u64,
+                None if rela.target_in_text => TEXT_INDEX as u64,
+                None => DATA_INDEX as u64,
+            };
+            rela_sections[usize::from(!rela.in_text)].extend_from_slice(&elf::record_bytes(&Elf64Rela {
+                r_offset: rela.offset,
+                r_info: index << 32 | rela.kind as u64,
+                r_addend: rela.addend,
+            }));
+        }
+
+        let rela_size = Elf64Rela::SIZE as u64;
+        let mut sections = vec![
+            Section { sh_type: SHT_PROGBITS, sh_flags: SHF_ALLOC | SHF_EXECINSTR, sh_addralign: self.generator.alignment(true).max(16), ..Section::new(".text", self.generator.text_section()) },
+            Section { sh_type: SHT_PROGBITS, sh_flags: SHF_ALLOC | SHF_WRITE, sh_addralign: self.generator.alignment(false).max(4), ..Section::new(".data", self.generator.data_section()) },
+            Section { sh_type: SHT_RELA, sh_flags: SHF_INFO_LINK, sh_link: SYMTAB_INDEX, sh_info: TEXT_INDEX as u32, sh_addralign: 8, sh_entsize: rela_size, ..Section::new(".rela.text", &rela_sections[0]) },
+            Section { sh_type: SHT_RELA, sh_flags: SHF_INFO_LINK, sh_link: SYMTAB_INDEX, sh_info: DATA_INDEX as u32, sh_addralign: 8, sh_entsize: rela_size, ..Section::new(".rela.data", &rela_sections[1]) },
+            Section { sh_type: SHT_SYMTAB, sh_link: STRTAB_INDEX, sh_info: first_global, sh_addralign: 8, sh_entsize: Elf64Symbol::SIZE as u64, ..Section::new(".symtab", &symtab) },
+            Section { sh_type: SHT_STRTAB, ..Section::new(".strtab", &names.bytes) },
+        ];
+        if let Some(tls) = self.generator.thread_local_block() {
+            let flags = SHF_ALLOC | SHF_WRITE | SHF_TLS;
+            let align = tls.align.max(4);
+            sections.push(Section { sh_type: SHT_PROGBITS, sh_flags: flags, sh_addralign: align, ..Section::new(".tdata", tls.image) });
+            sections.push(Section { sh_type: SHT_NOBITS, sh_flags: flags, sh_addralign: align, nobits_size: tls.bss_size, ..Section::new(".tbss", &[]) });
+        }
+        if let Some(comment) = self.generator.comment() {
+            sections.push(comment_section(comment));
+        }
+        Ok(write_sections(ET_REL, &self.generator.header(), &sections))
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        self.generator.warnings()
+    }
+
+    fn code(&self) -> Vec<(&Instruction, &[u8])> {
+        self.generator.code()
+    }
+
+    fn size_report(&self, file_size: u64) -> Option<SizeReport> {
+        Some(self.generator.layout_sizes(file_size))
+    }
+
+    fn source_map(&self, file: &str) -> Option<SourceMap> {
+        Some(self.generator.layout_source_map(file))
+    }
+}
@@ -0,0 +1,589 @@
+//! Turning collected diagnostics into text
+//!
+//! The collector picks a [`DiagnosticRenderer`] from its [`MessageFormat`];
+//! each renderer formats both single errors and a whole collector.
+
+use std::path::Path;
+use clap::ValueEnum;
+use colored::*;
+
+use crate::error::{same_line, get_affected_token_length, Error, ErrorCollector, ErrorSeverity};
+
+/// How diagnostics are printed (`--message-format`)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum MessageFormat {
+    /// Colored output with source snippets (default)
+    #[default]
+    Pretty,
+    /// One line per diagnostic, GCC style: `file:line:col: error: message`
+    Compact,
+    /// Nothing; only the exit code reports failure
+    Quiet,
+}
+
+impl MessageFormat {
+    /// The renderer for this format
+    pub fn renderer(self) -> Box<dyn DiagnosticRenderer> {
+        match self {
+            MessageFormat::Pretty => Box::new(PrettyRenderer),
+            MessageFormat::Compact => Box::new(CompactRenderer),
+            MessageFormat::Quiet => Box::new(QuietRenderer),
+        }
+    }
+}
+
+/// Formats diagnostics for display
+pub trait DiagnosticRenderer {
+    /// Text for a single diagnostic
+    fn render_error(&self, error: &Error) -> String;
+
+    /// Text for every diagnostic in `errors`
+    fn render(&self, errors: &ErrorCollector) -> String;
+}
+
+/// Colored output with highlighted source snippets
+pub struct PrettyRenderer;
+
+impl DiagnosticRenderer for PrettyRenderer {
+    /// A colorized tree-like display of the error and its sub-errors
+    fn render_error(&self, error: &Error) -> String {
+        let mut output = String::new();
+        
+        // Error header with error type
+        let error_type_str = format!("{}", error.error_type);
+        let header = format!("{} [{}]: {}", 
+            error.severity, 
+            error_type_str,
+            error.detail.message.white().bold()
+        );
+        output.push_str(&header);
+        output.push('\n');
+        
+        // Location with prettier formatting
+        if let Some(ref location) = error.location {
+            let location_str = format!("  {} {}", 
+                "→".bright_cyan().bold(), 
+                location.to_string().bright_blue().underline()
+            );
+            output.push_str(&location_str);
+            output.push('\n');
+            
+            // Line content if available - with syntax highlighting for assembly
+            if let Some(ref line_content) = location.line_content {
+                // Basic syntax highlighting
+                let highlighted_line = highlight_assembly_line(line_content);
+                output.push_str(&format!("    {}\n", highlighted_line));
+                
+                // Caret at the start of the offending text, underlined to its end
+                let (start, width) = location.underline(line_content);
+                let pointer = format!("{}{}{}",
+                    " ".repeat(start),
+                    "^".bright_red().bold(),
+                    "~".repeat(width - 1).bright_red().bold()
+                );
+
+                output.push_str(&format!("    {}\n", pointer));
+            }
+        }
+        
+        // Help message with nicer formatting
+        if let Some(ref help) = error.detail.help {
+            output.push_str(&format!("  {} {}\n", 
+                "💡".to_string() + &" help:".green().bold().to_string(), 
+                help.bright_green()
+            ));
+        }
+        
+        // Note with nicer formatting
+        if let Some(ref note) = error.detail.note {
+            output.push_str(&format!("  {} {}\n", 
+                "ℹ️".to_string() + &" note:".bright_cyan().bold().to_string(), 
+                note.cyan()
+            ));
+        }
+        
+        // Sub-errors with improved tree formatting
+        if !error.sub_errors.is_empty() {
+            output.push_str(&format!("  {} {}\n", 
+                "⤷".bright_blue().bold(), 
+                "caused by:".bright_blue().underline()
+            ));
+            
+            for (i, sub_error) in error.sub_errors.iter().enumerate() {
+                let is_last = i == error.sub_errors.len() - 1;
+                let prefix = if is_last { "  └─ " } else { "  ├─ " };
+                
+                // First line of sub-error
+                let sub_error_first_line = format!("{}{}: {}", 
+                    prefix.bright_blue(), 
+                    sub_error.severity, 
+                    sub_error.detail.message.white().bold()
+                );
+                output.push_str(&sub_error_first_line);
+                output.push('\n');
+                
+                // Location for sub-error
+                if let Some(ref location) = sub_error.location {
+                    let location_prefix = if is_last { "     " } else { "  │  " };
+                    let location_str = format!("{}{} {}", 
+                        location_prefix.bright_blue(), 
+                        "→".bright_cyan().bold(), 
+                        location.to_string().bright_blue().underline()
+                    );
+                    output.push_str(&location_str);
+                    output.push('\n');
+                    
+                    // Line content if available - with highlighting
+                    if let Some(ref line_content) = location.line_content {
+                        let content_prefix = if is_last { "       " } else { "  │    " };
+                        let highlighted_line = highlight_assembly_line(line_content);
+                        output.push_str(&format!("{}{}\n", content_prefix.bright_blue(), highlighted_line));
+                        
+                        // Enhanced pointer with wavy underline
+                        let mut pointer = String::new();
+                        for _ in 0..location.column {
+                            pointer.push(' ');
+                        }
+                        
+                        pointer.push_str(&"^".bright_red().bold().to_string());
+                        
+                        // Add wavy underline for affected text
+                        if let Some(affected_length) = get_affected_token_length(line_content, location.column) {
+                            for _ in 0..affected_length.saturating_sub(1) {
+                                pointer.push_str(&"~".bright_red().bold().to_string());
+                            }
+                        }
+                        
+                        let pointer_prefix = if is_last { "       " } else { "  │    " };
+                        output.push_str(&format!("{}{}\n", pointer_prefix.bright_blue(), pointer));
+                    }
+                }
+                
+                // Help message for sub-error
+                if let Some(ref help) = sub_error.detail.help {
+                    let help_prefix = if is_last { "     " } else { "  │  " };
+                    output.push_str(&format!("{}{} {}\n", 
+                        help_prefix.bright_blue(), 
+                        "💡 help:".green().bold(), 
+                        help.bright_green()
+                    ));
+                }
+                
+                // Note for sub-error
+                if let Some(ref note) = sub_error.detail.note {
+                    let note_prefix = if is_last { "     " } else { "  │  " };
+                    output.push_str(&format!("{}{} {}\n", 
+                        note_prefix.bright_blue(), 
+                        "ℹ️ note:".bright_cyan().bold(), 
+                        note.cyan()
+                    ));
+                }
+                
+                // Nested sub-errors are not shown directly
+                if !sub_error.sub_errors.is_empty() {
+                    let nested_prefix = if is_last { "     " } else { "  │  " };
+                    output.push_str(&format!("{}{} {} more nested errors not shown\n", 
+                        nested_prefix.bright_blue(), 
+                        "⚠️ note:".bright_cyan().bold(), 
+                        sub_error.sub_errors.len().to_string().yellow().bold()
+                    ));
+                }
+            }
+        }
+        
+        output
+    }
+
+    /// Errors grouped by file and line, with a summary at the end
+    fn render(&self, errors: &ErrorCollector) -> String {
+        if errors.errors().is_empty() {
+            return "✓ ".green().bold().to_string() + &"No errors or warnings.".green().to_string();
+        }
+        
+        let mut output = String::new();
+        
+        // Sort errors by severity, then by file, then by line
+        let mut sorted_errors = errors.errors().to_vec();
+        sorted_errors.sort_by(|a, b| {
+            let a_severity = a.severity;
+            let b_severity = b.severity;
+            
+            if a_severity != b_severity {
+                return a_severity.cmp(&b_severity);
+            }
+            
+            // Compare file paths, defaulting to empty string for missing locations
+            let a_file = match &a.location {
+                Some(loc) => &loc.file,
+                None => "",
+            };
+            
+            let b_file = match &b.location {
+                Some(loc) => &loc.file,
+                None => "",
+            };
+            
+            if a_file != b_file {
+                return a_file.cmp(b_file);
+            }
+            
+            let a_line = a.location.as_ref().map(|l| l.line).unwrap_or(0);
+            let b_line = b.location.as_ref().map(|l| l.line).unwrap_or(0);
+            
+            a_line.cmp(&b_line)
+        });
+        
+        // Group errors by file
+        let mut current_file = String::new();
+        // Errors on the same line as the one before them are usually knock-on
+        // effects of it, so they are listed under it without their own snippet
+        let mut primary: Option<&Error> = None;
+        let separator = format!("  {}\n", "―".repeat(25).bright_blue());
+        
+        for (error_counter, error) in (1..).zip(&sorted_errors) {
+            // Add error number prefix with colored symbol
+            let (severity_symbol, severity_color) = match error.severity {
+                ErrorSeverity::Fatal => ("✕", "red"),
+                ErrorSeverity::Error => ("×", "red"),
+                ErrorSeverity::Warning => ("!", "yellow"),
+                ErrorSeverity::Info => ("i", "blue"),
+            };
+            
+            let error_number = format!("{}{}", 
+                severity_symbol.color(severity_color).bold(),
+                format!("{:02}", error_counter).bright_white()
+            );
+            
+            // Compact error header with error type and message
+            let location_info = if let Some(ref location) = error.location {
+                format!("{}:{}", location.line, location.column).bright_blue().bold().to_string()
+            } else {
+                "".to_string()
+            };
+            
+            if primary.is_some_and(|primary| same_line(primary, error)) {
+                output.push_str(&format!("  {} {} {} {} {}\n",
+                    "↳".bright_blue(),
+                    error_number,
+                    location_info,
+                    error.error_type,
+                    error.detail.message.white()
+                ));
+                if let Some(ref help) = error.detail.help {
+                    output.push_str(&format!("      → {}\n", help.bright_green()));
+                }
+                continue;
+            }
+            
+            // Add a minimal separator between errors
+            if primary.is_some() {
+                output.push_str(&separator);
+            }
+            primary = Some(error);
+
+            let file = if let Some(ref location) = error.location {
+                let path = Path::new(&location.file);
+                path.file_name()
+                    .map(|f| f.to_string_lossy().to_string())
+                    .unwrap_or_else(|| location.file.clone())
+            } else {
+                "unknown".to_string()
+            };
+            
+            // Add file header if we're switching to a new file
+            if file != current_file {
+                if !current_file.is_empty() {
+                    output.push('\n');
+                }
+                
+                output.push_str(&format!("{}{}:{}\n", 
+                    "■ ".bright_blue(),
+                    "FILE".bright_white(),
+                    file.bright_white().bold()
+                ));
+                current_file = file;
+            }
+            
+            // Make the error header more compact - inline all the error info
+            let mut error_header = format!("{} {} {} ", 
+                error_number,
+                location_info,
+                error.error_type
+            );
+            
+            // Truncate message if it's too long for better display
+            let message = error.detail.message.clone();
+            
+            error_header.push_str(&message.white().to_string());
+            
+            output.push_str(&format!("{}\n", error_header));
+            
+            // Add code snippet in a more compact way if available
+            if let Some(ref location) = error.location {
+                if let Some(ref line_content) = location.line_content {
+                    // Highlighted code with pointer on the same line
+                    let highlighted_line = highlight_assembly_line(line_content);
+                    
+                    // Create pointer
+                    let (start, width) = location.underline(line_content);
+                    let pointer = format!("{}{}{}",
+                        " ".repeat(start),
+                        "^".bright_red().bold(),
+                        "~".repeat(width - 1).bright_red().bold()
+                    );
+
+                    // Surrounding lines, when the whole file is known
+                    let lines = errors.source_lines(&location.file);
+                    let first = location.line.saturating_sub(errors.context_lines()).max(1);
+                    let last = (location.line + errors.context_lines()).min(lines.len());
+                    let gutter = last.max(location.line).to_string().len();
+                    let context_line = |number: usize| format!("  {:>width$} │ {}\n",
+                        number.to_string().bright_blue(), lines[number - 1].dimmed(), width = gutter);
+
+                    // The pointer lines up with the code under the offending line
+                    output.extend((first..location.line).map(context_line));
+                    output.push_str(&format!("  {:>width$} │ {}\n",
+                        location.line.to_string().bright_blue().bold(), highlighted_line, width = gutter));
+                    output.push_str(&format!("  {} └→{}\n", " ".repeat(gutter), pointer));
+                    output.extend((location.line + 1..=last).map(context_line));
+                }
+            }
+            
+            // Add help and note in a compact inline format
+            let mut hints = String::new();
+            
+            if let Some(ref help) = error.detail.help {
+                hints.push_str(&format!("→ {}", help.bright_green()));
+            }
+            
+            if let Some(ref note) = error.detail.note {
+                if !hints.is_empty() {
+                    hints.push(' ');
+                }
+                hints.push_str(&format!("ⓘ {}", note.cyan()));
+            }
+            
+            if !hints.is_empty() {
+                output.push_str(&format!("  {}\n", hints));
+            }
+            
+        }
+        if primary.is_some() {
+            output.push_str(&separator);
+        }
+        
+        // Add summary with enhanced styling
+        let error_count = errors.error_count();
+        let warning_count = errors.warning_count();
+        
+        let mut summary = String::new();
+        
+        if error_count > 0 {
+            summary.push_str(&format!("{} {} {}", 
+                "×".bright_red().bold(), 
+                error_count.to_string().bright_red().bold(), 
+                if error_count == 1 { "err" } else { "errs" }
+            ));
+        }
+        
+        if warning_count > 0 {
+            if !summary.is_empty() {
+                summary.push_str(" + ");
+            }
+            
+            summary.push_str(&format!("{} {} {}", 
+                "!".bright_yellow().bold(), 
+                warning_count.to_string().bright_yellow().bold(), 
+                if warning_count == 1 { "warn" } else { "warns" }
+            ));
+        }
+        
+        if summary.is_empty() {
+            summary.push_str(&format!("{} {}", "✓".green().bold(), "No issues"));
+        }
+        
+        output.push_str(&format!("{}\n{}\n", "═".repeat(30).bright_blue(), summary));
+        
+        output
+    }
+}
+
+/// One line per diagnostic, in the `file:line:col: severity: message` form
+/// that editors and CI annotations understand
+pub struct CompactRenderer;
+
+impl DiagnosticRenderer for CompactRenderer {
+    fn render_error(&self, error: &Error) -> String {
+        let severity = match error.severity {
+            ErrorSeverity::Fatal => "fatal error",
+            ErrorSeverity::Error => "error",
+            ErrorSeverity::Warning => "warning",
+            ErrorSeverity::Info => "note",
+        };
+        let mut line = match &error.location {
+            Some(location) => format!("{}:{}:{}: {}: {}", location.file, location.line, location.column, severity, error.detail.message),
+            None => format!("nasembler: {}: {}", severity, error.detail.message),
+        };
+        if let Some(ref help) = error.detail.help {
+            line.push_str(&format!(" (help: {})", help));
+        }
+        line
+    }
+
+    fn render(&self, errors: &ErrorCollector) -> String {
+        // In source order, the way compilers list them
+        let mut sorted: Vec<&Error> = errors.errors().iter().collect();
+        sorted.sort_by_key(|error| error.location.as_ref().map(|l| (l.file.clone(), l.line, l.column)));
+        sorted.into_iter().map(|error| self.render_error(error)).collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// Prints nothing
+pub struct QuietRenderer;
+
+impl DiagnosticRenderer for QuietRenderer {
+    fn render_error(&self, _error: &Error) -> String {
+        String::new()
+    }
+
+    fn render(&self, _errors: &ErrorCollector) -> String {
+        String::new()
+    }
+}
+
+// Helper function to highlight assembly syntax
+fn highlight_assembly_line(line: &str) -> String {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    
+    if parts.is_empty() {
+        return line.to_string();
+    }
+    
+    let mut result = String::new();
+    let trimmed = line.trim_start();
+    
+    // Add original indentation
+    let indent_len = line.len() - trimmed.len();
+    if indent_len > 0 {
+        result.push_str(&line[0..indent_len]);
+    }
+    
+    // Check for label (ends with :)
+    if parts[0].ends_with(':') {
+        // Label
+        result.push_str(&parts[0].bright_green().bold().to_string());
+        if parts.len() > 1 {
+            result.push(' ');
+            let remainder = trimmed[parts[0].len()..].trim_start();
+            result.push_str(&highlight_assembly_remainder(remainder));
+        }
+        return result;
+    }
+    
+    // Check for instruction or directive
+    if parts[0].starts_with('.') {
+        // Directive
+        result.push_str(&parts[0].bright_cyan().bold().to_string());
+        if parts.len() > 1 {
+            result.push(' ');
+            let remainder = trimmed[parts[0].len()..].trim_start();
+            result.push_str(&highlight_assembly_remainder(remainder));
+        }
+    } else {
+        // Instruction
+        result.push_str(&parts[0].bright_yellow().bold().to_string());
+        if parts.len() > 1 {
+            result.push(' ');
+            let remainder = trimmed[parts[0].len()..].trim_start();
+            result.push_str(&highlight_assembly_remainder(remainder));
+        }
+    }
+    
+    result
+}
+
+// Helper function to highlight the remainder of an assembly line
+fn highlight_assembly_remainder(remainder: &str) -> String {
+    let mut result = String::new();
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut current_token = String::new();
+    
+    for c in remainder.chars() {
+        if in_comment {
+            // Everything after ; is a comment
+            result.push_str(&c.to_string().bright_black().to_string());
+            continue;
+        }
+        
+        if c == '"' {
+            if in_string {
+                // End of string
+                current_token.push(c);
+                result.push_str(&current_token.green().to_string());
+                current_token.clear();
+                in_string = false;
+            } else {
+                // Start of string
+                if !current_token.is_empty() {
+                    result.push_str(&highlight_assembly_token(&current_token));
+                    current_token.clear();
+                }
+                current_token.push(c);
+                in_string = true;
+            }
+        } else if in_string {
+            // Inside string
+            current_token.push(c);
+        } else if c == ';' {
+            // Start of comment
+            if !current_token.is_empty() {
+                result.push_str(&highlight_assembly_token(&current_token));
+                current_token.clear();
+            }
+            result.push_str(&c.to_string().bright_black().to_string());
+            in_comment = true;
+        } else if c.is_whitespace() {
+            // Whitespace
+            if !current_token.is_empty() {
+                result.push_str(&highlight_assembly_token(&current_token));
+                current_token.clear();
+            }
+            result.push(c);
+        } else if c == ',' || c == '[' || c == ']' || c == '+' || c == '-' || c == '*' {
+            // Special chars
+            if !current_token.is_empty() {
+                result.push_str(&highlight_assembly_token(&current_token));
+                current_token.clear();
+            }
+            result.push_str(&c.to_string().bright_magenta().to_string());
+        } else {
+            // Part of a token
+            current_token.push(c);
+        }
+    }
+    
+    // Don't forget any remaining token
+    if !current_token.is_empty() {
+        result.push_str(&highlight_assembly_token(&current_token));
+    }
+    
+    result
+}
+
+// Helper function to highlight a token based on its content
+fn highlight_assembly_token(token: &str) -> String {
+    if token.starts_with('r') || token == "rax" || token == "rbx" || token == "rcx" || token == "rdx" || 
+       token == "rsi" || token == "rdi" || token == "rbp" || token == "rsp" || 
+       token.starts_with("xmm") || token.starts_with("ymm") || token.starts_with("zmm") {
+        // Register
+        token.bright_blue().to_string()
+    } else if token.starts_with("0x") || token.chars().all(|c| c.is_ascii_digit()) {
+        // Numeric literal
+        token.bright_cyan().to_string()
+    } else {
+        // Default - likely a label reference or other identifier
+        token.white().to_string()
+    }
+}
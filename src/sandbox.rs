@@ -0,0 +1,392 @@
+//! Running programs with restricted access to the system.
+//!
+//! [`Sandbox::run`] executes a program under resource limits and a seccomp
+//! filter. The filter stops the program at the first syscall that reaches
+//! beyond its own process: using the network, starting or tracing other
+//! programs, signalling other processes, and changing the system's
+//! configuration. The limits cap its CPU time, address space, open files and
+//! the size of the files it writes.
+//!
+//! A program that breaks a rule is killed, and the [`Outcome`] says which
+//! rule it broke. Running out of memory or file descriptors isn't fatal: the
+//! call that would go over the limit fails with `ENOMEM` or `EMFILE`, which
+//! the program can handle like any other error.
+//!
+//! ```no_run
+//! use nasembler::sandbox::Sandbox;
+//!
+//! let outcome = Sandbox::new().run("./program".as_ref(), &[] as &[&str]).unwrap();
+//! if let Some(violation) = &outcome.violation {
+//!     eprintln!("the program {}", violation);
+//! }
+//! ```
+
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::ptr;
+use std::time::Duration;
+
+use libc::{c_char, c_void, pid_t, sock_filter};
+
+use crate::ptrace::{self, request, wait, RequestError};
+use crate::syscalls;
+
+pub const DEFAULT_CPU_TIME: Duration = Duration::from_secs(10);
+pub const DEFAULT_MEMORY: u64 = 256 << 20;
+pub const DEFAULT_OPEN_FILES: u64 = 64;
+pub const DEFAULT_FILE_SIZE: u64 = 64 << 20;
+
+/// Syscalls the program may not make, by what they would let it do
+const FORBIDDEN: &[(&str, &[&str])] = &[
+    ("use the network", &[
+        "socket", "socketpair", "socketcall", "connect", "accept", "accept4", "bind", "listen", "sendto",
+        "recvfrom", "sendmsg", "recvmsg", "shutdown", "getsockopt", "setsockopt", "getsockname", "getpeername",
+    ]),
+    ("start other programs", &["fork", "vfork", "clone", "clone3", "execve", "execveat"]),
+    ("trace other processes", &["ptrace"]),
+    ("change the system's configuration", &[
+        "mount", "umount2", "chroot", "pivot_root", "reboot", "swapon", "sethostname", "init_module",
+        "delete_module", "kexec_load", "iopl", "ioperm",
+    ]),
+];
+/// Syscalls that send signals, which the program may only send to itself
+const SIGNALS: &[&str] = &["kill", "tkill", "tgkill"];
+const SIGNALS_REASON: &str = "signal other processes";
+const X32_REASON: &str = "use the x32 syscall ABI";
+
+const AUDIT_ARCH_X86_64: u32 = 0xC000_003E;
+const AUDIT_ARCH_I386: u32 = 0x4000_0003;
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+/// Offsets into `struct seccomp_data`
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARG0: u32 = 16;
+/// Exit status of a child that couldn't set up the sandbox or execute the program
+const SETUP_FAILED: i32 = 127;
+
+/// Failure while running a sandboxed program
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SandboxError {
+    /// The program path or an argument contains a NUL byte
+    InvalidArgument,
+    /// The sandbox couldn't be set up or the program couldn't be executed
+    Spawn(io::Error),
+    /// A ptrace or wait request on the program failed
+    Ptrace { request: &'static str, source: io::Error },
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SandboxError::InvalidArgument => write!(f, "The program path and arguments can't contain NUL bytes"),
+            SandboxError::Spawn(_) => write!(f, "Failed to start the program in the sandbox"),
+            SandboxError::Ptrace { request, .. } => write!(f, "{} failed while supervising the program", request),
+        }
+    }
+}
+
+impl From<RequestError> for SandboxError {
+    fn from(error: RequestError) -> Self {
+        SandboxError::Ptrace { request: error.request, source: error.source }
+    }
+}
+
+impl std::error::Error for SandboxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SandboxError::Spawn(source) | SandboxError::Ptrace { source, .. } => Some(source),
+            SandboxError::InvalidArgument => None,
+        }
+    }
+}
+
+/// A rule the program broke, for which it was killed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// It made a forbidden syscall; `bits` is the mode of the code that made it
+    Syscall { number: u64, bits: u16 },
+    /// It used up its CPU time
+    CpuTime(Duration),
+    /// It tried to make a file larger than this many bytes
+    FileSize(u64),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Violation::Syscall { number, bits } => {
+                match syscalls::syscall_name(number as i64, bits) {
+                    Some(name) => write!(f, "called '{}' (syscall {})", name, number)?,
+                    None => write!(f, "made syscall {}", number)?,
+                }
+                write!(f, ", but sandboxed programs can't {}", forbidden_reason(number, bits))
+            },
+            Violation::CpuTime(limit) => write!(f, "used more than {} s of CPU time", limit.as_secs()),
+            Violation::FileSize(limit) => write!(f, "tried to write more than {} bytes to a file", limit),
+        }
+    }
+}
+
+/// How a sandboxed program ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub status: ExitStatus,
+    /// Why the sandbox killed the program, if it did
+    pub violation: Option<Violation>,
+}
+
+/// Limits and rules for running a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sandbox {
+    cpu_time: Duration,
+    memory: u64,
+    open_files: u64,
+    file_size: u64,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sandbox {
+    /// A sandbox with the default limits: 10 s of CPU time, 256 MiB of
+    /// address space, 64 open files and 64 MiB per written file
+    pub fn new() -> Self {
+        Self {
+            cpu_time: DEFAULT_CPU_TIME,
+            memory: DEFAULT_MEMORY,
+            open_files: DEFAULT_OPEN_FILES,
+            file_size: DEFAULT_FILE_SIZE,
+        }
+    }
+
+    /// Set the CPU time after which the program is killed, rounded up to whole seconds
+    pub fn cpu_time(mut self, cpu_time: Duration) -> Self {
+        self.cpu_time = cpu_time;
+        self
+    }
+
+    /// Set the most address space, in bytes, the program may map
+    pub fn memory(mut self, memory: u64) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Set how many file descriptors the program may have open, including stdin, stdout and stderr
+    pub fn open_files(mut self, open_files: u64) -> Self {
+        self.open_files = open_files;
+        self
+    }
+
+    /// Set the largest file, in bytes, the program may write
+    pub fn file_size(mut self, file_size: u64) -> Self {
+        self.file_size = file_size;
+        self
+    }
+
+    /// Run `program` with `args` to completion, inheriting stdin, stdout, stderr and the environment
+    pub fn run<S: AsRef<OsStr>>(&self, program: &Path, args: &[S]) -> Result<Outcome, SandboxError> {
+        let c_string = |value: &OsStr| CString::new(value.as_bytes()).map_err(|_| SandboxError::InvalidArgument);
+        let program = c_string(program.as_os_str())?;
+        let args = args.iter().map(|arg| c_string(arg.as_ref())).collect::<Result<Vec<_>, _>>()?;
+        let argv: Vec<*const c_char> = std::iter::once(program.as_ptr())
+            .chain(args.iter().map(|arg| arg.as_ptr()))
+            .chain(std::iter::once(ptr::null()))
+            .collect();
+        let cpu_seconds = self.cpu_time.as_secs() + u64::from(self.cpu_time.subsec_nanos() > 0);
+        let limits = [
+            // The hard limit is a second later, so the program gets SIGXCPU first
+            (libc::RLIMIT_CPU, cpu_seconds.max(1), cpu_seconds.max(1) + 1),
+            (libc::RLIMIT_AS, self.memory, self.memory),
+            (libc::RLIMIT_NOFILE, self.open_files, self.open_files),
+            (libc::RLIMIT_FSIZE, self.file_size, self.file_size),
+        ];
+        let (mut filter, pid_slots) = filter();
+
+        // SAFETY: the child only makes async-signal-safe calls on memory prepared before the fork
+        let pid = unsafe { libc::fork() };
+        if pid == -1 {
+            return Err(SandboxError::Spawn(io::Error::last_os_error()));
+        }
+        if pid == 0 {
+            // SAFETY: as above; the child never returns from here
+            unsafe { enter_sandbox(&program, &argv, &limits, &mut filter, &pid_slots) }
+        }
+        self.supervise(pid)
+    }
+
+    /// Follow the program from its first stop until it exits or breaks a rule
+    fn supervise(&self, pid: pid_t) -> Result<Outcome, SandboxError> {
+        // The child stops itself before installing the filter, so the
+        // options are in place before its first filtered syscall
+        let status = wait(pid)?;
+        if !libc::WIFSTOPPED(status) {
+            return Err(SandboxError::Spawn(io::Error::other("the sandbox couldn't be set up")));
+        }
+        let options = libc::PTRACE_O_TRACESECCOMP | libc::PTRACE_O_TRACEEXEC | libc::PTRACE_O_EXITKILL;
+        request("PTRACE_SETOPTIONS", pid, libc::PTRACE_SETOPTIONS, options as usize)?;
+
+        // Set once the program replaces the child; the execve that does so is ours
+        let mut loaded = false;
+        let mut signal = 0;
+        loop {
+            request("PTRACE_CONT", pid, libc::PTRACE_CONT, signal as usize)?;
+            let status = wait(pid)?;
+            signal = 0;
+            if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+                if !loaded {
+                    return Err(SandboxError::Spawn(io::Error::other("the program couldn't be executed")));
+                }
+                return Ok(Outcome { status: ExitStatus::from_raw(status), violation: None });
+            }
+
+            let violation = match status >> 16 {
+                libc::PTRACE_EVENT_SECCOMP if loaded => {
+                    let (number, bits) = ptrace::syscall_number(&ptrace::registers(pid)?);
+                    Violation::Syscall { number, bits }
+                },
+                libc::PTRACE_EVENT_EXEC => {
+                    loaded = true;
+                    continue;
+                },
+                0 => match libc::WSTOPSIG(status) {
+                    libc::SIGXCPU => Violation::CpuTime(self.cpu_time),
+                    libc::SIGXFSZ => Violation::FileSize(self.file_size),
+                    stop => {
+                        signal = stop;
+                        continue;
+                    },
+                },
+                _ => continue,
+            };
+            return Ok(Outcome { status: kill(pid)?, violation: Some(violation) });
+        }
+    }
+}
+
+/// Why syscall `number` is forbidden
+fn forbidden_reason(number: u64, bits: u16) -> &'static str {
+    if bits == 64 && number & u64::from(X32_SYSCALL_BIT) != 0 {
+        return X32_REASON;
+    }
+    let name = syscalls::syscall_name(number as i64, bits).unwrap_or_default();
+    if SIGNALS.contains(&name) {
+        return SIGNALS_REASON;
+    }
+    FORBIDDEN.iter()
+        .find(|(_, names)| names.contains(&name))
+        .map_or("make this syscall", |&(reason, _)| reason)
+}
+
+/// The seccomp program, and the positions of the instructions that compare
+/// against the child's pid, which only the child knows
+fn filter() -> (Vec<sock_filter>, Vec<usize>) {
+    let load = |offset: u32| statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset);
+    let allow = statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW);
+    let trace = statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_TRACE);
+
+    let mut program = vec![load(DATA_ARCH)];
+    let mut pid_slots = Vec::new();
+    for (arch, bits) in [(AUDIT_ARCH_X86_64, 64), (AUDIT_ARCH_I386, 32)] {
+        let mut block = vec![load(DATA_NR)];
+        let mut block_slots = Vec::new();
+        if bits == 64 {
+            block.push(jump(libc::BPF_JGE, X32_SYSCALL_BIT, 0, 1));
+            block.push(trace);
+        }
+        for name in SIGNALS {
+            let Some(number) = syscall_number(name, bits) else { continue };
+            block.push(jump(libc::BPF_JEQ, number, 0, 4));
+            block.push(load(DATA_ARG0));
+            block_slots.push(block.len());
+            block.push(jump(libc::BPF_JEQ, 0, 0, 1));
+            block.push(allow);
+            block.push(trace);
+        }
+        for name in FORBIDDEN.iter().flat_map(|(_, names)| names.iter()) {
+            let Some(number) = syscall_number(name, bits) else { continue };
+            block.push(jump(libc::BPF_JEQ, number, 0, 1));
+            block.push(trace);
+        }
+        block.push(allow);
+
+        // The accumulator still holds the arch when the block is skipped
+        let skip = u8::try_from(block.len()).expect("filter block fits a BPF jump");
+        program.push(jump(libc::BPF_JEQ, arch, 0, skip));
+        pid_slots.extend(block_slots.iter().map(|slot| program.len() + slot));
+        program.extend(block);
+    }
+    program.push(statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS));
+    (program, pid_slots)
+}
+
+fn syscall_number(name: &str, bits: u16) -> Option<u32> {
+    syscalls::constant(&format!("SYS_{}", name), bits).map(|number| number as u32)
+}
+
+fn statement(code: u32, k: u32) -> sock_filter {
+    sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+fn jump(condition: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code: (libc::BPF_JMP | condition | libc::BPF_K) as u16, jt, jf, k }
+}
+
+/// Set up the sandbox in the forked child and execute the program
+///
+/// # Safety
+///
+/// Must only be called in a child right after `fork`; `argv` must be a
+/// null-terminated array of pointers into live C strings
+unsafe fn enter_sandbox(
+    program: &CString,
+    argv: &[*const c_char],
+    limits: &[(libc::__rlimit_resource_t, u64, u64)],
+    filter: &mut [sock_filter],
+    pid_slots: &[usize],
+) -> ! {
+    let pid = libc::getpid() as u32;
+    for &slot in pid_slots {
+        filter[slot].k = pid;
+    }
+
+    if libc::ptrace(libc::PTRACE_TRACEME, 0, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) == -1 {
+        libc::_exit(SETUP_FAILED);
+    }
+    libc::raise(libc::SIGSTOP);
+
+    for &(resource, soft, hard) in limits {
+        let limit = libc::rlimit { rlim_cur: soft, rlim_max: hard };
+        if libc::setrlimit(resource, &limit) == -1 {
+            libc::_exit(SETUP_FAILED);
+        }
+    }
+    let program_filter = libc::sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+    if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1
+        || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program_filter as *const libc::sock_fprog) == -1
+    {
+        libc::_exit(SETUP_FAILED);
+    }
+    libc::execv(program.as_ptr(), argv.as_ptr());
+    libc::_exit(SETUP_FAILED)
+}
+
+/// Kill the program and collect its final status
+fn kill(pid: pid_t) -> Result<ExitStatus, SandboxError> {
+    // SAFETY: `pid` is our child, which hasn't been reaped yet
+    unsafe { libc::kill(pid, libc::SIGKILL) };
+    loop {
+        let status = wait(pid)?;
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            return Ok(ExitStatus::from_raw(status));
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Checking code for byte values it must not contain.
+//!
+//! Shellcode is usually delivered through a string copy or a line read, which
+//! stops at the first NUL or newline, so none of its bytes may have those
+//! values. Each instruction whose encoding contains a forbidden byte is
+//! reported, together with an equivalent instruction sequence free of them
+//! when one of the usual rewrites applies: clearing a register with `xor`,
+//! loading a small value through a narrower register or the stack, or
+//! addressing data relative to `rip`. Constants defined with `equ` or `%use`
+//! are loaded like the numbers they stand for.
+
+use std::collections::HashMap;
+
+use crate::encoder::{immediate_value, MachineCodeEncoder};
+use crate::parser::ast::{Instruction, Operand, Program, Statement};
+use crate::symbol::Symbol;
+
+/// Names of each general-purpose register at 64, 32, 16 and 8 bits
+const REGISTERS: [[&str; 4]; 16] = [
+    ["rax", "eax", "ax", "al"], ["rcx", "ecx", "cx", "cl"], ["rdx", "edx", "dx", "dl"],
+    ["rbx", "ebx", "bx", "bl"], ["rsp", "esp", "sp", "spl"], ["rbp", "ebp", "bp", "bpl"],
+    ["rsi", "esi", "si", "sil"], ["rdi", "edi", "di", "dil"], ["r8", "r8d", "r8w", "r8b"],
+    ["r9", "r9d", "r9w", "r9b"], ["r10", "r10d", "r10w", "r10b"], ["r11", "r11d", "r11w", "r11b"],
+    ["r12", "r12d", "r12w", "r12b"], ["r13", "r13d", "r13w", "r13b"], ["r14", "r14d", "r14w", "r14b"],
+    ["r15", "r15d", "r15w", "r15b"],
+];
+
+/// An instruction whose bytes include forbidden values
+pub(crate) struct ForbiddenBytes {
+    pub(crate) line: usize,
+    pub(crate) message: String,
+    /// A replacement that avoids them, if one is known
+    pub(crate) help: Option<String>,
+}
+
+/// Names the program defines with `equ`, whose values are constants rather than addresses
+pub(crate) fn constants(program: &Program) -> Vec<Symbol> {
+    program.statements.windows(2)
+        .filter_map(|pair| match pair {
+            [Statement::Label(name), Statement::Directive(directive)] if directive.name == "equ" => Some(*name),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Check each instruction's final bytes against `forbidden`; replacements
+/// are encoded for `bits`-bit code to make sure they avoid the bytes too.
+/// `constants` holds the value of each label that isn't an address.
+pub(crate) fn check(code: &[(&Instruction, &[u8])], forbidden: &[u8], bits: u16, constants: &HashMap<Symbol, i64>) -> Vec<ForbiddenBytes> {
+    let encoder = MachineCodeEncoder::new().with_bits(bits);
+    code.iter()
+        .filter_map(|&(instruction, bytes)| {
+            let mut found: Vec<u8> = bytes.iter().copied().filter(|byte| forbidden.contains(byte)).collect();
+            if found.is_empty() {
+                return None;
+            }
+            found.sort_unstable();
+            found.dedup();
+
+            let listed: Vec<String> = found.iter().map(|byte| format!("0x{:02x}", byte)).collect();
+            let (noun, listed) = match listed.as_slice() {
+                [single] => ("byte", single.clone()),
+                [rest @ .., last] => ("bytes", format!("{} and {}", rest.join(", "), last)),
+                [] => return None,
+            };
+            let encoding: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            Some(ForbiddenBytes {
+                line: instruction.line,
+                message: format!(
+                    "'{}' encodes to {}, which contains the forbidden {} {}",
+                    instruction.name, encoding.join(" "), noun, listed
+                ),
+                help: replacement(&encoder, instruction, bytes, forbidden, constants),
+            })
+        })
+        .collect()
+}
+
+/// A rewrite of `instruction` that avoids the forbidden bytes
+fn replacement(encoder: &MachineCodeEncoder, instruction: &Instruction, bytes: &[u8], forbidden: &[u8],
+               constants: &HashMap<Symbol, i64>) -> Option<String> {
+    match (instruction.name.as_str(), instruction.operands.as_slice()) {
+        ("mov", [Operand::Register(register), Operand::Immediate(value)]) => {
+            load_replacement(encoder, register.as_str(), immediate_value(value)?, forbidden)
+        },
+        // An equ is loaded like the number it stands for
+        ("mov", [Operand::Register(register), Operand::Label(label)]) if constants.contains_key(label) => {
+            load_replacement(encoder, register.as_str(), i128::from(constants[label]), forbidden)
+        },
+        ("mov", [Operand::Register(register), Operand::Label(label)]) if width_index(register.as_str()) == Some(0) => {
+            Some(format!(
+                "The absolute address of '{}' has forbidden bytes, and shellcode can't rely on where it is loaded anyway; \
+                 load it relative to rip with 'lea {}, [{}]'",
+                label, register, label
+            ))
+        },
+        // Only jumps and RIP-relative addressing (64-bit lea) count from the instruction
+        ("lea", [_, Operand::Memory(_) | Operand::Label(_)]) | ("call" | "jmp", [Operand::Label(_)])
+            if forbidden.contains(&0) && positive_displacement(bytes) && (instruction.name != "lea" || encoder.bits() == 64) =>
+        {
+            Some("Forward distances under 16 MiB have 00 bytes at the top of the 32-bit displacement; place the \
+                  target before this instruction so the displacement is negative".to_string())
+        },
+        _ => None,
+    }
+}
+
+/// The first of the [`load_sequences`] of `value` that avoids the forbidden bytes
+fn load_replacement(encoder: &MachineCodeEncoder, register: &str, value: i128, forbidden: &[u8]) -> Option<String> {
+    let sequence = load_sequences(register, value).into_iter()
+        .find(|sequence| sequence.iter().all(|candidate| {
+            encoder.encode(candidate).is_ok_and(|encoded| !encoded.iter().any(|byte| forbidden.contains(byte)))
+        }))?;
+    let steps: Vec<String> = sequence.iter().map(|step| format!("'{}'", step)).collect();
+    Some(format!("Use {} instead", steps.join(" followed by ")))
+}
+
+/// Instruction sequences that load `value` into `register`, most compact first
+fn load_sequences(register: &str, value: i128) -> Vec<Vec<Instruction>> {
+    let Some(width) = width_index(register) else { return Vec::new() };
+    let row = REGISTERS.iter().find(|row| row[width] == register).expect("width_index found the register");
+    // Writing a 32-bit register clears the upper half of the 64-bit one
+    let clear_target = if width <= 1 { row[1] } else { row[width] };
+
+    let mut sequences = Vec::new();
+    if value == 0 {
+        sequences.push(vec![instruction("xor", &[clear_target, clear_target])]);
+    }
+    if width == 0 && (1..=u32::MAX as i128).contains(&value) {
+        sequences.push(vec![instruction("mov", &[row[1], &value.to_string()])]);
+    }
+    if width != 3 && (1..=0xFF).contains(&value) {
+        sequences.push(vec![
+            instruction("xor", &[clear_target, clear_target]),
+            instruction("mov", &[row[3], &value.to_string()]),
+        ]);
+    }
+    // A pushed imm8 is sign-extended to the whole stack slot; negative
+    // numbers are written as the 64-bit value, since operands can't be negated
+    if width <= 1 && (-0x80..=0x7F).contains(&value) {
+        let literal = if value < 0 { format!("0x{:x}", value as u64) } else { value.to_string() };
+        sequences.push(vec![instruction("push", &[&literal]), instruction("pop", &[row[width]])]);
+    }
+    sequences
+}
+
+/// Index into a [`REGISTERS`] row of `register`'s width
+fn width_index(register: &str) -> Option<usize> {
+    REGISTERS.iter().find_map(|row| row.iter().position(|&name| name == register))
+}
+
+/// A register or immediate operand instruction for a suggested rewrite
+fn instruction(name: &str, operands: &[&str]) -> Instruction {
+    let operands = operands.iter()
+        .map(|&operand| match width_index(operand) {
+            Some(_) => Operand::Register(Symbol::intern(operand)),
+            None => Operand::Immediate(operand.to_string()),
+        })
+        .collect();
+    Instruction { name: Symbol::intern(name), operands, machine_code: Vec::new(), line: 0 }
+}
+
+/// Whether the instruction ends with a rel32 field holding a short forward distance
+fn positive_displacement(bytes: &[u8]) -> bool {
+    let Some(field) = bytes.len().checked_sub(4).and_then(|start| bytes.get(start..)) else { return false };
+    let distance = i32::from_le_bytes(field.try_into().expect("field is 4 bytes"));
+    (0..1 << 24).contains(&distance)
+}
+
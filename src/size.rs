@@ -0,0 +1,101 @@
+//! Where the bytes of an output file come from.
+//!
+//! A [`SizeReport`] attributes every byte of the file to a section, and every
+//! byte of a section to the label it follows, so the routines and data blobs
+//! that dominate a binary stand out. Each label covers the bytes up to the
+//! next label or the end of its section; bytes before a section's first label
+//! are listed as `[unlabeled]`. What belongs to no section, such as ELF
+//! headers and alignment padding, is listed as `[headers and padding]`.
+//!
+//! ```no_run
+//! use nasembler::Assembler;
+//!
+//! let mut assembler = Assembler::new().report_sizes(true);
+//! assembler.assemble_file("program.asm", "program").unwrap();
+//! print!("{}", assembler.size_report().unwrap());
+//! ```
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Name used for bytes that are in no section
+pub const OVERHEAD: &str = "[headers and padding]";
+/// Name used for bytes before the first label of a section
+pub const UNLABELED: &str = "[unlabeled]";
+
+/// Bytes one section or label accounts for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeEntry {
+    pub name: String,
+    /// Section the bytes are in; for a section entry, its own name
+    pub section: String,
+    pub size: u64,
+}
+
+/// Sizes of an output file's sections and labels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeReport {
+    pub file_size: u64,
+    /// Sections from largest to smallest, followed by the bytes in no section
+    pub sections: Vec<SizeEntry>,
+    /// Label regions from largest to smallest
+    pub symbols: Vec<SizeEntry>,
+}
+
+impl SizeReport {
+    /// A report for a file of `file_size` bytes, from the bytes each section
+    /// and label region holds; entries of the same name and section are merged
+    pub fn new(file_size: u64, sections: Vec<SizeEntry>, symbols: Vec<SizeEntry>) -> Self {
+        let mut sections = merged(sections);
+        let in_sections: u64 = sections.iter().map(|entry| entry.size).sum();
+        if file_size > in_sections {
+            sections.push(SizeEntry { name: OVERHEAD.to_string(), section: String::new(), size: file_size - in_sections });
+        }
+        Self { file_size, sections, symbols: merged(symbols) }
+    }
+
+    fn percent(&self, size: u64) -> f64 {
+        if self.file_size == 0 { 0.0 } else { size as f64 * 100.0 / self.file_size as f64 }
+    }
+}
+
+/// Merge entries with the same name and section, largest first
+fn merged(entries: Vec<SizeEntry>) -> Vec<SizeEntry> {
+    let mut merged: Vec<SizeEntry> = Vec::with_capacity(entries.len());
+    let mut positions: HashMap<(String, String), usize> = HashMap::new();
+    for entry in entries {
+        match positions.entry((entry.name.clone(), entry.section.clone())) {
+            Entry::Occupied(position) => merged[*position.get()].size += entry.size,
+            Entry::Vacant(position) => {
+                position.insert(merged.len());
+                merged.push(entry);
+            },
+        }
+    }
+    merged.retain(|entry| entry.size > 0);
+    // Ties keep layout order
+    merged.sort_by_key(|entry| std::cmp::Reverse(entry.size));
+    merged
+}
+
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:>10} {:>7}  section", "size", "%")?;
+        for entry in &self.sections {
+            writeln!(f, "{:>10} {:>6.1}%  {}", entry.size, self.percent(entry.size), entry.name)?;
+        }
+        writeln!(f, "{:>10} {:>6.1}%  total", self.file_size, self.percent(self.file_size))?;
+
+        if self.symbols.is_empty() {
+            return Ok(());
+        }
+        let name_width = self.symbols.iter().map(|entry| entry.name.len()).max().unwrap_or(0).max("symbol".len());
+        writeln!(f)?;
+        writeln!(f, "{:>10} {:>7}  {:<name_width$}  section", "size", "%", "symbol")?;
+        for entry in &self.symbols {
+            writeln!(f, "{:>10} {:>6.1}%  {:<name_width$}  {}", entry.size, self.percent(entry.size), entry.name, entry.section)?;
+        }
+        Ok(())
+    }
+}
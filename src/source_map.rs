@@ -0,0 +1,107 @@
+//! Which source line each byte of the output came from.
+//!
+//! A [`SourceMap`] lists every run of bytes a statement emitted, with the
+//! section and virtual address it was placed at and the file, line and text of
+//! the statement: instructions in .text, and data directives and reservations
+//! in the data segment. Labels, comments and directives that emit nothing have
+//! no entry. In a relocatable object addresses are offsets within the section,
+//! since the linker assigns the final ones.
+//!
+//! [`SourceMap::to_json`] writes the map as JSON for debuggers, coverage tools
+//! and other programs that read the output alongside the source:
+//!
+//! ```text
+//! {
+//!   "version": 1,
+//!   "file": "hello.asm",
+//!   "mappings": [
+//!     {"section": ".text", "address": 4194304, "offset": 0, "size": 7, "file": "hello.asm", "line": 7, "statement": "mov rax, 1"}
+//!   ]
+//! }
+//! ```
+//!
+//! ```no_run
+//! use nasembler::Assembler;
+//!
+//! let mut assembler = Assembler::new().map_source(true);
+//! assembler.assemble_file("program.asm", "program").unwrap();
+//! std::fs::write("program.map.json", assembler.source_map().unwrap().to_json()).unwrap();
+//! ```
+
+use std::fmt::Write;
+
+/// Version of the JSON layout written by [`SourceMap::to_json`]
+pub const VERSION: u32 = 1;
+
+/// Bytes one statement emitted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub section: String,
+    pub address: u64,
+    /// Offset of the first byte from the start of .text or the data segment
+    pub offset: u64,
+    pub size: u64,
+    pub line: usize,
+    /// The statement as the assembler understood it, e.g. `mov rax, 1`
+    pub statement: String,
+}
+
+/// Byte ranges of an output file and the source statements they came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMap {
+    /// Source file every mapping's line is in
+    pub file: String,
+    /// Mappings by address, .text before the data segment
+    pub mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    pub fn new(file: &str, mappings: Vec<Mapping>) -> Self {
+        Self { file: file.to_string(), mappings }
+    }
+
+    /// The mapping whose bytes include `address`
+    pub fn at_address(&self, address: u64) -> Option<&Mapping> {
+        self.mappings.iter().find(|mapping| (mapping.address..mapping.address + mapping.size).contains(&address))
+    }
+
+    /// The map as a JSON document, one mapping per line
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        json.push_str("{\n");
+        let _ = writeln!(json, "  \"version\": {},", VERSION);
+        let _ = writeln!(json, "  \"file\": {},", quoted(&self.file));
+        json.push_str("  \"mappings\": [");
+        for (index, mapping) in self.mappings.iter().enumerate() {
+            json.push_str(if index == 0 { "\n" } else { ",\n" });
+            let _ = write!(
+                json,
+                "    {{\"section\": {}, \"address\": {}, \"offset\": {}, \"size\": {}, \"file\": {}, \"line\": {}, \"statement\": {}}}",
+                quoted(&mapping.section), mapping.address, mapping.offset, mapping.size,
+                quoted(&self.file), mapping.line, quoted(&mapping.statement),
+            );
+        }
+        json.push_str(if self.mappings.is_empty() { "]\n" } else { "\n  ]\n" });
+        json.push_str("}\n");
+        json
+    }
+}
+
+/// `text` as a JSON string literal
+fn quoted(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if (c as u32) < 0x20 => { let _ = write!(quoted, "\\u{:04x}", c as u32); },
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,194 @@
+//! Interned names
+//!
+//! Labels, registers and mnemonics recur throughout a program, so each
+//! distinct name is stored once and referred to by a [`Symbol`]: copying one
+//! is free and comparing two is an integer comparison. Reading a symbol's
+//! name, which comparing it with a string does, takes no lock; only
+//! interning a new name does. Interned names live for the rest of the
+//! process.
+
+use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{PoisonError, RwLock};
+use once_cell::sync::{Lazy, OnceCell};
+
+/// An interned name
+///
+/// ```
+/// use nasembler::symbol::Symbol;
+///
+/// let rax = Symbol::intern("rax");
+/// assert_eq!(rax, Symbol::intern("rax"));
+/// assert_eq!(rax, "rax");
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Interner {
+    symbols: HashMap<&'static str, Symbol>,
+    /// Number of symbols handed out, the id of the next one
+    count: u32,
+}
+
+static INTERNER: Lazy<RwLock<Interner>> = Lazy::new(Default::default);
+
+/// Size of the first segment of [`NAMES`]; each one after is twice the
+/// size of the one before
+const FIRST_SEGMENT: usize = 256;
+
+/// Number of segments in [`NAMES`], enough for every `u32` id
+const SEGMENTS: usize = 25;
+
+/// Names by symbol id, readable without the interner's lock. A segment is
+/// allocated when its first name is interned, and a name is stored before
+/// its symbol is handed out, so every symbol finds its name here.
+static NAMES: [OnceCell<Box<[OnceCell<&'static str>]>>; SEGMENTS] = [const { OnceCell::new() }; SEGMENTS];
+
+/// Segment of [`NAMES`] holding the name of symbol `id`, and its index there
+fn slot(id: u32) -> (usize, usize) {
+    let id = id as usize;
+    // Segment k holds ids from FIRST_SEGMENT * (2^k - 1) on
+    let segment = (id / FIRST_SEGMENT + 1).ilog2() as usize;
+    (segment, id - FIRST_SEGMENT * ((1 << segment) - 1))
+}
+
+impl Symbol {
+    /// The symbol for `name`, interning it the first time it is seen
+    pub fn intern(name: &str) -> Symbol {
+        if let Some(&symbol) = INTERNER.read().unwrap_or_else(PoisonError::into_inner).symbols.get(name) {
+            return symbol;
+        }
+
+        let mut interner = INTERNER.write().unwrap_or_else(PoisonError::into_inner);
+        // Another thread may have interned it between the two locks
+        if let Some(&symbol) = interner.symbols.get(name) {
+            return symbol;
+        }
+        let name: &'static str = Box::leak(name.into());
+        let symbol = Symbol(interner.count);
+        let (segment, index) = slot(symbol.0);
+        let names = NAMES[segment].get_or_init(|| (0..FIRST_SEGMENT << segment).map(|_| OnceCell::new()).collect());
+        // Ids are handed out once each, so the slot is empty
+        let _ = names[index].set(name);
+        interner.count += 1;
+        interner.symbols.insert(name, symbol);
+        symbol
+    }
+
+    /// The symbol for `name` if it has been interned, without interning it
+    pub fn lookup(name: &str) -> Option<Symbol> {
+        INTERNER.read().unwrap_or_else(PoisonError::into_inner).symbols.get(name).copied()
+    }
+
+    /// The interned name
+    pub fn as_str(self) -> &'static str {
+        let (segment, index) = slot(self.0);
+        NAMES[segment].get()
+            .and_then(|names| names[index].get())
+            .expect("symbols are only made by interning their name")
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Symbol {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+// Ordered by name rather than by interning order, so sorted output is alphabetical
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self == other {
+            Ordering::Equal
+        } else {
+            self.as_str().cmp(other.as_str())
+        }
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for Symbol {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::intern(&name)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<Symbol> for String {
+    fn from(symbol: Symbol) -> Self {
+        symbol.as_str().to_string()
+    }
+}
+
+// Serialized as the name itself, since ids mean nothing outside this process
+#[cfg(feature = "serde")]
+impl serde::Serialize for Symbol {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Symbol {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        Ok(Symbol::intern(&name))
+    }
+}
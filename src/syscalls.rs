@@ -0,0 +1,157 @@
+//! Linux system call numbers and the constants their arguments use.
+//!
+//! `%use syscalls` at the top of a source makes every name here available to
+//! instructions, data and `equ` expressions, so a program can write
+//! `mov rax, SYS_write` instead of `mov rax, 1`. Only the constants a program
+//! refers to are defined, and a label or `equ` of the same name takes
+//! precedence.
+//!
+//! Syscall numbers depend on the code mode: 64-bit code gets the x86-64
+//! numbers used with `syscall`, 32-bit code the i386 numbers used with
+//! `int 0x80`. The other constants are the same for both.
+//!
+//! ```
+//! use nasembler::syscalls;
+//!
+//! assert_eq!(syscalls::constant("SYS_write", 64), Some(1));
+//! assert_eq!(syscalls::constant("SYS_write", 32), Some(4));
+//! assert_eq!(syscalls::syscall_name(60, 64), Some("exit"));
+//! ```
+
+/// The package name that enables these constants in `%use`
+pub const PACKAGE: &str = "syscalls";
+
+/// x86-64 syscall numbers, without the `SYS_` prefix
+const SYSCALLS_64: &[(&str, i64)] = &[
+    ("read", 0), ("write", 1), ("open", 2), ("close", 3), ("stat", 4), ("fstat", 5),
+    ("lstat", 6), ("poll", 7), ("lseek", 8), ("mmap", 9), ("mprotect", 10), ("munmap", 11),
+    ("brk", 12), ("rt_sigaction", 13), ("rt_sigprocmask", 14), ("rt_sigreturn", 15),
+    ("ioctl", 16), ("pread64", 17), ("pwrite64", 18), ("readv", 19), ("writev", 20),
+    ("access", 21), ("pipe", 22), ("select", 23), ("sched_yield", 24), ("mremap", 25),
+    ("msync", 26), ("mincore", 27), ("madvise", 28), ("dup", 32), ("dup2", 33),
+    ("pause", 34), ("nanosleep", 35), ("alarm", 37), ("getpid", 39), ("sendfile", 40),
+    ("socket", 41), ("connect", 42), ("accept", 43), ("sendto", 44), ("recvfrom", 45),
+    ("sendmsg", 46), ("recvmsg", 47), ("shutdown", 48), ("bind", 49), ("listen", 50),
+    ("getsockname", 51), ("getpeername", 52), ("socketpair", 53), ("setsockopt", 54),
+    ("getsockopt", 55), ("clone", 56), ("fork", 57), ("vfork", 58), ("execve", 59),
+    ("exit", 60), ("wait4", 61), ("kill", 62), ("uname", 63), ("fcntl", 72), ("flock", 73),
+    ("fsync", 74), ("fdatasync", 75), ("truncate", 76), ("ftruncate", 77), ("getdents", 78),
+    ("getcwd", 79), ("chdir", 80), ("fchdir", 81), ("rename", 82), ("mkdir", 83),
+    ("rmdir", 84), ("creat", 85), ("link", 86), ("unlink", 87), ("symlink", 88),
+    ("readlink", 89), ("chmod", 90), ("fchmod", 91), ("chown", 92), ("fchown", 93),
+    ("umask", 95), ("gettimeofday", 96), ("getrlimit", 97), ("getrusage", 98),
+    ("sysinfo", 99), ("times", 100), ("ptrace", 101), ("getuid", 102), ("getgid", 104),
+    ("setuid", 105), ("setgid", 106), ("geteuid", 107), ("getegid", 108), ("setpgid", 109),
+    ("getppid", 110), ("getpgrp", 111), ("setsid", 112), ("pivot_root", 155),
+    ("arch_prctl", 158), ("chroot", 161), ("mount", 165), ("umount2", 166), ("swapon", 167),
+    ("reboot", 169), ("sethostname", 170), ("iopl", 172), ("ioperm", 173), ("init_module", 175),
+    ("delete_module", 176), ("gettid", 186), ("tkill", 200), ("time", 201), ("futex", 202), ("getdents64", 217), ("set_tid_address", 218),
+    ("clock_gettime", 228), ("clock_nanosleep", 230), ("exit_group", 231), ("tgkill", 234),
+    ("kexec_load", 246), ("openat", 257), ("mkdirat", 258), ("unlinkat", 263), ("accept4", 288), ("pipe2", 293),
+    ("getrandom", 318), ("memfd_create", 319), ("execveat", 322), ("clone3", 435),
+];
+
+/// i386 syscall numbers, without the `SYS_` prefix
+const SYSCALLS_32: &[(&str, i64)] = &[
+    ("exit", 1), ("fork", 2), ("read", 3), ("write", 4), ("open", 5), ("close", 6),
+    ("waitpid", 7), ("creat", 8), ("link", 9), ("unlink", 10), ("execve", 11), ("chdir", 12),
+    ("time", 13), ("chmod", 15), ("lseek", 19), ("getpid", 20), ("mount", 21), ("setuid", 23),
+    ("getuid", 24), ("ptrace", 26), ("alarm", 27), ("pause", 29), ("access", 33),
+    ("kill", 37), ("rename", 38), ("mkdir", 39), ("rmdir", 40), ("dup", 41), ("pipe", 42),
+    ("times", 43), ("brk", 45), ("setgid", 46), ("getgid", 47), ("geteuid", 49),
+    ("getegid", 50), ("umount2", 52), ("ioctl", 54), ("fcntl", 55), ("setpgid", 57),
+    ("umask", 60), ("chroot", 61), ("dup2", 63), ("getppid", 64), ("getpgrp", 65),
+    ("setsid", 66), ("sethostname", 74), ("gettimeofday", 78), ("symlink", 83),
+    ("readlink", 85), ("swapon", 87), ("reboot", 88), ("mmap", 90), ("munmap", 91),
+    ("truncate", 92), ("ftruncate", 93), ("fchmod", 94), ("ioperm", 101), ("socketcall", 102),
+    ("stat", 106), ("lstat", 107), ("fstat", 108), ("iopl", 110), ("wait4", 114),
+    ("fsync", 118), ("clone", 120), ("uname", 122), ("mprotect", 125), ("init_module", 128),
+    ("delete_module", 129), ("getdents", 141), ("readv", 145), ("writev", 146),
+    ("sched_yield", 158), ("nanosleep", 162), ("poll", 168), ("getcwd", 183), ("vfork", 190),
+    ("mmap2", 192), ("pivot_root", 217), ("getdents64", 220), ("gettid", 224), ("tkill", 238),
+    ("futex", 240), ("exit_group", 252), ("clock_gettime", 265), ("tgkill", 270),
+    ("kexec_load", 283), ("openat", 295), ("pipe2", 331), ("getrandom", 355),
+    ("memfd_create", 356), ("execveat", 358), ("socket", 359), ("socketpair", 360),
+    ("bind", 361), ("connect", 362), ("listen", 363), ("accept4", 364), ("getsockopt", 365),
+    ("setsockopt", 366), ("getsockname", 367), ("getpeername", 368), ("sendto", 369),
+    ("sendmsg", 370), ("recvfrom", 371), ("recvmsg", 372), ("shutdown", 373), ("clone3", 435),
+];
+
+/// Constants shared by both code modes
+const CONSTANTS: &[(&str, i64)] = &[
+    // Standard file descriptors
+    ("STDIN_FILENO", 0), ("STDOUT_FILENO", 1), ("STDERR_FILENO", 2),
+    // open flags
+    ("O_RDONLY", 0), ("O_WRONLY", 0o1), ("O_RDWR", 0o2), ("O_CREAT", 0o100), ("O_EXCL", 0o200),
+    ("O_NOCTTY", 0o400), ("O_TRUNC", 0o1000), ("O_APPEND", 0o2000), ("O_NONBLOCK", 0o4000),
+    ("O_DSYNC", 0o10000), ("O_SYNC", 0o4010000), ("O_DIRECTORY", 0o200000),
+    ("O_NOFOLLOW", 0o400000), ("O_CLOEXEC", 0o2000000), ("AT_FDCWD", -100), ("AT_REMOVEDIR", 0x200),
+    // File permission bits
+    ("S_IRWXU", 0o700), ("S_IRUSR", 0o400), ("S_IWUSR", 0o200), ("S_IXUSR", 0o100),
+    ("S_IRWXG", 0o70), ("S_IRGRP", 0o40), ("S_IWGRP", 0o20), ("S_IXGRP", 0o10),
+    ("S_IRWXO", 0o7), ("S_IROTH", 0o4), ("S_IWOTH", 0o2), ("S_IXOTH", 0o1),
+    // lseek
+    ("SEEK_SET", 0), ("SEEK_CUR", 1), ("SEEK_END", 2),
+    // mmap and mprotect
+    ("PROT_NONE", 0), ("PROT_READ", 1), ("PROT_WRITE", 2), ("PROT_EXEC", 4),
+    ("MAP_SHARED", 0x1), ("MAP_PRIVATE", 0x2), ("MAP_FIXED", 0x10), ("MAP_ANONYMOUS", 0x20),
+    // Signals
+    ("SIGHUP", 1), ("SIGINT", 2), ("SIGQUIT", 3), ("SIGILL", 4), ("SIGTRAP", 5), ("SIGABRT", 6),
+    ("SIGBUS", 7), ("SIGFPE", 8), ("SIGKILL", 9), ("SIGUSR1", 10), ("SIGSEGV", 11),
+    ("SIGUSR2", 12), ("SIGPIPE", 13), ("SIGALRM", 14), ("SIGTERM", 15), ("SIGCHLD", 17),
+    ("SIGCONT", 18), ("SIGSTOP", 19), ("SIGTSTP", 20),
+    // Sockets
+    ("AF_UNIX", 1), ("AF_INET", 2), ("AF_INET6", 10), ("SOCK_STREAM", 1), ("SOCK_DGRAM", 2),
+    ("SOCK_NONBLOCK", 0o4000), ("SOCK_CLOEXEC", 0o2000000), ("IPPROTO_TCP", 6),
+    ("IPPROTO_UDP", 17), ("SOL_SOCKET", 1), ("SO_REUSEADDR", 2),
+    // Clocks
+    ("CLOCK_REALTIME", 0), ("CLOCK_MONOTONIC", 1),
+    // errno values, which syscalls return negated
+    ("EPERM", 1), ("ENOENT", 2), ("ESRCH", 3), ("EINTR", 4), ("EIO", 5), ("ENXIO", 6),
+    ("E2BIG", 7), ("ENOEXEC", 8), ("EBADF", 9), ("ECHILD", 10), ("EAGAIN", 11),
+    ("EWOULDBLOCK", 11), ("ENOMEM", 12), ("EACCES", 13), ("EFAULT", 14), ("EBUSY", 16),
+    ("EEXIST", 17), ("EXDEV", 18), ("ENODEV", 19), ("ENOTDIR", 20), ("EISDIR", 21),
+    ("EINVAL", 22), ("ENFILE", 23), ("EMFILE", 24), ("ENOTTY", 25), ("ETXTBSY", 26),
+    ("EFBIG", 27), ("ENOSPC", 28), ("ESPIPE", 29), ("EROFS", 30), ("EMLINK", 31),
+    ("EPIPE", 32), ("EDOM", 33), ("ERANGE", 34), ("EDEADLK", 35), ("ENAMETOOLONG", 36),
+    ("ENOLCK", 37), ("ENOSYS", 38), ("ENOTEMPTY", 39), ("ELOOP", 40), ("EOVERFLOW", 75),
+    ("ENOTSOCK", 88), ("EMSGSIZE", 90), ("EPROTONOSUPPORT", 93), ("EOPNOTSUPP", 95),
+    ("EAFNOSUPPORT", 97), ("EADDRINUSE", 98), ("EADDRNOTAVAIL", 99), ("ENETUNREACH", 101),
+    ("ECONNRESET", 104), ("ENOTCONN", 107), ("ETIMEDOUT", 110), ("ECONNREFUSED", 111),
+    ("EHOSTUNREACH", 113), ("EALREADY", 114), ("EINPROGRESS", 115), ("ECANCELED", 125),
+];
+
+fn syscall_table(bits: u16) -> &'static [(&'static str, i64)] {
+    if bits == 64 { SYSCALLS_64 } else { SYSCALLS_32 }
+}
+
+/// Value of the constant `name` in code of the given mode (16, 32 or 64)
+pub fn constant(name: &str, bits: u16) -> Option<i64> {
+    let (table, key) = match name.strip_prefix("SYS_") {
+        Some(syscall) => (syscall_table(bits), syscall),
+        None => (CONSTANTS, name),
+    };
+    table.iter().find(|(entry, _)| *entry == key).map(|&(_, value)| value)
+}
+
+/// Name of syscall `number` in code of the given mode, without the `SYS_` prefix
+pub fn syscall_name(number: i64, bits: u16) -> Option<&'static str> {
+    syscall_table(bits).iter().find(|&&(_, value)| value == number).map(|&(name, _)| name)
+}
+
+/// Every constant name available in code of the given mode
+pub fn names(bits: u16) -> impl Iterator<Item = String> {
+    syscall_table(bits).iter()
+        .map(|(name, _)| format!("SYS_{}", name))
+        .chain(CONSTANTS.iter().map(|(name, _)| name.to_string()))
+}
+
+/// Name of the errno `value`, such as `ENOENT` for 2
+pub fn errno_name(value: i64) -> Option<&'static str> {
+    CONSTANTS.iter().find(|&&(name, number)| name.starts_with('E') && number == value).map(|&(name, _)| name)
+}
+
+/// Name of signal `number`, such as `SIGSEGV` for 11
+pub fn signal_name(number: i64) -> Option<&'static str> {
+    CONSTANTS.iter().find(|&&(name, value)| name.starts_with("SIG") && value == number).map(|&(name, _)| name)
+}
@@ -0,0 +1,95 @@
+//! Compilation targets.
+//!
+//! A target bundles the settings that depend on where the output runs: the
+//! code mode instructions are encoded and checked against, the default output
+//! format and the address the code is loaded at. Selecting one with
+//! [`Assembler::target`](crate::Assembler::target) sets all of them at once;
+//! individual settings such as the format can still be overridden afterwards.
+
+use std::fmt;
+use clap::ValueEnum;
+
+use crate::assembler::Format;
+
+/// What the assembled program runs on
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum Target {
+    /// x86-64 Linux executable (default)
+    #[value(name = "x86_64-linux-elf")]
+    X86_64LinuxElf,
+    /// 32-bit i386 Linux executable
+    #[value(name = "i386-linux-elf")]
+    I386LinuxElf,
+    /// Flat 64-bit binary loaded at address 0
+    #[value(name = "x86_64-none-bin")]
+    X86_64NoneBin,
+    /// Flat 32-bit binary loaded at address 0
+    #[value(name = "i386-none-bin")]
+    I386NoneBin,
+    /// 16-bit BIOS boot sector, loaded at 0x7C00
+    #[value(name = "i8086-bios-bin")]
+    I8086BiosBin,
+    /// 16-bit DOS `.com` program, loaded at 0x100
+    #[value(name = "i8086-dos-com")]
+    I8086DosCom,
+}
+
+impl Target {
+    /// Name used on the command line, e.g. `x86_64-linux-elf`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Target::X86_64LinuxElf => "x86_64-linux-elf",
+            Target::I386LinuxElf => "i386-linux-elf",
+            Target::X86_64NoneBin => "x86_64-none-bin",
+            Target::I386NoneBin => "i386-none-bin",
+            Target::I8086BiosBin => "i8086-bios-bin",
+            Target::I8086DosCom => "i8086-dos-com",
+        }
+    }
+
+    /// Code mode (16, 32 or 64) programs start in. Operands are also checked
+    /// against it, so e.g. 64-bit registers are errors on 32-bit targets.
+    pub fn bits(&self) -> u16 {
+        match self {
+            Target::X86_64LinuxElf | Target::X86_64NoneBin => 64,
+            Target::I386LinuxElf | Target::I386NoneBin => 32,
+            Target::I8086BiosBin | Target::I8086DosCom => 16,
+        }
+    }
+
+    /// Output format used unless one is chosen explicitly
+    pub fn format(&self) -> Format {
+        match self {
+            Target::X86_64LinuxElf => Format::Elf,
+            Target::I386LinuxElf => Format::Elf32,
+            Target::X86_64NoneBin | Target::I386NoneBin | Target::I8086BiosBin | Target::I8086DosCom => Format::Bin,
+        }
+    }
+
+    /// Virtual addresses .text and the data segment are laid out at. Flat
+    /// binaries have no data address of their own: their data follows the code.
+    pub fn addresses(&self) -> (u64, Option<u64>) {
+        match self {
+            Target::X86_64LinuxElf => (0x400000, Some(0x600000)),
+            Target::I386LinuxElf => (0x8048000, Some(0x8248000)),
+            Target::X86_64NoneBin | Target::I386NoneBin => (0, None),
+            Target::I8086BiosBin => (0x7C00, None),
+            Target::I8086DosCom => (0x100, None),
+        }
+    }
+
+    /// File extension of the output when no path is given, if it differs from the format's
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Target::I8086DosCom => Some("com"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -1,10 +1,18 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::fmt;
-use once_cell::sync::Lazy;
+use std::io::{self, BufRead};
+use phf::phf_map;
+use uncased::UncasedStr;
+
+use crate::error::ErrorType;
+use crate::symbol::Symbol;
 
 #[allow(dead_code)]
 /// Different types of tokens that can be recognized in assembly code
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum TokenType {
     // Main categories
     Instruction,    // Assembly instructions (mov, push, add, etc.)
@@ -40,6 +48,19 @@ pub enum TokenType {
     Asterisk,       // Multiplication in address calculations
     OpenBracket,    // Opening brackets for memory references
     CloseBracket,   // Closing brackets for memory references
+    // Constant expression operators
+    OpenParen,      // (
+    CloseParen,     // )
+    ShiftLeft,      // <<
+    ShiftRight,     // >>
+    Ampersand,      // Bitwise and (&)
+    Pipe,           // Bitwise or (|)
+    Caret,          // Bitwise xor (^)
+    Tilde,          // Bitwise not (~)
+    // Preprocessor syntax, recognized but not expanded yet
+    Preprocessor,   // Preprocessor directives and macro parameters (%define, %1)
+    MacroLocal,     // Macro-local labels (%%loop)
+    MacroExpansion, // Braced expansions (%{1}, %{name})
     Whitespace,     // Spaces, tabs, etc.
     NewLine,        // Line breaks
     Unknown,        // Unrecognized tokens
@@ -52,309 +73,693 @@ impl fmt::Display for TokenType {
     }
 }
 
-/// Token struct representing a single token in the assembly code
+/// Byte range `start..end` of a token in the source text
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// Smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+
+    /// Length in bytes
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The source text the span covers, or "" if it doesn't lie within `source`
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        source.get(self.start..self.end).unwrap_or("")
+    }
+}
+
+/// A problem found while tokenizing. Tokenizing carries on past it, so one
+/// bad line doesn't hide the rest of the file.
+#[derive(Debug, Clone)]
+pub struct TokenizerDiagnostic {
+    pub error_type: ErrorType,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,
+    pub help: Option<String>,
+}
+
+/// Warnings silenced by a `; nasembler: allow(name, ...)` comment. After code
+/// it covers that line only; on a line of its own it lasts until a
+/// `; nasembler: warn(name)` comment or the end of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suppression {
+    pub names: Vec<String>,
+    pub first_line: usize,
+    /// Last line covered, inclusive
+    pub last_line: usize,
+    /// Where the comment is
+    pub column: usize,
+}
+
+impl Suppression {
+    /// Whether this silences warning `name` at `line`
+    pub fn covers(&self, name: &str, line: usize) -> bool {
+        (self.first_line..=self.last_line).contains(&line) && self.names.iter().any(|n| n == name)
+    }
+}
+
+/// Token struct representing a single token in the assembly code.
+///
+/// `value` borrows from the source text; only string literals with escape
+/// sequences and tokens built by hand own theirs.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Token {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Token<'src> {
     pub token_type: TokenType,
-    pub value: String,
+    pub value: Cow<'src, str>,
     pub line: usize,
     pub column: usize,
+    /// Where the token is in the source; empty for tokens that weren't read from it
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub span: Span,
 }
 
-impl Token {
+impl<'src> Token<'src> {
     #[inline(always)]
-    pub fn new(token_type: TokenType, value: String, line: usize, column: usize) -> Self {
+    pub fn new(token_type: TokenType, value: impl Into<Cow<'src, str>>, line: usize, column: usize) -> Self {
         Self {
             token_type,
-            value,
+            value: value.into(),
             line,
             column,
+            span: Span::default(),
         }
     }
+
+    /// A copy that owns its text, so it can outlive the source
+    pub fn into_owned(self) -> Token<'static> {
+        Token {
+            token_type: self.token_type,
+            value: Cow::Owned(self.value.into_owned()),
+            line: self.line,
+            column: self.column,
+            span: self.span,
+        }
+    }
+
+    /// The token's text as an interned name, for labels, registers and mnemonics
+    pub fn symbol(&self) -> Symbol {
+        Symbol::intern(&self.value)
+    }
 }
 
-impl fmt::Display for Token {
+impl fmt::Display for Token<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}({})", self.token_type, self.value)
     }
 }
 
-// Static lookup tables for fast token recognition
-static INSTRUCTIONS: Lazy<HashMap<&'static str, (&'static str, TokenType)>> = Lazy::new(|| {
-    let mut map = HashMap::with_capacity(200); // Pre-allocate capacity for better performance
-    
+// Static lookup tables for fast token recognition, built at compile time.
+// Keys are matched case-insensitively, so `MOV RAX` reads like `mov rax`.
+static INSTRUCTIONS: phf::Map<&'static UncasedStr, (&'static str, TokenType)> = phf_map! {
     // Data Movement Instructions
-    map.insert("mov", ("48 B8", TokenType::InstrData));
-    map.insert("movq", ("48 B8", TokenType::InstrData));
-    map.insert("movb", ("88", TokenType::InstrData));
-    map.insert("movw", ("66 89", TokenType::InstrData));
-    map.insert("movl", ("89", TokenType::InstrData));
-    map.insert("movabs", ("48 B8", TokenType::InstrData));
-    map.insert("lea", ("48 8D", TokenType::InstrData));
-    map.insert("push", ("50", TokenType::InstrData));
-    map.insert("pushq", ("50", TokenType::InstrData));
-    map.insert("pop", ("58", TokenType::InstrData));
-    map.insert("popq", ("58", TokenType::InstrData));
-    map.insert("xchg", ("87", TokenType::InstrData));
-    map.insert("cmovz", ("48 0F 44", TokenType::InstrData));
-    map.insert("cmove", ("48 0F 44", TokenType::InstrData));
-    map.insert("cmovne", ("48 0F 45", TokenType::InstrData));
-    
+    UncasedStr::new("mov") => ("48 B8", TokenType::InstrData),
+    UncasedStr::new("movq") => ("48 B8", TokenType::InstrData),
+    UncasedStr::new("movb") => ("88", TokenType::InstrData),
+    UncasedStr::new("movw") => ("66 89", TokenType::InstrData),
+    UncasedStr::new("movl") => ("89", TokenType::InstrData),
+    UncasedStr::new("movabs") => ("48 B8", TokenType::InstrData),
+    UncasedStr::new("lea") => ("48 8D", TokenType::InstrData),
+    UncasedStr::new("movzx") => ("0F B6", TokenType::InstrData),
+    UncasedStr::new("movsx") => ("0F BE", TokenType::InstrData),
+    UncasedStr::new("movsxd") => ("48 63", TokenType::InstrData),
+    UncasedStr::new("push") => ("50", TokenType::InstrData),
+    UncasedStr::new("pushq") => ("50", TokenType::InstrData),
+    UncasedStr::new("pop") => ("58", TokenType::InstrData),
+    UncasedStr::new("popq") => ("58", TokenType::InstrData),
+    UncasedStr::new("xchg") => ("87", TokenType::InstrData),
+    UncasedStr::new("cmovo") => ("48 0F 40", TokenType::InstrData),
+    UncasedStr::new("cmovno") => ("48 0F 41", TokenType::InstrData),
+    UncasedStr::new("cmovb") => ("48 0F 42", TokenType::InstrData),
+    UncasedStr::new("cmovc") => ("48 0F 42", TokenType::InstrData),
+    UncasedStr::new("cmovnae") => ("48 0F 42", TokenType::InstrData),
+    UncasedStr::new("cmovae") => ("48 0F 43", TokenType::InstrData),
+    UncasedStr::new("cmovnb") => ("48 0F 43", TokenType::InstrData),
+    UncasedStr::new("cmovnc") => ("48 0F 43", TokenType::InstrData),
+    UncasedStr::new("cmove") => ("48 0F 44", TokenType::InstrData),
+    UncasedStr::new("cmovz") => ("48 0F 44", TokenType::InstrData),
+    UncasedStr::new("cmovne") => ("48 0F 45", TokenType::InstrData),
+    UncasedStr::new("cmovnz") => ("48 0F 45", TokenType::InstrData),
+    UncasedStr::new("cmovbe") => ("48 0F 46", TokenType::InstrData),
+    UncasedStr::new("cmovna") => ("48 0F 46", TokenType::InstrData),
+    UncasedStr::new("cmova") => ("48 0F 47", TokenType::InstrData),
+    UncasedStr::new("cmovnbe") => ("48 0F 47", TokenType::InstrData),
+    UncasedStr::new("cmovs") => ("48 0F 48", TokenType::InstrData),
+    UncasedStr::new("cmovns") => ("48 0F 49", TokenType::InstrData),
+    UncasedStr::new("cmovp") => ("48 0F 4A", TokenType::InstrData),
+    UncasedStr::new("cmovpe") => ("48 0F 4A", TokenType::InstrData),
+    UncasedStr::new("cmovnp") => ("48 0F 4B", TokenType::InstrData),
+    UncasedStr::new("cmovpo") => ("48 0F 4B", TokenType::InstrData),
+    UncasedStr::new("cmovl") => ("48 0F 4C", TokenType::InstrData),
+    UncasedStr::new("cmovnge") => ("48 0F 4C", TokenType::InstrData),
+    UncasedStr::new("cmovge") => ("48 0F 4D", TokenType::InstrData),
+    UncasedStr::new("cmovnl") => ("48 0F 4D", TokenType::InstrData),
+    UncasedStr::new("cmovle") => ("48 0F 4E", TokenType::InstrData),
+    UncasedStr::new("cmovng") => ("48 0F 4E", TokenType::InstrData),
+    UncasedStr::new("cmovg") => ("48 0F 4F", TokenType::InstrData),
+    UncasedStr::new("cmovnle") => ("48 0F 4F", TokenType::InstrData),
+
     // Arithmetic Instructions
-    map.insert("add", ("48 83 C0", TokenType::InstrArith));
-    map.insert("addq", ("48 83 C0", TokenType::InstrArith));
-    map.insert("sub", ("48 83 E8", TokenType::InstrArith));
-    map.insert("subq", ("48 83 E8", TokenType::InstrArith));
-    map.insert("mul", ("48 F7 E0", TokenType::InstrArith));
-    map.insert("imul", ("48 F7 E8", TokenType::InstrArith));
-    map.insert("div", ("48 F7 F0", TokenType::InstrArith));
-    map.insert("idiv", ("48 F7 F8", TokenType::InstrArith));
-    map.insert("inc", ("48 FF C0", TokenType::InstrArith));
-    map.insert("dec", ("48 FF C8", TokenType::InstrArith));
-    map.insert("neg", ("48 F7 D8", TokenType::InstrArith));
-    
+    UncasedStr::new("add") => ("48 83 C0", TokenType::InstrArith),
+    UncasedStr::new("addq") => ("48 83 C0", TokenType::InstrArith),
+    UncasedStr::new("sub") => ("48 83 E8", TokenType::InstrArith),
+    UncasedStr::new("subq") => ("48 83 E8", TokenType::InstrArith),
+    UncasedStr::new("mul") => ("48 F7 E0", TokenType::InstrArith),
+    UncasedStr::new("imul") => ("48 F7 E8", TokenType::InstrArith),
+    UncasedStr::new("div") => ("48 F7 F0", TokenType::InstrArith),
+    UncasedStr::new("idiv") => ("48 F7 F8", TokenType::InstrArith),
+    UncasedStr::new("inc") => ("48 FF C0", TokenType::InstrArith),
+    UncasedStr::new("dec") => ("48 FF C8", TokenType::InstrArith),
+    UncasedStr::new("neg") => ("48 F7 D8", TokenType::InstrArith),
+
     // Logical Instructions
-    map.insert("and", ("48 83 E0", TokenType::InstrLogic));
-    map.insert("or", ("48 83 C8", TokenType::InstrLogic));
-    map.insert("xor", ("48 83 F0", TokenType::InstrLogic));
-    map.insert("not", ("48 F7 D0", TokenType::InstrLogic));
-    map.insert("shl", ("48 C1 E0", TokenType::InstrLogic));
-    map.insert("shr", ("48 C1 E8", TokenType::InstrLogic));
-    map.insert("sal", ("48 C1 E0", TokenType::InstrLogic));
-    map.insert("sar", ("48 C1 F8", TokenType::InstrLogic));
-    map.insert("rol", ("48 C1 C0", TokenType::InstrLogic));
-    map.insert("ror", ("48 C1 C8", TokenType::InstrLogic));
-    map.insert("test", ("48 85", TokenType::InstrLogic));
-    map.insert("cmp", ("48 39", TokenType::InstrLogic));
-    
+    UncasedStr::new("and") => ("48 83 E0", TokenType::InstrLogic),
+    UncasedStr::new("or") => ("48 83 C8", TokenType::InstrLogic),
+    UncasedStr::new("xor") => ("48 83 F0", TokenType::InstrLogic),
+    UncasedStr::new("not") => ("48 F7 D0", TokenType::InstrLogic),
+    UncasedStr::new("shl") => ("48 C1 E0", TokenType::InstrLogic),
+    UncasedStr::new("shr") => ("48 C1 E8", TokenType::InstrLogic),
+    UncasedStr::new("sal") => ("48 C1 E0", TokenType::InstrLogic),
+    UncasedStr::new("sar") => ("48 C1 F8", TokenType::InstrLogic),
+    UncasedStr::new("rol") => ("48 C1 C0", TokenType::InstrLogic),
+    UncasedStr::new("ror") => ("48 C1 C8", TokenType::InstrLogic),
+    UncasedStr::new("test") => ("48 85", TokenType::InstrLogic),
+    UncasedStr::new("cmp") => ("48 39", TokenType::InstrLogic),
+
+    // Conditional Set Instructions
+    UncasedStr::new("seto") => ("0F 90", TokenType::InstrLogic),
+    UncasedStr::new("setno") => ("0F 91", TokenType::InstrLogic),
+    UncasedStr::new("setb") => ("0F 92", TokenType::InstrLogic),
+    UncasedStr::new("setc") => ("0F 92", TokenType::InstrLogic),
+    UncasedStr::new("setnae") => ("0F 92", TokenType::InstrLogic),
+    UncasedStr::new("setae") => ("0F 93", TokenType::InstrLogic),
+    UncasedStr::new("setnb") => ("0F 93", TokenType::InstrLogic),
+    UncasedStr::new("setnc") => ("0F 93", TokenType::InstrLogic),
+    UncasedStr::new("sete") => ("0F 94", TokenType::InstrLogic),
+    UncasedStr::new("setz") => ("0F 94", TokenType::InstrLogic),
+    UncasedStr::new("setne") => ("0F 95", TokenType::InstrLogic),
+    UncasedStr::new("setnz") => ("0F 95", TokenType::InstrLogic),
+    UncasedStr::new("setbe") => ("0F 96", TokenType::InstrLogic),
+    UncasedStr::new("setna") => ("0F 96", TokenType::InstrLogic),
+    UncasedStr::new("seta") => ("0F 97", TokenType::InstrLogic),
+    UncasedStr::new("setnbe") => ("0F 97", TokenType::InstrLogic),
+    UncasedStr::new("sets") => ("0F 98", TokenType::InstrLogic),
+    UncasedStr::new("setns") => ("0F 99", TokenType::InstrLogic),
+    UncasedStr::new("setp") => ("0F 9A", TokenType::InstrLogic),
+    UncasedStr::new("setpe") => ("0F 9A", TokenType::InstrLogic),
+    UncasedStr::new("setnp") => ("0F 9B", TokenType::InstrLogic),
+    UncasedStr::new("setpo") => ("0F 9B", TokenType::InstrLogic),
+    UncasedStr::new("setl") => ("0F 9C", TokenType::InstrLogic),
+    UncasedStr::new("setnge") => ("0F 9C", TokenType::InstrLogic),
+    UncasedStr::new("setge") => ("0F 9D", TokenType::InstrLogic),
+    UncasedStr::new("setnl") => ("0F 9D", TokenType::InstrLogic),
+    UncasedStr::new("setle") => ("0F 9E", TokenType::InstrLogic),
+    UncasedStr::new("setng") => ("0F 9E", TokenType::InstrLogic),
+    UncasedStr::new("setg") => ("0F 9F", TokenType::InstrLogic),
+    UncasedStr::new("setnle") => ("0F 9F", TokenType::InstrLogic),
+
     // Control Flow Instructions
-    map.insert("jmp", ("E9", TokenType::InstrJump));
-    map.insert("je", ("74", TokenType::InstrJump));
-    map.insert("jz", ("74", TokenType::InstrJump));
-    map.insert("jne", ("75", TokenType::InstrJump));
-    map.insert("jnz", ("75", TokenType::InstrJump));
-    map.insert("jg", ("7F", TokenType::InstrJump));
-    map.insert("jge", ("7D", TokenType::InstrJump));
-    map.insert("jl", ("7C", TokenType::InstrJump));
-    map.insert("jle", ("7E", TokenType::InstrJump));
-    map.insert("ja", ("77", TokenType::InstrJump));
-    map.insert("jae", ("73", TokenType::InstrJump));
-    map.insert("jb", ("72", TokenType::InstrJump));
-    map.insert("jbe", ("76", TokenType::InstrJump));
-    map.insert("call", ("E8", TokenType::InstrJump));
-    map.insert("ret", ("C3", TokenType::InstrJump));
-    map.insert("syscall", ("0F 05", TokenType::InstrJump));
-    
+    UncasedStr::new("jmp") => ("E9", TokenType::InstrJump),
+    UncasedStr::new("jo") => ("70", TokenType::InstrJump),
+    UncasedStr::new("jno") => ("71", TokenType::InstrJump),
+    UncasedStr::new("jb") => ("72", TokenType::InstrJump),
+    UncasedStr::new("jc") => ("72", TokenType::InstrJump),
+    UncasedStr::new("jnae") => ("72", TokenType::InstrJump),
+    UncasedStr::new("jae") => ("73", TokenType::InstrJump),
+    UncasedStr::new("jnb") => ("73", TokenType::InstrJump),
+    UncasedStr::new("jnc") => ("73", TokenType::InstrJump),
+    UncasedStr::new("je") => ("74", TokenType::InstrJump),
+    UncasedStr::new("jz") => ("74", TokenType::InstrJump),
+    UncasedStr::new("jne") => ("75", TokenType::InstrJump),
+    UncasedStr::new("jnz") => ("75", TokenType::InstrJump),
+    UncasedStr::new("jbe") => ("76", TokenType::InstrJump),
+    UncasedStr::new("jna") => ("76", TokenType::InstrJump),
+    UncasedStr::new("ja") => ("77", TokenType::InstrJump),
+    UncasedStr::new("jnbe") => ("77", TokenType::InstrJump),
+    UncasedStr::new("js") => ("78", TokenType::InstrJump),
+    UncasedStr::new("jns") => ("79", TokenType::InstrJump),
+    UncasedStr::new("jp") => ("7A", TokenType::InstrJump),
+    UncasedStr::new("jpe") => ("7A", TokenType::InstrJump),
+    UncasedStr::new("jnp") => ("7B", TokenType::InstrJump),
+    UncasedStr::new("jpo") => ("7B", TokenType::InstrJump),
+    UncasedStr::new("jl") => ("7C", TokenType::InstrJump),
+    UncasedStr::new("jnge") => ("7C", TokenType::InstrJump),
+    UncasedStr::new("jge") => ("7D", TokenType::InstrJump),
+    UncasedStr::new("jnl") => ("7D", TokenType::InstrJump),
+    UncasedStr::new("jle") => ("7E", TokenType::InstrJump),
+    UncasedStr::new("jng") => ("7E", TokenType::InstrJump),
+    UncasedStr::new("jg") => ("7F", TokenType::InstrJump),
+    UncasedStr::new("jnle") => ("7F", TokenType::InstrJump),
+    UncasedStr::new("call") => ("E8", TokenType::InstrJump),
+    UncasedStr::new("ret") => ("C3", TokenType::InstrJump),
+    UncasedStr::new("syscall") => ("0F 05", TokenType::InstrJump),
+    UncasedStr::new("int") => ("CD", TokenType::InstrJump),
+
+    // Other Instructions
+    UncasedStr::new("nop") => ("90", TokenType::Instruction),
+
     // SIMD Instructions
-    map.insert("movdqa", ("66 0F 6F", TokenType::InstrSIMD));
-    map.insert("movdqu", ("F3 0F 6F", TokenType::InstrSIMD));
-    map.insert("movaps", ("0F 28", TokenType::InstrSIMD));
-    map.insert("movups", ("0F 10", TokenType::InstrSIMD));
-    map.insert("movss", ("F3 0F 10", TokenType::InstrSIMD));
-    map.insert("movsd", ("F2 0F 10", TokenType::InstrSIMD));
-    map.insert("paddb", ("66 0F FC", TokenType::InstrSIMD));
-    map.insert("paddw", ("66 0F FD", TokenType::InstrSIMD));
-    map.insert("paddd", ("66 0F FE", TokenType::InstrSIMD));
-    map.insert("paddq", ("66 0F D4", TokenType::InstrSIMD));
-    map.insert("psubb", ("66 0F F8", TokenType::InstrSIMD));
-    map.insert("psubw", ("66 0F F9", TokenType::InstrSIMD));
-    map.insert("psubd", ("66 0F FA", TokenType::InstrSIMD));
-    map.insert("psubq", ("66 0F FB", TokenType::InstrSIMD));
-    map.insert("pand", ("66 0F DB", TokenType::InstrSIMD));
-    map.insert("por", ("66 0F EB", TokenType::InstrSIMD));
-    map.insert("pxor", ("66 0F EF", TokenType::InstrSIMD));
-    
+    UncasedStr::new("movdqa") => ("66 0F 6F", TokenType::InstrSIMD),
+    UncasedStr::new("movdqu") => ("F3 0F 6F", TokenType::InstrSIMD),
+    UncasedStr::new("movaps") => ("0F 28", TokenType::InstrSIMD),
+    UncasedStr::new("movups") => ("0F 10", TokenType::InstrSIMD),
+    UncasedStr::new("movss") => ("F3 0F 10", TokenType::InstrSIMD),
+    UncasedStr::new("movsd") => ("F2 0F 10", TokenType::InstrSIMD),
+    UncasedStr::new("paddb") => ("66 0F FC", TokenType::InstrSIMD),
+    UncasedStr::new("paddw") => ("66 0F FD", TokenType::InstrSIMD),
+    UncasedStr::new("paddd") => ("66 0F FE", TokenType::InstrSIMD),
+    UncasedStr::new("paddq") => ("66 0F D4", TokenType::InstrSIMD),
+    UncasedStr::new("psubb") => ("66 0F F8", TokenType::InstrSIMD),
+    UncasedStr::new("psubw") => ("66 0F F9", TokenType::InstrSIMD),
+    UncasedStr::new("psubd") => ("66 0F FA", TokenType::InstrSIMD),
+    UncasedStr::new("psubq") => ("66 0F FB", TokenType::InstrSIMD),
+    UncasedStr::new("pand") => ("66 0F DB", TokenType::InstrSIMD),
+    UncasedStr::new("por") => ("66 0F EB", TokenType::InstrSIMD),
+    UncasedStr::new("pxor") => ("66 0F EF", TokenType::InstrSIMD),
+
     // AVX Instructions
-    map.insert("vmovdqa", ("C5 F9 6F", TokenType::InstrSIMD));
-    map.insert("vmovdqu", ("C5 FA 6F", TokenType::InstrSIMD));
-    map.insert("vmovaps", ("C5 F8 28", TokenType::InstrSIMD));
-    map.insert("vmovups", ("C5 F8 10", TokenType::InstrSIMD));
-    map.insert("vpaddb", ("C5 F9 FC", TokenType::InstrSIMD));
-    map.insert("vpaddw", ("C5 F9 FD", TokenType::InstrSIMD));
-    map.insert("vpaddd", ("C5 F9 FE", TokenType::InstrSIMD));
-    map.insert("vpaddq", ("C5 F9 D4", TokenType::InstrSIMD));
-    
-    map
-});
+    UncasedStr::new("vmovdqa") => ("C5 F9 6F", TokenType::InstrSIMD),
+    UncasedStr::new("vmovdqu") => ("C5 FA 6F", TokenType::InstrSIMD),
+    UncasedStr::new("vmovaps") => ("C5 F8 28", TokenType::InstrSIMD),
+    UncasedStr::new("vmovups") => ("C5 F8 10", TokenType::InstrSIMD),
+    UncasedStr::new("vpaddb") => ("C5 F9 FC", TokenType::InstrSIMD),
+    UncasedStr::new("vpaddw") => ("C5 F9 FD", TokenType::InstrSIMD),
+    UncasedStr::new("vpaddd") => ("C5 F9 FE", TokenType::InstrSIMD),
+    UncasedStr::new("vpaddq") => ("C5 F9 D4", TokenType::InstrSIMD),
+};
 
-static REGISTERS: Lazy<HashMap<String, TokenType>> = Lazy::new(|| {
-    let mut map = HashMap::with_capacity(100);
-    
+static REGISTERS: phf::Map<&'static UncasedStr, TokenType> = phf_map! {
     // 64-bit registers
-    map.insert("rax".to_string(), TokenType::Reg64Bit);
-    map.insert("rbx".to_string(), TokenType::Reg64Bit);
-    map.insert("rcx".to_string(), TokenType::Reg64Bit);
-    map.insert("rdx".to_string(), TokenType::Reg64Bit);
-    map.insert("rsi".to_string(), TokenType::Reg64Bit);
-    map.insert("rdi".to_string(), TokenType::Reg64Bit);
-    map.insert("rbp".to_string(), TokenType::Reg64Bit);
-    map.insert("rsp".to_string(), TokenType::Reg64Bit);
-    map.insert("r8".to_string(), TokenType::Reg64Bit);
-    map.insert("r9".to_string(), TokenType::Reg64Bit);
-    map.insert("r10".to_string(), TokenType::Reg64Bit);
-    map.insert("r11".to_string(), TokenType::Reg64Bit);
-    map.insert("r12".to_string(), TokenType::Reg64Bit);
-    map.insert("r13".to_string(), TokenType::Reg64Bit);
-    map.insert("r14".to_string(), TokenType::Reg64Bit);
-    map.insert("r15".to_string(), TokenType::Reg64Bit);
-    
+    UncasedStr::new("rax") => TokenType::Reg64Bit,
+    UncasedStr::new("rbx") => TokenType::Reg64Bit,
+    UncasedStr::new("rcx") => TokenType::Reg64Bit,
+    UncasedStr::new("rdx") => TokenType::Reg64Bit,
+    UncasedStr::new("rsi") => TokenType::Reg64Bit,
+    UncasedStr::new("rdi") => TokenType::Reg64Bit,
+    UncasedStr::new("rbp") => TokenType::Reg64Bit,
+    UncasedStr::new("rsp") => TokenType::Reg64Bit,
+    UncasedStr::new("r8") => TokenType::Reg64Bit,
+    UncasedStr::new("r9") => TokenType::Reg64Bit,
+    UncasedStr::new("r10") => TokenType::Reg64Bit,
+    UncasedStr::new("r11") => TokenType::Reg64Bit,
+    UncasedStr::new("r12") => TokenType::Reg64Bit,
+    UncasedStr::new("r13") => TokenType::Reg64Bit,
+    UncasedStr::new("r14") => TokenType::Reg64Bit,
+    UncasedStr::new("r15") => TokenType::Reg64Bit,
+
     // 32-bit registers
-    map.insert("eax".to_string(), TokenType::Reg32Bit);
-    map.insert("ebx".to_string(), TokenType::Reg32Bit);
-    map.insert("ecx".to_string(), TokenType::Reg32Bit);
-    map.insert("edx".to_string(), TokenType::Reg32Bit);
-    map.insert("esi".to_string(), TokenType::Reg32Bit);
-    map.insert("edi".to_string(), TokenType::Reg32Bit);
-    map.insert("ebp".to_string(), TokenType::Reg32Bit);
-    map.insert("esp".to_string(), TokenType::Reg32Bit);
-    map.insert("r8d".to_string(), TokenType::Reg32Bit);
-    map.insert("r9d".to_string(), TokenType::Reg32Bit);
-    map.insert("r10d".to_string(), TokenType::Reg32Bit);
-    map.insert("r11d".to_string(), TokenType::Reg32Bit);
-    map.insert("r12d".to_string(), TokenType::Reg32Bit);
-    map.insert("r13d".to_string(), TokenType::Reg32Bit);
-    map.insert("r14d".to_string(), TokenType::Reg32Bit);
-    map.insert("r15d".to_string(), TokenType::Reg32Bit);
-    
+    UncasedStr::new("eax") => TokenType::Reg32Bit,
+    UncasedStr::new("ebx") => TokenType::Reg32Bit,
+    UncasedStr::new("ecx") => TokenType::Reg32Bit,
+    UncasedStr::new("edx") => TokenType::Reg32Bit,
+    UncasedStr::new("esi") => TokenType::Reg32Bit,
+    UncasedStr::new("edi") => TokenType::Reg32Bit,
+    UncasedStr::new("ebp") => TokenType::Reg32Bit,
+    UncasedStr::new("esp") => TokenType::Reg32Bit,
+    UncasedStr::new("r8d") => TokenType::Reg32Bit,
+    UncasedStr::new("r9d") => TokenType::Reg32Bit,
+    UncasedStr::new("r10d") => TokenType::Reg32Bit,
+    UncasedStr::new("r11d") => TokenType::Reg32Bit,
+    UncasedStr::new("r12d") => TokenType::Reg32Bit,
+    UncasedStr::new("r13d") => TokenType::Reg32Bit,
+    UncasedStr::new("r14d") => TokenType::Reg32Bit,
+    UncasedStr::new("r15d") => TokenType::Reg32Bit,
+
     // 16-bit registers
-    map.insert("ax".to_string(), TokenType::Reg16Bit);
-    map.insert("bx".to_string(), TokenType::Reg16Bit);
-    map.insert("cx".to_string(), TokenType::Reg16Bit);
-    map.insert("dx".to_string(), TokenType::Reg16Bit);
-    map.insert("si".to_string(), TokenType::Reg16Bit);
-    map.insert("di".to_string(), TokenType::Reg16Bit);
-    map.insert("bp".to_string(), TokenType::Reg16Bit);
-    map.insert("sp".to_string(), TokenType::Reg16Bit);
-    map.insert("r8w".to_string(), TokenType::Reg16Bit);
-    map.insert("r9w".to_string(), TokenType::Reg16Bit);
-    map.insert("r10w".to_string(), TokenType::Reg16Bit);
-    map.insert("r11w".to_string(), TokenType::Reg16Bit);
-    map.insert("r12w".to_string(), TokenType::Reg16Bit);
-    map.insert("r13w".to_string(), TokenType::Reg16Bit);
-    map.insert("r14w".to_string(), TokenType::Reg16Bit);
-    map.insert("r15w".to_string(), TokenType::Reg16Bit);
-    
+    UncasedStr::new("ax") => TokenType::Reg16Bit,
+    UncasedStr::new("bx") => TokenType::Reg16Bit,
+    UncasedStr::new("cx") => TokenType::Reg16Bit,
+    UncasedStr::new("dx") => TokenType::Reg16Bit,
+    UncasedStr::new("si") => TokenType::Reg16Bit,
+    UncasedStr::new("di") => TokenType::Reg16Bit,
+    UncasedStr::new("bp") => TokenType::Reg16Bit,
+    UncasedStr::new("sp") => TokenType::Reg16Bit,
+    UncasedStr::new("r8w") => TokenType::Reg16Bit,
+    UncasedStr::new("r9w") => TokenType::Reg16Bit,
+    UncasedStr::new("r10w") => TokenType::Reg16Bit,
+    UncasedStr::new("r11w") => TokenType::Reg16Bit,
+    UncasedStr::new("r12w") => TokenType::Reg16Bit,
+    UncasedStr::new("r13w") => TokenType::Reg16Bit,
+    UncasedStr::new("r14w") => TokenType::Reg16Bit,
+    UncasedStr::new("r15w") => TokenType::Reg16Bit,
+
     // 8-bit registers
-    map.insert("al".to_string(), TokenType::Reg8Bit);
-    map.insert("bl".to_string(), TokenType::Reg8Bit);
-    map.insert("cl".to_string(), TokenType::Reg8Bit);
-    map.insert("dl".to_string(), TokenType::Reg8Bit);
-    map.insert("ah".to_string(), TokenType::Reg8Bit);
-    map.insert("bh".to_string(), TokenType::Reg8Bit);
-    map.insert("ch".to_string(), TokenType::Reg8Bit);
-    map.insert("dh".to_string(), TokenType::Reg8Bit);
-    map.insert("sil".to_string(), TokenType::Reg8Bit);
-    map.insert("dil".to_string(), TokenType::Reg8Bit);
-    map.insert("bpl".to_string(), TokenType::Reg8Bit);
-    map.insert("spl".to_string(), TokenType::Reg8Bit);
-    map.insert("r8b".to_string(), TokenType::Reg8Bit);
-    map.insert("r9b".to_string(), TokenType::Reg8Bit);
-    map.insert("r10b".to_string(), TokenType::Reg8Bit);
-    map.insert("r11b".to_string(), TokenType::Reg8Bit);
-    map.insert("r12b".to_string(), TokenType::Reg8Bit);
-    map.insert("r13b".to_string(), TokenType::Reg8Bit);
-    map.insert("r14b".to_string(), TokenType::Reg8Bit);
-    map.insert("r15b".to_string(), TokenType::Reg8Bit);
-    
+    UncasedStr::new("al") => TokenType::Reg8Bit,
+    UncasedStr::new("bl") => TokenType::Reg8Bit,
+    UncasedStr::new("cl") => TokenType::Reg8Bit,
+    UncasedStr::new("dl") => TokenType::Reg8Bit,
+    UncasedStr::new("ah") => TokenType::Reg8Bit,
+    UncasedStr::new("bh") => TokenType::Reg8Bit,
+    UncasedStr::new("ch") => TokenType::Reg8Bit,
+    UncasedStr::new("dh") => TokenType::Reg8Bit,
+    UncasedStr::new("sil") => TokenType::Reg8Bit,
+    UncasedStr::new("dil") => TokenType::Reg8Bit,
+    UncasedStr::new("bpl") => TokenType::Reg8Bit,
+    UncasedStr::new("spl") => TokenType::Reg8Bit,
+    UncasedStr::new("r8b") => TokenType::Reg8Bit,
+    UncasedStr::new("r9b") => TokenType::Reg8Bit,
+    UncasedStr::new("r10b") => TokenType::Reg8Bit,
+    UncasedStr::new("r11b") => TokenType::Reg8Bit,
+    UncasedStr::new("r12b") => TokenType::Reg8Bit,
+    UncasedStr::new("r13b") => TokenType::Reg8Bit,
+    UncasedStr::new("r14b") => TokenType::Reg8Bit,
+    UncasedStr::new("r15b") => TokenType::Reg8Bit,
+
     // SIMD registers
-    for i in 0..32 {
-        map.insert(format!("xmm{}", i), TokenType::RegXMM);
-        map.insert(format!("ymm{}", i), TokenType::RegYMM);
-        map.insert(format!("zmm{}", i), TokenType::RegZMM);
-    }
-    
+    UncasedStr::new("xmm0") => TokenType::RegXMM,
+    UncasedStr::new("xmm1") => TokenType::RegXMM,
+    UncasedStr::new("xmm2") => TokenType::RegXMM,
+    UncasedStr::new("xmm3") => TokenType::RegXMM,
+    UncasedStr::new("xmm4") => TokenType::RegXMM,
+    UncasedStr::new("xmm5") => TokenType::RegXMM,
+    UncasedStr::new("xmm6") => TokenType::RegXMM,
+    UncasedStr::new("xmm7") => TokenType::RegXMM,
+    UncasedStr::new("xmm8") => TokenType::RegXMM,
+    UncasedStr::new("xmm9") => TokenType::RegXMM,
+    UncasedStr::new("xmm10") => TokenType::RegXMM,
+    UncasedStr::new("xmm11") => TokenType::RegXMM,
+    UncasedStr::new("xmm12") => TokenType::RegXMM,
+    UncasedStr::new("xmm13") => TokenType::RegXMM,
+    UncasedStr::new("xmm14") => TokenType::RegXMM,
+    UncasedStr::new("xmm15") => TokenType::RegXMM,
+    UncasedStr::new("xmm16") => TokenType::RegXMM,
+    UncasedStr::new("xmm17") => TokenType::RegXMM,
+    UncasedStr::new("xmm18") => TokenType::RegXMM,
+    UncasedStr::new("xmm19") => TokenType::RegXMM,
+    UncasedStr::new("xmm20") => TokenType::RegXMM,
+    UncasedStr::new("xmm21") => TokenType::RegXMM,
+    UncasedStr::new("xmm22") => TokenType::RegXMM,
+    UncasedStr::new("xmm23") => TokenType::RegXMM,
+    UncasedStr::new("xmm24") => TokenType::RegXMM,
+    UncasedStr::new("xmm25") => TokenType::RegXMM,
+    UncasedStr::new("xmm26") => TokenType::RegXMM,
+    UncasedStr::new("xmm27") => TokenType::RegXMM,
+    UncasedStr::new("xmm28") => TokenType::RegXMM,
+    UncasedStr::new("xmm29") => TokenType::RegXMM,
+    UncasedStr::new("xmm30") => TokenType::RegXMM,
+    UncasedStr::new("xmm31") => TokenType::RegXMM,
+    UncasedStr::new("ymm0") => TokenType::RegYMM,
+    UncasedStr::new("ymm1") => TokenType::RegYMM,
+    UncasedStr::new("ymm2") => TokenType::RegYMM,
+    UncasedStr::new("ymm3") => TokenType::RegYMM,
+    UncasedStr::new("ymm4") => TokenType::RegYMM,
+    UncasedStr::new("ymm5") => TokenType::RegYMM,
+    UncasedStr::new("ymm6") => TokenType::RegYMM,
+    UncasedStr::new("ymm7") => TokenType::RegYMM,
+    UncasedStr::new("ymm8") => TokenType::RegYMM,
+    UncasedStr::new("ymm9") => TokenType::RegYMM,
+    UncasedStr::new("ymm10") => TokenType::RegYMM,
+    UncasedStr::new("ymm11") => TokenType::RegYMM,
+    UncasedStr::new("ymm12") => TokenType::RegYMM,
+    UncasedStr::new("ymm13") => TokenType::RegYMM,
+    UncasedStr::new("ymm14") => TokenType::RegYMM,
+    UncasedStr::new("ymm15") => TokenType::RegYMM,
+    UncasedStr::new("ymm16") => TokenType::RegYMM,
+    UncasedStr::new("ymm17") => TokenType::RegYMM,
+    UncasedStr::new("ymm18") => TokenType::RegYMM,
+    UncasedStr::new("ymm19") => TokenType::RegYMM,
+    UncasedStr::new("ymm20") => TokenType::RegYMM,
+    UncasedStr::new("ymm21") => TokenType::RegYMM,
+    UncasedStr::new("ymm22") => TokenType::RegYMM,
+    UncasedStr::new("ymm23") => TokenType::RegYMM,
+    UncasedStr::new("ymm24") => TokenType::RegYMM,
+    UncasedStr::new("ymm25") => TokenType::RegYMM,
+    UncasedStr::new("ymm26") => TokenType::RegYMM,
+    UncasedStr::new("ymm27") => TokenType::RegYMM,
+    UncasedStr::new("ymm28") => TokenType::RegYMM,
+    UncasedStr::new("ymm29") => TokenType::RegYMM,
+    UncasedStr::new("ymm30") => TokenType::RegYMM,
+    UncasedStr::new("ymm31") => TokenType::RegYMM,
+    UncasedStr::new("zmm0") => TokenType::RegZMM,
+    UncasedStr::new("zmm1") => TokenType::RegZMM,
+    UncasedStr::new("zmm2") => TokenType::RegZMM,
+    UncasedStr::new("zmm3") => TokenType::RegZMM,
+    UncasedStr::new("zmm4") => TokenType::RegZMM,
+    UncasedStr::new("zmm5") => TokenType::RegZMM,
+    UncasedStr::new("zmm6") => TokenType::RegZMM,
+    UncasedStr::new("zmm7") => TokenType::RegZMM,
+    UncasedStr::new("zmm8") => TokenType::RegZMM,
+    UncasedStr::new("zmm9") => TokenType::RegZMM,
+    UncasedStr::new("zmm10") => TokenType::RegZMM,
+    UncasedStr::new("zmm11") => TokenType::RegZMM,
+    UncasedStr::new("zmm12") => TokenType::RegZMM,
+    UncasedStr::new("zmm13") => TokenType::RegZMM,
+    UncasedStr::new("zmm14") => TokenType::RegZMM,
+    UncasedStr::new("zmm15") => TokenType::RegZMM,
+    UncasedStr::new("zmm16") => TokenType::RegZMM,
+    UncasedStr::new("zmm17") => TokenType::RegZMM,
+    UncasedStr::new("zmm18") => TokenType::RegZMM,
+    UncasedStr::new("zmm19") => TokenType::RegZMM,
+    UncasedStr::new("zmm20") => TokenType::RegZMM,
+    UncasedStr::new("zmm21") => TokenType::RegZMM,
+    UncasedStr::new("zmm22") => TokenType::RegZMM,
+    UncasedStr::new("zmm23") => TokenType::RegZMM,
+    UncasedStr::new("zmm24") => TokenType::RegZMM,
+    UncasedStr::new("zmm25") => TokenType::RegZMM,
+    UncasedStr::new("zmm26") => TokenType::RegZMM,
+    UncasedStr::new("zmm27") => TokenType::RegZMM,
+    UncasedStr::new("zmm28") => TokenType::RegZMM,
+    UncasedStr::new("zmm29") => TokenType::RegZMM,
+    UncasedStr::new("zmm30") => TokenType::RegZMM,
+    UncasedStr::new("zmm31") => TokenType::RegZMM,
+
     // Special registers
-    map.insert("rip".to_string(), TokenType::RegSpecial);
-    map.insert("rflags".to_string(), TokenType::RegSpecial);
-    map.insert("eflags".to_string(), TokenType::RegSpecial);
-    map.insert("flags".to_string(), TokenType::RegSpecial);
-    
-    map
-});
-
-static DIRECTIVES: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
-    let mut map = HashMap::new();
-    map.insert("section", "section");
-    map.insert("segment", "segment");
-    map.insert("global", "global");
-    map.insert("extern", "extern");
-    map.insert("db", "db");
-    map.insert("dw", "dw");
-    map.insert("dd", "dd");
-    map.insert("dq", "dq");
-    map.insert("dt", "dt");
-    map.insert("equ", "equ");
-    map.insert("times", "times");
-    map.insert("align", "align");
-    map.insert("default", "default");
-    map.insert("rel", "rel");
-    map.insert("abs", "abs");
-    map.insert("org", "org");
-    map.insert("bits", "bits");
-    map.insert("use16", "use16");
-    map.insert("use32", "use32");
-    map.insert("use64", "use64");
-    map
-});
+    UncasedStr::new("rip") => TokenType::RegSpecial,
+    UncasedStr::new("rflags") => TokenType::RegSpecial,
+    UncasedStr::new("eflags") => TokenType::RegSpecial,
+    UncasedStr::new("flags") => TokenType::RegSpecial,
+};
+
+static DIRECTIVES: phf::Map<&'static UncasedStr, &'static str> = phf_map! {
+    UncasedStr::new("section") => "section",
+    UncasedStr::new("segment") => "segment",
+    UncasedStr::new("global") => "global",
+    UncasedStr::new("extern") => "extern",
+    UncasedStr::new("db") => "db",
+    UncasedStr::new("dw") => "dw",
+    UncasedStr::new("dd") => "dd",
+    UncasedStr::new("dq") => "dq",
+    UncasedStr::new("dt") => "dt",
+    UncasedStr::new("equ") => "equ",
+    UncasedStr::new("times") => "times",
+    UncasedStr::new("resb") => "resb",
+    UncasedStr::new("resw") => "resw",
+    UncasedStr::new("resd") => "resd",
+    UncasedStr::new("resq") => "resq",
+    UncasedStr::new("align") => "align",
+    UncasedStr::new("default") => "default",
+    UncasedStr::new("rel") => "rel",
+    UncasedStr::new("abs") => "abs",
+    UncasedStr::new("org") => "org",
+    UncasedStr::new("bits") => "bits",
+    UncasedStr::new("use16") => "use16",
+    UncasedStr::new("use32") => "use32",
+    UncasedStr::new("use64") => "use64",
+};
+
+/// Token type of a register name (`Reg64Bit`, `RegXMM`, ...), or None if it isn't one
+pub fn register_type(name: &str) -> Option<TokenType> {
+    REGISTERS.get(UncasedStr::new(name)).cloned()
+}
+
+/// Every instruction mnemonic the tokenizer recognizes
+pub(crate) fn instruction_names<'a>() -> impl Iterator<Item = &'a str> {
+    INSTRUCTIONS.keys().map(|name| name.as_str())
+}
+
+/// Every register name the tokenizer recognizes
+pub(crate) fn register_names<'a>() -> impl Iterator<Item = &'a str> {
+    REGISTERS.keys().map(|name| name.as_str())
+}
+
+/// Every directive name the tokenizer recognizes
+pub(crate) fn directive_names<'a>() -> impl Iterator<Item = &'a str> {
+    DIRECTIVES.keys().map(|name| name.as_str())
+}
+
+/// Whether `ch` can start an identifier. As in NASM, a leading `.` marks a
+/// local label and `..` a special symbol such as `..start` or `..@name`.
+pub fn is_identifier_start(ch: char) -> bool {
+    ch.is_alphabetic() || matches!(ch, '_' | '.' | '?')
+}
+
+/// Whether `ch` can appear in an identifier after its first character
+pub fn is_identifier_char(ch: char) -> bool {
+    ch.is_alphanumeric() || matches!(ch, '_' | '.' | '$' | '#' | '@' | '~' | '?')
+}
 
 /// Fast tokenizer for x86_64 assembly code
-pub struct Tokenizer {
-    input: Vec<char>,
-    position: usize,
+pub struct Tokenizer<'src> {
+    input: &'src str,
+    /// Byte offset of the cursor in the source
+    offset: usize,
     line: usize,
     column: usize,
-    tokens: Vec<Token>,
-    // Adding a cache to improve performance for repeated lookups
-    instruction_cache: HashMap<String, Option<TokenType>>,
-    register_cache: HashMap<String, Option<TokenType>>,
+    tokens: Vec<Token<'src>>,
+    diagnostics: Vec<TokenizerDiagnostic>,
+    suppressions: Vec<Suppression>,
+    /// Report mnemonics, registers and directives that aren't lowercase
+    strict_case: bool,
 }
 
-impl Tokenizer {
+impl<'src> Tokenizer<'src> {
     /// Create a new tokenizer for the given input string
     #[inline(always)]
-    pub fn new(input: &str) -> Self {
+    pub fn new(input: &'src str) -> Self {
         Self {
-            input: input.chars().collect(),
-            position: 0,
+            input,
+            offset: 0,
             line: 1,
             column: 1,
             tokens: Vec::with_capacity(input.len() / 4), // Estimate token count
-            instruction_cache: HashMap::new(),
-            register_cache: HashMap::new(),
+            diagnostics: Vec::new(),
+            suppressions: Vec::new(),
+            strict_case: false,
+        }
+    }
+
+    /// Require mnemonics, registers and directives to be written in lowercase.
+    /// Other spellings are still recognized, but each one is reported.
+    pub fn with_strict_case(mut self, strict: bool) -> Self {
+        self.strict_case = strict;
+        self
+    }
+
+    /// Problems found by the last `tokenize` call
+    pub fn diagnostics(&self) -> &[TokenizerDiagnostic] {
+        &self.diagnostics
+    }
+
+    /// Warning suppression comments found by the last `tokenize` call
+    pub fn suppressions(&self) -> &[Suppression] {
+        &self.suppressions
+    }
+
+    /// Record a `nasembler: allow(...)` or `nasembler: warn(...)` comment
+    fn record_suppression(&mut self, comment: &Token, after_code: bool) {
+        let Some(rest) = comment.value.strip_prefix("nasembler:") else {
+            return;
+        };
+        let rest = rest.trim();
+        let (allow, names) = if let Some(names) = rest.strip_prefix("allow(") {
+            (true, names)
+        } else if let Some(names) = rest.strip_prefix("warn(") {
+            (false, names)
+        } else {
+            return;
+        };
+        let Some(names) = names.trim_end().strip_suffix(')') else {
+            return;
+        };
+        let names: Vec<String> = names.split(',').map(|n| n.trim().to_string()).filter(|n| !n.is_empty()).collect();
+        let line = comment.line;
+
+        if allow {
+            let last_line = if after_code { line } else { usize::MAX };
+            self.suppressions.push(Suppression { names, first_line: line, last_line, column: comment.column });
+            return;
+        }
+
+        // End the open regions for these names; other names in them stay silenced
+        let mut reopened = Vec::new();
+        for region in self.suppressions.iter_mut().filter(|s| s.last_line == usize::MAX) {
+            if region.names.iter().any(|n| names.contains(n)) {
+                region.last_line = line;
+                let rest: Vec<String> = region.names.iter().filter(|n| !names.contains(n)).cloned().collect();
+                if !rest.is_empty() {
+                    reopened.push(Suppression { names: rest, first_line: line + 1, last_line: usize::MAX, column: region.column });
+                }
+            }
+        }
+        self.suppressions.extend(reopened);
+    }
+
+    /// Report a string whose closing quote is missing, pointing at the opening quote
+    fn unclosed_string(&mut self, quote: char, line: usize, column: usize, start: usize) {
+        self.diagnostics.push(TokenizerDiagnostic {
+            error_type: ErrorType::UnclosedString,
+            message: format!("Unclosed string literal: missing closing {} before the end of the line", quote),
+            line,
+            column,
+            span: Span::new(start, self.offset),
+            help: None,
+        });
+    }
+
+    /// Report a character that can't start any token
+    fn unexpected_character(&mut self, ch: char) {
+        let help = match ch {
+            '#' => Some("Comments start with ';'".to_string()),
+            '\u{201c}' | '\u{201d}' | '\u{2018}' | '\u{2019}' => Some("Use a plain \" or ' quote; this one was probably pasted from a word processor".to_string()),
+            '/' | '<' | '>' => Some("Expressions support '+', '-', '*', '<<', '>>', '&', '|', '^', '~' and parentheses".to_string()),
+            _ => None,
+        };
+        self.diagnostics.push(TokenizerDiagnostic {
+            error_type: ErrorType::UnexpectedCharacter,
+            message: if ch.is_control() {
+                format!("Unexpected control character '{}'", ch.escape_default())
+            } else {
+                format!("Unexpected character '{}'", ch)
+            },
+            line: self.line,
+            column: self.column,
+            span: Span::new(self.offset, self.offset + ch.len_utf8()),
+            help,
+        });
+    }
+
+    /// Report a keyword that isn't lowercase, in strict-case mode
+    fn check_case(&mut self, value: &str, kind: &str, line: usize, column: usize, start: usize) {
+        if !self.strict_case || !value.bytes().any(|b| b.is_ascii_uppercase()) {
+            return;
         }
+        self.diagnostics.push(TokenizerDiagnostic {
+            error_type: ErrorType::InvalidToken,
+            message: format!("{} '{}' must be written in lowercase", kind, value),
+            line,
+            column,
+            span: Span::new(start, start + value.len()),
+            help: Some(format!("Write '{}', or drop --strict-case to accept any case", value.to_ascii_lowercase())),
+        });
     }
 
     /// Check if we've reached the end of the input
     #[inline(always)]
     fn is_eof(&self) -> bool {
-        self.position >= self.input.len()
+        self.offset >= self.input.len()
     }
 
     /// Get the current character under the cursor
     #[inline(always)]
     fn current_char(&self) -> Option<char> {
-        if self.is_eof() {
-            None
-        } else {
-            Some(self.input[self.position])
-        }
-    }
-
-    /// Peek at the next character without advancing
-    #[inline(always)]
-    fn peek_char(&self) -> Option<char> {
-        if self.position + 1 >= self.input.len() {
-            None
-        } else {
-            Some(self.input[self.position + 1])
-        }
+        self.input[self.offset..].chars().next()
     }
 
     /// Advance to the next character
     #[inline(always)]
     fn advance(&mut self) {
         if let Some(ch) = self.current_char() {
-            self.position += 1;
+            self.offset += ch.len_utf8();
             if ch == '\n' {
                 self.line += 1;
                 self.column = 1;
@@ -378,43 +783,31 @@ impl Tokenizer {
 
     /// Tokenize alphanumeric identifiers (instructions, registers, labels, etc.)
     #[inline]
-    fn tokenize_identifier(&mut self, is_equ: bool) -> Token {
+    fn tokenize_identifier(&mut self, is_equ: bool) -> Token<'src> {
         let start_column = self.column;
-        let mut value = String::new();
+        let start = self.offset;
         
-        // Collect all alphanumeric chars and underscores
+        // The first character was checked by the caller
+        self.advance();
         while let Some(ch) = self.current_char() {
-            if ch.is_alphanumeric() || ch == '_' || ch == '.' || (is_equ && (ch == '$' || ch == '-')) {
-                value.push(ch);
+            if is_identifier_char(ch) || (is_equ && ch == '-') {
                 self.advance();
             } else {
                 break;
             }
         }
-        
-        // Fast path: Check caches first
-        if let Some(cached_type) = self.instruction_cache.get(&value) {
-            if let Some(token_type) = cached_type {
-                return Token::new(token_type.clone(), value, self.line, start_column);
-            }
-        }
-        
-        if let Some(cached_reg_type) = self.register_cache.get(&value) {
-            if let Some(reg_type) = cached_reg_type {
-                return Token::new(reg_type.clone(), value, self.line, start_column);
-            }
-        }
+        let value = &self.input[start..self.offset];
         
         // Determine token type based on the value
-        let token_type = if let Some(&(_, ref instr_type)) = INSTRUCTIONS.get(value.as_str()) {
-            // Cache this lookup for future use
-            self.instruction_cache.insert(value.clone(), Some(instr_type.clone()));
+        let key = UncasedStr::new(value);
+        let token_type = if let Some((_, instr_type)) = INSTRUCTIONS.get(key) {
+            self.check_case(value, "Instruction", self.line, start_column, start);
             instr_type.clone()
-        } else if let Some(reg_type) = REGISTERS.get(&value) {
-            // Cache this lookup for future use
-            self.register_cache.insert(value.clone(), Some(reg_type.clone()));
+        } else if let Some(reg_type) = REGISTERS.get(key) {
+            self.check_case(value, "Register", self.line, start_column, start);
             reg_type.clone()
-        } else if let Some(_) = DIRECTIVES.get(value.as_str()) {
+        } else if DIRECTIVES.contains_key(key) {
+            self.check_case(value, "Directive", self.line, start_column, start);
             TokenType::Directive
         } else if self.current_char() == Some(':') {
             // This is a label definition (will consume the colon later)
@@ -423,9 +816,6 @@ impl Tokenizer {
             // Section names and other dotted identifiers are treated as label references
             TokenType::LabelRef
         } else {
-            // Cache negative lookups too
-            self.instruction_cache.insert(value.clone(), None);
-            self.register_cache.insert(value.clone(), None);
             // This could be a label ref, var name, etc. Let parser decide.
             TokenType::Identifier
         };
@@ -433,25 +823,123 @@ impl Tokenizer {
         Token::new(token_type, value, self.line, start_column)
     }
 
+    /// Tokenize `%name`, `%%label` or `%{...}`; a lone `%` is an unknown token
+    fn tokenize_preprocessor(&mut self) -> Token<'src> {
+        let start_column = self.column;
+        let start_line = self.line;
+        let start = self.offset;
+
+        let token_type = match self.input[start + 1..].chars().next() {
+            Some('%') => {
+                self.advance();
+                self.advance();
+                self.skip_name();
+                TokenType::MacroLocal
+            },
+            Some('{') => {
+                self.advance();
+                self.advance();
+                // Braces may nest, as in %{%{name}}
+                let mut depth = 1;
+                while let Some(ch) = self.current_char() {
+                    if ch == '\n' {
+                        break;
+                    }
+                    self.advance();
+                    match ch {
+                        '{' => depth += 1,
+                        '}' => depth -= 1,
+                        _ => {},
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                if depth > 0 {
+                    self.diagnostics.push(TokenizerDiagnostic {
+                        error_type: ErrorType::InvalidToken,
+                        message: "Unclosed '%{': missing '}' before the end of the line".to_string(),
+                        line: start_line,
+                        column: start_column,
+                        span: Span::new(start, self.offset),
+                        help: None,
+                    });
+                }
+                TokenType::MacroExpansion
+            },
+            Some(ch) if ch.is_alphanumeric() || ch == '_' => {
+                self.advance();
+                self.skip_name();
+                TokenType::Preprocessor
+            },
+            _ => {
+                self.unexpected_character('%');
+                self.advance();
+                TokenType::Unknown
+            },
+        };
+
+        Token::new(token_type, &self.input[start..self.offset], start_line, start_column)
+    }
+
+    /// Tokenize an expression operator or parenthesis. `<` and `>` only
+    /// count when doubled; on their own they are unknown tokens.
+    fn tokenize_operator(&mut self) -> Token<'src> {
+        let start_column = self.column;
+        let start = self.offset;
+        let mut chars = self.input[start..].chars();
+        let ch = chars.next().unwrap_or('\0');
+        let doubled = chars.next() == Some(ch);
+
+        let token_type = match ch {
+            '(' => TokenType::OpenParen,
+            ')' => TokenType::CloseParen,
+            '&' => TokenType::Ampersand,
+            '|' => TokenType::Pipe,
+            '^' => TokenType::Caret,
+            '~' => TokenType::Tilde,
+            '<' if doubled => TokenType::ShiftLeft,
+            '>' if doubled => TokenType::ShiftRight,
+            _ => {
+                self.unexpected_character(ch);
+                TokenType::Unknown
+            },
+        };
+        self.advance();
+        if matches!(token_type, TokenType::ShiftLeft | TokenType::ShiftRight) {
+            self.advance();
+        }
+
+        Token::new(token_type, &self.input[start..self.offset], self.line, start_column)
+    }
+
+    /// Skip the characters of a name following a sigil
+    fn skip_name(&mut self) {
+        while let Some(ch) = self.current_char() {
+            if is_identifier_char(ch) {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+    }
+
     /// Tokenize numeric literals (immediate values)
     #[inline]
-    fn tokenize_number(&mut self) -> Token {
+    fn tokenize_number(&mut self) -> Token<'src> {
         let start_column = self.column;
-        let mut value = String::new();
+        let start = self.offset;
         let mut is_hex = false;
         let mut is_binary = false;
         
         // Check for hex or binary prefix
         if self.current_char() == Some('0') {
-            value.push('0');
             self.advance();
             
             if self.current_char() == Some('x') || self.current_char() == Some('X') {
-                value.push(self.current_char().unwrap());
                 self.advance();
                 is_hex = true;
             } else if self.current_char() == Some('b') || self.current_char() == Some('B') {
-                value.push(self.current_char().unwrap());
                 self.advance();
                 is_binary = true;
             }
@@ -459,52 +947,56 @@ impl Tokenizer {
         
         // Collect all digits and hex/binary chars
         while let Some(ch) = self.current_char() {
-            if ch.is_digit(10) || 
-               (is_hex && (ch.is_digit(16) || ('a'..='f').contains(&ch) || ('A'..='F').contains(&ch))) ||
+            if ch.is_ascii_digit() || 
+               (is_hex && ch.is_ascii_hexdigit()) ||
                (is_binary && (ch == '0' || ch == '1')) {
-                value.push(ch);
                 self.advance();
             } else {
                 break;
             }
         }
 
-        Token::new(TokenType::Immediate, value, self.line, start_column)
+        Token::new(TokenType::Immediate, &self.input[start..self.offset], self.line, start_column)
     }
 
-    /// Tokenize string literals (enclosed in quotes)
+    /// Tokenize string literals enclosed in `quote`. The value borrows the
+    /// source unless an escape sequence has to be translated.
     #[inline]
-    fn tokenize_string(&mut self) -> Token {
+    fn tokenize_string(&mut self, quote: char) -> Token<'src> {
         let start_column = self.column;
-        let mut value = String::new();
         let start_line = self.line;
+        let start = self.offset;
         
         // Skip the opening quote
         self.advance();
+        let content_start = self.offset;
         
         // Collect everything until the closing quote, handling escapes
+        let mut escaped: Option<String> = None;
         let mut is_escaped = false;
         let mut found_closing_quote = false;
+        let mut content_end = self.offset;
         
         while let Some(ch) = self.current_char() {
             if is_escaped {
                 // Handle escaped character
+                let value = escaped.get_or_insert_with(String::new);
                 match ch {
                     'n' => value.push('\n'),
                     't' => value.push('\t'),
                     'r' => value.push('\r'),
-                    '\\' => value.push('\\'),
-                    '"' => value.push('"'),
-                    '\'' => value.push('\''),
                     '0' => value.push('\0'),
                     _ => value.push(ch),
                 }
                 is_escaped = false;
                 self.advance();
             } else if ch == '\\' {
+                // Switch to an owned value holding what came before
+                escaped.get_or_insert_with(|| self.input[content_start..self.offset].to_string());
                 is_escaped = true;
                 self.advance();
-            } else if ch == '"' {
+            } else if ch == quote {
+                content_end = self.offset;
                 self.advance(); // Skip the closing quote
                 found_closing_quote = true;
                 break;
@@ -512,106 +1004,51 @@ impl Tokenizer {
                 // We've hit a newline without closing the string
                 break;
             } else {
-                value.push(ch);
+                if let Some(value) = &mut escaped {
+                    value.push(ch);
+                }
                 self.advance();
             }
         }
-
-        // Check if we found the closing quote
+        // The token is still produced so the rest of the line parses
         if !found_closing_quote {
-            // Create a token, but also indicate the error
-            let token = Token::new(TokenType::StringLiteral, value, start_line, start_column);
-            // Note: Since the tokenizer doesn't have a reference to the error collector,
-            // we'll need to detect this issue in the parser
-            return token;
+            content_end = self.offset;
+            self.unclosed_string(quote, start_line, start_column, start);
         }
 
+        let value = match escaped {
+            Some(value) => Cow::Owned(value),
+            None => Cow::Borrowed(&self.input[content_start..content_end]),
+        };
         Token::new(TokenType::StringLiteral, value, start_line, start_column)
     }
 
     /// Tokenize comments (starting with ; or #)
     #[inline]
-    fn tokenize_comment(&mut self) -> Token {
+    fn tokenize_comment(&mut self) -> Token<'src> {
         let start_column = self.column;
-        let mut value = String::new();
         
         // Skip the comment marker (;)
         self.advance();
+        let start = self.offset;
         
         // Collect everything until the end of the line
         while let Some(ch) = self.current_char() {
             if ch == '\n' {
                 break;
-            } else {
-                value.push(ch);
-                self.advance();
             }
+            self.advance();
         }
 
-        Token::new(TokenType::Comment, value.trim().to_string(), self.line, start_column)
-    }
-
-    /// Tokenize string literals enclosed in single quotes
-    #[inline]
-    fn tokenize_single_quoted_string(&mut self) -> Token {
-        let start_column = self.column;
-        let mut value = String::new();
-        let start_line = self.line;
-        
-        // Skip the opening quote
-        self.advance();
-        
-        // Collect everything until the closing quote, handling escapes
-        let mut is_escaped = false;
-        let mut found_closing_quote = false;
-        
-        while let Some(ch) = self.current_char() {
-            if is_escaped {
-                // Handle escaped character
-                match ch {
-                    'n' => value.push('\n'),
-                    't' => value.push('\t'),
-                    'r' => value.push('\r'),
-                    '\\' => value.push('\\'),
-                    '\'' => value.push('\''),
-                    '"' => value.push('"'),
-                    '0' => value.push('\0'),
-                    _ => value.push(ch),
-                }
-                is_escaped = false;
-                self.advance();
-            } else if ch == '\\' {
-                is_escaped = true;
-                self.advance();
-            } else if ch == '\'' {
-                self.advance(); // Skip the closing quote
-                found_closing_quote = true;
-                break;
-            } else if ch == '\n' {
-                // We've hit a newline without closing the string
-                break;
-            } else {
-                value.push(ch);
-                self.advance();
-            }
-        }
-
-        // Check if we found the closing quote
-        if !found_closing_quote {
-            // Create a token, but also indicate the error
-            let token = Token::new(TokenType::StringLiteral, value, start_line, start_column);
-            // Note: Since the tokenizer doesn't have a reference to the error collector,
-            // we'll need to detect this issue in the parser
-            return token;
-        }
-
-        Token::new(TokenType::StringLiteral, value, start_line, start_column)
+        Token::new(TokenType::Comment, self.input[start..self.offset].trim(), self.line, start_column)
     }
 
     /// Tokenize the entire input
     #[inline]
-    pub fn tokenize(&mut self) -> &Vec<Token> {
+    pub fn tokenize(&mut self) -> &Vec<Token<'src>> {
         while !self.is_eof() {
+            let start = self.offset;
+            let token_count = self.tokens.len();
             match self.current_char() {
                 Some(ch) if ch.is_whitespace() && ch != '\n' => {
                     self.skip_whitespace();
@@ -619,36 +1056,42 @@ impl Tokenizer {
                 Some('\n') => {
                     self.tokens.push(Token::new(
                         TokenType::NewLine, 
-                        "\n".to_string(), 
+                        "\n", 
                         self.line, 
                         self.column
                     ));
                     self.advance();
                 },
-                Some(ch) if ch.is_alphabetic() || ch == '_' || ch == '.' => {
+                Some(ch) if is_identifier_start(ch) => {
                     let token = self.tokenize_identifier(false);
                     self.tokens.push(token);
                 },
-                Some(ch) if ch.is_digit(10) => {
+                Some(ch) if ch.is_ascii_digit() => {
                     let token = self.tokenize_number();
                     self.tokens.push(token);
                 },
                 Some(';') => {
                     let token = self.tokenize_comment();
+                    let after_code = self.tokens.last().is_some_and(|t| t.token_type != TokenType::NewLine);
+                    self.record_suppression(&token, after_code);
                     self.tokens.push(token);
                 },
                 Some('"') => {
-                    let token = self.tokenize_string();
+                    let token = self.tokenize_string('"');
+                    self.tokens.push(token);
+                },
+                Some('%') => {
+                    let token = self.tokenize_preprocessor();
                     self.tokens.push(token);
                 },
                 Some('\'') => {
-                    let token = self.tokenize_single_quoted_string();
+                    let token = self.tokenize_string('\'');
                     self.tokens.push(token);
                 },
                 Some(',') => {
                     self.tokens.push(Token::new(
                         TokenType::Comma, 
-                        ",".to_string(), 
+                        ",", 
                         self.line, 
                         self.column
                     ));
@@ -657,7 +1100,7 @@ impl Tokenizer {
                 Some(':') => {
                     self.tokens.push(Token::new(
                         TokenType::Colon, 
-                        ":".to_string(), 
+                        ":", 
                         self.line, 
                         self.column
                     ));
@@ -666,7 +1109,7 @@ impl Tokenizer {
                 Some('+') => {
                     self.tokens.push(Token::new(
                         TokenType::Plus, 
-                        "+".to_string(), 
+                        "+", 
                         self.line, 
                         self.column
                     ));
@@ -675,7 +1118,7 @@ impl Tokenizer {
                 Some('-') => {
                     self.tokens.push(Token::new(
                         TokenType::Minus, 
-                        "-".to_string(), 
+                        "-", 
                         self.line, 
                         self.column
                     ));
@@ -684,16 +1127,20 @@ impl Tokenizer {
                 Some('*') => {
                     self.tokens.push(Token::new(
                         TokenType::Asterisk, 
-                        "*".to_string(), 
+                        "*", 
                         self.line, 
                         self.column
                     ));
                     self.advance();
                 },
+                Some('(' | ')' | '&' | '|' | '^' | '~' | '<' | '>') => {
+                    let token = self.tokenize_operator();
+                    self.tokens.push(token);
+                },
                 Some('[') => {
                     self.tokens.push(Token::new(
                         TokenType::OpenBracket, 
-                        "[".to_string(), 
+                        "[", 
                         self.line, 
                         self.column
                     ));
@@ -702,59 +1149,77 @@ impl Tokenizer {
                 Some(']') => {
                     self.tokens.push(Token::new(
                         TokenType::CloseBracket, 
-                        "]".to_string(), 
+                        "]", 
                         self.line, 
                         self.column
                     ));
                     self.advance();
                 },
                 Some(ch) => {
-                    // Unknown token
+                    // Unknown token; '$' is the location counter in equ expressions
+                    if ch != '$' {
+                        self.unexpected_character(ch);
+                    }
+                    let text = &self.input[self.offset..self.offset + ch.len_utf8()];
                     self.tokens.push(Token::new(
-                        TokenType::Unknown, 
-                        ch.to_string(), 
-                        self.line, 
+                        TokenType::Unknown,
+                        text,
+                        self.line,
                         self.column
                     ));
                     self.advance();
                 },
                 None => break,
             }
+            if self.tokens.len() > token_count {
+                if let Some(token) = self.tokens.last_mut() {
+                    token.span = Span::new(start, self.offset);
+                }
+            }
         }
         
         // Add EOF token
-        self.tokens.push(Token::new(
+        let mut eof = Token::new(
             TokenType::EOF,
-            "".to_string(),
+            "",
             self.line,
             self.column
-        ));
+        );
+        eof.span = Span::new(self.offset, self.offset);
+        self.tokens.push(eof);
         
         &self.tokens
     }
 
+    /// The tokens of the last `tokenize` call, without copying them
+    pub fn into_tokens(self) -> Vec<Token<'src>> {
+        self.tokens
+    }
+
     /// Tokenize an expression for the equ directive
-    pub fn tokenize_equ_expression(&mut self, input: &str) -> Vec<Token> {
+    pub fn tokenize_equ_expression<'a>(&mut self, input: &'a str) -> Vec<Token<'a>> {
         let mut tokenizer = Tokenizer::new(input);
         let mut tokens = Vec::new();
         
         while !tokenizer.is_eof() {
+            let start = tokenizer.offset;
+            let token_count = tokens.len();
             match tokenizer.current_char() {
                 Some(ch) if ch.is_whitespace() && ch != '\n' => {
                     tokenizer.skip_whitespace();
                 },
-                Some(ch) if ch.is_alphabetic() || ch == '_' || ch == '.' => {
+                Some(ch) if is_identifier_start(ch) => {
                     let token = tokenizer.tokenize_identifier(true);
                     tokens.push(token);
                 },
-                Some(ch) if ch.is_digit(10) => {
+                Some(ch) if ch.is_ascii_digit() => {
                     let token = tokenizer.tokenize_number();
                     tokens.push(token);
                 },
                 Some('+') => {
                     tokens.push(Token::new(
                         TokenType::Plus, 
-                        "+".to_string(), 
+                        "+", 
                         tokenizer.line, 
                         tokenizer.column
                     ));
@@ -763,7 +1228,7 @@ impl Tokenizer {
                 Some('-') => {
                     tokens.push(Token::new(
                         TokenType::Minus, 
-                        "-".to_string(), 
+                        "-", 
                         tokenizer.line, 
                         tokenizer.column
                     ));
@@ -772,17 +1237,22 @@ impl Tokenizer {
                 Some('*') => {
                     tokens.push(Token::new(
                         TokenType::Asterisk, 
-                        "*".to_string(), 
+                        "*", 
                         tokenizer.line, 
                         tokenizer.column
                     ));
                     tokenizer.advance();
                 },
+                Some('(' | ')' | '&' | '|' | '^' | '~' | '<' | '>') => {
+                    let token = tokenizer.tokenize_operator();
+                    tokens.push(token);
+                },
                 Some(ch) => {
                     // Unknown token
+                    let text = &input[tokenizer.offset..tokenizer.offset + ch.len_utf8()];
                     tokens.push(Token::new(
                         TokenType::Unknown, 
-                        ch.to_string(), 
+                        text, 
                         tokenizer.line, 
                         tokenizer.column
                     ));
@@ -790,12 +1260,138 @@ impl Tokenizer {
                 },
                 None => break,
             }
+            if let Some(token) = tokens.get_mut(token_count) {
+                token.span = Span::new(start, tokenizer.offset);
+            }
         }
         
         tokens
     }
 }
 
+/// Tokenizer that reads its input a line at a time and yields tokens as it
+/// goes, so callers can start on the first lines before the rest has arrived.
+///
+/// No token spans a line break, so each line is tokenized on its own; line
+/// numbers, spans and suppression regions still count from the start of the
+/// whole input. The last token is always `EOF`.
+///
+/// ```
+/// use nasembler::tokenizer::{StreamTokenizer, TokenType};
+///
+/// let source = "_start:\n    syscall\n";
+/// let tokens: Vec<_> = StreamTokenizer::new(source.as_bytes()).collect::<Result<_, _>>().unwrap();
+/// assert_eq!(tokens[3].value, "syscall");
+/// assert_eq!(tokens[3].line, 2);
+/// assert_eq!(tokens.last().unwrap().token_type, TokenType::EOF);
+/// ```
+pub struct StreamTokenizer<R> {
+    reader: R,
+    line_buffer: String,
+    /// Tokens of the last line read that haven't been yielded yet
+    pending: VecDeque<Token<'static>>,
+    /// Line number and byte offset of the start of the next line
+    line: usize,
+    offset: usize,
+    /// Yielded once the input runs out
+    eof: Option<Token<'static>>,
+    diagnostics: Vec<TokenizerDiagnostic>,
+    suppressions: Vec<Suppression>,
+    strict_case: bool,
+}
+
+impl<R: BufRead> StreamTokenizer<R> {
+    /// Create a streaming tokenizer reading from `reader`
+    pub fn new(reader: R) -> Self {
+        let mut eof = Token::new(TokenType::EOF, "", 1, 1);
+        eof.span = Span::new(0, 0);
+        Self {
+            reader,
+            line_buffer: String::new(),
+            pending: VecDeque::new(),
+            line: 1,
+            offset: 0,
+            eof: Some(eof),
+            diagnostics: Vec::new(),
+            suppressions: Vec::new(),
+            strict_case: false,
+        }
+    }
+
+    /// Require mnemonics, registers and directives to be written in lowercase,
+    /// as with [`Tokenizer::with_strict_case`]
+    pub fn with_strict_case(mut self, strict: bool) -> Self {
+        self.strict_case = strict;
+        self
+    }
+
+    /// Problems found in the lines read so far
+    pub fn diagnostics(&self) -> &[TokenizerDiagnostic] {
+        &self.diagnostics
+    }
+
+    /// Warning suppression comments found in the lines read so far
+    pub fn suppressions(&self) -> &[Suppression] {
+        &self.suppressions
+    }
+
+    /// Tokenize the next line into `pending`; false at the end of the input
+    fn read_line(&mut self) -> io::Result<bool> {
+        self.line_buffer.clear();
+        if self.reader.read_line(&mut self.line_buffer)? == 0 {
+            return Ok(false);
+        }
+
+        let mut tokenizer = Tokenizer::new(&self.line_buffer).with_strict_case(self.strict_case);
+        tokenizer.line = self.line;
+        // Open `allow` regions carry over from earlier lines
+        tokenizer.suppressions = std::mem::take(&mut self.suppressions);
+        tokenizer.tokenize();
+
+        let base = self.offset;
+        let shift = |span: Span| Span::new(span.start + base, span.end + base);
+        let mut tokens = tokenizer.tokens;
+        self.eof = tokens.pop().map(|mut eof| {
+            eof.span = shift(eof.span);
+            eof.into_owned()
+        });
+        self.pending.extend(tokens.into_iter().map(|mut token| {
+            token.span = shift(token.span);
+            token.into_owned()
+        }));
+        self.diagnostics.extend(tokenizer.diagnostics.into_iter().map(|mut diagnostic| {
+            diagnostic.span = shift(diagnostic.span);
+            diagnostic
+        }));
+        self.suppressions = tokenizer.suppressions;
+        self.line = tokenizer.line;
+        self.offset += self.line_buffer.len();
+        Ok(true)
+    }
+}
+
+impl<R: BufRead> Iterator for StreamTokenizer<R> {
+    type Item = io::Result<Token<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.pending.pop_front() {
+                return Some(Ok(token));
+            }
+            // The EOF token is taken once the input runs out, ending the stream
+            self.eof.as_ref()?;
+            match self.read_line() {
+                Ok(true) => {},
+                Ok(false) => return self.eof.take().map(Ok),
+                Err(err) => {
+                    self.eof = None;
+                    return Some(Err(err));
+                },
+            }
+        }
+    }
+}
+
 // Function to format tokens for pretty printing
 pub fn format_tokens(tokens: &[Token]) -> String {
     let mut result = String::new();
@@ -806,7 +1402,7 @@ pub fn format_tokens(tokens: &[Token]) -> String {
     for token in tokens {
         if token.token_type == TokenType::NewLine {
             line_num += 1;
-            result.push_str("\n");
+            result.push('\n');
             result.push_str(&format!("Line {:4} | ", line_num));
         } else if token.token_type != TokenType::EOF {
             result.push_str(&format!("{} ", token));
@@ -0,0 +1,289 @@
+//! Logging the system calls a program makes.
+//!
+//! [`trace`] runs a program under `ptrace` and reports each syscall it makes,
+//! with its arguments and return value, and each signal it receives. Buffers
+//! passed to `read` and `write` are shown as text, and failed calls as the
+//! errno name they return. Programs running in 32-bit mode are traced with
+//! the i386 syscall numbers and argument registers.
+//!
+//! Only the program's own process is followed, not children it forks.
+//!
+//! ```no_run
+//! use std::process::Command;
+//!
+//! let status = nasembler::trace::trace(&mut Command::new("./hello"), |event| eprintln!("{}", event)).unwrap();
+//! println!("exited with {:?}", status.code());
+//! ```
+
+use std::fmt;
+use std::io;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus};
+use std::ptr;
+
+use libc::{c_void, pid_t};
+
+use crate::ptrace::{self, registers, request, wait, RequestError};
+use crate::syscalls;
+
+/// Buffer bytes shown for `read` and `write`
+const SHOWN_BYTES: usize = 32;
+/// Return values from -1 to this are errno values
+const MAX_ERRNO: i64 = 4095;
+
+/// Failure while tracing a program
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TraceError {
+    /// The program couldn't be started
+    Spawn(io::Error),
+    /// A ptrace or wait request on the program failed
+    Ptrace { request: &'static str, source: io::Error },
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Spawn(_) => write!(f, "Failed to start the program"),
+            TraceError::Ptrace { request, .. } => write!(f, "{} failed while tracing the program", request),
+        }
+    }
+}
+
+impl From<RequestError> for TraceError {
+    fn from(error: RequestError) -> Self {
+        TraceError::Ptrace { request: error.request, source: error.source }
+    }
+}
+
+impl std::error::Error for TraceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TraceError::Spawn(source) | TraceError::Ptrace { source, .. } => Some(source),
+        }
+    }
+}
+
+/// A syscall made by the traced program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Syscall {
+    pub number: u64,
+    /// Mode of the code that made the call, which decides what `number` means
+    pub bits: u16,
+    pub arguments: [u64; 6],
+    /// The return value; `None` for calls that don't return, like `exit`
+    pub result: Option<i64>,
+    /// Start of the buffer a `read` or `write` transferred
+    pub data: Option<Vec<u8>>,
+}
+
+impl Syscall {
+    /// The syscall's name, without the `SYS_` prefix
+    pub fn name(&self) -> Option<&'static str> {
+        syscalls::syscall_name(self.number as i64, self.bits)
+    }
+
+    fn enter(registers: &libc::user_regs_struct) -> Self {
+        let (number, bits) = ptrace::syscall_number(registers);
+        let arguments = if bits == 32 {
+            [registers.rbx, registers.rcx, registers.rdx, registers.rsi, registers.rdi, registers.rbp].map(|value| value & 0xFFFF_FFFF)
+        } else {
+            [registers.rdi, registers.rsi, registers.rdx, registers.r10, registers.r8, registers.r9]
+        };
+        Self { number, bits, arguments, result: None, data: None }
+    }
+
+    fn returns(&self) -> bool {
+        !matches!(self.name(), Some("exit" | "exit_group"))
+    }
+
+    /// A register value as the program sees it, sign-extended from 32 bits in 32-bit code
+    fn signed(&self, value: u64) -> i64 {
+        if self.bits == 32 { value as u32 as i32 as i64 } else { value as i64 }
+    }
+
+    /// Bytes the call moved through its buffer
+    fn transferred(&self) -> usize {
+        match self.name() {
+            Some("write") => self.arguments[2] as usize,
+            _ => self.result.map_or(0, |result| result.max(0) as usize),
+        }
+    }
+}
+
+impl fmt::Display for Syscall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.name();
+        match name {
+            Some(name) => write!(f, "{}(", name)?,
+            None => write!(f, "syscall_{}(", self.number)?,
+        }
+        for (index, &argument) in self.arguments.iter().take(argument_count(name)).enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            match &self.data {
+                Some(data) if index == 1 => write_buffer(f, data, self.transferred())?,
+                _ => write_value(f, self.signed(argument))?,
+            }
+        }
+        write!(f, ") = ")?;
+        match self.result {
+            None => write!(f, "?"),
+            Some(result) if (-MAX_ERRNO..0).contains(&result) => match syscalls::errno_name(-result) {
+                Some(errno) => write!(f, "-1 {}", errno),
+                None => write!(f, "-1 errno {}", -result),
+            },
+            Some(result) => write_value(f, result),
+        }
+    }
+}
+
+/// Something the traced program did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Syscall(Syscall),
+    /// The program received the signal with this number
+    Signal(i32),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Syscall(syscall) => write!(f, "{}", syscall),
+            Event::Signal(signal) => match syscalls::signal_name(i64::from(*signal)) {
+                Some(name) => write!(f, "--- {} ---", name),
+                None => write!(f, "--- signal {} ---", signal),
+            },
+        }
+    }
+}
+
+/// Number of arguments a syscall takes; all six registers for ones not listed
+fn argument_count(name: Option<&str>) -> usize {
+    match name.unwrap_or_default() {
+        "fork" | "vfork" | "getpid" | "getppid" | "gettid" | "getuid" | "geteuid" | "getgid" | "getegid"
+        | "getpgrp" | "setsid" | "sched_yield" | "pause" | "rt_sigreturn" => 0,
+        "close" | "exit" | "exit_group" | "brk" | "pipe" | "dup" | "alarm" | "uname" | "chdir" | "fchdir"
+        | "rmdir" | "unlink" | "fsync" | "fdatasync" | "umask" | "time" | "set_tid_address" | "setuid"
+        | "setgid" | "sysinfo" | "times" => 1,
+        "stat" | "fstat" | "lstat" | "munmap" | "access" | "pipe2" | "dup2" | "nanosleep" | "listen"
+        | "shutdown" | "kill" | "mkdir" | "rename" | "link" | "symlink" | "chmod" | "fchmod" | "creat"
+        | "getcwd" | "arch_prctl" | "clock_gettime" | "gettimeofday" | "truncate" | "ftruncate"
+        | "setpgid" | "getrlimit" | "getrusage" | "flock" | "memfd_create" => 2,
+        "read" | "write" | "open" | "poll" | "lseek" | "mprotect" | "ioctl" | "readv" | "writev" | "socket"
+        | "connect" | "accept" | "bind" | "execve" | "fcntl" | "getdents" | "getdents64" | "getrandom"
+        | "readlink" | "chown" | "fchown" | "mkdirat" | "unlinkat" | "tgkill" | "madvise" | "mincore"
+        | "msync" | "getsockname" | "getpeername" => 3,
+        "openat" | "wait4" | "rt_sigaction" | "rt_sigprocmask" | "pread64" | "pwrite64" | "sendfile"
+        | "accept4" | "socketpair" | "clock_nanosleep" | "mremap" => 4,
+        "clone" | "setsockopt" | "getsockopt" | "execveat" | "select" => 5,
+        _ => 6,
+    }
+}
+
+/// Small values and errno-range negatives in decimal, addresses in hex
+fn write_value(f: &mut fmt::Formatter<'_>, value: i64) -> fmt::Result {
+    if (-MAX_ERRNO..0x10000).contains(&value) {
+        write!(f, "{}", value)
+    } else {
+        write!(f, "{:#x}", value)
+    }
+}
+
+/// A buffer as a quoted string, marking bytes that weren't shown
+fn write_buffer(f: &mut fmt::Formatter<'_>, data: &[u8], length: usize) -> fmt::Result {
+    write!(f, "\"")?;
+    for &byte in data {
+        match byte {
+            b'\n' => write!(f, "\\n")?,
+            b'\t' => write!(f, "\\t")?,
+            b'"' => write!(f, "\\\"")?,
+            b'\\' => write!(f, "\\\\")?,
+            0x20..=0x7E => write!(f, "{}", byte as char)?,
+            _ => write!(f, "\\x{:02x}", byte)?,
+        }
+    }
+    write!(f, "\"")?;
+    if length > data.len() {
+        write!(f, "...")?;
+    }
+    Ok(())
+}
+
+/// Run `command` to completion, calling `on_event` for each syscall it makes and
+/// each signal it receives. Signals are passed on to the program as usual.
+pub fn trace(command: &mut Command, mut on_event: impl FnMut(&Event)) -> Result<ExitStatus, TraceError> {
+    // SAFETY: the hook only makes a single async-signal-safe call
+    unsafe {
+        command.pre_exec(|| {
+            if libc::ptrace(libc::PTRACE_TRACEME, 0, ptr::null_mut::<c_void>(), ptr::null_mut::<c_void>()) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn().map_err(TraceError::Spawn)?;
+    let pid = child.id() as pid_t;
+
+    // The program stops with SIGTRAP once execve has loaded it
+    let status = wait(pid)?;
+    if !libc::WIFSTOPPED(status) {
+        return Ok(ExitStatus::from_raw(status));
+    }
+    let options = libc::PTRACE_O_TRACESYSGOOD | libc::PTRACE_O_TRACEEXEC | libc::PTRACE_O_EXITKILL;
+    request("PTRACE_SETOPTIONS", pid, libc::PTRACE_SETOPTIONS, options as usize)?;
+
+    let mut pending: Option<Syscall> = None;
+    let mut signal = 0;
+    loop {
+        request("PTRACE_SYSCALL", pid, libc::PTRACE_SYSCALL, signal as usize)?;
+        let status = wait(pid)?;
+        signal = 0;
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            if let Some(syscall) = pending.take() {
+                on_event(&Event::Syscall(syscall));
+            }
+            return Ok(ExitStatus::from_raw(status));
+        }
+
+        let stop = libc::WSTOPSIG(status);
+        if stop == libc::SIGTRAP | 0x80 {
+            let registers = registers(pid)?;
+            match pending.take() {
+                Some(mut syscall) => {
+                    syscall.result = Some(syscall.signed(registers.rax));
+                    if matches!(syscall.name(), Some("read" | "write")) {
+                        syscall.data = read_memory(pid, syscall.arguments[1], syscall.transferred().min(SHOWN_BYTES));
+                    }
+                    on_event(&Event::Syscall(syscall));
+                },
+                None => {
+                    let syscall = Syscall::enter(&registers);
+                    if syscall.returns() {
+                        pending = Some(syscall);
+                    } else {
+                        on_event(&Event::Syscall(syscall));
+                    }
+                },
+            }
+        } else if stop == libc::SIGTRAP && status >> 16 != 0 {
+            // A ptrace event, such as the program calling execve; not a signal
+        } else {
+            on_event(&Event::Signal(stop));
+            signal = stop;
+        }
+    }
+}
+
+/// Up to `length` bytes at `address` in the program, if it's readable
+fn read_memory(pid: pid_t, address: u64, length: usize) -> Option<Vec<u8>> {
+    let mut buffer = vec![0u8; length];
+    let local = libc::iovec { iov_base: buffer.as_mut_ptr() as *mut c_void, iov_len: length };
+    let remote = libc::iovec { iov_base: address as *mut c_void, iov_len: length };
+    // SAFETY: `local` covers `buffer`; the kernel checks `remote` against the program's memory
+    let read = unsafe { libc::process_vm_readv(pid, &local, 1, &remote, 1, 0) };
+    let read = usize::try_from(read).ok()?;
+    buffer.truncate(read);
+    Some(buffer)
+}
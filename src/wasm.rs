@@ -0,0 +1,97 @@
+//! WebAssembly bindings (`wasm` feature).
+//!
+//! Build with `wasm-pack build --target web --features wasm` and call
+//! `assemble(source)` from JavaScript:
+//!
+//! ```js
+//! import init, { assemble } from "./pkg/nasembler.js";
+//!
+//! await init();
+//! const result = assemble("section .text\n_start:\n    syscall\n");
+//! if (result.success) {
+//!     download(result.bytes);
+//! } else {
+//!     for (const d of result.diagnostics) console.log(`${d.line}:${d.column} ${d.message}`);
+//! }
+//! ```
+
+use wasm_bindgen::prelude::*;
+
+use crate::assembler::{Assembler, Format};
+use crate::error::{Error, ErrorSeverity};
+
+/// A single diagnostic reported during assembly
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    /// "fatal", "error", "warning" or "info"
+    pub severity: String,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl From<&Error> for Diagnostic {
+    fn from(error: &Error) -> Self {
+        let severity = match error.severity {
+            ErrorSeverity::Fatal => "fatal",
+            ErrorSeverity::Error => "error",
+            ErrorSeverity::Warning => "warning",
+            ErrorSeverity::Info => "info",
+        };
+
+        Self {
+            severity: severity.to_string(),
+            message: error.detail.message.clone(),
+            file: error.location.as_ref().map(|location| location.file.clone()),
+            line: error.location.as_ref().map_or(0, |location| location.line),
+            column: error.location.as_ref().map_or(0, |location| location.column),
+        }
+    }
+}
+
+/// Output bytes and diagnostics of one `assemble` call
+#[wasm_bindgen]
+pub struct AssembleResult {
+    success: bool,
+    bytes: Vec<u8>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+#[wasm_bindgen]
+impl AssembleResult {
+    /// Whether assembly succeeded and `bytes` holds the output
+    #[wasm_bindgen(getter)]
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    /// Assembled output (empty on failure)
+    #[wasm_bindgen(getter)]
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    /// Errors and warnings, in the order they were reported
+    #[wasm_bindgen(getter)]
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.clone()
+    }
+}
+
+/// Assemble `source` into an ELF executable entirely in memory
+#[wasm_bindgen]
+pub fn assemble(source: &str) -> AssembleResult {
+    let mut assembler = Assembler::new()
+        .format(Format::Elf)
+        .file_name("<input>");
+
+    let result = assembler.assemble(source);
+    let diagnostics = assembler.errors().errors().iter().map(Diagnostic::from).collect();
+
+    match result {
+        Ok(bytes) => AssembleResult { success: true, bytes, diagnostics },
+        Err(_) => AssembleResult { success: false, bytes: Vec::new(), diagnostics },
+    }
+}